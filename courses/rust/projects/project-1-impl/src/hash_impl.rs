@@ -1,5 +1,5 @@
 #![deny(missing_docs)]
-use std::collections::HashMap;
+use std::collections::{hash_map, HashMap};
 
 ///
 /// KvStore is a in-memory key-value store,
@@ -39,6 +39,26 @@ impl KvStore {
         }
     }
 
+    /// new a KvStore instance with room for at least `capacity` key-value pairs,
+    /// so that inserting them doesn't rehash
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::KvStore;
+    ///
+    /// let mut kv = KvStore::with_capacity(1000);
+    /// for i in 0..1000 {
+    ///     kv.set(format!("key{}", i), format!("value{}", i));
+    /// }
+    /// assert_eq!(kv.get("key999".to_owned()), Some("value999".to_owned()));
+    /// ```
+    pub fn with_capacity(capacity: usize) -> Self {
+        KvStore {
+            map: HashMap::with_capacity(capacity),
+        }
+    }
+
     /// insert a key-value pair if key is not in store else overwrite the key-value
     ///
     /// # Example
@@ -72,6 +92,35 @@ impl KvStore {
         self.map.get(&key).cloned()
     }
 
+    /// get a value by key without cloning it, as a borrow into the store
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::KvStore;
+    ///
+    /// let mut kv = KvStore::new();
+    /// kv.set("test".to_owned(), "test1".to_owned());
+    /// let v: Option<&str> = kv.get_ref("test");
+    /// assert_eq!(v, Some("test1"));
+    /// assert_eq!(v.map(str::len), Some(5));
+    /// ```
+    ///
+    /// The value is borrowed from the store, so the store can't be changed
+    /// while the borrow is alive. Use `get` to keep the value across writes:
+    ///
+    /// ```compile_fail
+    /// use kvs::KvStore;
+    ///
+    /// let mut kv = KvStore::new();
+    /// kv.set("test".to_owned(), "test1".to_owned());
+    /// let v = kv.get_ref("test");
+    /// kv.remove("test".to_owned());
+    /// println!("{:?}", v);
+    /// ```
+    pub fn get_ref(&self, key: &str) -> Option<&str> {
+        self.map.get(key).map(String::as_str)
+    }
+
     /// remove a key-value by key
     ///
     /// # Example
@@ -88,4 +137,70 @@ impl KvStore {
     pub fn remove(&mut self, key: String) -> Option<String> {
         self.map.remove(&key)
     }
+
+    /// iterate over the key-value pairs, in no particular order
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::KvStore;
+    ///
+    /// let mut kv = KvStore::new();
+    /// kv.set("key1".to_owned(), "value1".to_owned());
+    /// kv.set("key2".to_owned(), "value2".to_owned());
+    /// let mut pairs: Vec<(&String, &String)> = kv.iter().collect();
+    /// pairs.sort();
+    /// assert_eq!(pairs[0], (&"key1".to_owned(), &"value1".to_owned()));
+    /// assert_eq!(pairs.len(), 2);
+    /// ```
+    pub fn iter(&self) -> Iter<'_> {
+        Iter(self.map.iter())
+    }
+}
+
+/// An iterator over the key-value pairs of a `KvStore`, see `KvStore::iter`
+pub struct Iter<'a>(hash_map::Iter<'a, String, String>);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a String, &'a String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+impl<'a> IntoIterator for &'a KvStore {
+    type Item = (&'a String, &'a String);
+    type IntoIter = Iter<'a>;
+
+    /// same as `KvStore::iter`
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::KvStore;
+    ///
+    /// let mut kv = KvStore::new();
+    /// kv.set("key1".to_owned(), "value1".to_owned());
+    /// for (key, value) in &kv {
+    ///     assert_eq!((key.as_str(), value.as_str()), ("key1", "value1"));
+    /// }
+    /// ```
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl Default for KvStore {
+    /// an empty KvStore, same as `KvStore::new`
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use kvs::KvStore;
+    ///
+    /// let kv = KvStore::default();
+    /// assert_eq!(kv.get("test".to_owned()), None);
+    /// ```
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -1,3 +1,3 @@
 mod hash_impl;
 
-pub use hash_impl::KvStore;
+pub use hash_impl::{Iter, KvStore};
@@ -1,7 +1,10 @@
 use clap::{command, Arg};
-use kvs::{addr_check, KvsEngine, Result, Server, SledKvsEngine};
-use std::{env::current_dir, fs, process::exit};
-use tracing::{error, info, warn, Level};
+use kvs::{addr_check, KvsEngine, KvsError, Result, Server, SledKvsEngine};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::{env::current_dir, process::exit};
+use tracing::{error, info, Level};
 
 fn main() {
     let tgt = "svr-main";
@@ -39,16 +42,18 @@ fn main() {
             error!(msg = "incorrect ip:port format");
             exit(1);
         }
-        let mut engine = matches.get_one("engine");
-        if engine.is_none() {
-            engine = curr_engine.as_ref();
-        }
-        if curr_engine.is_some() && engine != curr_engine.as_ref() {
-            error!(msg = "Mismatched engine!");
-            exit(1);
-        }
-        info!(msg = "finish config", engine = engine, ip_port = ip_port);
-        run(engine.unwrap(), ip_port)
+        let engine = matches
+            .get_one::<String>("engine")
+            .map(|name| EngineKind::parse(name).expect("checked by the value parser"));
+        let engine = match (engine, curr_engine) {
+            (Some(engine), Some(curr_engine)) if engine != curr_engine => {
+                error!(msg = "Mismatched engine!");
+                exit(1);
+            }
+            (engine, curr_engine) => engine.or(curr_engine).unwrap_or(EngineKind::Kvs),
+        };
+        info!(msg = "finish config", engine = %engine, ip_port = ip_port);
+        run(engine, ip_port)
     });
     if let Err(e) = res {
         error!(msg="running error", err=%e);
@@ -56,29 +61,69 @@ fn main() {
     }
 }
 
-fn run(engine: &str, ip_port: &str) -> Result<()> {
+fn run(engine: EngineKind, ip_port: &str) -> Result<()> {
     let current_dir = current_dir()?;
-    // change the engine option in dir
-    fs::write(current_dir.join("engine"), engine)?;
-    info!(msg = "flush engine option to engine file", engine = engine);
+    // change the engine option in dir, through a temporary file renamed over
+    // the marker so that a crash never leaves it half written. The file is
+    // synced before the rename, else a crash may leave the renamed marker
+    // empty, and the directory after it, else the rename may be lost.
+    let tmp = current_dir.join("engine.tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(engine.to_string().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, current_dir.join("engine"))?;
+    #[cfg(unix)]
+    File::open(&current_dir)?.sync_all()?;
+    info!(msg = "flush engine option to engine file", engine = %engine);
     match engine {
-        "kvs" => Server::new(KvsEngine::open(current_dir)?).run(ip_port),
-        "sled" => Server::new(SledKvsEngine::open(current_dir)?).run(ip_port),
-        _ => unreachable!(),
+        EngineKind::Kvs => Server::new(KvsEngine::open(current_dir)?).run(ip_port),
+        EngineKind::Sled => Server::new(SledKvsEngine::open(current_dir)?).run(ip_port),
+    }
+}
+
+/// The engines a server can run on, as named by `--engine` and the marker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EngineKind {
+    Kvs,
+    Sled,
+}
+
+impl EngineKind {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "kvs" => Some(EngineKind::Kvs),
+            "sled" => Some(EngineKind::Sled),
+            _ => None,
+        }
     }
 }
 
-fn current_engine() -> Result<Option<String>> {
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineKind::Kvs => write!(f, "kvs"),
+            EngineKind::Sled => write!(f, "sled"),
+        }
+    }
+}
+
+/// the engine recorded in the marker of the current directory, `None` for
+/// a new one. A marker which can't be read or names no engine is an error:
+/// guessing could open the data with the wrong engine.
+fn current_engine() -> Result<Option<EngineKind>> {
     let engine = current_dir()?.join("engine");
     if !engine.exists() {
         return Ok(None);
     }
 
-    match fs::read_to_string(engine) {
-        Ok(engine) => Ok(Some(engine)),
-        Err(e) => {
-            warn!(target="load engines", msg="The content of engine file is invalid", content=%e);
-            Ok(None)
-        }
+    let content = fs::read(&engine)?;
+    let name = String::from_utf8_lossy(&content);
+    match EngineKind::parse(name.trim()) {
+        Some(kind) => Ok(Some(kind)),
+        None => Err(KvsError::StringErr(format!(
+            "corrupt engine marker {}: {:?} is neither kvs nor sled",
+            engine.display(),
+            name
+        ))),
     }
 }
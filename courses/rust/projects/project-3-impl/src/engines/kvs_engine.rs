@@ -5,17 +5,48 @@
 use crate::Engine;
 
 use serde_json::Deserializer;
-use std::collections::BTreeMap;
+use std::cmp::Ordering;
+use std::collections::{btree_map, BTreeMap};
 use std::ffi::OsStr;
 use std::fs::{self, create_dir_all, read_dir, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::Path;
 use std::{collections::HashMap, path::PathBuf};
 
 use crate::{Cmd, KvsError, Result};
 
 const COMPACT_THREADHOLD: u64 = 1024 * 1024;
+
+/// An order of the keys, see `KvsOptions::comparator`.
+pub type Comparator = fn(&str, &str) -> Ordering;
+
+/// Options to open a `KvsEngine` with.
+#[derive(Debug, Clone, Copy)]
+pub struct KvsOptions {
+    comparator: Comparator,
+}
+
+impl Default for KvsOptions {
+    fn default() -> Self {
+        Self {
+            comparator: |a, b| a.cmp(b),
+        }
+    }
+}
+
+impl KvsOptions {
+    /// order the keys by `comparator` instead of byte-lexically, which is
+    /// the order `range` returns them in. Keys the comparator finds equal
+    /// are ordered byte-lexically, so distinct keys never collide.
+    ///
+    /// The order is not persisted, the index is rebuilt with the comparator
+    /// given at every open.
+    pub fn comparator(mut self, comparator: Comparator) -> Self {
+        self.comparator = comparator;
+        self
+    }
+}
 ///
 /// KvStore is a log-structured key-value store,
 /// inspired by bitcask model.
@@ -42,7 +73,8 @@ const COMPACT_THREADHOLD: u64 = 1024 * 1024;
 /// ```
 #[derive(Debug)]
 pub struct KvsEngine {
-    key_dir: BTreeMap<String, CmdPos>,
+    key_dir: BTreeMap<OrderedKey, CmdPos>,
+    comparator: Comparator,
     readers: HashMap<u64, BufReaderWithPos<File>>,
 
     path: PathBuf,
@@ -74,10 +106,10 @@ impl Engine for KvsEngine {
         serde_json::to_writer(&mut self.writer, &cmd)?;
         self.writer.flush()?;
         if let Cmd::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self
-                .key_dir
-                .insert(key, (self.current_file_id, posi..self.writer.pos).into())
-            {
+            if let Some(old_cmd) = self.key_dir.insert(
+                OrderedKey::new(key, self.comparator),
+                (self.current_file_id, posi..self.writer.pos).into(),
+            ) {
                 self.uncompact += old_cmd.len;
             }
         }
@@ -102,19 +134,9 @@ impl Engine for KvsEngine {
     /// ```
     fn get(&mut self, key: String) -> Result<Option<String>> {
         // convert Option<&T> to Option<T>
-        if self.key_dir.contains_key(&key) {
-            let cmd_pos = self.key_dir.get(&key).expect("key not found");
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.file_id)
-                .expect("inconsistancy! Can't find this log file");
-            reader.seek(SeekFrom::Start(cmd_pos.kv_pos))?;
-            let reader = reader.take(cmd_pos.len);
-            if let Cmd::Set { value, .. } = serde_json::from_reader(reader)? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::CommandNotSupported)
-            }
+        let key = OrderedKey::new(key, self.comparator);
+        if let Some(cmd_pos) = self.key_dir.get(&key) {
+            read_value(&mut self.readers, cmd_pos).map(Some)
         } else {
             Ok(None)
         }
@@ -136,11 +158,13 @@ impl Engine for KvsEngine {
     /// assert_eq!(kv.get("test".to_owned()).unwrap(), None);
     /// ```
     fn remove(&mut self, key: String) -> Result<()> {
+        let key = OrderedKey::new(key, self.comparator);
         if self.key_dir.contains_key(&key) {
-            let cmd = Cmd::Remove { key };
+            let cmd = Cmd::Remove { key: key.key };
             serde_json::to_writer(&mut self.writer, &cmd)?;
             self.writer.flush()?;
             if let Cmd::Remove { key } = cmd {
+                let key = OrderedKey::new(key, self.comparator);
                 let old_cmd = self.key_dir.remove(&key).expect("key not found");
                 self.uncompact += old_cmd.len;
                 if self.uncompact >= COMPACT_THREADHOLD {
@@ -167,6 +191,11 @@ impl KvsEngine {
     /// let mut store = KvStore::open(temp_file.path());
     /// ```
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path, KvsOptions::default())
+    }
+
+    /// open a KvStore like `open`, with the given options.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvsOptions) -> Result<Self> {
         // create store path
         let path = path.into();
         let mut uncompact: u64 = 0;
@@ -178,7 +207,7 @@ impl KvsEngine {
         let file_list = sorted_file_list(&path)?;
         for file_id in &file_list {
             let mut reader = BufReaderWithPos::new(File::open(to_log_file(*file_id, &path))?)?;
-            uncompact += load_log(*file_id, &mut reader, &mut key_dir)?;
+            uncompact += load_log(*file_id, &mut reader, &mut key_dir, options.comparator)?;
             readers.insert(*file_id, reader);
         }
 
@@ -189,6 +218,7 @@ impl KvsEngine {
         // return
         Ok(KvsEngine {
             key_dir,
+            comparator: options.comparator,
             readers,
             path,
             writer,
@@ -197,6 +227,64 @@ impl KvsEngine {
         })
     }
 
+    /// the keys in the order of the comparator the store was opened with
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::{Engine, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_file = TempDir::new().expect("unable to create temporary working directory");
+    /// let mut kv = KvsEngine::open(temp_file.path()).unwrap();
+    /// kv.set("b".to_owned(), "2".to_owned()).unwrap();
+    /// kv.set("a".to_owned(), "1".to_owned()).unwrap();
+    /// assert_eq!(kv.keys().collect::<Vec<_>>(), ["a", "b"]);
+    /// ```
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.key_dir.keys().map(|key| &key.key)
+    }
+
+    /// the key-value pairs whose key is in `range`, in the order of the
+    /// comparator the store was opened with. Each value is read from the
+    /// log as the iterator gets to it. A range whose start is after its
+    /// end is empty.
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::{Engine, KvsEngine, Result};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_file = TempDir::new().expect("unable to create temporary working directory");
+    /// let mut kv = KvsEngine::open(temp_file.path()).unwrap();
+    /// kv.set("a".to_owned(), "1".to_owned()).unwrap();
+    /// kv.set("b".to_owned(), "2".to_owned()).unwrap();
+    /// kv.set("c".to_owned(), "3".to_owned()).unwrap();
+    /// let pairs: Vec<_> = kv.range("a".to_owned().."c".to_owned()).collect::<Result<_>>().unwrap();
+    /// assert_eq!(pairs, [(&"a".to_owned(), "1".to_owned()), (&"b".to_owned(), "2".to_owned())]);
+    /// ```
+    pub fn range<R: RangeBounds<String>>(&mut self, range: R) -> RangeIter<'_> {
+        let ordered = |bound: Bound<&String>| match bound {
+            Bound::Included(key) => Bound::Included(OrderedKey::new(key.clone(), self.comparator)),
+            Bound::Excluded(key) => Bound::Excluded(OrderedKey::new(key.clone(), self.comparator)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let start = ordered(range.start_bound());
+        let end = ordered(range.end_bound());
+        // `BTreeMap::range` panics on these
+        let empty = match (&start, &end) {
+            (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) => start > end,
+            _ => false,
+        };
+        RangeIter {
+            entries: (!empty).then(|| self.key_dir.range((start, end))),
+            readers: &mut self.readers,
+        }
+    }
+
     fn compact(&mut self) -> Result<()> {
         let compact_file_id = self.current_file_id + 1;
         self.current_file_id += 2;
@@ -238,6 +326,56 @@ impl KvsEngine {
     }
 }
 
+/// Iterator over the key-value pairs of a range of keys, see
+/// `KvsEngine::range`.
+#[derive(Debug)]
+pub struct RangeIter<'a> {
+    entries: Option<btree_map::Range<'a, OrderedKey, CmdPos>>,
+    readers: &'a mut HashMap<u64, BufReaderWithPos<File>>,
+}
+
+impl<'a> Iterator for RangeIter<'a> {
+    type Item = Result<(&'a String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, cmd_pos) = self.entries.as_mut()?.next()?;
+        Some(read_value(self.readers, cmd_pos).map(|value| (&key.key, value)))
+    }
+}
+
+/// A key of the index, ordered by the comparator of the store.
+#[derive(Debug)]
+struct OrderedKey {
+    key: String,
+    comparator: Comparator,
+}
+
+impl OrderedKey {
+    fn new(key: String, comparator: Comparator) -> Self {
+        Self { key, comparator }
+    }
+}
+
+impl Ord for OrderedKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.comparator)(&self.key, &other.key).then_with(|| self.key.cmp(&other.key))
+    }
+}
+
+impl PartialOrd for OrderedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for OrderedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for OrderedKey {}
+
 #[derive(Debug)]
 struct CmdPos {
     file_id: u64,
@@ -358,10 +496,27 @@ fn to_log_file(file_id: u64, dir: &Path) -> PathBuf {
     dir.join(format!("{}.log", file_id))
 }
 
+fn read_value(
+    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
+    cmd_pos: &CmdPos,
+) -> Result<String> {
+    let reader = readers
+        .get_mut(&cmd_pos.file_id)
+        .expect("inconsistancy! Can't find this log file");
+    reader.seek(SeekFrom::Start(cmd_pos.kv_pos))?;
+    let reader = reader.take(cmd_pos.len);
+    if let Cmd::Set { value, .. } = serde_json::from_reader(reader)? {
+        Ok(value)
+    } else {
+        Err(KvsError::CommandNotSupported)
+    }
+}
+
 fn load_log(
     file_id: u64,
     reader: &mut BufReaderWithPos<File>,
-    key_dir: &mut BTreeMap<String, CmdPos>,
+    key_dir: &mut BTreeMap<OrderedKey, CmdPos>,
+    comparator: Comparator,
 ) -> Result<u64> {
     let mut posi = reader.seek(SeekFrom::Start(0))?;
     let mut stream = Deserializer::from_reader(reader).into_iter::<Cmd>();
@@ -370,7 +525,7 @@ fn load_log(
         let new_pos = stream.byte_offset() as u64;
         match cmd? {
             Cmd::Remove { key } => {
-                if let Some(old_cmd) = key_dir.remove(&key) {
+                if let Some(old_cmd) = key_dir.remove(&OrderedKey::new(key, comparator)) {
                     // old command can be compacted
                     uncompacted += old_cmd.len;
                 }
@@ -378,6 +533,7 @@ fn load_log(
                 uncompacted += new_pos - posi;
             }
             Cmd::Set { key, .. } => {
+                let key = OrderedKey::new(key, comparator);
                 if let Some(old_cmd) = key_dir.insert(key, (file_id, posi..new_pos).into()) {
                     // old command will be overwritten, so can be compacted
                     uncompacted += old_cmd.len;
@@ -1,6 +1,6 @@
 mod kvs_engine;
 mod sled_engine;
-pub use kvs_engine::KvsEngine;
+pub use kvs_engine::{Comparator, KvsEngine, KvsOptions, RangeIter};
 pub use sled_engine::SledKvsEngine;
 
 use crate::Result;
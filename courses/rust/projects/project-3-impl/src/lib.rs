@@ -11,6 +11,7 @@ pub use cmd::Cmd;
 pub use engines::Engine;
 pub use engines::KvsEngine;
 pub use engines::SledKvsEngine;
+pub use engines::{Comparator, KvsOptions, RangeIter};
 pub use errors::{KvsError, Result};
 pub use requests::*;
 pub use server::Server;
@@ -212,6 +212,30 @@ fn cli_wrong_engine() {
     }
 }
 
+// A corrupt engine marker should stop the server with an error naming it,
+// whatever `--engine`, and be left as it is
+#[test]
+fn cli_corrupt_engine_marker() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine"), "sl").unwrap();
+    for args in [
+        &["--addr", "127.0.0.1:4006"][..],
+        &["--engine", "kvs", "--addr", "127.0.0.1:4006"],
+    ] {
+        Command::cargo_bin("kvs_server")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stdout(contains("corrupt engine marker"));
+    }
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+        "sl"
+    );
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -0,0 +1,162 @@
+use kvs::{Engine, KvsEngine, KvsOptions, Result};
+use std::cmp::Ordering;
+use std::ops::Bound;
+use tempfile::TempDir;
+
+// order keys like `item9` < `item10` by their numeric suffix
+fn numeric_suffix(a: &str, b: &str) -> Ordering {
+    fn split(key: &str) -> (&str, Option<u64>) {
+        let at = key.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        (&key[..at], key[at..].parse().ok())
+    }
+    split(a).cmp(&split(b))
+}
+
+fn range_keys(store: &mut KvsEngine, range: (Bound<String>, Bound<String>)) -> Result<Vec<String>> {
+    store
+        .range(range)
+        .map(|pair| pair.map(|(key, _)| key.clone()))
+        .collect()
+}
+
+// Range queries should return the keys in the order of the comparator
+#[test]
+fn range_with_custom_comparator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvsOptions::default().comparator(numeric_suffix);
+    let mut store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+    for i in [10, 9, 100, 1, 11, 2] {
+        store.set(format!("item{}", i), i.to_string())?;
+    }
+
+    let keys: Vec<String> = store
+        .range("item1".to_owned().."item100".to_owned())
+        .map(|pair| pair.map(|(key, _)| key.clone()))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["item1", "item2", "item9", "item10", "item11"]);
+    assert_eq!(
+        store.keys().collect::<Vec<_>>(),
+        ["item1", "item2", "item9", "item10", "item11", "item100"]
+    );
+
+    // the order is rebuilt at reopen
+    drop(store);
+    let mut store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+    store.remove("item10".to_owned())?;
+    let pairs: Vec<(String, String)> = store
+        .range("item9".to_owned().."item1000".to_owned())
+        .map(|pair| pair.map(|(key, value)| (key.clone(), value)))
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("item9".to_owned(), "9".to_owned()),
+            ("item11".to_owned(), "11".to_owned()),
+            ("item100".to_owned(), "100".to_owned()),
+        ]
+    );
+    assert_eq!(store.get("item100".to_owned())?, Some("100".to_owned()));
+    Ok(())
+}
+
+// Without a comparator keys are ordered byte-lexically
+#[test]
+fn range_in_lexical_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvsEngine::open(temp_dir.path())?;
+    for i in [10, 9, 1, 2] {
+        store.set(format!("item{}", i), i.to_string())?;
+    }
+    let keys: Vec<String> = store
+        .range("item".to_owned().."item9".to_owned())
+        .map(|pair| pair.map(|(key, _)| key.clone()))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["item1", "item10", "item2"]);
+    assert_eq!(
+        store.range("item9".to_owned().."item1".to_owned()).count(),
+        0
+    );
+    Ok(())
+}
+
+// Keys and ranges should be listed in lexical order, whatever the order of
+// the writes, and again after a reopen
+#[test]
+fn keys_in_lexical_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvsEngine::open(temp_dir.path())?;
+    for key in ["b", "d", "a", "c", "e"] {
+        store.set(key.to_owned(), key.to_uppercase())?;
+    }
+    store.remove("e".to_owned())?;
+    assert_eq!(store.keys().collect::<Vec<_>>(), ["a", "b", "c", "d"]);
+
+    drop(store);
+    let mut store = KvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.keys().collect::<Vec<_>>(), ["a", "b", "c", "d"]);
+    let pairs: Vec<(String, String)> = store
+        .range(..)
+        .map(|pair| pair.map(|(key, value)| (key.clone(), value)))
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("a".to_owned(), "A".to_owned()),
+            ("b".to_owned(), "B".to_owned()),
+            ("c".to_owned(), "C".to_owned()),
+            ("d".to_owned(), "D".to_owned()),
+        ]
+    );
+    Ok(())
+}
+
+// Range bounds should include or exclude their key as asked
+#[test]
+fn range_bounds() -> Result<()> {
+    use Bound::*;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvsEngine::open(temp_dir.path())?;
+    for key in ["a", "b", "c", "d"] {
+        store.set(key.to_owned(), key.to_owned())?;
+    }
+    let key = |key: &str| key.to_owned();
+
+    assert_eq!(
+        range_keys(&mut store, (Included(key("b")), Included(key("c"))))?,
+        ["b", "c"]
+    );
+    assert_eq!(
+        range_keys(&mut store, (Included(key("b")), Excluded(key("c"))))?,
+        ["b"]
+    );
+    assert_eq!(
+        range_keys(&mut store, (Excluded(key("b")), Included(key("d"))))?,
+        ["c", "d"]
+    );
+    assert_eq!(
+        range_keys(&mut store, (Excluded(key("a")), Excluded(key("d"))))?,
+        ["b", "c"]
+    );
+    assert_eq!(
+        range_keys(&mut store, (Unbounded, Excluded(key("b"))))?,
+        ["a"]
+    );
+    assert_eq!(
+        range_keys(&mut store, (Excluded(key("b")), Unbounded))?,
+        ["c", "d"]
+    );
+    // bounds on keys which aren't in the store
+    assert_eq!(
+        range_keys(&mut store, (Included(key("bb")), Included(key("z"))))?,
+        ["c", "d"]
+    );
+    // empty ranges
+    assert!(range_keys(&mut store, (Included(key("c")), Excluded(key("c"))))?.is_empty());
+    assert!(range_keys(&mut store, (Excluded(key("c")), Excluded(key("c"))))?.is_empty());
+    assert!(range_keys(&mut store, (Included(key("d")), Included(key("a"))))?.is_empty());
+    assert_eq!(
+        range_keys(&mut store, (Included(key("c")), Included(key("c"))))?,
+        ["c"]
+    );
+    Ok(())
+}
@@ -1,9 +1,37 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{Engine, KvsEngine, SledKvsEngine};
+use kvs::{Engine, FlushPolicy, GetResp, KvsEngine, KvsOptions, Request, Server, SledKvsEngine};
 use rand::prelude::*;
+use serde::Deserialize;
 use sled;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 
+/// The system allocator, counting the allocations.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
 fn set_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("set_bench");
     group.bench_function("kvs", |b| {
@@ -12,7 +40,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (KvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |(mut store, _temp_dir)| {
+            |(store, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     store.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -26,7 +54,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (SledKvsEngine::new(sled::open(&temp_dir).unwrap()), temp_dir)
             },
-            |(mut db, _temp_dir)| {
+            |(db, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     db.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -42,7 +70,7 @@ fn get_bench(c: &mut Criterion) {
     for i in &vec![8, 12, 16, 20] {
         group.bench_with_input(format!("kvs_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut store = KvsEngine::open(temp_dir.path()).unwrap();
+            let store = KvsEngine::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
                 store
                     .set(format!("key{}", key_i), "value".to_string())
@@ -59,7 +87,7 @@ fn get_bench(c: &mut Criterion) {
     for i in &vec![8, 12, 16, 20] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut db = SledKvsEngine::new(sled::open(&temp_dir).unwrap());
+            let db = SledKvsEngine::new(sled::open(&temp_dir).unwrap());
             for key_i in 1..(1 << i) {
                 db.set(format!("key{}", key_i), "value".to_string())
                     .unwrap();
@@ -73,5 +101,205 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+// reads of existing keys, with the key borrowed or passed as a new `String`
+// the way every read had to before `get` took `impl AsRef<str>`
+fn read_heavy_bench(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvsEngine::open(temp_dir.path()).unwrap();
+    let keys: Vec<String> = (0..1 << 12).map(|i| format!("key{}", i)).collect();
+    for key in &keys {
+        store.set(key.clone(), "value".to_string()).unwrap();
+    }
+
+    let mut group = c.benchmark_group("read_heavy_bench");
+    group.bench_function("borrowed_key", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % keys.len();
+            store.get(&keys[i]).unwrap();
+        })
+    });
+    group.bench_function("owned_key", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % keys.len();
+            store.get(keys[i].clone()).unwrap();
+        })
+    });
+    group.finish();
+
+    let count = |get: &dyn Fn(&str)| {
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        for key in &keys {
+            get(key);
+        }
+        (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / keys.len() as f64
+    };
+    println!(
+        "allocations per get: borrowed key {:.1}, owned key {:.1}",
+        count(&|key| {
+            store.get(key).unwrap();
+        }),
+        count(&|key| {
+            store.get(String::from(key)).unwrap();
+        }),
+    );
+}
+
+// reads of existing keys, the index hashing them with the default hasher
+// or with `KvsOptions::fast_key_hash`
+fn key_hash_bench(c: &mut Criterion) {
+    let keys: Vec<String> = (0..1 << 16).map(|i| format!("key{}", i)).collect();
+    let mut group = c.benchmark_group("key_hash_bench");
+    for fast in [false, true] {
+        let name = if fast { "fxhash" } else { "default" };
+        let temp_dir = TempDir::new().unwrap();
+        let options = KvsOptions::default().fast_key_hash(fast);
+        let store = KvsEngine::open_with_options(temp_dir.path(), options).unwrap();
+        for key in &keys {
+            store.set(key.clone(), "value".to_string()).unwrap();
+        }
+        group.bench_function(name, |b| {
+            let mut i = 0;
+            b.iter(|| {
+                i = (i + 1) % keys.len();
+                store.get(&keys[i]).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+// a client sending 1000 gets at once before reading the responses, against
+// a server flushing every response or coalescing the flushes
+fn pipelined_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipelined_bench");
+    for (addr, coalesce) in [("127.0.0.1:4100", false), ("127.0.0.1:4101", true)] {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvsEngine::open(temp_dir.path()).unwrap();
+        store.set("key".to_owned(), "value".to_owned()).unwrap();
+        thread::spawn(move || {
+            Server::new(store)
+                .coalesce_flushes(coalesce)
+                .run(addr)
+                .unwrap()
+        });
+        thread::sleep(Duration::from_millis(200));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut responses = serde_json::Deserializer::from_reader(stream.try_clone().unwrap());
+        let mut requests = Vec::new();
+        for _ in 0..1000 {
+            let req = Request::Get {
+                key: "key".to_owned(),
+            };
+            serde_json::to_writer(&mut requests, &req).unwrap();
+        }
+        let name = if coalesce { "coalesced" } else { "flush_each" };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                stream.write_all(&requests).unwrap();
+                for _ in 0..1000 {
+                    GetResp::deserialize(&mut responses).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+// sets and removes on sled under each flush policy
+fn sled_flush_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sled_flush_bench");
+    group.sample_size(10);
+    let policies = [
+        ("per_operation", FlushPolicy::PerOperation),
+        ("periodic", FlushPolicy::default()),
+        ("manual", FlushPolicy::Manual),
+    ];
+    for (name, policy) in policies {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let db = SledKvsEngine::open_with_flush_policy(temp_dir.path(), policy);
+                    (db.unwrap(), temp_dir)
+                },
+                |(db, temp_dir)| {
+                    for i in 0..1 << 8 {
+                        db.set(format!("key{}", i), "value".to_string()).unwrap();
+                    }
+                    for i in 0..1 << 8 {
+                        db.remove(format!("key{}", i)).unwrap();
+                    }
+                    (db, temp_dir)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+// the first random gets on a freshly opened store, with or without a
+// prefetch before them. Without a cold page cache both read a warm one and
+// the difference is noise, but dropping it slows down the whole machine, so
+// the bench only does so before each open when asked to with
+// `KVS_BENCH_DROP_CACHES=1`, which also takes root on Linux:
+//
+//     sudo KVS_BENCH_DROP_CACHES=1 cargo bench -- first_read_bench
+fn first_read_bench(c: &mut Criterion) {
+    let drop_caches = env::var_os("KVS_BENCH_DROP_CACHES").is_some_and(|var| var == "1");
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvsEngine::open(temp_dir.path()).unwrap();
+        for i in 0..1 << 16 {
+            store.set(format!("key{}", i), "value".repeat(20)).unwrap();
+        }
+    }
+
+    let mut group = c.benchmark_group("first_read_bench");
+    group.sample_size(10);
+    for prefetch in [false, true] {
+        let name = if prefetch { "prefetched" } else { "cold" };
+        group.bench_function(name, |b| {
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter_batched(
+                || {
+                    if drop_caches {
+                        fs::write("/proc/sys/vm/drop_caches", "1")
+                            .expect("unable to drop the page cache");
+                    }
+                    let store = KvsEngine::open(temp_dir.path()).unwrap();
+                    if prefetch {
+                        store.prefetch().unwrap();
+                    }
+                    store
+                },
+                |store| {
+                    for _ in 0..100 {
+                        store
+                            .get(format!("key{}", rng.gen_range(0, 1 << 16)))
+                            .unwrap();
+                    }
+                    // dropped once timed
+                    store
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    set_bench,
+    get_bench,
+    read_heavy_bench,
+    key_hash_bench,
+    pipelined_bench,
+    first_read_bench,
+    sled_flush_bench
+);
 criterion_main!(benches);
@@ -0,0 +1,226 @@
+use clap::{arg, command, value_parser, ArgMatches, Command};
+use kvs::{DirLock, Engine, FsStorage, KvsEngine, KvsError, Result, FORMAT_VERSION};
+use serde::de::{self, Deserializer as _, MapAccess, Visitor};
+use serde_json::Deserializer;
+use std::fmt;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+fn main() {
+    let matches = command!() // requires `cargo` feature
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("kvs store administration, on the data directory of a kvs engine")
+        .subcommand_required(true)
+        .subcommands(vec![
+            Command::new("dump-records")
+                .about("print every record of the logs as a JSON line, superseded and removed ones included, without building the index")
+                .arg(
+                    arg!([dir] "the data directory")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--file <N> "only dump the log file N")
+                        .required(false)
+                        .value_parser(value_parser!(u64)),
+                ),
+            Command::new("export")
+                .about("print the live pairs of the store on stdout, as a JSON object of string values by default")
+                .arg(format_arg())
+                .arg(
+                    arg!([dir] "the data directory")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+            Command::new("import")
+                .about("set the pairs read from stdin in the store, as written by export, overwriting the keys already there")
+                .arg(format_arg())
+                .arg(
+                    arg!([dir] "the data directory, created if needed")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+            Command::new("upgrade")
+                .about("rewrite a store written by an older build in the current record format, keeping its live pairs")
+                .arg(
+                    arg!([dir] "the data directory")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--to <DEST> "write the upgraded store to the new directory DEST, leaving dir as it is")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        ])
+        .get_matches();
+    let res = match matches.subcommand() {
+        Some(("dump-records", m)) => {
+            let dir: &PathBuf = m.get_one("dir").unwrap();
+            dump_records(dir, m.get_one::<u64>("file").copied())
+        }
+        Some(("export", m)) => export(m),
+        Some(("import", m)) => import(m),
+        Some(("upgrade", m)) => upgrade(m),
+        _ => unreachable!("a subcommand is required"),
+    };
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+/// the `--format` of export and import, JSON being the only one so far
+fn format_arg() -> clap::Arg<'static> {
+    arg!(--format <FORMAT> "the format of the pairs")
+        .required(false)
+        .value_parser(["json"])
+        .default_value("json")
+}
+
+fn dump_records(dir: &PathBuf, file_id: Option<u64>) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    KvsEngine::dump_records(dir, file_id, |record| {
+        serde_json::to_writer(&mut stdout, &record)?;
+        writeln!(stdout)?;
+        Ok(())
+    })?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// open the kvs store in the `dir` of a subcommand, kept from any server
+/// meanwhile by the lock returned with it. Only a `create` opens a new
+/// store, in a directory created if needed; none opens the data of the sled
+/// engine.
+fn open_store(m: &ArgMatches, create: bool) -> Result<(DirLock, KvsEngine)> {
+    let dir: &PathBuf = m.get_one("dir").unwrap();
+    if dir.join("db").is_file() {
+        return Err(KvsError::StringErr(format!(
+            "{} holds data of the sled engine, not a kvs store",
+            dir.display()
+        )));
+    }
+    if create {
+        std::fs::create_dir_all(dir)?;
+    } else if FsStorage::detect(dir)?.is_none() {
+        return Err(KvsError::StringErr(format!(
+            "{} holds no kvs store",
+            dir.display()
+        )));
+    }
+    let lock = DirLock::acquire(dir)?;
+    Ok((lock, KvsEngine::open(dir)?))
+}
+
+/// write the live pairs as a single JSON object, one pair at a time, so
+/// that no more than a value is held in memory
+fn export(m: &ArgMatches) -> Result<()> {
+    let (_lock, store) = open_store(m, false)?;
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    stdout.write_all(b"{")?;
+    for (i, pair) in store.iter().enumerate() {
+        let (key, value) = pair?;
+        if i > 0 {
+            stdout.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut stdout, &key)?;
+        stdout.write_all(b":")?;
+        serde_json::to_writer(&mut stdout, &value)?;
+    }
+    stdout.write_all(b"}\n")?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// set the pairs of the JSON object on stdin as they are parsed, without
+/// reading the whole object first
+fn import(m: &ArgMatches) -> Result<()> {
+    let (_lock, store) = open_store(m, true)?;
+    let mut de = Deserializer::from_reader(io::stdin().lock());
+    let count = de.deserialize_map(Importer(&store))?;
+    de.end()?;
+    store.flush()?;
+    eprintln!("imported {} pairs", count);
+    Ok(())
+}
+
+/// rewrite the store of `dir` in the current record format, in place or
+/// into `--to`, refusing a store already current
+fn upgrade(m: &ArgMatches) -> Result<()> {
+    let dir: &PathBuf = m.get_one("dir").unwrap();
+    let _lock = DirLock::acquire(dir)?;
+    let version = match KvsEngine::format_version(dir)? {
+        Some(version) if version < FORMAT_VERSION => version,
+        Some(_) => {
+            return Err(KvsError::StringErr(format!(
+                "{} is already at format version {}",
+                dir.display(),
+                FORMAT_VERSION
+            )))
+        }
+        None => {
+            return Err(KvsError::StringErr(format!(
+                "{} holds no store to upgrade",
+                dir.display()
+            )))
+        }
+    };
+    let store = KvsEngine::open(dir)?;
+    let (target, count) = match m.get_one::<PathBuf>("to") {
+        Some(dest) => (dest.as_path(), copy_store(&store, dest)?),
+        None => (dir.as_path(), store.upgrade()?),
+    };
+    eprintln!(
+        "upgraded {} from format version {} to {}: rewrote {} pairs",
+        target.display(),
+        version,
+        FORMAT_VERSION,
+        count
+    );
+    Ok(())
+}
+
+/// set every live pair of `store` in a new store in `dest`, which must not
+/// hold one, returning their number
+fn copy_store(store: &KvsEngine, dest: &Path) -> Result<u64> {
+    std::fs::create_dir_all(dest)?;
+    let _lock = DirLock::acquire(dest)?;
+    if KvsEngine::format_version(dest)?.is_some() {
+        return Err(KvsError::StringErr(format!(
+            "{} already holds a store",
+            dest.display()
+        )));
+    }
+    let copy = KvsEngine::open(dest)?;
+    let mut count = 0;
+    for pair in store.iter() {
+        let (key, value) = pair?;
+        copy.set(key, value)?;
+        count += 1;
+    }
+    copy.flush()?;
+    Ok(count)
+}
+
+/// Sets every pair of a JSON object in a store, counting them.
+struct Importer<'a>(&'a KvsEngine);
+
+impl<'de> Visitor<'de> for Importer<'_> {
+    type Value = u64;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a JSON object of string values")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<u64, A::Error> {
+        let mut count = 0;
+        while let Some((key, value)) = map.next_entry::<String, String>()? {
+            self.0.set(key, value).map_err(de::Error::custom)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
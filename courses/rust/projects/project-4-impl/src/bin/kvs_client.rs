@@ -11,6 +11,9 @@ fn main() -> Result<()> {
             Command::new("get")
                 .about("get a value by key")
                 .arg(arg!([key] "key").required(true)),
+            Command::new("exists")
+                .about("tell whether a key exists, without reading its value")
+                .arg(arg!([key] "key").required(true)),
             Command::new("set")
                 .about("set a key-value")
                 .arg(arg!([key] "key").required(true))
@@ -18,6 +21,7 @@ fn main() -> Result<()> {
             Command::new("rm")
                 .about("remove a key-value")
                 .arg(arg!([key] "key").required(true)),
+            Command::new("stats").about("show the health of the server's store"),
         ])
         .arg(
             Arg::new("addr")
@@ -43,9 +47,19 @@ fn main() -> Result<()> {
             let value = client.get(key.to_owned())?;
             match value {
                 Some(v) => println!("{}", v),
-                None => print!("nil"),
+                None => {
+                    // a missing key is told apart from any stored value by the exit code
+                    println!("Key not found");
+                    exit(1);
+                }
             }
         }
+        Some(("exists", m)) => {
+            let key: &String = m.get_one("key").unwrap();
+
+            let mut client = Client::connect(ip_port)?;
+            println!("{}", client.contains(key.to_owned())?);
+        }
         Some(("set", m)) => {
             let key: &String = m.get_one("key").unwrap();
             let value: &String = m.get_one("value").unwrap();
@@ -65,6 +79,14 @@ fn main() -> Result<()> {
                 Err(e) => println!("{}", e),
             }
         }
+        Some(("stats", _)) => {
+            let mut client = Client::connect(ip_port)?;
+            let stats = client.stats()?;
+            println!("{:<12} {:>14}", "keys", stats.keys);
+            println!("{:<12} {:>12} B", "disk usage", stats.disk_usage);
+            println!("{:<12} {:>12} B", "uncompacted", stats.uncompacted);
+            println!("{:<12} {:>14}", "compactions", stats.compactions);
+        }
         _ => {
             unreachable!("unimplemented");
         }
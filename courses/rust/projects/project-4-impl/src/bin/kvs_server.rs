@@ -1,16 +1,24 @@
-use clap::{command, Arg};
-use kvs::{addr_check, KvsEngine, Result, Server, SledKvsEngine};
-use std::{env::current_dir, fs, process::exit};
+use clap::{arg, command, value_parser, Arg, ArgAction, ArgMatches, Command};
+use kvs::{
+    addr_check, DirLock, Engine, FlushPolicy, FsStorage, KvsEngine, KvsError, KvsOptions,
+    RecoveryReport, Result, Server, SledKvsEngine, DEFAULT_BACKLOG, DEFAULT_DB,
+};
+use serde::Serialize;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::fmt::{self, Debug};
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use std::{env::current_dir, fs, io, process, process::exit, thread};
+use tempfile::TempDir;
 use tracing::{error, info, warn, Level};
+use tracing_subscriber::fmt::MakeWriter;
 
 fn main() {
     let tgt = "svr-main";
-    tracing_subscriber::fmt()
-        .json()
-        .with_max_level(Level::DEBUG)
-        .flatten_event(true)
-        .init();
-    info!(target = tgt, "starting the server");
+    let default_backlog = DEFAULT_BACKLOG.to_string();
     let matches = command!() // requires `cargo` feature
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -19,7 +27,7 @@ fn main() {
             .long("engine")
             .value_name("ENGINE_NAME")
             .value_parser(["kvs", "sled"])
-            .help("use [ENGINE_NAME] store engine, chosen in kvs and sled, default using last used")
+            .help("use [ENGINE_NAME] store engine, chosen in kvs and sled, for a new data directory only; an existing one keeps its engine, default kvs")
             .takes_value(true)
         )
         .arg(
@@ -30,8 +38,167 @@ fn main() {
             .help("exec this kv store in ip:port")
             .takes_value(true)
         )
+        .arg(
+            Arg::new("db")
+            .long("db")
+            .value_name("NAME")
+            .help("also serve the database NAME, stored in databases/NAME, may be repeated")
+            .takes_value(true)
+            .action(ArgAction::Append)
+        )
+        .arg(
+            Arg::new("metrics-addr")
+            .long("metrics-addr")
+            .value_name("IP-PORT")
+            .help("serve metrics in the Prometheus text format at http://IP-PORT/metrics")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("no-delay")
+            .long("no-delay")
+            .value_name("BOOL")
+            .value_parser(clap::value_parser!(bool))
+            .default_value("true")
+            .help("set TCP_NODELAY on the client connections")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("reuse-addr")
+            .long("reuse-addr")
+            .value_name("BOOL")
+            .value_parser(clap::value_parser!(bool))
+            .default_value("true")
+            .help("bind the address with SO_REUSEADDR, to restart while old connections are in TIME_WAIT")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("backlog")
+            .long("backlog")
+            .value_name("N")
+            .value_parser(clap::value_parser!(u32))
+            .default_value(&default_backlog)
+            .help("queue up to N connections waiting to be served, so that bursts wait rather than being refused; capped by the system")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("threads")
+            .long("threads")
+            .value_name("N")
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .help("serve up to N clients at once from a pool of N threads, instead of one at a time")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("idle-timeout")
+            .long("idle-timeout")
+            .value_name("SECS")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .help("close a client connection which sends no request for SECS seconds, so that it doesn't hold back the others")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("report-timing")
+            .long("report-timing")
+            .help("answer the timed requests with the time spent on them, to tell the server latency from the network one")
+            .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("durability")
+            .long("durability")
+            .value_name("MODE")
+            .value_parser(parse_durability)
+            .help("when the writes are synced to the disk: buffered, only on flush and shutdown; sync, each before it is acknowledged; periodic:MS, every MS milliseconds. Default buffered for kvs, periodic:500 for sled; also applies to bench")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("stdio")
+            .long("stdio")
+            .help("serve the process which spawned this one over stdin and stdout instead of TCP, logging to stderr")
+            .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("check")
+            .long("check")
+            .help("replay the logs of the kvs engine without opening them, print a JSON recovery report on stdout and exit, with status 2 if corruption was found; logs go to stderr")
+            .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("pid-file")
+            .long("pid-file")
+            .value_name("PATH")
+            .value_parser(clap::value_parser!(PathBuf))
+            .help("write the process id to PATH, removed on shutdown")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("log-file")
+            .long("log-file")
+            .value_name("PATH")
+            .value_parser(clap::value_parser!(PathBuf))
+            .help("append the logs to PATH instead of stdout, reopened on SIGHUP for log rotation")
+            .takes_value(true)
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("run a set then a get workload on a temporary store in this process, print the ops/sec of each as a JSON line and exit; logs go to stderr")
+                .arg(
+                    arg!(--engine <ENGINE_NAME> "the engine of the temporary store")
+                        .required(false)
+                        .value_parser(["kvs", "sled"])
+                        .default_value("kvs"),
+                )
+                .arg(
+                    arg!(--ops <N> "the number of sets, then of gets, on as many keys")
+                        .required(false)
+                        .value_parser(value_parser!(u64).range(1..))
+                        .default_value("10000"),
+                )
+                .arg(
+                    arg!(--"value-size" <B> "the size of the values in bytes")
+                        .required(false)
+                        .value_parser(value_parser!(usize))
+                        .default_value("100"),
+                ),
+        )
         .get_matches();
-    let res = current_engine().and_then(move |curr_engine| {
+    let durability = matches.get_one::<FlushPolicy>("durability").copied();
+    let bench = matches
+        .subcommand_matches("bench")
+        .map(|m| Bench::from_matches(m, durability));
+    let stdio = *matches.get_one::<bool>("stdio").expect("has a default");
+    let check = *matches.get_one::<bool>("check").expect("has a default");
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_max_level(Level::DEBUG)
+        .flatten_event(true);
+    if let Some(path) = matches.get_one::<PathBuf>("log-file") {
+        let log_file = match LogFile::open(path) {
+            Ok(log_file) => log_file,
+            Err(e) => {
+                eprintln!("fail to open log file {}: {}", path.display(), e);
+                exit(1);
+            }
+        };
+        subscriber.with_writer(log_file.clone()).init();
+        if let Err(e) = reopen_on_sighup(log_file) {
+            error!(msg = "fail to handle SIGHUP", err = %e);
+            exit(1);
+        }
+    } else if stdio || check || bench.is_some() {
+        // stdout carries the responses, or the report
+        subscriber.with_writer(io::stderr).init();
+    } else {
+        subscriber.init();
+    }
+    if let Some(bench) = bench {
+        if let Err(e) = bench.run() {
+            error!(msg = "bench error", err = %e);
+            exit(1);
+        }
+        return;
+    }
+    info!(target = tgt, "starting the server");
+    let res = current_dir().map_err(Into::into).and_then(move |dir| {
         let ip_port = matches
             .get_one::<String>("addr")
             .expect("please give a valid ip:port");
@@ -39,16 +206,56 @@ fn main() {
             error!(msg = "incorrect ip:port format");
             exit(1);
         }
-        let mut engine = matches.get_one("engine");
-        if engine.is_none() {
-            engine = curr_engine.as_ref();
+        let flag = matches
+            .get_one::<String>("engine")
+            .map(|name| EngineKind::parse(name).expect("checked by the value parser"));
+        // held until the server stops: the engine is detected from data no
+        // other server can be writing, and no other server opens it after
+        let _dir_lock = DirLock::acquire(&dir)?;
+        let engine = select_engine(&dir, flag)?;
+        let dbs: Vec<&str> = matches
+            .get_many::<String>("db")
+            .map(|dbs| dbs.map(String::as_str).collect())
+            .unwrap_or_default();
+        if check {
+            if engine != EngineKind::Kvs {
+                return Err(KvsError::StringErr(format!(
+                    "--check only supports the kvs engine, {} holds {} data",
+                    dir.display(),
+                    engine
+                )));
+            }
+            if !check_databases(&dir, &dbs)? {
+                exit(2);
+            }
+            return Ok(());
         }
-        if curr_engine.is_some() && engine != curr_engine.as_ref() {
-            error!(msg = "Mismatched engine!");
+        let metrics_addr = matches
+            .get_one::<String>("metrics-addr")
+            .map(String::as_str);
+        if metrics_addr.is_some_and(|addr| !addr_check(addr)) {
+            error!(msg = "incorrect metrics ip:port format");
             exit(1);
         }
-        info!(msg = "finish config", engine = engine, ip_port = ip_port);
-        run(engine.unwrap(), ip_port)
+        let listen = Listen {
+            ip_port,
+            metrics_addr,
+            no_delay: *matches.get_one("no-delay").expect("has a default"),
+            reuse_addr: *matches.get_one("reuse-addr").expect("has a default"),
+            backlog: *matches.get_one("backlog").expect("has a default"),
+            threads: matches.get_one("threads").copied(),
+            idle_timeout: matches
+                .get_one::<u64>("idle-timeout")
+                .map(|&secs| Duration::from_secs(secs)),
+            report_timing: *matches.get_one("report-timing").expect("has a default"),
+            stdio,
+        };
+        info!(msg = "finish config", engine = %engine, ip_port = ip_port);
+        let _pid_file = match matches.get_one::<PathBuf>("pid-file") {
+            Some(path) => Some(PidFile::create(path)?),
+            None => None,
+        };
+        run(engine, &dir, &dbs, durability, &listen)
     });
     if let Err(e) = res {
         error!(msg="running error", err=%e);
@@ -56,29 +263,371 @@ fn main() {
     }
 }
 
-fn run(engine: &str, ip_port: &str) -> Result<()> {
-    let current_dir = current_dir()?;
-    // change the engine option in dir
-    fs::write(current_dir.join("engine"), engine)?;
-    info!(msg = "flush engine option to engine file", engine = engine);
+fn run(
+    engine: EngineKind,
+    dir: &Path,
+    dbs: &[&str],
+    durability: Option<FlushPolicy>,
+    listen: &Listen,
+) -> Result<()> {
     match engine {
-        "kvs" => Server::new(KvsEngine::open(current_dir)?).run(ip_port),
-        "sled" => Server::new(SledKvsEngine::open(current_dir)?).run(ip_port),
-        _ => unreachable!(),
+        EngineKind::Kvs => serve(open_databases(dir, dbs, kvs_opener(durability))?, listen),
+        EngineKind::Sled => serve(open_databases(dir, dbs, sled_opener(durability))?, listen),
+    }
+}
+
+/// open a kvs store syncing its writes as `--durability` says
+fn kvs_opener(durability: Option<FlushPolicy>) -> impl Fn(PathBuf) -> Result<KvsEngine> {
+    let options = match durability {
+        Some(policy) => KvsOptions::default().flush_policy(policy),
+        None => KvsOptions::default(),
+    };
+    move |path| KvsEngine::open_with_options(path, options)
+}
+
+/// open a sled store flushing its writes as `--durability` says
+fn sled_opener(durability: Option<FlushPolicy>) -> impl Fn(PathBuf) -> Result<SledKvsEngine> {
+    let policy = durability.unwrap_or_default();
+    move |path| SledKvsEngine::open_with_flush_policy(path, policy)
+}
+
+/// the `FlushPolicy` of a `--durability` mode
+fn parse_durability(mode: &str) -> std::result::Result<FlushPolicy, String> {
+    match mode {
+        "buffered" => return Ok(FlushPolicy::Manual),
+        "sync" => return Ok(FlushPolicy::PerOperation),
+        _ => {}
+    }
+    match mode.strip_prefix("periodic:").map(str::parse::<u64>) {
+        Some(Ok(ms)) if ms > 0 => Ok(FlushPolicy::Periodic(Duration::from_millis(ms))),
+        _ => Err("expected buffered, sync or periodic:MS with MS > 0".to_owned()),
+    }
+}
+
+/// Where and how the server listens, from the command line.
+struct Listen<'a> {
+    ip_port: &'a str,
+    metrics_addr: Option<&'a str>,
+    no_delay: bool,
+    reuse_addr: bool,
+    backlog: u32,
+    threads: Option<u32>,
+    idle_timeout: Option<Duration>,
+    report_timing: bool,
+    // serve over stdin and stdout instead of `ip_port`
+    stdio: bool,
+}
+
+/// The engines a server can run on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EngineKind {
+    Kvs,
+    Sled,
+}
+
+impl EngineKind {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "kvs" => Some(EngineKind::Kvs),
+            "sled" => Some(EngineKind::Sled),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineKind::Kvs => write!(f, "kvs"),
+            EngineKind::Sled => write!(f, "sled"),
+        }
+    }
+}
+
+/// the engine to open `dir` with: the one whose data it already holds, else
+/// the one of `--engine`, else kvs. `--engine` naming another engine than the
+/// data's is an error, rather than a second store beside the first.
+fn select_engine(dir: &Path, flag: Option<EngineKind>) -> Result<EngineKind> {
+    match (detect_on_disk_engine(dir)?, flag) {
+        (Some(on_disk), Some(flag)) if on_disk != flag => Err(KvsError::StringErr(format!(
+            "{} holds data of the {} engine, it can't be opened with --engine {}",
+            dir.display(),
+            on_disk,
+            flag
+        ))),
+        (Some(on_disk), _) => {
+            info!(msg = "detect engine from the data directory", engine = %on_disk);
+            Ok(on_disk)
+        }
+        (None, flag) => Ok(flag.unwrap_or(EngineKind::Kvs)),
+    }
+}
+
+/// the engine whose data `dir` holds: bitcask log files for kvs, a `db` file
+/// for sled, `None` for a new directory
+fn detect_on_disk_engine(dir: &Path) -> Result<Option<EngineKind>> {
+    let kvs = FsStorage::detect(dir)?.is_some();
+    let sled = dir.join("db").is_file();
+    match (kvs, sled) {
+        (true, true) => Err(KvsError::StringErr(format!(
+            "{} holds data of both the kvs and the sled engine",
+            dir.display()
+        ))),
+        (true, false) => Ok(Some(EngineKind::Kvs)),
+        (false, true) => Ok(Some(EngineKind::Sled)),
+        (false, false) => Ok(None),
+    }
+}
+
+/// open the default database in `dir` and every named one in its own
+/// subdirectory of `dir/databases`, each with `open`
+fn open_databases<E: Engine + Debug>(
+    dir: &Path,
+    dbs: &[&str],
+    open: impl Fn(PathBuf) -> Result<E>,
+) -> Result<Server<E>> {
+    let mut server = Server::new(open(dir.to_owned())?);
+    for &db in dbs {
+        // the name becomes a directory, keep it from escaping `dir/databases`
+        let valid = !db.is_empty()
+            && db != DEFAULT_DB
+            && db
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(KvsError::StringErr(format!(
+                "invalid database name {:?}",
+                db
+            )));
+        }
+        info!(msg = "open database", db = db);
+        server = server.database(db, open(dir.join("databases").join(db))?);
+    }
+    Ok(server)
+}
+
+/// The workload of the `bench` subcommand.
+struct Bench {
+    engine: EngineKind,
+    ops: u64,
+    value_size: usize,
+    durability: Option<FlushPolicy>,
+}
+
+/// The throughput of an operation, as printed by `bench`.
+#[derive(Serialize)]
+struct BenchReport {
+    engine: String,
+    op: &'static str,
+    ops: u64,
+    value_size: usize,
+    secs: f64,
+    ops_per_sec: f64,
+}
+
+impl Bench {
+    fn from_matches(m: &ArgMatches, durability: Option<FlushPolicy>) -> Self {
+        let engine = m.get_one::<String>("engine").expect("has a default");
+        Self {
+            engine: EngineKind::parse(engine).expect("checked by the value parser"),
+            ops: *m.get_one("ops").expect("has a default"),
+            value_size: *m.get_one("value-size").expect("has a default"),
+            durability,
+        }
+    }
+
+    /// run the workload on an engine opened in a temporary directory,
+    /// removed once done
+    fn run(&self) -> Result<()> {
+        let dir = TempDir::new()?;
+        info!(msg = "start bench", engine = %self.engine, dir = %dir.path().display());
+        match self.engine {
+            EngineKind::Kvs => self.run_on(kvs_opener(self.durability)(dir.path().to_owned())?),
+            EngineKind::Sled => self.run_on(sled_opener(self.durability)(dir.path().to_owned())?),
+        }
+    }
+
+    /// set every key, then get every key back, checking the values
+    fn run_on<E: Engine>(&self, engine: E) -> Result<()> {
+        let value = "x".repeat(self.value_size);
+        let start = Instant::now();
+        for i in 0..self.ops {
+            engine.set(format!("key{}", i), value.clone())?;
+        }
+        engine.flush()?;
+        self.report("set", start.elapsed())?;
+
+        let start = Instant::now();
+        for i in 0..self.ops {
+            let key = format!("key{}", i);
+            if engine.get(&key)?.as_deref() != Some(value.as_str()) {
+                return Err(KvsError::StringErr(format!("{} lost its value", key)));
+            }
+        }
+        self.report("get", start.elapsed())
+    }
+
+    fn report(&self, op: &'static str, elapsed: Duration) -> Result<()> {
+        let secs = elapsed.as_secs_f64();
+        let report = BenchReport {
+            engine: self.engine.to_string(),
+            op,
+            ops: self.ops,
+            value_size: self.value_size,
+            secs,
+            ops_per_sec: self.ops as f64 / secs,
+        };
+        println!("{}", serde_json::to_string(&report)?);
+        Ok(())
+    }
+}
+
+/// The recovery report of a database, as printed by `--check`.
+#[derive(Serialize)]
+struct DbReport<'a> {
+    db: &'a str,
+    #[serde(flatten)]
+    report: RecoveryReport,
+}
+
+/// print a recovery report of the default database in `dir` and of every
+/// named one, one JSON line each, returning whether they are all clean
+fn check_databases(dir: &Path, dbs: &[&str]) -> Result<bool> {
+    let mut clean = true;
+    let dirs = dbs.iter().map(|&db| (db, dir.join("databases").join(db)));
+    for (db, path) in std::iter::once((DEFAULT_DB, dir.to_owned())).chain(dirs) {
+        let report = KvsEngine::check(&path)?;
+        info!(msg = "checked database", db = db, clean = report.is_clean());
+        clean &= report.is_clean();
+        println!("{}", serde_json::to_string(&DbReport { db, report })?);
     }
+    Ok(clean)
 }
 
-fn current_engine() -> Result<Option<String>> {
-    let engine = current_dir()?.join("engine");
-    if !engine.exists() {
-        return Ok(None);
+/// run the server until SIGTERM or SIGINT asks for a graceful shutdown
+fn serve<E: Engine + Debug + Sync>(mut server: Server<E>, listen: &Listen) -> Result<()> {
+    server = server
+        .no_delay(listen.no_delay)
+        .reuse_addr(listen.reuse_addr)
+        .backlog(listen.backlog)
+        .report_timing(listen.report_timing);
+    if let Some(metrics_addr) = listen.metrics_addr {
+        server = server.metrics_addr(metrics_addr);
     }
+    if let Some(threads) = listen.threads {
+        server = server.threads(threads);
+    }
+    if let Some(idle_timeout) = listen.idle_timeout {
+        server = server.idle_timeout(idle_timeout);
+    }
+    // the server stops once its client closes stdin, a signal couldn't
+    // interrupt the read anyway, so it keeps killing the process
+    if listen.stdio {
+        return server.run_stdio();
+    }
+    let handle = server.shutdown_handle();
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!(msg = "received signal, shutting down", signal = signal);
+            handle.shutdown();
+        }
+    });
+    server.run(listen.ip_port)
+}
+
+/// The file of `--log-file`, which the logs are appended to. Its clones share
+/// the open file, so reopening it moves every writer to the new one.
+#[derive(Clone)]
+struct LogFile {
+    path: PathBuf,
+    file: Arc<RwLock<Arc<File>>>,
+}
+
+impl LogFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_owned(),
+            file: Arc::new(RwLock::new(Arc::new(Self::open_file(path)?))),
+        })
+    }
+
+    fn open_file(path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    /// open the path again, after log rotation renamed the file. The events
+    /// recorded between the rename and the reopen are in the renamed file.
+    fn reopen(&self) -> io::Result<()> {
+        let file = Self::open_file(&self.path)?;
+        let mut current = self
+            .file
+            .write()
+            .expect("no writer panics holding the lock");
+        *current = Arc::new(file);
+        Ok(())
+    }
+}
+
+impl MakeWriter for LogFile {
+    type Writer = LogWriter;
+
+    fn make_writer(&self) -> LogWriter {
+        LogWriter(Arc::clone(
+            &self.file.read().expect("no writer panics holding the lock"),
+        ))
+    }
+}
+
+/// Writes an event to the log file open when it was recorded. The
+/// subscriber writes an event at once, appended whole to the file.
+struct LogWriter(Arc<File>);
+
+impl io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+/// reopen `log_file` on every SIGHUP, as logrotate expects
+fn reopen_on_sighup(log_file: LogFile) -> Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            let path = log_file.path.display();
+            match log_file.reopen() {
+                Ok(()) => info!(msg = "reopened log file", path = %path),
+                // the logs keep going to the renamed file
+                Err(e) => error!(msg = "fail to reopen log file", path = %path, err = %e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// A file holding the server's process id, removed when dropped.
+struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    fn create(path: &Path) -> Result<Self> {
+        fs::write(path, process::id().to_string())?;
+        info!(msg = "write pid file", path = %path.display());
+        Ok(Self {
+            path: path.to_owned(),
+        })
+    }
+}
 
-    match fs::read_to_string(engine) {
-        Ok(engine) => Ok(Some(engine)),
-        Err(e) => {
-            warn!(target="load engines", msg="The content of engine file is invalid", content=%e);
-            Ok(None)
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(msg = "fail to remove pid file", path = %self.path.display(), err = %e);
         }
     }
 }
@@ -1,51 +1,1037 @@
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::TcpStream,
+    collections::VecDeque,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    process::Child,
+    sync::mpsc::SyncSender,
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::{GetResp, KvsError, RemoveResp, Request, Result, SetResp};
-use serde::Deserialize;
+use crate::{
+    AppendResp, ContainsResp, CountPrefixResp, DiscardResp, Engine, EngineStats, FlushResp,
+    GetManyResp, GetResp, KvsError, RemoveIfResp, RemovePrefixResp, RemoveResp, RenameResp,
+    Request, Result, ScanCloseResp, ScanFilterResp, ScanPage, ScanResp, SelectResp, SetAllResp,
+    SetResp, StatsResp, ValueFilter, ValueLenResp,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{de::IoRead, Deserializer};
+
+/// the stream of responses from the server
+type RespReader = Deserializer<IoRead<BufReader<Box<dyn Read + Send>>>>;
+
+/// the stream of requests to the server
+type ReqWriter = BufWriter<Box<dyn Write + Send>>;
+
+/// time to wait before the first retry, doubled on every further attempt
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// pairs fetched at a time by `Client::scan_into`
+const SCAN_INTO_PAGE: usize = 100;
+
+/// time given to each address of `Client::connect_any` to accept
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// requests written ahead of their responses by a pipelining call, at most,
+/// so that the client and the server never both block writing to a full
+/// socket: the server waits to send its responses while the client sends
+/// more requests
+const PIPELINE_WINDOW: usize = 64;
+
+/// bytes of requests written ahead of their responses, at most, unless a
+/// single request is larger
+const PIPELINE_WINDOW_BYTES: usize = 64 * 1024;
+
+/// The operations every kvs client offers, whatever the transport.
+pub trait KvClient {
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+
+    fn remove(&mut self, key: String) -> Result<()>;
+
+    /// remove the key if it exists, returning whether it did
+    fn discard(&mut self, key: String) -> Result<bool>;
+
+    /// remove the key if its value is `expected`, returning whether it did
+    fn remove_if(&mut self, key: String, expected: String) -> Result<bool>;
+
+    /// append `suffix` to the value of `key`, returning the new length
+    fn append(&mut self, key: String, suffix: String) -> Result<usize>;
+}
+
+/// An operation of `Client::batch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Get { key: String },
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
 pub struct Client {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    // `None` over pipes, which can't be opened again
+    addr: Option<String>,
+    retries: u32,
+    // the database selected on the server, selected again on reconnection
+    db: Option<String>,
+    // prepended to every key sent, stripped from every key received
+    prefix: String,
+    reader: RespReader,
+    writer: ReqWriter,
+    // the connection, to time its reads out, `None` over pipes
+    stream: Option<TcpStream>,
+    // whether responses may still come for requests given up on, in which
+    // case the next request opens a new connection
+    broken: bool,
 }
 
 impl Client {
     pub fn connect(addr: &str) -> Result<Self> {
-        let stream = TcpStream::connect(addr)?;
-        let reader = Deserializer::from_reader(BufReader::new(stream.try_clone()?));
-        let writer = BufWriter::new(stream);
-        Ok(Self { reader, writer })
+        Ok(Self::over_tcp(addr, open(addr, None)?))
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let resp = GetResp::deserialize(&mut self.reader)?;
-        match resp {
-            GetResp::Ok(v) => Ok(v),
-            GetResp::Err(e) => Err(KvsError::StringErr(e)),
+    /// connect to the first of `addrs` to accept, trying them in order for
+    /// up to a second each, e.g. the replicas of a server. Fails only if
+    /// every address does, with the error of each. Reconnections go to the
+    /// address connected to.
+    pub fn connect_any(addrs: &[&str]) -> Result<Self> {
+        let mut failures = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            match open(addr, Some(CONNECT_TIMEOUT)) {
+                Ok(conn) => return Ok(Self::over_tcp(addr, conn)),
+                Err(e) => failures.push(format!("{}: {}", addr, e)),
+            }
+        }
+        Err(KvsError::StringErr(format!(
+            "unable to connect to any of {} addresses: {}",
+            addrs.len(),
+            failures.join("; ")
+        )))
+    }
+
+    fn over_tcp(addr: &str, (reader, writer, stream): (RespReader, ReqWriter, TcpStream)) -> Self {
+        Self {
+            addr: Some(addr.to_owned()),
+            retries: 0,
+            db: None,
+            prefix: String::new(),
+            reader,
+            writer,
+            stream: Some(stream),
+            broken: false,
         }
     }
 
+    /// talk to a server run by `child` with `Server::run_stdio`, through
+    /// its stdin and stdout, which must be piped. They are taken from
+    /// `child`, and its stdin is closed when the client is dropped, which
+    /// stops the server.
+    ///
+    /// The pipes can't be opened again, so a broken connection is not
+    /// retried even with `set_retries`.
+    pub fn connect_stdio(child: &mut Child) -> Result<Self> {
+        let missing = |pipe| KvsError::StringErr(format!("the {} of the child is not piped", pipe));
+        let stdin = child.stdin.take().ok_or_else(|| missing("stdin"))?;
+        let stdout = child.stdout.take().ok_or_else(|| missing("stdout"))?;
+        Ok(Self {
+            addr: None,
+            retries: 0,
+            db: None,
+            prefix: String::new(),
+            reader: Deserializer::from_reader(BufReader::new(Box::new(stdout))),
+            writer: BufWriter::new(Box::new(stdin)),
+            stream: None,
+            broken: false,
+        })
+    }
+
+    /// resend a request up to `retries` times when it fails with a retryable
+    /// error (see `KvsError::is_retryable`), reconnecting first if the
+    /// connection itself failed. No retry by default. A request which
+    /// mustn't be applied twice, like an append, is only resent if it failed
+    /// to be sent.
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    /// keep the keys of this client in their own namespace: `prefix` is
+    /// prepended to every key sent and stripped from the keys a scan
+    /// returns, so clients with different prefixes don't see each other's
+    /// keys on the same database. The server knows nothing of it.
+    ///
+    /// A prefix which is a prefix of another one (`app` and `app2`) doesn't
+    /// isolate them: end the prefixes with a separator, like `app:`.
+    pub fn with_prefix(mut self, prefix: String) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// send the next requests to the server's database `db`
+    pub fn select(&mut self, db: String) -> Result<()> {
+        self.retry(|client| client.send_select(&db))?;
+        self.db = Some(db);
+        Ok(())
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let key = self.namespaced(key);
+        let req = Request::Get { key };
+        self.retry(|client| {
+            client.send(&req)?;
+            read_get(&mut client.reader)?
+        })
+    }
+
+    /// the values of `keys` in order, in a single request. A key repeated is
+    /// read once by the server, its value sent for every position.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let keys = keys.into_iter().map(|key| self.namespaced(key)).collect();
+        let req = Request::GetMany { keys };
+        self.retry(|client| {
+            client.send(&req)?;
+            match GetManyResp::deserialize(&mut client.reader)? {
+                GetManyResp::Ok(values) => Ok(values),
+                GetManyResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// the length in bytes of the value of the key, without receiving the
+    /// value, see `Engine::value_len`
+    pub fn value_len(&mut self, key: String) -> Result<Option<usize>> {
+        let key = self.namespaced(key);
+        let req = Request::ValueLen { key };
+        self.retry(|client| {
+            client.send(&req)?;
+            match ValueLenResp::deserialize(&mut client.reader)? {
+                ValueLenResp::Ok(len) => Ok(len),
+                ValueLenResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// whether the key exists, which the server can tell without reading
+    /// the value, see `Engine::contains_key`
+    pub fn contains(&mut self, key: String) -> Result<bool> {
+        let key = self.namespaced(key);
+        let req = Request::Contains { key };
+        self.retry(|client| {
+            client.send(&req)?;
+            match ContainsResp::deserialize(&mut client.reader)? {
+                ContainsResp::Ok(exists) => Ok(exists),
+                ContainsResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// get a value like `get`, with the time the server spent on the get,
+    /// which must report timing, see `Server::report_timing`
+    pub fn get_timed(&mut self, key: String) -> Result<(Option<String>, Duration)> {
+        let key = self.namespaced(key);
+        let req = Request::Timed(Box::new(Request::Get { key }));
+        self.retry(|client| {
+            client.send(&req)?;
+            match read_timed(&mut client.reader)? {
+                (GetResp::Ok(value), spent) => Ok((value, spent?)),
+                (GetResp::Err { msg, retryable }, _) => Err(KvsError::Server { msg, retryable }),
+                (resp, _) => Err(KvsError::Protocol(format!(
+                    "unexpected response to a timed get: {:?}",
+                    resp
+                ))),
+            }
+        })
+    }
+
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResp::deserialize(&mut self.reader)?;
-        match resp {
-            SetResp::Ok(_) => Ok(()),
-            SetResp::Err(e) => Err(KvsError::StringErr(e)),
+        let key = self.namespaced(key);
+        let req = Request::Set { key, value };
+        self.retry(|client| {
+            client.send(&req)?;
+            match SetResp::deserialize(&mut client.reader)? {
+                SetResp::Ok(_) => Ok(()),
+                SetResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// set a value like `set`, returning the time the server spent on the
+    /// set, which must report timing, see `Server::report_timing`
+    pub fn set_timed(&mut self, key: String, value: String) -> Result<Duration> {
+        let key = self.namespaced(key);
+        let req = Request::Timed(Box::new(Request::Set { key, value }));
+        self.retry(|client| {
+            client.send(&req)?;
+            match read_timed(&mut client.reader)? {
+                (SetResp::Ok(_), spent) => spent,
+                (SetResp::Err { msg, retryable }, _) => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// get a value stored by `set_as`, deserialized from its JSON
+    pub fn get_as<T: DeserializeOwned>(&mut self, key: String) -> Result<Option<T>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
         }
     }
 
+    /// set a value serialized as JSON, to be read back by `get_as`
+    pub fn set_as<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<()> {
+        let value = serde_json::to_string(value)?;
+        self.set(key, value)
+    }
+
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
+        let key = self.namespaced(key);
+        let req = Request::Remove { key };
+        // removing again would report the key as missing
+        self.retry_send(&req, |client| {
+            match RemoveResp::deserialize(&mut client.reader)? {
+                RemoveResp::Ok(_) => Ok(()),
+                RemoveResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    pub fn discard(&mut self, key: String) -> Result<bool> {
+        let key = self.namespaced(key);
+        let req = Request::Discard { key };
+        // discarding again would report the key as missing
+        self.retry_send(&req, |client| {
+            match DiscardResp::deserialize(&mut client.reader)? {
+                DiscardResp::Ok(removed) => Ok(removed),
+                DiscardResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    pub fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        let key = self.namespaced(key);
+        let req = Request::RemoveIf { key, expected };
+        // removing again would report the value as changed
+        self.retry_send(&req, |client| {
+            match RemoveIfResp::deserialize(&mut client.reader)? {
+                RemoveIfResp::Ok(removed) => Ok(removed),
+                RemoveIfResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    pub fn append(&mut self, key: String, suffix: String) -> Result<usize> {
+        let key = self.namespaced(key);
+        let req = Request::Append { key, suffix };
+        // appending twice would append twice
+        self.retry_send(&req, |client| {
+            match AppendResp::deserialize(&mut client.reader)? {
+                AppendResp::Ok(len) => Ok(len),
+                AppendResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// move the value of `from` to `to` atomically, overwriting `to`, see
+    /// `Engine::rename`
+    pub fn rename(&mut self, from: String, to: String) -> Result<()> {
+        let req = Request::Rename {
+            from: self.namespaced(from),
+            to: self.namespaced(to),
+        };
+        // renaming again would report `from` as missing
+        self.retry_send(&req, |client| {
+            match RenameResp::deserialize(&mut client.reader)? {
+                RenameResp::Ok(_) => Ok(()),
+                RenameResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// remove every key starting with `prefix` in a single request, all at
+    /// once, and return how many were removed. See `Engine::remove_prefix`.
+    pub fn delete_prefix(&mut self, prefix: String) -> Result<usize> {
+        let prefix = self.namespaced(prefix);
+        let req = Request::RemovePrefix { prefix };
+        // deleting again would count none of the keys removed
+        self.retry_send(&req, |client| {
+            match RemovePrefixResp::deserialize(&mut client.reader)? {
+                RemovePrefixResp::Ok(count) => Ok(count),
+                RemovePrefixResp::Err { msg, retryable } => {
+                    Err(KvsError::Server { msg, retryable })
+                }
+            }
+        })
+    }
+
+    /// the number of keys starting with `prefix`, counted by the server
+    /// without sending them. See `Engine::count_prefix`.
+    pub fn count_prefix(&mut self, prefix: String) -> Result<usize> {
+        let prefix = self.namespaced(prefix);
+        let req = Request::CountPrefix { prefix };
+        self.retry(|client| {
+            client.send(&req)?;
+            match CountPrefixResp::deserialize(&mut client.reader)? {
+                CountPrefixResp::Ok(count) => Ok(count),
+                CountPrefixResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// get the health figures of the server's engine
+    pub fn stats(&mut self) -> Result<EngineStats> {
+        self.retry(|client| {
+            client.send(&Request::Stats)?;
+            match StatsResp::deserialize(&mut client.reader)? {
+                StatsResp::Ok(stats) => Ok(stats),
+                StatsResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// make the writes done so far durable on the server, returning once
+    /// they are, whatever the engine's own flushing (see `Engine::flush`)
+    pub fn flush(&mut self) -> Result<()> {
+        self.retry(|client| {
+            client.send(&Request::Flush)?;
+            match FlushResp::deserialize(&mut client.reader)? {
+                FlushResp::Ok(_) => Ok(()),
+                FlushResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// open a server-side cursor over the keys starting with `prefix`
+    /// and get its first page of at most `count` pairs
+    pub fn scan_start(&mut self, prefix: String, count: usize) -> Result<ScanPage> {
+        let prefix = self.namespaced(prefix);
+        let req = Request::ScanStart { prefix, count };
+        self.retry(|client| {
+            client.send(&req)?;
+            match ScanResp::deserialize(&mut client.reader)? {
+                ScanResp::Ok(mut page) => {
+                    client.strip_namespace(&mut page.entries);
+                    Ok(page)
+                }
+                ScanResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// get the next page of at most `count` pairs of an open cursor
+    pub fn scan_next(&mut self, cursor: u64, count: usize) -> Result<ScanPage> {
+        let req = Request::ScanNext { cursor, count };
+        self.retry(|client| {
+            client.send(&req)?;
+            match ScanResp::deserialize(&mut client.reader)? {
+                ScanResp::Ok(mut page) => {
+                    client.strip_namespace(&mut page.entries);
+                    Ok(page)
+                }
+                ScanResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// close an open cursor before it is exhausted, returning whether it
+    /// was open. An exhausted cursor is closed by the server on its own.
+    pub fn scan_close(&mut self, cursor: u64) -> Result<bool> {
+        let req = Request::ScanClose { cursor };
+        self.retry(|client| {
+            client.send(&req)?;
+            match ScanCloseResp::deserialize(&mut client.reader)? {
+                ScanCloseResp::Ok(open) => Ok(open),
+                ScanCloseResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// send the pairs whose key starts with `prefix` into `tx` in key
+    /// order, fetching them a page at a time over a cursor, and return how
+    /// many were sent. The scan waits while the channel is full, so at most
+    /// a page and the channel's capacity are held at once. Once the
+    /// receiver is dropped, the scan stops and closes its cursor.
+    pub fn scan_into(&mut self, prefix: String, tx: SyncSender<(String, String)>) -> Result<usize> {
+        let mut page = self.scan_start(prefix, SCAN_INTO_PAGE)?;
+        let mut sent = 0;
+        loop {
+            for entry in page.entries {
+                if tx.send(entry).is_err() {
+                    if let Some(cursor) = page.cursor {
+                        self.scan_close(cursor)?;
+                    }
+                    return Ok(sent);
+                }
+                sent += 1;
+            }
+            match page.cursor {
+                Some(cursor) => page = self.scan_next(cursor, SCAN_INTO_PAGE)?,
+                None => return Ok(sent),
+            }
+        }
+    }
+
+    /// the pairs whose key starts with `prefix` and whose value matches
+    /// `filter`, in key order. The server evaluates the filter, so only the
+    /// matches are sent.
+    pub fn scan_filter(
+        &mut self,
+        prefix: String,
+        filter: ValueFilter,
+    ) -> Result<Vec<(String, String)>> {
+        let prefix = self.namespaced(prefix);
+        let req = Request::ScanFilter { prefix, filter };
+        self.retry(|client| {
+            client.send(&req)?;
+            match ScanFilterResp::deserialize(&mut client.reader)? {
+                ScanFilterResp::Ok(mut entries) => {
+                    client.strip_namespace(&mut entries);
+                    Ok(entries)
+                }
+                ScanFilterResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }
+        })
+    }
+
+    /// iterate over the pairs whose key starts with `prefix` in key order,
+    /// fetching them `page_size` at a time
+    pub fn scan(&mut self, prefix: String, page_size: usize) -> Scan<'_> {
+        assert!(page_size > 0, "page size must not be zero");
+        Scan {
+            client: self,
+            prefix: Some(prefix),
+            cursor: None,
+            page_size,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// send `ops` at once, without waiting for a response in between, and
+    /// get their results in order: the value of a get, `None` for a set or
+    /// a remove. The responses are read as they come until `timeout` has
+    /// elapsed since the call. The operations not answered by then fail
+    /// with `KvsError::Timeout`: the server may or may not have applied
+    /// them, but it applied the ones answered, so the caller can resume
+    /// from the first which timed out.
+    ///
+    /// Late responses would be taken for those of the next requests, so the
+    /// next request after a timeout opens a new connection. A batch is not
+    /// retried, whatever `set_retries`. Over pipes, which can't time out,
+    /// every response is waited for.
+    pub fn batch(
+        &mut self,
+        ops: Vec<BatchOp>,
+        timeout: Duration,
+    ) -> Result<Vec<Result<Option<String>>>> {
+        let deadline = Instant::now() + timeout;
+        if self.broken {
+            self.reconnect()?;
+        }
+        let reqs: Vec<Request> = ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Get { key } => Request::Get {
+                    key: self.namespaced(key),
+                },
+                BatchOp::Set { key, value } => Request::Set {
+                    key: self.namespaced(key),
+                    value,
+                },
+                BatchOp::Remove { key } => Request::Remove {
+                    key: self.namespaced(key),
+                },
+            })
+            .collect();
+        let frames = reqs
+            .iter()
+            .map(serde_json::to_vec)
+            .collect::<serde_json::Result<Vec<_>>>()?;
+        let mut results = Vec::with_capacity(reqs.len());
+        let mut failure = None;
+        let mut sent = Ok(());
+        // pipelined a window at a time, see `PIPELINE_WINDOW`
+        let mut start = 0;
+        'windows: while start < reqs.len() {
+            let end = start + window_len(&frames[start..]);
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                failure = Some(KvsError::Timeout);
+                break;
+            }
+            self.set_timeout(Some(left))?;
+            // the requests written before a failure may still be answered
+            sent = frames[start..end]
+                .iter()
+                .try_for_each(|frame| self.writer.write_all(frame))
+                .and_then(|_| self.writer.flush());
+            for req in &reqs[start..end] {
+                match self.recv_batch(req, deadline) {
+                    Ok(res) => results.push(res),
+                    Err(e) => {
+                        failure = Some(e);
+                        break 'windows;
+                    }
+                }
+            }
+            if let Err(e) = &sent {
+                failure = Some(io::Error::new(e.kind(), e.to_string()).into());
+                break;
+            }
+            start = end;
+        }
+        if results.len() < reqs.len() || sent.is_err() {
+            self.broken = true;
+        } else {
+            self.set_timeout(None)?;
+        }
+        // the unanswered operations share the fate of the first of them
+        let (kind, msg) = match &failure {
+            Some(KvsError::Timeout) | None => (None, String::new()),
+            Some(e) => (Some(io_error_kind(e)), e.to_string()),
+        };
+        while results.len() < reqs.len() {
+            results.push(Err(match kind {
+                Some(kind) => io::Error::new(kind, msg.clone()).into(),
+                None => KvsError::Timeout,
+            }));
+        }
+        Ok(results)
+    }
+
+    /// set every pair of `pairs`, in a single round trip, returning the
+    /// result of each pair in order.
+    ///
+    /// Atomic, the pairs are set at once or not at all, see
+    /// `Engine::set_all`: a failure fails the call, nothing being set.
+    /// Otherwise they are sent as independent sets, pipelined like a
+    /// `batch`, cheaper for the server: each may fail on its own, the others
+    /// being set, and a reader may see some of them set before the others.
+    /// If the connection fails partway, the pairs answered by then keep their
+    /// result and the others fail with its error, as in a `batch`.
+    /// Only an atomic call is retried, see `set_retries`.
+    pub fn set_many(
+        &mut self,
+        pairs: Vec<(String, String)>,
+        atomic: bool,
+    ) -> Result<Vec<Result<()>>> {
+        let len = pairs.len();
+        let pairs: Vec<(String, String)> = pairs
+            .into_iter()
+            .map(|(key, value)| (self.namespaced(key), value))
+            .collect();
+        if atomic {
+            let req = Request::SetAll { pairs };
+            self.retry(|client| {
+                client.send(&req)?;
+                match SetAllResp::deserialize(&mut client.reader)? {
+                    SetAllResp::Ok(_) => Ok(()),
+                    SetAllResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+                }
+            })?;
+            return Ok((0..len).map(|_| Ok(())).collect());
+        }
+
+        if self.broken {
+            self.reconnect()?;
+        }
+        let frames = pairs
+            .into_iter()
+            .map(|(key, value)| serde_json::to_vec(&Request::Set { key, value }))
+            .collect::<serde_json::Result<Vec<_>>>()?;
+        let mut results = Vec::with_capacity(len);
+        let mut failure = None;
+        let mut rest = &frames[..];
+        'windows: while !rest.is_empty() {
+            let (window, after) = rest.split_at(window_len(rest));
+            // the sets written before a failure may still be answered
+            let sent = window
+                .iter()
+                .try_for_each(|frame| self.writer.write_all(frame))
+                .and_then(|_| self.writer.flush());
+            for _ in window {
+                match SetResp::deserialize(&mut self.reader) {
+                    Ok(SetResp::Ok(_)) => results.push(Ok(())),
+                    Ok(SetResp::Err { msg, retryable }) => {
+                        results.push(Err(KvsError::Server { msg, retryable }))
+                    }
+                    Err(e) => {
+                        failure = Some(KvsError::from(e));
+                        break 'windows;
+                    }
+                }
+            }
+            if let Err(e) = sent {
+                failure = Some(e.into());
+                break;
+            }
+            rest = after;
+        }
+        // the unanswered sets share the fate of the first of them, and may
+        // or may not have been applied
+        if let Some(e) = failure {
+            self.broken = true;
+            let (kind, msg) = (io_error_kind(&e), e.to_string());
+            while results.len() < len {
+                results.push(Err(io::Error::new(kind, msg.clone()).into()));
+            }
+        }
+        Ok(results)
+    }
+
+    /// the response to the `req` of a batch, or the reason none came
+    /// before `deadline`
+    fn recv_batch(&mut self, req: &Request, deadline: Instant) -> Result<Result<Option<String>>> {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(KvsError::Timeout);
+        }
+        self.set_timeout(Some(left))?;
+        let res = match req {
+            Request::Get { .. } => read_get(&mut self.reader),
+            Request::Set { .. } => SetResp::deserialize(&mut self.reader).map(|resp| match resp {
+                SetResp::Ok(_) => Ok(None),
+                SetResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+            }),
+            Request::Remove { .. } => {
+                RemoveResp::deserialize(&mut self.reader).map(|resp| match resp {
+                    RemoveResp::Ok(_) => Ok(None),
+                    RemoveResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+                })
+            }
+            req => unreachable!("{:?} is not a batch operation", req),
+        };
+        match res {
+            // a read timing out fails as the deadline passes
+            Err(e) if e.is_io() && Instant::now() >= deadline => Err(KvsError::Timeout),
+            res => Ok(res?),
+        }
+    }
+
+    /// bound the reads and writes of the connection by `timeout`, if over TCP
+    fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        if let Some(stream) = &self.stream {
+            stream.set_read_timeout(timeout)?;
+            stream.set_write_timeout(timeout)?;
+        }
+        Ok(())
+    }
+
+    /// the key the server stores `key` under, see `with_prefix`
+    fn namespaced(&self, key: String) -> String {
+        if self.prefix.is_empty() {
+            key
+        } else {
+            format!("{}{}", self.prefix, key)
+        }
+    }
+
+    /// the keys of `entries` as this client named them, the server only
+    /// returns keys starting with the prefix
+    fn strip_namespace(&self, entries: &mut [(String, String)]) {
+        for (key, _) in entries {
+            key.drain(..self.prefix.len());
+        }
+    }
+
+    fn send(&mut self, req: &Request) -> Result<()> {
+        if self.broken {
+            self.reconnect()?;
+        }
+        serde_json::to_writer(&mut self.writer, req)?;
         self.writer.flush()?;
-        let resp = RemoveResp::deserialize(&mut self.reader)?;
-        match resp {
-            RemoveResp::Ok(_) => Ok(()),
-            RemoveResp::Err(e) => Err(KvsError::StringErr(e)),
+        Ok(())
+    }
+
+    fn send_select(&mut self, db: &str) -> Result<()> {
+        self.send(&Request::Select { db: db.to_owned() })?;
+        match SelectResp::deserialize(&mut self.reader)? {
+            SelectResp::Ok(_) => Ok(()),
+            SelectResp::Err { msg, retryable } => Err(KvsError::Server { msg, retryable }),
+        }
+    }
+
+    fn retry<T>(&mut self, mut f: impl FnMut(&mut Self) -> Result<T>) -> Result<T> {
+        let mut attempt = 0;
+        let mut broken = false;
+        loop {
+            let res = if broken {
+                self.reconnect().and_then(|_| f(self))
+            } else {
+                f(self)
+            };
+            match res {
+                Err(e) if e.is_retryable() && attempt < self.retries => {
+                    thread::sleep(RETRY_BACKOFF * 2u32.pow(attempt));
+                    attempt += 1;
+                    // an error on our side leaves the stream in an unknown state
+                    broken = !matches!(e, KvsError::Server { .. });
+                }
+                res => {
+                    if let Err(e) = &res {
+                        // so that a late response isn't read as the next one
+                        self.broken = !matches!(e, KvsError::Server { .. });
+                    }
+                    return res;
+                }
+            }
+        }
+    }
+
+    /// send `req`, which mustn't be applied twice, and read its response with
+    /// `recv`. Unlike `retry`, only a failure to send it is retried: once
+    /// sent, the server may have applied it even if no response comes back,
+    /// or a retryable error does.
+    fn retry_send<T>(
+        &mut self,
+        req: &Request,
+        recv: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match self.send(req) {
+                Ok(()) => break,
+                // a request not written whole can't be read by the server
+                Err(e) if e.is_retryable() && attempt < self.retries => {
+                    self.broken = true;
+                    thread::sleep(RETRY_BACKOFF * 2u32.pow(attempt));
+                    attempt += 1;
+                }
+                Err(e) => {
+                    self.broken = true;
+                    return Err(e);
+                }
+            }
+        }
+        let res = recv(self);
+        if let Err(e) = &res {
+            // an error on our side leaves the stream in an unknown state
+            self.broken = !matches!(e, KvsError::Server { .. });
+        }
+        res
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        let addr = self.addr.as_deref().ok_or_else(|| {
+            KvsError::StringErr("can't reconnect to a server over pipes".to_owned())
+        })?;
+        let (reader, writer, stream) = open(addr, None)?;
+        self.reader = reader;
+        self.writer = writer;
+        self.stream = Some(stream);
+        self.broken = false;
+        // a new connection starts on the default database
+        if let Some(db) = self.db.clone() {
+            self.send_select(&db)?;
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over a cursor scan, see `Client::scan`.
+pub struct Scan<'a> {
+    client: &'a mut Client,
+    // the prefix until the cursor is opened
+    prefix: Option<String>,
+    cursor: Option<u64>,
+    page_size: usize,
+    entries: VecDeque<(String, String)>,
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.entries.is_empty() {
+            let page = match (self.prefix.take(), self.cursor) {
+                (Some(prefix), _) => self.client.scan_start(prefix, self.page_size),
+                (None, Some(cursor)) => self.client.scan_next(cursor, self.page_size),
+                (None, None) => return None,
+            };
+            match page {
+                Ok(page) => {
+                    self.cursor = page.cursor;
+                    self.entries = page.entries.into();
+                }
+                Err(e) => {
+                    self.cursor = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.entries.pop_front().map(Ok)
+    }
+}
+
+impl KvClient for Client {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Client::get(self, key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        Client::set(self, key, value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        Client::remove(self, key)
+    }
+
+    fn discard(&mut self, key: String) -> Result<bool> {
+        Client::discard(self, key)
+    }
+
+    fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        Client::remove_if(self, key, expected)
+    }
+
+    fn append(&mut self, key: String, suffix: String) -> Result<usize> {
+        Client::append(self, key, suffix)
+    }
+}
+
+///
+/// LoopbackClient calls an in-process engine directly, without a server,
+/// sockets or serialization, so client code can be tested fast and
+/// deterministically. Engine errors are reported the way `Client` reports
+/// the errors sent back by a server.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{KvClient, KvsEngine, LoopbackClient};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let mut client = LoopbackClient::new(KvsEngine::open(temp_dir.path()).unwrap());
+/// client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+/// assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+/// ```
+#[derive(Debug, Clone)]
+pub struct LoopbackClient<E: Engine> {
+    engine: E,
+}
+
+impl<E: Engine> LoopbackClient<E> {
+    pub fn new(engine: E) -> Self {
+        Self { engine }
+    }
+}
+
+impl<E: Engine> KvClient for LoopbackClient<E> {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.engine.get(key).map_err(as_server_error)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.engine.set(key, value).map_err(as_server_error)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.engine.remove(key).map_err(as_server_error)
+    }
+
+    fn discard(&mut self, key: String) -> Result<bool> {
+        self.engine.discard(key).map_err(as_server_error)
+    }
+
+    fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        self.engine
+            .remove_if(key, expected)
+            .map_err(as_server_error)
+    }
+
+    fn append(&mut self, key: String, suffix: String) -> Result<usize> {
+        self.engine.append(key, suffix).map_err(as_server_error)
+    }
+}
+
+/// the error `Client` gets when the server fails with `e`
+fn as_server_error(e: KvsError) -> KvsError {
+    KvsError::Server {
+        retryable: e.is_retryable(),
+        msg: format!("{}", e),
+    }
+}
+
+/// read the response to a `Request::Get`, reassembling a value sent in
+/// chunks
+fn read_get(reader: &mut RespReader) -> serde_json::Result<Result<Option<String>>> {
+    let mut chunked = String::new();
+    loop {
+        match GetResp::deserialize(&mut *reader)? {
+            GetResp::Ok(v) => return Ok(Ok(v)),
+            GetResp::Err { msg, retryable } => return Ok(Err(KvsError::Server { msg, retryable })),
+            GetResp::ValueChunk { data } => chunked.push_str(&data),
+            GetResp::ValueEnd => return Ok(Ok(Some(chunked))),
+        }
+    }
+}
+
+/// read the response to a `Request::Timed`, with the time the server spent
+/// on the request, an error if the server doesn't report timing
+fn read_timed<T: DeserializeOwned>(reader: &mut RespReader) -> Result<(T, Result<Duration>)> {
+    let mut resp = serde_json::Value::deserialize(&mut *reader)?;
+    let micros = resp
+        .as_object_mut()
+        .and_then(|fields| fields.remove("server_micros"))
+        .and_then(|micros| micros.as_u64());
+    let spent = micros.map(Duration::from_micros).ok_or_else(|| {
+        KvsError::StringErr("the server doesn't report timing, see --report-timing".to_owned())
+    });
+    Ok((serde_json::from_value(resp)?, spent))
+}
+
+/// the number of the `frames` of requests to write before reading their
+/// responses, see `PIPELINE_WINDOW`: at least one
+fn window_len(frames: &[Vec<u8>]) -> usize {
+    let mut bytes = 0;
+    let within = frames
+        .iter()
+        .take(PIPELINE_WINDOW)
+        .take_while(|frame| {
+            bytes += frame.len();
+            bytes <= PIPELINE_WINDOW_BYTES
+        })
+        .count();
+    within.max(1)
+}
+
+/// the kind of I/O error a connection failure stands for
+fn io_error_kind(e: &KvsError) -> io::ErrorKind {
+    match e {
+        KvsError::IoErr(e) => e.kind(),
+        KvsError::SerdeErr(e) if e.is_eof() => io::ErrorKind::UnexpectedEof,
+        _ => io::ErrorKind::Other,
+    }
+}
+
+/// open a connection to `addr`, giving up on each of its resolved
+/// addresses after `timeout` if any
+fn open(addr: &str, timeout: Option<Duration>) -> Result<(RespReader, ReqWriter, TcpStream)> {
+    let stream = match timeout {
+        None => TcpStream::connect(addr)?,
+        Some(timeout) => connect_timeout(addr, timeout)?,
+    };
+    let reader: Box<dyn Read + Send> = Box::new(stream.try_clone()?);
+    let writer: Box<dyn Write + Send> = Box::new(stream.try_clone()?);
+    Ok((
+        Deserializer::from_reader(BufReader::new(reader)),
+        BufWriter::new(writer),
+        stream,
+    ))
+}
+
+/// `TcpStream::connect_timeout` to the first resolved address of `addr` to
+/// accept, failing with the error of the last one
+fn connect_timeout(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
         }
     }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
 }
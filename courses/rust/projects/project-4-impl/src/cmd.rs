@@ -1,7 +1,128 @@
+//! # cmd
+//! the commands stored in the log, and their on-disk record format.
+//!
+//! Every record written today is a JSON pair `[version, cmd]`, the format
+//! version in its header selecting how `cmd` is deserialized. Logs written
+//! before the version existed hold bare `cmd` objects, read as version 0.
+//! A store may hold both kinds until its next compaction, which copies the
+//! records as they are.
+//!
+//! A record may also be pretty-printed, see `KvsOptions::human_readable_log`.
+//! JSON ignores the whitespace between and inside records, so compact and
+//! pretty records mix freely in a log.
+//!
+//! The logs are portable across architectures: a record is text, holding no
+//! integer in the byte order of the host. The only binary fields of a store,
+//! the CRC32 ending a hint file and the entries of a spilled index, are
+//! little-endian whatever the host. A binary record format, if one is ever
+//! added, must fix its byte order the same way rather than take bincode's
+//! native one.
+//!
+//! To extend `Cmd` without breaking the old logs, give the new fields
+//! `#[serde(default)]` so older records still deserialize. A change that
+//! can't be expressed that way bumps `FORMAT_VERSION` and adds a match arm
+//! to `Record::into_cmd` for the new version, keeping the old ones.
+use std::io::Write;
+
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{KvsError, Result};
 
-#[derive(Serialize, Deserialize)]
+/// version of the records written by this build
+pub const FORMAT_VERSION: u8 = 1;
+
+/// A set carries the version of its key, see `KvsEngine::set_if_version`:
+/// 0 until the writer gives it the next version of the store. A set
+/// written before the versions existed reads as the first version.
+#[derive(Debug, Serialize, Deserialize)]
 pub enum Cmd {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+        #[serde(default = "first_version")]
+        version: u64,
+    },
+    Remove {
+        key: String,
+    },
+    /// a value set in a value log, as the set record of `len` bytes at
+    /// `pos` of its file `file_id`, see `KvsOptions::separate_values`
+    SetRef {
+        key: String,
+        file_id: u64,
+        pos: u64,
+        len: u64,
+        #[serde(default = "first_version")]
+        version: u64,
+    },
+}
+
+pub(crate) fn first_version() -> u64 {
+    1
+}
+
+impl Cmd {
+    /// the key the command writes
+    pub(crate) fn key(&self) -> &str {
+        match self {
+            Cmd::Set { key, .. } | Cmd::Remove { key } | Cmd::SetRef { key, .. } => key,
+        }
+    }
+
+    /// the version of the key a set writes, 0 for a remove
+    pub(crate) fn version(&self) -> u64 {
+        match self {
+            Cmd::Set { version, .. } | Cmd::SetRef { version, .. } => *version,
+            Cmd::Remove { .. } => 0,
+        }
+    }
+
+    /// write the command as a record of the current format, indented and
+    /// followed by a newline when `pretty`
+    pub(crate) fn write_record<W: Write>(&self, mut writer: W, pretty: bool) -> Result<()> {
+        #[cfg(feature = "test-util")]
+        if crate::test_util::armed(crate::test_util::PanicPoint::MidRecord) {
+            let record = serde_json::to_vec(&(FORMAT_VERSION, self))?;
+            writer.write_all(&record[..record.len() / 2])?;
+            panic!("panic injected in the middle of a record");
+        }
+        if pretty {
+            serde_json::to_writer_pretty(&mut writer, &(FORMAT_VERSION, self))?;
+            writer.write_all(b"\n")?;
+        } else {
+            serde_json::to_writer(writer, &(FORMAT_VERSION, self))?;
+        }
+        Ok(())
+    }
+}
+
+/// A record of the log, in any format version.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Record {
+    Versioned(u8, Value),
+    // written before the format was versioned
+    Legacy(Cmd),
+}
+
+impl Record {
+    /// the format version of the record, 0 for a legacy one
+    pub(crate) fn version(&self) -> u8 {
+        match self {
+            Record::Versioned(version, _) => *version,
+            Record::Legacy(_) => 0,
+        }
+    }
+
+    pub(crate) fn into_cmd(self) -> Result<Cmd> {
+        match self {
+            Record::Versioned(1, cmd) => Ok(serde_json::from_value(cmd)?),
+            Record::Versioned(version, _) => Err(KvsError::StringErr(format!(
+                "unsupported record format version {}",
+                version
+            ))),
+            Record::Legacy(cmd) => Ok(cmd),
+        }
+    }
 }
@@ -0,0 +1,252 @@
+use std::any;
+use std::fmt;
+use std::path::PathBuf;
+
+use super::{Engine, EngineStats, KvsEngine};
+use crate::Result;
+
+/// The operations of `Engine` in a form callable through a trait object:
+/// no `Clone` bound, no generic arguments.
+trait ErasedEngine: Send {
+    fn clone_box(&self) -> Box<dyn ErasedEngine>;
+
+    fn type_name(&self) -> &'static str;
+
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    fn get(&self, key: &str) -> Result<Option<String>>;
+
+    fn contains_key(&self, key: &str) -> Result<bool>;
+
+    fn value_len(&self, key: &str) -> Result<Option<usize>>;
+
+    fn remove(&self, key: &str) -> Result<()>;
+
+    fn remove_lenient(&self, key: &str) -> Result<()>;
+
+    fn discard(&self, key: &str) -> Result<bool>;
+
+    fn remove_if(&self, key: &str, expected: &str) -> Result<bool>;
+
+    fn append(&self, key: String, suffix: String) -> Result<usize>;
+
+    fn set_all(&self, pairs: Vec<(String, String)>) -> Result<()>;
+
+    fn rename(&self, from: String, to: String) -> Result<()>;
+
+    fn remove_prefix(&self, prefix: String) -> Result<usize>;
+
+    fn count_prefix(&self, prefix: String) -> Result<usize>;
+
+    fn flush(&self) -> Result<()>;
+
+    fn stats(&self) -> Result<EngineStats>;
+
+    fn scan(
+        &self,
+        prefix: String,
+        start_after: Option<String>,
+        count: usize,
+    ) -> Result<Vec<(String, String)>>;
+}
+
+impl<E: Engine> ErasedEngine for E {
+    fn clone_box(&self) -> Box<dyn ErasedEngine> {
+        Box::new(self.clone())
+    }
+
+    fn type_name(&self) -> &'static str {
+        any::type_name::<E>()
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        Engine::set(self, key, value)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        Engine::get(self, key)
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        Engine::contains_key(self, key)
+    }
+
+    fn value_len(&self, key: &str) -> Result<Option<usize>> {
+        Engine::value_len(self, key)
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        Engine::remove(self, key)
+    }
+
+    fn remove_lenient(&self, key: &str) -> Result<()> {
+        Engine::remove_lenient(self, key)
+    }
+
+    fn discard(&self, key: &str) -> Result<bool> {
+        Engine::discard(self, key)
+    }
+
+    fn remove_if(&self, key: &str, expected: &str) -> Result<bool> {
+        Engine::remove_if(self, key, expected)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        Engine::append(self, key, suffix)
+    }
+
+    fn set_all(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        Engine::set_all(self, pairs)
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        Engine::rename(self, from, to)
+    }
+
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        Engine::remove_prefix(self, prefix)
+    }
+
+    fn count_prefix(&self, prefix: String) -> Result<usize> {
+        Engine::count_prefix(self, prefix)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Engine::flush(self)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Engine::stats(self)
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        start_after: Option<String>,
+        count: usize,
+    ) -> Result<Vec<(String, String)>> {
+        Engine::scan(self, prefix, start_after, count)
+    }
+}
+
+///
+/// BoxedEngine holds any engine behind a trait object, so engines of
+/// different types can be picked at runtime or stored side by side. It is
+/// an `Engine` itself, every call dispatched to the engine it holds, and a
+/// clone is a handle on the same store, like a clone of that engine.
+///
+/// # Example
+///
+/// ```rust
+/// use kvs::{BoxedEngine, Engine, KvsEngine, SledKvsEngine};
+/// use tempfile::TempDir;
+///
+/// let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let engines = vec![
+///     BoxedEngine::new(KvsEngine::open(kvs_dir.path()).unwrap()),
+///     BoxedEngine::new(SledKvsEngine::open(sled_dir.path()).unwrap()),
+/// ];
+/// for engine in &engines {
+///     engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+///     assert_eq!(engine.get("key1").unwrap(), Some("value1".to_owned()));
+/// }
+/// ```
+pub struct BoxedEngine(Box<dyn ErasedEngine>);
+
+impl BoxedEngine {
+    pub fn new<E: Engine>(engine: E) -> Self {
+        Self(Box::new(engine))
+    }
+}
+
+impl Clone for BoxedEngine {
+    fn clone(&self) -> Self {
+        Self(self.0.clone_box())
+    }
+}
+
+impl fmt::Debug for BoxedEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("BoxedEngine")
+            .field(&self.0.type_name())
+            .finish()
+    }
+}
+
+impl Engine for BoxedEngine {
+    /// open a `KvsEngine`, the default engine. Use `BoxedEngine::new` to
+    /// box any other.
+    fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(Self::new(KvsEngine::open(path)?))
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        self.0.get(key.as_ref())
+    }
+
+    fn contains_key(&self, key: impl AsRef<str>) -> Result<bool> {
+        self.0.contains_key(key.as_ref())
+    }
+
+    fn value_len(&self, key: impl AsRef<str>) -> Result<Option<usize>> {
+        self.0.value_len(key.as_ref())
+    }
+
+    fn remove(&self, key: impl AsRef<str>) -> Result<()> {
+        self.0.remove(key.as_ref())
+    }
+
+    fn remove_lenient(&self, key: impl AsRef<str>) -> Result<()> {
+        self.0.remove_lenient(key.as_ref())
+    }
+
+    fn discard(&self, key: impl AsRef<str>) -> Result<bool> {
+        self.0.discard(key.as_ref())
+    }
+
+    fn remove_if(&self, key: impl AsRef<str>, expected: impl AsRef<str>) -> Result<bool> {
+        self.0.remove_if(key.as_ref(), expected.as_ref())
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        self.0.append(key, suffix)
+    }
+
+    fn set_all(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.0.set_all(pairs)
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        self.0.rename(from, to)
+    }
+
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        self.0.remove_prefix(prefix)
+    }
+
+    fn count_prefix(&self, prefix: String) -> Result<usize> {
+        self.0.count_prefix(prefix)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.0.stats()
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        start_after: Option<String>,
+        count: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.0.scan(prefix, start_after, count)
+    }
+}
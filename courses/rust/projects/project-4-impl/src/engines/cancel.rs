@@ -0,0 +1,29 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A handle to cancel a long-running operation, e.g. `KvsEngine::compact_cancellable`,
+/// `KvsEngine::check_cancellable` or an `Iter` made to stop with
+/// `Iter::cancel_on`, from another thread.
+/// The operation notices it between two records and fails with
+/// `KvsError::Cancelled`, leaving the store as it was. Clones share the
+/// same state, so cancelling any of them cancels the operation.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ask the operations watching the token to stop. A cancelled token
+    /// stays cancelled, so it cancels any later operation given it too.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,88 @@
+//! # contention
+//! diagnostics for writers queueing on the `KvsEngine` writer lock.
+//!
+//! Every write serializes on a single lock, so many threads writing at once
+//! (typically the same few hot keys) spend most of their time waiting. The
+//! monitor measures how long each write waited for the lock and, when most
+//! writes of a window waited longer than the threshold, warns with the keys
+//! written the most by the waiting writers. That is the hint to shard them.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+/// length of the window over which contention is measured
+const WINDOW: Duration = Duration::from_secs(1);
+/// minimal number of writes in a window for the contention to be reported
+const MIN_WRITES: usize = 100;
+
+#[derive(Debug)]
+pub(crate) struct ContentionMonitor {
+    // wait for the lock above which a write counts as contended
+    threshold: Duration,
+    window: Mutex<Window>,
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    writes: usize,
+    contended: usize,
+    // number of contended writes of each key
+    keys: HashMap<String, usize>,
+}
+
+impl ContentionMonitor {
+    pub(crate) fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            window: Mutex::new(Window {
+                started: Instant::now(),
+                writes: 0,
+                contended: 0,
+                keys: HashMap::new(),
+            }),
+        }
+    }
+
+    /// record a write of `key` which waited `waited` for the writer lock.
+    /// Must be called with the writer lock held, so recording adds no contention.
+    pub(crate) fn record(&self, key: &str, waited: Duration) {
+        let mut window = self.window.lock().unwrap();
+        window.writes += 1;
+        if waited >= self.threshold {
+            window.contended += 1;
+            match window.keys.get_mut(key) {
+                Some(count) => *count += 1,
+                None => {
+                    window.keys.insert(key.to_owned(), 1);
+                }
+            }
+        }
+        if window.started.elapsed() < WINDOW {
+            return;
+        }
+
+        // contention is sustained if most writes of the window waited
+        if window.writes >= MIN_WRITES && window.contended * 2 >= window.writes {
+            let (hot_key, hot_key_writes) = window
+                .keys
+                .iter()
+                .max_by_key(|(_, count)| **count)
+                .map(|(key, count)| (key.as_str(), *count))
+                .unwrap_or_default();
+            warn!(
+                msg = "writer lock is contended, consider sharding the hot keys",
+                writes = window.writes,
+                contended = window.contended,
+                hot_key,
+                hot_key_writes,
+            );
+        }
+        window.started = Instant::now();
+        window.writes = 0;
+        window.contended = 0;
+        window.keys.clear();
+    }
+}
@@ -0,0 +1,24 @@
+use std::time::Duration;
+
+/// When a `SledKvsEngine` flushes its writes to the disk, or a `KvsEngine`
+/// syncs them, see `KvsOptions::flush_policy`. Whatever the policy,
+/// `Engine::flush` flushes every write done so far, and so does dropping the
+/// last clone of the engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// every write, before it returns: a write survives any crash once
+    /// acknowledged, but waits on the disk
+    PerOperation,
+    /// every given interval, in the background: the writes of the last
+    /// interval may be lost in a crash
+    Periodic(Duration),
+    /// only on `Engine::flush`, and once the engine is dropped
+    Manual,
+}
+
+impl Default for FlushPolicy {
+    /// every 500 ms, as sled does by default
+    fn default() -> Self {
+        FlushPolicy::Periodic(Duration::from_millis(500))
+    }
+}
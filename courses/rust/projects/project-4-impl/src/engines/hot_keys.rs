@@ -0,0 +1,104 @@
+//! # hot keys
+//! approximate access counts of the keys of a `KvsEngine`, to find the keys
+//! read and written the most, i.e. the ones worth caching or sharding.
+//!
+//! Every access increments the counters of its key in a count-min sketch, a
+//! fixed grid of counters which overestimates a count at worst by the
+//! accesses of the keys it collides with, and never underestimates it. Only
+//! the `capacity` keys counted the most so far are remembered by name: an
+//! access takes a lock only when its key counts more than the least of them.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// number of rows of the sketch, i.e. of counters incremented per access
+const DEPTH: usize = 4;
+/// number of counters in a row of the sketch
+const WIDTH: usize = 4096;
+
+#[derive(Debug)]
+pub(crate) struct HotKeys {
+    counters: Vec<AtomicU64>,
+    // the keys counted the most, with their count when last recorded
+    top: Mutex<HashMap<String, u64>>,
+    capacity: usize,
+    // the least count of `top` once full, below which an access skips it
+    floor: AtomicU64,
+}
+
+impl HotKeys {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            counters: (0..DEPTH * WIDTH).map(|_| AtomicU64::new(0)).collect(),
+            top: Mutex::new(HashMap::with_capacity(capacity + 1)),
+            capacity,
+            floor: AtomicU64::new(0),
+        }
+    }
+
+    /// the counter of `key` in each row of the sketch
+    fn slots(key: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        // the rows hash with h1 + row * h2, h2 odd to cover the whole row
+        let (h1, h2) = (hash as u32 as usize, (hash >> 32) as usize | 1);
+        (0..DEPTH).map(move |row| row * WIDTH + h1.wrapping_add(row.wrapping_mul(h2)) % WIDTH)
+    }
+
+    /// the estimated number of accesses of `key`
+    fn estimate(&self, key: &str) -> u64 {
+        Self::slots(key)
+            .map(|slot| self.counters[slot].load(Ordering::Relaxed))
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// count an access of `key`
+    pub(crate) fn record(&self, key: &str) {
+        let count = Self::slots(key)
+            .map(|slot| self.counters[slot].fetch_add(1, Ordering::Relaxed) + 1)
+            .min()
+            .unwrap_or_default();
+        if count <= self.floor.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut top = self.top.lock().unwrap();
+        match top.get_mut(key) {
+            Some(recorded) => *recorded = count,
+            None => {
+                top.insert(key.to_owned(), count);
+            }
+        }
+        if top.len() > self.capacity {
+            let coldest = top
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, _)| key.clone())
+                .expect("more keys than the capacity");
+            top.remove(&coldest);
+        }
+        if top.len() == self.capacity {
+            let floor = top.values().min().copied().unwrap_or_default();
+            self.floor.store(floor, Ordering::Relaxed);
+        }
+    }
+
+    /// the `n` keys accessed the most with their estimated number of
+    /// accesses, the most accessed first
+    pub(crate) fn hottest(&self, n: usize) -> Vec<(String, u64)> {
+        let top = self.top.lock().unwrap();
+        let mut hottest: Vec<(String, u64)> = top
+            .keys()
+            .map(|key| (key.clone(), self.estimate(key)))
+            .collect();
+        drop(top);
+        hottest.sort_unstable_by(|(key1, count1), (key2, count2)| {
+            count2.cmp(count1).then_with(|| key1.cmp(key2))
+        });
+        hottest.truncate(n);
+        hottest
+    }
+}
@@ -0,0 +1,265 @@
+//! # key_dir
+//! the index of a `KvsEngine`: where the live record of every key is.
+//!
+//! It is held in memory, unless spilling is on (see `KvsOptions::index_spill`):
+//! then at most a given number of entries stay in memory, the others move to
+//! an on-disk tree, a temporary sled database removed with the engine. The
+//! index is rebuilt from the logs and the hint files at every open, so the
+//! tree doesn't need to outlive the process.
+//!
+//! # Read amplification
+//!
+//! A key found in memory costs a hash lookup, as without spilling. A spilled
+//! key costs a lookup of the tree as well, which may read its pages from the
+//! disk, before its value is read from the log: up to two disk reads per
+//! `get` instead of one. A missing key is looked up in both tiers.
+//!
+//! The entries written last are in memory: an entry is only spilled to make
+//! room for another one, and which one is picked arbitrarily. A spilled key
+//! comes back to memory when it is written again, not when it is read.
+//!
+//! Only the positions spill: the ordered set of keys which `Engine::scan`
+//! walks stays in memory.
+//!
+//! # Hashing
+//!
+//! The keys in memory are hashed with the randomly seeded hasher of the
+//! standard library by default, or with FxHash, faster on short keys but
+//! open to crafted collisions, see `KvsOptions::fast_key_hash`.
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dashmap::DashMap;
+use fxhash::FxHasher;
+
+use crate::Result;
+
+/// An index entry the spilled tier can store, as bytes.
+pub(super) trait SpilledEntry: Clone {
+    fn to_bytes(&self) -> Vec<u8>;
+
+    fn from_bytes(bytes: &[u8]) -> Self;
+}
+
+/// How the keys of the index are hashed.
+#[derive(Debug, Clone)]
+pub(super) enum KeyHasher {
+    /// the standard library's SipHash, with random keys
+    Random(RandomState),
+    /// FxHash, unseeded
+    Fx,
+}
+
+impl KeyHasher {
+    pub(super) fn new(fast: bool) -> Self {
+        if fast {
+            KeyHasher::Fx
+        } else {
+            KeyHasher::Random(RandomState::new())
+        }
+    }
+}
+
+impl BuildHasher for KeyHasher {
+    type Hasher = KeyHasherState;
+
+    fn build_hasher(&self) -> KeyHasherState {
+        match self {
+            KeyHasher::Random(state) => KeyHasherState::Random(state.build_hasher()),
+            KeyHasher::Fx => KeyHasherState::Fx(FxHasher::default()),
+        }
+    }
+}
+
+/// The state of a `KeyHasher` hashing a key.
+pub(super) enum KeyHasherState {
+    Random(DefaultHasher),
+    Fx(FxHasher),
+}
+
+impl Hasher for KeyHasherState {
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            KeyHasherState::Random(hasher) => hasher.write(bytes),
+            KeyHasherState::Fx(hasher) => hasher.write(bytes),
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        match self {
+            KeyHasherState::Random(hasher) => hasher.finish(),
+            KeyHasherState::Fx(hasher) => hasher.finish(),
+        }
+    }
+}
+
+/// The index of a store: the entry of each key, in memory or spilled.
+///
+/// Only the writer changes it, the readers look it up concurrently. Every
+/// change keeps an entry visible in one tier or the other to a lookup.
+#[derive(Debug)]
+pub(super) struct KeyDir<V> {
+    memory: DashMap<String, V, KeyHasher>,
+    spill: Option<Spill>,
+}
+
+#[derive(Debug)]
+struct Spill {
+    tree: sled::Db,
+    // number of entries kept in memory above which some are spilled
+    cap: usize,
+    // number of entries in the tree, which sled only knows by counting
+    len: AtomicUsize,
+}
+
+impl<V: SpilledEntry> KeyDir<V> {
+    /// an index held in memory
+    pub(super) fn in_memory(hasher: KeyHasher) -> Self {
+        Self {
+            memory: DashMap::with_hasher(hasher),
+            spill: None,
+        }
+    }
+
+    /// an index keeping at most `cap` entries in memory, the others spilled
+    /// to a temporary tree on the disk
+    pub(super) fn spilling(cap: usize, hasher: KeyHasher) -> Result<Self> {
+        Ok(Self {
+            memory: DashMap::with_hasher(hasher),
+            spill: Some(Spill {
+                tree: sled::Config::new().temporary(true).open()?,
+                cap: cap.max(1),
+                len: AtomicUsize::new(0),
+            }),
+        })
+    }
+
+    /// whether entries may be spilled
+    pub(super) fn is_spilling(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    pub(super) fn get(&self, key: &str) -> Result<Option<V>> {
+        if let Some(entry) = self.memory.get(key) {
+            return Ok(Some(entry.clone()));
+        }
+        let spill = match &self.spill {
+            Some(spill) => spill,
+            None => return Ok(None),
+        };
+        if let Some(bytes) = spill.tree.get(key)? {
+            return Ok(Some(V::from_bytes(&bytes)));
+        }
+        // a spilled entry written again goes to memory before it leaves the
+        // tree, so it may have just moved
+        Ok(self.memory.get(key).map(|entry| entry.clone()))
+    }
+
+    pub(super) fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// number of keys, in either tier
+    pub(super) fn len(&self) -> usize {
+        let spilled = match &self.spill {
+            Some(spill) => spill.len.load(Ordering::SeqCst),
+            None => 0,
+        };
+        self.memory.len() + spilled
+    }
+
+    /// the entries held in memory
+    pub(super) fn memory(&self) -> &DashMap<String, V, KeyHasher> {
+        &self.memory
+    }
+
+    /// every entry, the ones in memory first. The writer moves entries
+    /// between the tiers, so an entry may be missed or listed twice unless
+    /// the writer is kept from writing meanwhile.
+    pub(super) fn iter(&self) -> impl Iterator<Item = Result<(String, V)>> + '_ {
+        let memory = self
+            .memory
+            .iter()
+            .map(|entry| Ok((entry.key().clone(), entry.value().clone())));
+        let spilled = self.spill.iter().flat_map(|spill| {
+            spill.tree.iter().map(|entry| {
+                let (key, bytes) = entry?;
+                Ok((String::from_utf8(key.to_vec())?, V::from_bytes(&bytes)))
+            })
+        });
+        memory.chain(spilled)
+    }
+
+    /// set the entry of `key`, returning the one it replaces. It goes to
+    /// memory, spilling others if that holds too many.
+    pub(super) fn insert(&self, key: String, entry: V) -> Result<Option<V>> {
+        let spill = match &self.spill {
+            Some(spill) => spill,
+            None => return Ok(self.memory.insert(key, entry)),
+        };
+        let mut old = self.memory.insert(key.clone(), entry);
+        if let Some(bytes) = spill.tree.remove(&key)? {
+            spill.len.fetch_sub(1, Ordering::SeqCst);
+            old = Some(V::from_bytes(&bytes));
+        }
+        if self.memory.len() > spill.cap {
+            self.spill_some(spill, &key)?;
+        }
+        Ok(old)
+    }
+
+    /// change the entry of `key` in place, if there is one
+    pub(super) fn update(&self, key: &str, f: impl FnOnce(&mut V)) -> Result<()> {
+        if let Some(mut entry) = self.memory.get_mut(key) {
+            f(entry.value_mut());
+            return Ok(());
+        }
+        if let Some(spill) = &self.spill {
+            if let Some(bytes) = spill.tree.get(key)? {
+                let mut entry = V::from_bytes(&bytes);
+                f(&mut entry);
+                spill.tree.insert(key, entry.to_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// remove the entry of `key`, returning it
+    pub(super) fn remove(&self, key: &str) -> Result<Option<V>> {
+        let mut old = self.memory.remove(key).map(|(_, entry)| entry);
+        if let Some(spill) = &self.spill {
+            if let Some(bytes) = spill.tree.remove(key)? {
+                spill.len.fetch_sub(1, Ordering::SeqCst);
+                old = Some(V::from_bytes(&bytes));
+            }
+        }
+        Ok(old)
+    }
+
+    /// move entries from memory to the tree until memory holds at most
+    /// the cap less an eighth of it, keeping `keep`, which was just written.
+    /// Spilling a batch at once spares a scan of the map per write.
+    fn spill_some(&self, spill: &Spill, keep: &str) -> Result<()> {
+        let count = self.memory.len() - spill.cap + spill.cap / 8;
+        let victims: Vec<(String, V)> = self
+            .memory
+            .iter()
+            .filter(|entry| entry.key() != keep)
+            .take(count)
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        for (key, entry) in victims {
+            // in the tree before it leaves memory, so a lookup always finds it
+            if spill
+                .tree
+                .insert(key.as_bytes(), entry.to_bytes())?
+                .is_none()
+            {
+                spill.len.fetch_add(1, Ordering::SeqCst);
+            }
+            self.memory.remove(&key);
+        }
+        Ok(())
+    }
+}
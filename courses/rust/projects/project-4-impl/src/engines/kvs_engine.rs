@@ -2,22 +2,341 @@
 //! this KvStore implement bitcask model,  which is a
 //! log-structured key-value database.
 //!
-use crate::Engine;
+//! A log file written by compaction gets a hint file `<id>.hint` next to it,
+//! holding the index of that file, so that it is loaded without replaying
+//! the log at open. The hint file ends with a CRC32 of its contents; a hint
+//! file which is missing or fails the check is ignored and its log replayed.
+//!
+//! The log and hint files are kept by a `Storage`, which defaults to a
+//! directory of the filesystem, see `FsStorage`.
+//!
+//! The readers don't take the writer lock, yet see every write which
+//! returned: a write is flushed to its log before the index points at it,
+//! and a read seeks the log to the position the index gives, which drops
+//! whatever its buffer held.
+//!
+//! A panic while the writer lock is held, in the engine or in code it calls,
+//! poisons the engine: its writes panic from then on. Once every clone of it
+//! is dropped, reopening the store gives a consistent state:
+//!
+//! - every write which returned is there, flushed or not;
+//! - the write which panicked is there whole or not at all, a record it left
+//!   half written at the end of the log being skipped as torn;
+//! - a compaction which panicked leaves the store as it was before.
+//!
+use super::cancel::CancelToken;
+use super::contention::ContentionMonitor;
+use super::flush_policy::FlushPolicy;
+use super::hot_keys::HotKeys;
+use super::key_dir::{KeyDir, KeyHasher, SpilledEntry};
+use super::storage::{FsStorage, LogLayout, Storage};
+use crate::{Engine, EngineStats};
 
+use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
-use std::ffi::OsStr;
-use std::fs::{create_dir_all, read_dir, File, OpenOptions, remove_file};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
-use std::path::Path;
+use std::mem;
+use std::ops::{Bound, Range};
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
 use dashmap::DashMap;
 
+use crate::cmd::{first_version, Record};
+#[cfg(feature = "test-util")]
+use crate::test_util::{reach, PanicPoint};
 use crate::{Cmd, KvsError, Result};
 
 const COMPACT_THRESHOLD: u64 = 1024 * 1024;
+
+/// attempts at creating or opening a log file before its error is returned
+const LOG_OPEN_ATTEMPTS: u32 = 4;
+
+/// time to wait before the second attempt at creating or opening a log file,
+/// doubled on every further attempt
+const LOG_OPEN_BACKOFF: Duration = Duration::from_millis(10);
+
+/// What a `get` does when the index points at a value it can't read: a log
+/// file removed behind the back of the engine, or a corrupt record. I/O
+/// errors, which may pass, are always returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DanglingPolicy {
+    /// return the error
+    #[default]
+    Strict,
+    /// log a warning and read the key as missing, every time it is read
+    Lenient,
+    /// log a warning and drop the index entry, so that the key is missing
+    /// from then on, for the other reads and the scans too. The logs are
+    /// left as they are: the entry is back at the next open if it can be
+    /// read then.
+    SelfHeal,
+}
+
+/// Options to open a `KvsEngine` with.
+#[derive(Debug, Clone, Copy)]
+pub struct KvsOptions {
+    layout: LogLayout,
+    auto_compact: bool,
+    min_files_to_compact: usize,
+    human_readable_log: bool,
+    index_spill: bool,
+    index_memory_cap: usize,
+    fast_key_hash: bool,
+    compact_on_open: Option<f64>,
+    compact_check_interval: Option<(Duration, f64)>,
+    separate_values: bool,
+    sync_dirs: bool,
+    dangling: DanglingPolicy,
+    flush_policy: FlushPolicy,
+}
+
+impl Default for KvsOptions {
+    fn default() -> Self {
+        Self {
+            layout: LogLayout::default(),
+            auto_compact: true,
+            min_files_to_compact: 1,
+            human_readable_log: false,
+            index_spill: false,
+            index_memory_cap: 1_000_000,
+            fast_key_hash: false,
+            compact_on_open: None,
+            compact_check_interval: None,
+            separate_values: false,
+            sync_dirs: true,
+            dangling: DanglingPolicy::Strict,
+            flush_policy: FlushPolicy::Manual,
+        }
+    }
+}
+
+impl KvsOptions {
+    /// lay out the log files of a new store as `layout`, flat by default.
+    ///
+    /// An existing store keeps the layout it was created with, whatever
+    /// the option: it is detected from the files at open. Only stores on
+    /// the filesystem have a layout, other storages ignore it.
+    pub fn layout(mut self, layout: LogLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// whether writes compact the logs once enough of them can be reclaimed,
+    /// on by default. When off, the logs only shrink on `KvsEngine::compact`,
+    /// so no write ever stalls on a compaction.
+    pub fn auto_compact(mut self, auto_compact: bool) -> Self {
+        self.auto_compact = auto_compact;
+        self
+    }
+
+    /// number of log files the store must have for a write to compact it,
+    /// however much could be reclaimed; 1 by default, i.e. any number.
+    /// Spares small stores, kept in a couple of files, the I/O of
+    /// compactions reclaiming little. `KvsEngine::compact` ignores it.
+    pub fn min_files_to_compact(mut self, min_files_to_compact: usize) -> Self {
+        self.min_files_to_compact = min_files_to_compact;
+        self
+    }
+
+    /// whether records are written pretty-printed, one line per field and
+    /// each ending with a newline, so that `cat`ing a log is legible. Off by
+    /// default: the logs get several times larger.
+    ///
+    /// Only the records written from now on are affected, a store reads
+    /// both kinds whatever the option.
+    pub fn human_readable_log(mut self, human_readable_log: bool) -> Self {
+        self.human_readable_log = human_readable_log;
+        self
+    }
+
+    /// whether the index keeps only `index_memory_cap` entries in memory,
+    /// spilling the others to a temporary tree on the disk. Off by default.
+    ///
+    /// For stores with more keys than the memory can index, at the cost of
+    /// a disk lookup of the tree for the reads of spilled keys. The entries
+    /// written last stay in memory. The keys are still all held in memory
+    /// once, in the ordered key set of the scans.
+    pub fn index_spill(mut self, index_spill: bool) -> Self {
+        self.index_spill = index_spill;
+        self
+    }
+
+    /// number of index entries kept in memory when `index_spill` is on,
+    /// a million by default
+    pub fn index_memory_cap(mut self, index_memory_cap: usize) -> Self {
+        self.index_memory_cap = index_memory_cap;
+        self
+    }
+
+    /// whether the index hashes the keys with FxHash instead of the
+    /// standard library's SipHash, off by default. Hashing a short key gets
+    /// several times cheaper, though a `get` mostly costs reading the value:
+    /// a few percent faster in `key_hash_bench`.
+    ///
+    /// FxHash is not seeded: whoever chooses the keys, e.g. the clients of
+    /// a server, can pick many keys of the same hash, making every lookup
+    /// slow. Only turn it on when the keys come from trusted code.
+    pub fn fast_key_hash(mut self, fast_key_hash: bool) -> Self {
+        self.fast_key_hash = fast_key_hash;
+        self
+    }
+
+    /// compact the logs right after opening them if more than `ratio` of
+    /// their bytes are garbage, i.e. overwritten or removed values and their
+    /// tombstones, `0.5` for half. Off by default.
+    ///
+    /// The logs are still replayed once, but the open that compacts leaves
+    /// a single compacted log with its hints, so the next ones are quick.
+    pub fn compact_on_open(mut self, ratio: f64) -> Self {
+        self.compact_on_open = Some(ratio);
+        self
+    }
+
+    /// check every `interval` from a background thread whether more than
+    /// `ratio` of the bytes of the logs are garbage, like `compact_on_open`,
+    /// and compact them if so. Off by default.
+    ///
+    /// Writes only compact once enough can be reclaimed, so the garbage
+    /// left by the last writes before a store goes idle is otherwise kept
+    /// until the next ones. The check leaves the writer alone, the compaction
+    /// holds the writer like any other. The thread ends with the store.
+    pub fn compact_check_interval(mut self, interval: Duration, ratio: f64) -> Self {
+        self.compact_check_interval = Some((interval, ratio));
+        self
+    }
+
+    /// whether values are written to a value log of their own, the logs
+    /// holding only the keys and where their values are, as in WiscKey.
+    /// Off by default.
+    ///
+    /// A compaction then copies the small key records, never the values,
+    /// which pays off for large values. The value log is reclaimed apart,
+    /// by `KvsEngine::compact_values`, and a read takes a second seek. A
+    /// store reads the values of both kinds whatever the option. Needs a
+    /// storage with a `Storage::value_log`.
+    pub fn separate_values(mut self, separate_values: bool) -> Self {
+        self.separate_values = separate_values;
+        self
+    }
+
+    /// whether the store directory is synced after a log file is created or
+    /// removed in it, on by default, so that a power loss can't lose the
+    /// file or bring a removed one back. See `FsStorage::sync_dirs`.
+    pub fn sync_dirs(mut self, sync_dirs: bool) -> Self {
+        self.sync_dirs = sync_dirs;
+        self
+    }
+
+    /// what a `get` does when the index points at a value it can't read,
+    /// `DanglingPolicy::Strict` by default
+    pub fn on_dangling(mut self, dangling: DanglingPolicy) -> Self {
+        self.dangling = dangling;
+        self
+    }
+
+    /// when the writes are synced to the disk, `FlushPolicy::Manual` by
+    /// default. Whatever the policy, a write is handed to the OS before it
+    /// returns, so it survives a crash of the process: the policy is about
+    /// a crash of the machine.
+    pub fn flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+}
+
+/// Writes applied together by `KvsEngine::write_batch`, in the order they
+/// were added, under a single lock of the writer and a single flush.
+///
+/// # Example
+/// ```rust
+/// use kvs::{Engine, KvsEngine, WriteBatch};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let store = KvsEngine::open(temp_dir.path()).unwrap();
+/// let mut batch = WriteBatch::new().coalesce(true);
+/// for i in 0..100 {
+///     batch.set("key".to_owned(), format!("value{}", i));
+/// }
+/// batch.remove("missing".to_owned());
+/// store.write_batch(batch).unwrap();
+/// assert_eq!(store.get("key").unwrap(), Some("value99".to_owned()));
+/// ```
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    cmds: Vec<Cmd>,
+    coalesce: bool,
+}
+
+impl WriteBatch {
+    /// an empty batch, not coalescing
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// whether only the last write of each key goes to the log, off by
+    /// default. A batch overwriting the same keys many times, like a replayed
+    /// change stream, then writes each key once instead of every intermediate
+    /// value, for the same resulting store.
+    pub fn coalesce(mut self, coalesce: bool) -> Self {
+        self.coalesce = coalesce;
+        self
+    }
+
+    /// set `key` to `value`
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.cmds.push(Cmd::Set {
+            key,
+            value,
+            version: 0,
+        });
+        self
+    }
+
+    /// remove `key`, nothing if it doesn't exist by then
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.cmds.push(Cmd::Remove { key });
+        self
+    }
+
+    /// the number of writes added
+    pub fn len(&self) -> usize {
+        self.cmds.len()
+    }
+
+    /// whether no write was added
+    pub fn is_empty(&self) -> bool {
+        self.cmds.is_empty()
+    }
+
+    /// the commands to write, only the last one of each key if coalescing
+    fn into_cmds(self) -> Vec<Cmd> {
+        if !self.coalesce {
+            return self.cmds;
+        }
+        let mut last = HashMap::new();
+        for (i, cmd) in self.cmds.iter().enumerate() {
+            last.insert(cmd.key(), i);
+        }
+        let kept: HashSet<usize> = last.into_values().collect();
+        self.cmds
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| kept.contains(i))
+            .map(|(_, cmd)| cmd)
+            .collect()
+    }
+}
+
 ///
 /// KvStore is a log-structured key-value store,
 /// inspired by bitcask model.
@@ -42,34 +361,298 @@ const COMPACT_THRESHOLD: u64 = 1024 * 1024;
 /// # test().expect("");
 /// # }
 /// ```
-#[derive(Debug, Clone)]
-pub struct KvsEngine {
-    key_dir: Arc<DashMap<String, CmdPos>>,
-    path: Arc<PathBuf>,
+///
+/// The store is kept on the filesystem unless opened on another storage with
+/// `KvsEngine::with_storage`.
+#[derive(Debug)]
+pub struct KvsEngine<S: Storage = FsStorage> {
+    key_dir: Arc<KeyDir<CmdPos>>,
+    // the keys of `key_dir` in order, for scans
+    keys: Arc<RwLock<BTreeSet<String>>>,
+    // the live and total bytes of every log file
+    file_stats: Arc<DashMap<u64, FileStats>>,
+    storage: Arc<S>,
 
-    reader: KvsReader,
-    writer: Arc<Mutex<KvsWriter>>,
+    reader: KvsReader<S>,
+    writer: Arc<Mutex<KvsWriter<S>>>,
+    versions: Arc<VersionSet>,
+    // held for writing while a batch is indexed, so that a scan lists all of
+    // the batch or none of it
+    publish: Arc<RwLock<()>>,
+    contention: Option<Arc<ContentionMonitor>>,
+    hot_keys: Option<Arc<HotKeys>>,
+    key_validator: Option<KeyValidator>,
+    dangling: DanglingPolicy,
+}
+
+/// The check and rewrite of the keys, see `KvsEngine::validate_keys`.
+#[derive(Clone)]
+struct KeyValidator(Arc<ValidateKey>);
+
+type ValidateKey = dyn Fn(&str) -> Result<Cow<str>> + Send + Sync;
+
+impl fmt::Debug for KeyValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyValidator")
+    }
 }
 
 #[derive(Debug)]
-struct KvsReader {
-    path: Arc<PathBuf>,
-    readers: Arc<DashMap<u64, BufReaderWithPos<File>>>,
+struct KvsReader<S: Storage> {
+    storage: Arc<S>,
+    readers: Arc<DashMap<u64, BufReaderWithPos<S::Reader>>>,
     check_point: Arc<AtomicU64>,
+    // the reader of the value log, if the store has one
+    values: Option<Box<KvsReader<S>>>,
 }
 
 #[derive(Debug)]
-struct KvsWriter {
-    reader: KvsReader,
-    key_dir: Arc<DashMap<String, CmdPos>>,
-    writer: BufWriterWithPos<File>,
-    path: Arc<PathBuf>,
+struct KvsWriter<S: Storage> {
+    reader: KvsReader<S>,
+    key_dir: Arc<KeyDir<CmdPos>>,
+    keys: Arc<RwLock<BTreeSet<String>>>,
+    file_stats: Arc<DashMap<u64, FileStats>>,
+    writer: BufWriterWithPos<S::Writer>,
+    storage: Arc<S>,
+    versions: Arc<VersionSet>,
+    publish: Arc<RwLock<()>>,
 
     current_file_id: u64,
+    // the last version given to a set, see `next_version`
+    last_version: u64,
+    // the active value log, when the values are separated
+    value_writer: Option<ValueWriter<S>>,
     uncompact: u64,
+    compactions: u64,
+    auto_compact: bool,
+    min_files_to_compact: usize,
+    human_readable_log: bool,
+    // whether every write is synced, see `FlushPolicy::PerOperation`
+    sync_writes: bool,
+}
+
+/// The writer is dropped with the last clone of its store, which flushes
+/// the store one last time like `Engine::flush`, see `KvsEngine::close`.
+impl<S: Storage> Drop for KvsWriter<S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            error!(
+                msg = "fail to flush the kvs engine on drop, the last writes may be lost",
+                err = %e
+            );
+        }
+    }
+}
+
+/// The value log file the separated values are appended to.
+#[derive(Debug)]
+struct ValueWriter<S: Storage> {
+    file_id: u64,
+    writer: BufWriterWithPos<S::Writer>,
+}
+
+/// The bytes of a log file, and how many of them hold the live value of a key.
+/// The rest is garbage: overwritten or removed values, removes, torn records,
+/// and the versions retained for snapshots.
+#[derive(Debug, Clone, Copy, Default)]
+struct FileStats {
+    live_bytes: u64,
+    total_bytes: u64,
+}
+
+/// whether more than `ratio` of the bytes of the logs are garbage, counting
+/// the `retained` bytes of the versions a compaction kept for snapshots as
+/// live: compacting again would keep them as well
+fn mostly_garbage(file_stats: &DashMap<u64, FileStats>, ratio: f64, retained: u64) -> bool {
+    let (live, total) = file_stats.iter().fold((0, 0), |(live, total), stats| {
+        (live + stats.live_bytes, total + stats.total_bytes)
+    });
+    let live = live + retained;
+    total > 0 && total.saturating_sub(live) as f64 > ratio * total as f64
+}
+
+/// What replaying the logs of a store found, see `KvsEngine::check`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RecoveryReport {
+    /// number of log files replayed
+    pub files: u64,
+    /// number of keys holding a value once every log is replayed
+    pub keys_recovered: u64,
+    /// number of records read
+    pub records: u64,
+    /// bytes of the logs read, torn records included
+    pub bytes_scanned: u64,
+    /// number of records skipped as torn by a crash, at the end of a log
+    pub records_skipped: u64,
+    /// the records which can't be read, at most one per log: what follows
+    /// it in its log is not scanned
+    pub corruption: Vec<Corruption>,
+}
+
+impl RecoveryReport {
+    /// whether no corruption was found. Torn records are expected after a
+    /// crash, they don't make the store unclean.
+    pub fn is_clean(&self) -> bool {
+        self.corruption.is_empty()
+    }
+}
+
+/// A record of a log which can't be read.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Corruption {
+    /// the log file holding the record
+    pub file_id: u64,
+    /// the offset of the record in the file
+    pub offset: u64,
+    /// why it can't be read
+    pub error: String,
+}
+
+/// A record of a log, as listed by `KvsEngine::dump_records`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LogRecord {
+    /// the log file holding the record
+    pub file_id: u64,
+    /// the offset of the record in the file
+    pub offset: u64,
+    /// the length of the record in bytes, up to the end of the file for
+    /// one which can't be read
+    pub len: u64,
+    /// what the record holds
+    #[serde(flatten)]
+    pub content: RecordContent,
+}
+
+/// What a record of a log holds. The records carry no checksum of their
+/// own: a record is valid when it can be read.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum RecordContent {
+    /// a value set, whether still live or not
+    Set { key: String, value_len: u64 },
+    /// a removal
+    Remove { key: String },
+    /// a value set in the value log, see `KvsOptions::separate_values`
+    SetRef {
+        key: String,
+        value_file_id: u64,
+        value_offset: u64,
+    },
+    /// the last record of a log, torn by a crash while it was written
+    Torn,
+    /// a record which can't be read, and why. The rest of the log isn't.
+    Corrupted { error: String },
+}
+
+/// A consistent, read-only view of a `KvsEngine` as of the moment it was taken.
+///
+/// Every write is tagged with a monotonically increasing sequence number, and
+/// a snapshot only sees writes whose sequence is not greater than its own.
+///
+/// # Retention cost
+///
+/// While a snapshot is alive, every value it can still see is retained even
+/// after it gets overwritten or removed: the superseded `CmdPos` stays in an
+/// in-memory version list (one entry per overwritten key) and its record is
+/// carried over by compaction instead of being reclaimed. So holding a snapshot
+/// across a heavy write workload grows both memory and disk usage; drop it as
+/// soon as the reads are done.
+///
+/// # Example
+/// ```rust
+/// use kvs::{Engine, KvsEngine};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let store = KvsEngine::open(temp_dir.path()).unwrap();
+/// store.set("key".to_owned(), "old".to_owned()).unwrap();
+/// let snapshot = store.snapshot();
+/// store.set("key".to_owned(), "new".to_owned()).unwrap();
+/// assert_eq!(snapshot.read("key".to_owned()).unwrap(), Some("old".to_owned()));
+/// ```
+#[derive(Debug)]
+pub struct Snapshot<S: Storage = FsStorage> {
+    seq: u64,
+    key_dir: Arc<KeyDir<CmdPos>>,
+    reader: KvsReader<S>,
+    versions: Arc<VersionSet>,
+}
+
+/// Iterator over the key-value pairs of a `KvsEngine` in key order, see
+/// `KvsEngine::iter`.
+#[derive(Debug)]
+pub struct Iter<S: Storage = FsStorage> {
+    key_dir: Arc<KeyDir<CmdPos>>,
+    reader: KvsReader<S>,
+    // the positions of the values as of the start of the iteration
+    entries: std::vec::IntoIter<(String, CmdPos)>,
+    // why they couldn't be listed, returned first
+    error: Option<KvsError>,
+    // stops the iteration once cancelled, see `cancel_on`
+    cancel: Option<CancelToken>,
+}
+
+/// A read-only copy of a store that another process, the primary, keeps
+/// writing: it follows the logs of the primary as they grow, so that its
+/// reads are as recent as its last poll.
+///
+/// A poll indexes the records appended since the one before, and the log
+/// files created meanwhile; a record the primary is still writing is left for
+/// the next poll. Once a compaction of the primary removes a log file the
+/// replica read, the replica reloads the whole store instead.
+///
+/// Nothing is written to the store, not even a log file of its own, so a
+/// replica may follow a store it can't write to.
+///
+/// ```rust
+/// use kvs::{Engine, KvsEngine, Replica};
+/// use std::time::Duration;
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let primary = KvsEngine::open(temp_dir.path()).unwrap();
+/// let replica = Replica::open(temp_dir.path(), Duration::from_secs(1)).unwrap();
+/// primary.set("key".to_owned(), "value".to_owned()).unwrap();
+/// replica.poll().unwrap();
+/// assert_eq!(replica.get("key").unwrap(), Some("value".to_owned()));
+/// ```
+#[derive(Debug)]
+pub struct Replica<S: Storage = FsStorage> {
+    storage: Arc<S>,
+    state: Arc<RwLock<ReplicaState<S>>>,
+}
+
+/// What a `Replica` loaded of the store, replaced whole when reloaded.
+#[derive(Debug)]
+struct ReplicaState<S: Storage> {
+    key_dir: KeyDir<CmdPos>,
+    reader: KvsReader<S>,
+    // log file id -> the bytes of it indexed so far
+    tailed: BTreeMap<u64, u64>,
+}
+
+/// Sequence numbers plus the superseded versions that live snapshots may still read.
+#[derive(Debug, Default)]
+struct VersionSet {
+    // sequence number of the latest write
+    seq: AtomicU64,
+    // sequence of each live snapshot -> number of snapshots at it
+    live: Mutex<BTreeMap<u64, usize>>,
+    // superseded versions of a key, in sequence order; `None` marks a removal
+    history: DashMap<String, Vec<Version>>,
 }
 
-impl Engine for KvsEngine {
+#[derive(Debug, Clone)]
+struct Version {
+    seq: u64,
+    pos: Option<CmdPos>,
+}
+
+impl<S: Storage> Engine for KvsEngine<S> {
+    fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        KvsEngine::with_storage(S::open(path.into())?, KvsOptions::default())
+    }
+
     /// insert a key-value pair if key is not in store else overwrite the key-value
     ///
     /// # Example
@@ -86,7 +669,11 @@ impl Engine for KvsEngine {
     /// assert_eq!(kv.get("test".to_owned()).unwrap(), Some("test2".to_owned()));
     /// ```
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+        let key = self.validate_owned(key)?;
+        if let Some(hot_keys) = &self.hot_keys {
+            hot_keys.record(&key);
+        }
+        self.lock_writer(&key).set(key, value)
     }
 
     /// get a value by key
@@ -99,16 +686,17 @@ impl Engine for KvsEngine {
     /// let temp_file = TempDir::new().expect("unable to create temporary working directory");
     /// let mut kv = KvStore::open(temp_file.path()).unwrap();
     /// kv.set("test".to_owned(), "test1".to_owned()).unwrap();
-    /// let v = kv.get("test".to_owned()).unwrap();
+    /// let v = kv.get("test").unwrap();
     /// assert_eq!(v, Some("test1".to_owned()));
     /// ```
-    fn get(&self, key: String) -> Result<Option<String>> {
-        // convert Option<&T> to Option<T>
-        if let Some(cmd_pos) = self.key_dir.get(&key) {
-            self.reader.read(cmd_pos.value())
-        } else {
-            Ok(None)
+    fn get(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        let key = self.validate(key.as_ref())?;
+        let key = key.as_ref();
+        if let Some(hot_keys) = &self.hot_keys {
+            hot_keys.record(key);
         }
+        self.reader.check_point();
+        self.read_value(key, || self.key_dir.get(key))
     }
 
     /// remove a key-value by key
@@ -123,16 +711,241 @@ impl Engine for KvsEngine {
     /// assert_eq!(kv.get("test".to_owned()).unwrap(), None);
     /// kv.set("test".to_owned(), "test1".to_owned()).unwrap();
     /// assert_eq!(kv.get("test".to_owned()).unwrap(), Some("test1".to_owned()));
-    /// kv.remove("test".to_owned()).unwrap();
-    /// assert_eq!(kv.get("test".to_owned()).unwrap(), None);
+    /// kv.remove("test").unwrap();
+    /// assert_eq!(kv.get("test").unwrap(), None);
     /// ```
-    fn remove(&self, key: String) -> Result<()> {
-        if self.key_dir.contains_key(&key) {
-            self.writer.lock().unwrap().remove(key)
+    fn remove(&self, key: impl AsRef<str>) -> Result<()> {
+        let key = self.validate(key.as_ref())?;
+        let key = key.as_ref();
+        if self.key_dir.contains_key(key)? {
+            self.lock_writer(key).remove(key)
         } else {
             Err(KvsError::KeyNotFound)
         }
     }
+
+    /// remove a key-value if the key exists, returning whether it did
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::{Engine, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_file = TempDir::new().expect("unable to create temporary working directory");
+    /// let kv = KvsEngine::open(temp_file.path()).unwrap();
+    /// kv.set("test".to_owned(), "test1".to_owned()).unwrap();
+    /// assert!(kv.discard("test").unwrap());
+    /// assert!(!kv.discard("test").unwrap());
+    /// ```
+    fn discard(&self, key: impl AsRef<str>) -> Result<bool> {
+        let key = self.validate(key.as_ref())?;
+        let key = key.as_ref();
+        // checked under the writer lock, so a concurrent remove can't win in between
+        let mut writer = self.lock_writer(key);
+        if !self.key_dir.contains_key(key)? {
+            return Ok(false);
+        }
+        writer.remove(key)?;
+        Ok(true)
+    }
+
+    fn contains_key(&self, key: impl AsRef<str>) -> Result<bool> {
+        self.key_dir.contains_key(&self.validate(key.as_ref())?)
+    }
+
+    /// remove a key-value if the value is the expected one, comparing and
+    /// removing under the writer lock
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::{Engine, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_file = TempDir::new().expect("unable to create temporary working directory");
+    /// let kv = KvsEngine::open(temp_file.path()).unwrap();
+    /// kv.set("test".to_owned(), "test1".to_owned()).unwrap();
+    /// assert!(!kv.remove_if("test", "test2").unwrap());
+    /// assert!(kv.remove_if("test", "test1").unwrap());
+    /// assert_eq!(kv.get("test").unwrap(), None);
+    /// ```
+    fn remove_if(&self, key: impl AsRef<str>, expected: impl AsRef<str>) -> Result<bool> {
+        let key = self.validate(key.as_ref())?;
+        let key = key.as_ref();
+        let mut writer = self.lock_writer(key);
+        let cmd_pos = match self.key_dir.get(key)? {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(false),
+        };
+        if self.reader.read(&cmd_pos)?.as_deref() != Some(expected.as_ref()) {
+            return Ok(false);
+        }
+        writer.remove(key)?;
+        Ok(true)
+    }
+
+    /// append to a value, reading the old value and writing the new one
+    /// under the writer lock, so that no concurrent write gets lost
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::{Engine, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_file = TempDir::new().expect("unable to create temporary working directory");
+    /// let kv = KvsEngine::open(temp_file.path()).unwrap();
+    /// assert_eq!(kv.append("log".to_owned(), "a".to_owned()).unwrap(), 1);
+    /// assert_eq!(kv.append("log".to_owned(), "bc".to_owned()).unwrap(), 3);
+    /// assert_eq!(kv.get("log".to_owned()).unwrap(), Some("abc".to_owned()));
+    /// ```
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        let key = self.validate_owned(key)?;
+        self.lock_writer(&key).append(key, suffix)
+    }
+
+    /// the keys are listed and removed under the writer lock, as a single
+    /// batch which scans see indexed whole or not at all
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        let mut writer = self.lock_writer(&prefix);
+        let cmds: Vec<Cmd> = self
+            .keys
+            .read()
+            .unwrap()
+            .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+            .take_while(|key| key.starts_with(&prefix))
+            .map(|key| Cmd::Remove { key: key.clone() })
+            .collect();
+        let count = cmds.len();
+        writer.write_batch(cmds)?;
+        Ok(count)
+    }
+
+    /// a range count of the ordered keys, under their read lock only
+    fn count_prefix(&self, prefix: String) -> Result<usize> {
+        let _publish = self.publish.read().unwrap();
+        Ok(self
+            .keys
+            .read()
+            .unwrap()
+            .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+            .take_while(|key| key.starts_with(&prefix))
+            .count())
+    }
+
+    /// rename a key, reading its value and writing both keys under the
+    /// writer lock. The new key is indexed before the old one is removed,
+    /// and a crash between the two records leaves both keys, never neither.
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::{Engine, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_file = TempDir::new().expect("unable to create temporary working directory");
+    /// let kv = KvsEngine::open(temp_file.path()).unwrap();
+    /// kv.set("staging:x".to_owned(), "1".to_owned()).unwrap();
+    /// kv.rename("staging:x".to_owned(), "prod:x".to_owned()).unwrap();
+    /// assert_eq!(kv.get("staging:x").unwrap(), None);
+    /// assert_eq!(kv.get("prod:x").unwrap(), Some("1".to_owned()));
+    /// ```
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        let (from, to) = (self.validate_owned(from)?, self.validate_owned(to)?);
+        let mut writer = self.lock_writer(&from);
+        let cmd_pos = self.key_dir.get(&from)?.ok_or(KvsError::KeyNotFound)?;
+        if from == to {
+            return Ok(());
+        }
+        let value = self.reader.read(&cmd_pos)?.unwrap_or_default();
+        let set = Cmd::Set {
+            key: to,
+            value,
+            version: 0,
+        };
+        writer.write_batch(vec![set, Cmd::Remove { key: from }])
+    }
+
+    /// write the pairs as a batch, which scans see indexed whole or not at all
+    fn set_all(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in pairs {
+            batch.set(key, value);
+        }
+        self.write_batch(batch)
+    }
+
+    /// flush the active log file to the disk, after the active value log
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync()
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        // the writer lock keeps compaction from removing files while they're measured
+        let writer = self.writer.lock().unwrap();
+        let mut disk_usage = 0;
+        for file_id in self.storage.list()? {
+            disk_usage += self.storage.len(file_id)?;
+        }
+        if let Some(values) = &self.reader.values {
+            for file_id in values.storage.list()? {
+                disk_usage += values.storage.len(file_id)?;
+            }
+        }
+        Ok(EngineStats {
+            keys: self.key_dir.len() as u64,
+            disk_usage,
+            uncompacted: writer.uncompact,
+            compactions: writer.compactions,
+        })
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        start_after: Option<String>,
+        count: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::with_capacity(count);
+        let mut after = start_after;
+        self.reader.check_point();
+        while entries.len() < count {
+            // only the keys and their positions are read under the locks, so
+            // that a batch is listed whole or not at all, values are read
+            // afterward
+            let listed: Vec<(String, Option<CmdPos>)> = {
+                let _publish = self.publish.read().unwrap();
+                let keys = self.keys.read().unwrap();
+                let start = match &after {
+                    Some(after) if *after >= prefix => Bound::Excluded(after.as_str()),
+                    _ => Bound::Included(prefix.as_str()),
+                };
+                keys.range::<str, _>((start, Bound::Unbounded))
+                    .take_while(|key| key.starts_with(&prefix))
+                    .take(count - entries.len())
+                    .map(|key| Ok((key.clone(), self.key_dir.get(key)?)))
+                    .collect::<Result<_>>()?
+            };
+            if listed.is_empty() {
+                break;
+            }
+            after = listed.last().map(|(key, _)| key.clone());
+            for (key, cmd_pos) in listed {
+                if let Some(hot_keys) = &self.hot_keys {
+                    hot_keys.record(&key);
+                }
+                // the listed position, then the one the value moved to if a
+                // compaction removed its log file meanwhile
+                let listed = Cell::new(cmd_pos);
+                let value = self.read_value(&key, || match listed.take() {
+                    Some(cmd_pos) => Ok(Some(cmd_pos)),
+                    None => self.key_dir.get(&key),
+                })?;
+                // skip the keys removed since they were listed
+                if let Some(value) = value {
+                    entries.push((key, value));
+                }
+            }
+        }
+        Ok(entries)
+    }
 }
 
 impl KvsEngine {
@@ -148,113 +961,1491 @@ impl KvsEngine {
     /// let mut store = KvStore::open(temp_file.path());
     /// ```
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        // create store path
+        Self::open_with_options(path, KvsOptions::default())
+    }
+
+    /// open a KvStore like `open`, with the given options.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvsOptions) -> Result<Self> {
+        let storage = FsStorage::with_layout(path, options.layout)?.sync_dirs(options.sync_dirs);
+        Self::with_storage(storage, options)
+    }
+
+    /// replay every log of the store in the directory `path` and report what
+    /// was recovered, without opening it: nothing is written, not even the
+    /// new log an open creates. The hint files are ignored, so that every
+    /// record gets read.
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::{Engine, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let store = KvsEngine::open(temp_dir.path()).unwrap();
+    /// store.set("key".to_owned(), "value".to_owned()).unwrap();
+    /// drop(store);
+    /// let report = KvsEngine::check(temp_dir.path()).unwrap();
+    /// assert!(report.is_clean());
+    /// assert_eq!(report.keys_recovered, 1);
+    /// ```
+    pub fn check(path: impl Into<PathBuf>) -> Result<RecoveryReport> {
+        Self::check_unless(path.into(), None)
+    }
+
+    /// `check`, stopped with `KvsError::Cancelled` once `token` is
+    /// cancelled, e.g. when the process is asked to exit while replaying a
+    /// large store
+    pub fn check_cancellable(
+        path: impl Into<PathBuf>,
+        token: &CancelToken,
+    ) -> Result<RecoveryReport> {
+        Self::check_unless(path.into(), Some(token))
+    }
+
+    fn check_unless(path: PathBuf, cancel: Option<&CancelToken>) -> Result<RecoveryReport> {
+        if !path.is_dir() {
+            return Err(KvsError::StringErr(format!(
+                "{} is not a directory",
+                path.display()
+            )));
+        }
+        let layout = FsStorage::detect(&path)?.unwrap_or_default();
+        Self::check_storage_unless(&FsStorage::with_layout(path, layout)?, cancel)
+    }
+
+    /// pass every record of the logs of the store in the directory `path`
+    /// to `f`, superseded values and removals included, in the order they
+    /// were written. Only the log file `file_id` is read if given. Like
+    /// `check`, the store isn't opened and the index isn't built.
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::{Engine, KvsEngine, RecordContent};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let store = KvsEngine::open(temp_dir.path()).unwrap();
+    /// store.set("key".to_owned(), "value".to_owned()).unwrap();
+    /// store.remove("key").unwrap();
+    /// drop(store);
+    /// let mut records = Vec::new();
+    /// KvsEngine::dump_records(temp_dir.path(), None, |record| {
+    ///     records.push(record.content);
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// assert_eq!(records[1], RecordContent::Remove { key: "key".to_owned() });
+    /// ```
+    pub fn dump_records(
+        path: impl Into<PathBuf>,
+        file_id: Option<u64>,
+        f: impl FnMut(LogRecord) -> Result<()>,
+    ) -> Result<()> {
         let path = path.into();
+        if !path.is_dir() {
+            return Err(KvsError::StringErr(format!(
+                "{} is not a directory",
+                path.display()
+            )));
+        }
+        let layout = FsStorage::detect(&path)?.unwrap_or_default();
+        Self::dump_storage_records(&FsStorage::with_layout(path, layout)?, file_id, f)
+    }
+
+    /// the oldest format version of the records in the logs of the store in
+    /// `path`, `None` if they hold none. A store older than `FORMAT_VERSION`
+    /// is readable as it is, `KvsEngine::upgrade` rewrites it in the current
+    /// format. A log is read up to its first record which can't be.
+    pub fn format_version(path: impl Into<PathBuf>) -> Result<Option<u8>> {
+        let path = path.into();
+        if !path.is_dir() {
+            return Err(KvsError::StringErr(format!(
+                "{} is not a directory",
+                path.display()
+            )));
+        }
+        let layout = FsStorage::detect(&path)?.unwrap_or_default();
+        let storage = FsStorage::with_layout(path, layout)?;
+        let mut oldest: Option<u8> = None;
+        for file_id in storage.list()? {
+            let reader = BufReader::new(storage.open_reader(file_id)?);
+            for record in Deserializer::from_reader(reader).into_iter::<Record>() {
+                let version = match record {
+                    Ok(record) => record.version(),
+                    Err(_) => break,
+                };
+                oldest = Some(oldest.map_or(version, |oldest| oldest.min(version)));
+            }
+        }
+        Ok(oldest)
+    }
+
+    /// write a fresh copy of the store into the empty directory `dest`, as a
+    /// single log holding only the live values: no overwritten values and no
+    /// removals. The store itself is left untouched, but writes are blocked
+    /// while the copy is made.
+    pub fn compact_to(&self, dest: impl Into<PathBuf>) -> Result<()> {
+        let dest = FsStorage::with_layout(dest, self.storage.layout())?;
+        if !dest.is_empty()? {
+            return Err(KvsError::StringErr(format!(
+                "{} already holds a store",
+                dest.path().display()
+            )));
+        }
+        self.compact_into(&dest)
+    }
+
+    /// the path of the log file written to, and the length written so far
+    #[cfg(feature = "test-util")]
+    pub(crate) fn active_log(&self) -> (PathBuf, u64) {
+        let writer = self.writer.lock().unwrap();
+        (
+            self.storage.log_file(writer.current_file_id),
+            writer.writer.pos,
+        )
+    }
+}
+
+impl<S: Storage> KvsEngine<S> {
+    /// open the store kept by `storage`, creating it if it is empty
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::{Engine, KvsEngine, KvsOptions, MemStorage};
+    ///
+    /// let kv = KvsEngine::with_storage(MemStorage::new(), KvsOptions::default()).unwrap();
+    /// kv.set("test".to_owned(), "test1".to_owned()).unwrap();
+    /// assert_eq!(kv.get("test").unwrap(), Some("test1".to_owned()));
+    /// ```
+    pub fn with_storage(storage: S, options: KvsOptions) -> Result<Self> {
         let mut uncompact: u64 = 0;
-        create_dir_all(&path)?;
-        let mut key_dir = DashMap::new();
+        let hasher = KeyHasher::new(options.fast_key_hash);
+        let key_dir = if options.index_spill {
+            KeyDir::spilling(options.index_memory_cap, hasher)?
+        } else {
+            KeyDir::in_memory(hasher)
+        };
         let mut readers = DashMap::new();
 
-        // load history file
-        let file_list = sorted_file_list(&path)?;
-        for file_id in &file_list {
-            let mut reader = BufReaderWithPos::new(File::open(to_log_file(*file_id, &path))?)?;
-            uncompact += load_log(*file_id, &mut reader, &mut key_dir)?;
-            readers.insert(*file_id, reader);
+        // load history file
+        let file_list = storage.list()?;
+        let file_stats = DashMap::new();
+        for file_id in &file_list {
+            let mut reader = BufReaderWithPos::new(storage.open_reader(*file_id)?)?;
+            uncompact += match read_hints(*file_id, &storage) {
+                Some(hints) => load_hints(*file_id, &storage, hints, &key_dir)?,
+                None => load_log(*file_id, &mut reader, &key_dir)?,
+            };
+            readers.insert(*file_id, reader);
+            let total_bytes = storage.len(*file_id)?;
+            file_stats.insert(
+                *file_id,
+                FileStats {
+                    live_bytes: 0,
+                    total_bytes,
+                },
+            );
+        }
+        // only the latest record of a key is live, known once every file is
+        // loaded; the keys are listed for the scans on the way
+        let mut keys = BTreeSet::new();
+        let mut last_version = 0;
+        for entry in key_dir.iter() {
+            let (key, cmd_pos) = entry?;
+            last_version = last_version.max(cmd_pos.version);
+            if let Some(mut stats) = file_stats.get_mut(&cmd_pos.file_id) {
+                stats.live_bytes += cmd_pos.len;
+            }
+            keys.insert(key);
+        }
+
+        // create current log file
+        let current_file_id = file_list.last().unwrap_or(&0) + 1;
+        let writer = BufWriterWithPos::new(retry_open(current_file_id, || {
+            storage.create(current_file_id)
+        })?)?;
+        file_stats.insert(current_file_id, FileStats::default());
+        let file_stats = Arc::new(file_stats);
+        readers.insert(
+            current_file_id,
+            BufReaderWithPos::new(retry_open(current_file_id, || {
+                storage.open_reader(current_file_id)
+            })?)?,
+        );
+        let (values, value_writer) = open_value_log(&storage, options.separate_values)?;
+        let storage = Arc::new(storage);
+        let reader = KvsReader {
+            storage: storage.clone(),
+            readers: Arc::new(readers),
+            check_point: Arc::new(AtomicU64::new(0)),
+            values: values.map(Box::new),
+        };
+        let keys = Arc::new(RwLock::new(keys));
+        let key_dir = Arc::new(key_dir);
+        let versions = Arc::new(VersionSet::default());
+        let publish = Arc::new(RwLock::new(()));
+        let engine = KvsEngine{
+            key_dir: key_dir.clone(),
+            keys: keys.clone(),
+            file_stats: file_stats.clone(),
+            storage: storage.clone(),
+            reader: reader.clone(),
+            writer: Arc::new(Mutex::new(KvsWriter {
+                reader: reader.clone(),
+                key_dir: key_dir.clone(),
+                keys,
+                file_stats,
+                writer,
+                current_file_id,
+                last_version,
+                value_writer,
+                uncompact,
+                compactions: 0,
+                auto_compact: options.auto_compact,
+                min_files_to_compact: options.min_files_to_compact,
+                human_readable_log: options.human_readable_log,
+                sync_writes: options.flush_policy == FlushPolicy::PerOperation,
+                storage,
+                versions: versions.clone(),
+                publish: publish.clone(),
+            })),
+            versions,
+            publish,
+            contention: None,
+            hot_keys: None,
+            key_validator: None,
+            dangling: options.dangling,
+        };
+        if let Some(ratio) = options.compact_on_open {
+            if mostly_garbage(&engine.file_stats, ratio, 0) {
+                engine.compact()?;
+            }
+        }
+        if let Some((interval, ratio)) = options.compact_check_interval {
+            engine.spawn_compact_check(interval, ratio)?;
+        }
+        if let FlushPolicy::Periodic(interval) = options.flush_policy {
+            engine.spawn_periodic_sync(interval)?;
+        }
+        Ok(engine)
+    }
+
+    /// start the thread of `FlushPolicy::Periodic`, holding the store weakly
+    /// so that it ends once every clone is dropped
+    fn spawn_periodic_sync(&self, interval: Duration) -> Result<()> {
+        let writer = Arc::downgrade(&self.writer);
+        thread::Builder::new()
+            .name("kvs-periodic-sync".to_owned())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                let writer = match writer.upgrade() {
+                    Some(writer) => writer,
+                    None => return,
+                };
+                let res = writer.lock().unwrap().sync();
+                if let Err(e) = res {
+                    warn!(msg = "periodic sync failed", err = %e);
+                }
+            })?;
+        Ok(())
+    }
+
+    /// start the thread of `KvsOptions::compact_check_interval`, holding
+    /// the store weakly so that it ends once every clone is dropped
+    fn spawn_compact_check(&self, interval: Duration, ratio: f64) -> Result<()> {
+        let writer = Arc::downgrade(&self.writer);
+        let file_stats = Arc::downgrade(&self.file_stats);
+        thread::Builder::new()
+            .name("kvs-compact-check".to_owned())
+            .spawn(move || {
+                // the bytes of the versions retained by the last compaction
+                // of the check, until the next compaction of the store
+                let (mut retained, mut compactions) = (0, 0);
+                loop {
+                    thread::sleep(interval);
+                    let (writer, file_stats) = match (writer.upgrade(), file_stats.upgrade()) {
+                        (Some(writer), Some(file_stats)) => (writer, file_stats),
+                        _ => return,
+                    };
+                    if !mostly_garbage(&file_stats, ratio, retained) {
+                        continue;
+                    }
+                    let mut writer = writer.lock().unwrap();
+                    if writer.compactions != compactions {
+                        retained = 0;
+                    }
+                    // a write may have compacted meanwhile
+                    if mostly_garbage(&file_stats, ratio, retained) {
+                        match writer.compact() {
+                            // nothing but the retained versions is garbage yet
+                            Ok(()) => retained = writer.uncompact,
+                            Err(e) => warn!(msg = "periodic compaction failed", err = %e),
+                        }
+                    }
+                    compactions = writer.compactions;
+                }
+            })?;
+        Ok(())
+    }
+
+    /// replay every log of `storage` like `KvsEngine::check`
+    pub fn check_storage(storage: &S) -> Result<RecoveryReport> {
+        Self::check_storage_unless(storage, None)
+    }
+
+    /// `check_storage`, stopped like `KvsEngine::check_cancellable`
+    pub fn check_storage_cancellable(storage: &S, token: &CancelToken) -> Result<RecoveryReport> {
+        Self::check_storage_unless(storage, Some(token))
+    }
+
+    fn check_storage_unless(storage: &S, cancel: Option<&CancelToken>) -> Result<RecoveryReport> {
+        let mut report = RecoveryReport::default();
+        let key_dir = KeyDir::in_memory(KeyHasher::new(false));
+        for file_id in storage.list()? {
+            let mut reader = BufReaderWithPos::new(storage.open_reader(file_id)?)?;
+            let scan = replay_log(file_id, &mut reader, &key_dir, cancel)?.scan;
+            report.files += 1;
+            report.records += scan.records;
+            report.bytes_scanned += scan.scanned;
+            if scan.torn.is_some() {
+                report.records_skipped += 1;
+            }
+            if let Some((offset, e)) = scan.corruption {
+                warn!(msg = "corrupted record in the log", file_id, offset, err = %e);
+                report.corruption.push(Corruption {
+                    file_id,
+                    offset,
+                    error: e.to_string(),
+                });
+            }
+        }
+        report.keys_recovered = key_dir.len() as u64;
+        Ok(report)
+    }
+
+    /// pass the records of the logs of `storage` to `f` like
+    /// `KvsEngine::dump_records`
+    pub fn dump_storage_records(
+        storage: &S,
+        file_id: Option<u64>,
+        mut f: impl FnMut(LogRecord) -> Result<()>,
+    ) -> Result<()> {
+        let file_ids = storage.list()?;
+        let file_ids = match file_id {
+            Some(file_id) if !file_ids.contains(&file_id) => {
+                return Err(KvsError::StringErr(format!("no log file {}", file_id)));
+            }
+            Some(file_id) => vec![file_id],
+            None => file_ids,
+        };
+        for file_id in file_ids {
+            let mut reader = BufReaderWithPos::new(storage.open_reader(file_id)?)?;
+            let scan = scan_log(file_id, &mut reader, |cmd, range| {
+                let content = match cmd {
+                    Cmd::Set { key, value, .. } => RecordContent::Set {
+                        key,
+                        value_len: value.len() as u64,
+                    },
+                    Cmd::Remove { key } => RecordContent::Remove { key },
+                    Cmd::SetRef {
+                        key, file_id, pos, ..
+                    } => RecordContent::SetRef {
+                        key,
+                        value_file_id: file_id,
+                        value_offset: pos,
+                    },
+                };
+                f(LogRecord {
+                    file_id,
+                    offset: range.start,
+                    len: range.end - range.start,
+                    content,
+                })
+            })?;
+            let file_len = storage.len(file_id)?;
+            let unreadable = match (scan.torn, scan.corruption) {
+                (Some(offset), _) => Some((offset, RecordContent::Torn)),
+                (None, Some((offset, e))) => Some((
+                    offset,
+                    RecordContent::Corrupted {
+                        error: e.to_string(),
+                    },
+                )),
+                (None, None) => None,
+            };
+            if let Some((offset, content)) = unreadable {
+                f(LogRecord {
+                    file_id,
+                    offset,
+                    len: file_len - offset,
+                    content,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// warn through `tracing` when most writes wait longer than `threshold`
+    /// for the writer lock, naming the hottest keys. Measuring the waits
+    /// costs two clock reads per write, so it is off by default.
+    ///
+    /// Only the clones made after this call are monitored.
+    pub fn warn_on_contention(mut self, threshold: Duration) -> Self {
+        self.contention = Some(Arc::new(ContentionMonitor::new(threshold)));
+        self
+    }
+
+    /// count the `get`s and `set`s of every key, to find the hottest ones
+    /// with `hot_keys`, remembering the `capacity` keys counted the most.
+    /// Off by default: counting costs a few atomic increments per access.
+    ///
+    /// The counts are approximate, possibly higher than the actual ones for
+    /// keys accessed little. Only the clones made after this call count.
+    pub fn track_hot_keys(mut self, capacity: usize) -> Self {
+        self.hot_keys = Some(Arc::new(HotKeys::new(capacity)));
+        self
+    }
+
+    /// the `n` keys got and set the most since `track_hot_keys`, at most
+    /// its `capacity`, with their approximate number of accesses, the most
+    /// accessed first. Empty if the accesses aren't counted.
+    pub fn hot_keys(&self, n: usize) -> Vec<(String, u64)> {
+        match &self.hot_keys {
+            Some(hot_keys) => hot_keys.hottest(n),
+            None => Vec::new(),
+        }
+    }
+
+    /// check every key given to the engine with `validator` before it
+    /// reaches the index, e.g. for a maximum length or a charset, or rewrite
+    /// it, e.g. lowercased. A key it rejects, with `KvsError::InvalidKey`
+    /// by convention, fails the operation and is never written.
+    ///
+    /// Applies to the keys of the reads and writes of a single key, like
+    /// `get`, `set` and `remove`, and of `write_batch`, not to the prefixes
+    /// of scans. The keys stored before are kept as they were. Only the
+    /// clones made after this call validate.
+    ///
+    /// # Example
+    /// ```rust
+    /// use std::borrow::Cow;
+    /// use kvs::{Engine, KvsEngine, KvsError};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    /// let kv = KvsEngine::open(temp_dir.path()).unwrap().validate_keys(|key| match key {
+    ///     "" => Err(KvsError::InvalidKey("empty key".to_owned())),
+    ///     key => Ok(Cow::Owned(key.to_lowercase())),
+    /// });
+    /// kv.set("Key".to_owned(), "value".to_owned()).unwrap();
+    /// assert_eq!(kv.get("KEY").unwrap(), Some("value".to_owned()));
+    /// assert!(kv.set(String::new(), "value".to_owned()).is_err());
+    /// ```
+    pub fn validate_keys(
+        mut self,
+        validator: impl Fn(&str) -> Result<Cow<str>> + Send + Sync + 'static,
+    ) -> Self {
+        self.key_validator = Some(KeyValidator(Arc::new(validator)));
+        self
+    }
+
+    /// `key` as checked and rewritten by the validator of `validate_keys`
+    fn validate<'a>(&self, key: &'a str) -> Result<Cow<'a, str>> {
+        match &self.key_validator {
+            Some(validator) => validator.0(key),
+            None => Ok(Cow::Borrowed(key)),
+        }
+    }
+
+    /// `validate` an owned key, only copied if rewritten
+    fn validate_owned(&self, key: String) -> Result<String> {
+        match self.validate(&key)? {
+            Cow::Borrowed(valid) if valid.len() == key.len() => {}
+            valid => return Ok(valid.into_owned()),
+        }
+        Ok(key)
+    }
+
+    /// lock the writer to write `key`, measuring the wait if monitored
+    fn lock_writer(&self, key: &str) -> MutexGuard<'_, KvsWriter<S>> {
+        match &self.contention {
+            Some(monitor) => {
+                let start = Instant::now();
+                let writer = self.writer.lock().unwrap();
+                monitor.record(key, start.elapsed());
+                writer
+            }
+            None => self.writer.lock().unwrap(),
+        }
+    }
+
+    /// read the value of `key` at the position `lookup` finds it, like
+    /// `KvsReader::read_moving`, minding the `DanglingPolicy`
+    fn read_value(
+        &self,
+        key: &str,
+        lookup: impl Fn() -> Result<Option<CmdPos>>,
+    ) -> Result<Option<String>> {
+        match self.reader.read_moving(key, lookup) {
+            Err(e) if is_dangling(&e) && self.dangling != DanglingPolicy::Strict => {
+                self.read_dangling(key, e)
+            }
+            res => res,
+        }
+    }
+
+    /// the value of `key` once its index entry couldn't be read with `e`, see
+    /// `DanglingPolicy`
+    fn read_dangling(&self, key: &str, e: KvsError) -> Result<Option<String>> {
+        warn!(msg = "the value of a key can't be read, reading it as missing", key, err = %e);
+        if self.dangling != DanglingPolicy::SelfHeal {
+            return Ok(None);
+        }
+        let mut writer = self.lock_writer(key);
+        let cmd_pos = match self.key_dir.get(key)? {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        // a write may have replaced the entry meanwhile
+        match writer.reader.try_read_at(&cmd_pos) {
+            Some(Ok(value)) => Ok(Some(value)),
+            Some(Err(e)) if !is_dangling(&e) => Err(e),
+            _ => {
+                writer.forget(key)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// set a key-value pair only if the writer lock is free right now, so the
+    /// caller never waits behind other writers and can do its own backoff.
+    ///
+    /// `Ok(false)` means the write was not attempted, not that it failed:
+    /// nothing was stored and the call can simply be retried later.
+    pub fn try_set(&self, key: String, value: String) -> Result<bool> {
+        let key = self.validate_owned(key)?;
+        let mut writer = match self.writer.try_lock() {
+            Ok(writer) => writer,
+            Err(TryLockError::WouldBlock) => return Ok(false),
+            Err(e @ TryLockError::Poisoned(_)) => panic!("{}", e),
+        };
+        writer.set(key, value)?;
+        Ok(true)
+    }
+
+    /// get a value like `get`, skipping the cleanup of the readers of
+    /// compacted files, which iterates over the shared reader map
+    pub fn try_get(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        let key = self.validate(key.as_ref())?;
+        let key = key.as_ref();
+        self.reader.read_moving(key, || self.key_dir.get(key))
+    }
+
+    /// get a value with the version of its key, which grows with every write
+    /// of the key, see `set_if_version`
+    pub fn get_with_version(&self, key: impl AsRef<str>) -> Result<Option<(String, u64)>> {
+        let key = self.validate(key.as_ref())?;
+        let key = key.as_ref();
+        if let Some(hot_keys) = &self.hot_keys {
+            hot_keys.record(key);
+        }
+        self.reader.check_point();
+        // the version of the position the value is finally read at
+        let version = Cell::new(0);
+        let value = self.reader.read_moving(key, || {
+            let cmd_pos = self.key_dir.get(key)?;
+            version.set(cmd_pos.as_ref().map_or(0, |cmd_pos| cmd_pos.version));
+            Ok(cmd_pos)
+        })?;
+        Ok(value.map(|value| (value, version.get())))
+    }
+
+    /// set `key` to `value` only if its version is still `expected_version`,
+    /// 0 for a key which must not exist, returning the new version, or
+    /// `None` if another write came first. A writer reads a value and its
+    /// version with `get_with_version`, then commits what it derived from
+    /// it, or reads again on a conflict, without holding any lock meanwhile.
+    ///
+    /// The versions are drawn from a single sequence of the store, never
+    /// given twice even across removals, compactions and restarts, so a
+    /// writer which read a version before a removal never matches the key
+    /// set again.
+    pub fn set_if_version(
+        &self,
+        key: String,
+        value: String,
+        expected_version: u64,
+    ) -> Result<Option<u64>> {
+        let key = self.validate_owned(key)?;
+        let mut writer = self.lock_writer(&key);
+        if writer.version(&key)? != expected_version {
+            return Ok(None);
+        }
+        writer.set(key, value)?;
+        Ok(Some(writer.last_version))
+    }
+
+    /// apply the writes of `batch`, holding off other writers until all of
+    /// them are indexed. The batch isn't atomic across a crash: the records
+    /// written before one are kept.
+    pub fn write_batch(&self, mut batch: WriteBatch) -> Result<()> {
+        if self.key_validator.is_some() {
+            for cmd in &mut batch.cmds {
+                let (Cmd::Set { key, .. } | Cmd::Remove { key } | Cmd::SetRef { key, .. }) = cmd;
+                *key = self.validate_owned(mem::take(key))?;
+            }
+        }
+        let cmds = batch.into_cmds();
+        match cmds.first() {
+            Some(cmd) => self.lock_writer(cmd.key()).write_batch(cmds),
+            None => Ok(()),
+        }
+    }
+
+    /// flush the store and drop this clone of it, returning the error of
+    /// the flush. Dropping the last clone flushes the store too, but can
+    /// only log a failure: close it for a shutdown sure that every write
+    /// made it to the disk.
+    pub fn close(self) -> Result<()> {
+        self.flush()
+    }
+
+    /// rewrite the logs keeping only the live values, whatever the amount
+    /// that can be reclaimed. Writes are blocked meanwhile.
+    pub fn compact(&self) -> Result<()> {
+        self.writer.lock().unwrap().compact()
+    }
+
+    /// `compact`, stopped with `KvsError::Cancelled` once `token` is
+    /// cancelled. The partly written compacted log is then removed and the
+    /// store keeps reading the old logs, with the writes going on to a
+    /// fresh one.
+    pub fn compact_cancellable(&self, token: &CancelToken) -> Result<()> {
+        self.writer.lock().unwrap().compact_unless(Some(token))
+    }
+
+    /// reclaim the value log of a store separating its values, see
+    /// `KvsOptions::separate_values`: the live values are copied to a new
+    /// value log file, their keys pointed at the copies, and the old files
+    /// removed. Returns the number of bytes reclaimed. Writes are blocked
+    /// meanwhile. Nothing is done while a snapshot is live, as it may read
+    /// the values the store doesn't hold anymore.
+    pub fn compact_values(&self) -> Result<u64> {
+        self.writer.lock().unwrap().compact_values()
+    }
+
+    /// write a fresh copy of the store into the empty storage `dest`, as a
+    /// single log holding only the live values, see `compact_to`. Separated
+    /// values are written back into the log.
+    pub fn compact_into<D: Storage>(&self, dest: &D) -> Result<()> {
+        // the writer lock keeps both the index and the log files still
+        let _writer = self.writer.lock().unwrap();
+        let mut writer = BufWriterWithPos::new(dest.create(1)?)?;
+        let mut pos = 0;
+        for entry in self.key_dir.iter() {
+            let (key, mut cmd_pos) = entry?;
+            if self.reader.values.is_some() {
+                let value = self.reader.read_at(&cmd_pos)?.unwrap_or_default();
+                let version = cmd_pos.version;
+                Cmd::Set {
+                    key,
+                    value,
+                    version,
+                }
+                .write_record(&mut writer, false)?;
+            } else {
+                self.reader
+                    .copy_to(&mut cmd_pos, 1, &mut writer, &mut pos)?;
+            }
+        }
+        writer.flush()?;
+        dest.sync(writer.get_ref())?;
+        Ok(())
+    }
+
+    /// rewrite every live pair as a record of the current format, then
+    /// compact the logs down to those records, and return the number of
+    /// pairs rewritten. Upgrades in place a store written by an older build,
+    /// see `KvsEngine::format_version`: compactions copy the records as they
+    /// are, older formats included. A crash midway leaves a readable store,
+    /// partly upgraded.
+    pub fn upgrade(&self) -> Result<u64> {
+        let mut count = 0;
+        for pair in self.iter() {
+            let (key, value) = pair?;
+            self.set(key, value)?;
+            count += 1;
+        }
+        self.compact()?;
+        Ok(count)
+    }
+
+    /// approximate bytes of memory held by the index: every key twice (in
+    /// the hash index and in the ordered key set) plus the fixed size of an
+    /// entry in each. It grows with the number and the length of the keys.
+    /// The spare capacity of the maps, allocator overhead and the versions
+    /// retained for snapshots are not counted, nor are the spilled entries
+    /// but for their key in the key set.
+    pub fn index_memory_estimate(&self) -> usize {
+        // fixed size of a hash index entry and of a key set entry
+        const ENTRY: usize = mem::size_of::<(String, CmdPos)>();
+        const KEY: usize = mem::size_of::<String>();
+        let in_memory: usize = self
+            .key_dir
+            .memory()
+            .iter()
+            .map(|entry| ENTRY + entry.key().capacity())
+            .sum();
+        let keys = self.keys.read().unwrap();
+        in_memory + keys.iter().map(|key| KEY + key.capacity()).sum::<usize>()
+    }
+
+    /// the share of every log file which is garbage, by file id: the bytes
+    /// not holding the live value of a key over the bytes of the file, from
+    /// 0 for a file holding only live values to 1 for one a compaction would
+    /// drop entirely. An empty file counts as 0.
+    ///
+    /// Tracked as the keys are written, so it costs no read of the files.
+    pub fn file_fragmentation(&self) -> Vec<(u64, f64)> {
+        let mut fragmentation: Vec<_> = self
+            .file_stats
+            .iter()
+            .map(|entry| {
+                let stats = entry.value();
+                let ratio = match stats.total_bytes {
+                    0 => 0.0,
+                    total => (total - stats.live_bytes.min(total)) as f64 / total as f64,
+                };
+                (*entry.key(), ratio)
+            })
+            .collect();
+        fragmentation.sort_by_key(|(file_id, _)| *file_id);
+        fragmentation
+    }
+
+    /// read every log file through once, and the value log of a store
+    /// separating its values, so that the OS page cache holds them and the
+    /// first `get`s after opening a cold store don't wait on the disk. It
+    /// reads sequentially, far faster than the random reads it spares, but
+    /// costs the size of the logs in cache; nothing calls it, so a store
+    /// which starts faster without it just skips it.
+    pub fn prefetch(&self) -> Result<()> {
+        prefetch_files(&*self.storage)?;
+        if let Some(values) = &self.reader.values {
+            prefetch_files(&*values.storage)?;
+        }
+        Ok(())
+    }
+
+    /// get the value of a key as `Bytes`, for the code sharing buffers with
+    /// the `bytes` ecosystem. The buffer of the value read is handed over to
+    /// the `Bytes` as is, never copied; the records being JSON, the value is
+    /// still decoded and checked as UTF-8 on the way, like by `get`.
+    #[cfg(feature = "bytes")]
+    pub fn get_bytes(&self, key: impl AsRef<str>) -> Result<Option<bytes::Bytes>> {
+        Ok(self.get(key)?.map(bytes::Bytes::from))
+    }
+
+    /// iterate over the key-value pairs in key order. Only the keys and the
+    /// positions of their values are copied up front, the values are read
+    /// lazily, so writers are never blocked by a long iteration.
+    ///
+    /// The iteration sees the keys as of the call, but not a consistent
+    /// state: a value overwritten meanwhile may be read either before or
+    /// after the write. Take a `snapshot` for a consistent view.
+    ///
+    /// # Example
+    /// ```rust
+    /// use kvs::{Engine, KvsEngine, Result};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_file = TempDir::new().expect("unable to create temporary working directory");
+    /// let kv = KvsEngine::open(temp_file.path()).unwrap();
+    /// kv.set("b".to_owned(), "2".to_owned()).unwrap();
+    /// kv.set("a".to_owned(), "1".to_owned()).unwrap();
+    /// let pairs: Vec<(String, String)> = kv.iter().collect::<Result<_>>().unwrap();
+    /// assert_eq!(pairs, [("a".to_owned(), "1".to_owned()), ("b".to_owned(), "2".to_owned())]);
+    /// ```
+    pub fn iter(&self) -> Iter<S> {
+        // the writer moves the entries between the tiers of a spilling index
+        let _writer = self
+            .key_dir
+            .is_spilling()
+            .then(|| self.writer.lock().unwrap());
+        let publish = self.publish.read().unwrap();
+        let (mut entries, error) = match self.key_dir.iter().collect::<Result<Vec<_>>>() {
+            Ok(entries) => (entries, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        drop(publish);
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Iter {
+            key_dir: self.key_dir.clone(),
+            reader: self.reader.clone(),
+            entries: entries.into_iter(),
+            error,
+            cancel: None,
+        }
+    }
+
+    /// the key-value pairs for which `pred(key, value)` holds, in key order,
+    /// read like by `iter`. A server evaluates the filters of
+    /// `Request::ScanFilter` the same way, sending only the matches.
+    pub fn scan_filter<F: Fn(&str, &str) -> bool>(&self, pred: F) -> Result<Vec<(String, String)>> {
+        self.iter()
+            .filter(|entry| match entry {
+                Ok((key, value)) => pred(key, value),
+                Err(_) => true,
+            })
+            .collect()
+    }
+
+    /// a SHA-256 over the live key-value pairs in key order, each key and
+    /// value preceded by its length as a little-endian u64. It depends only
+    /// on the contents, not on the log layout or on compaction, so that two
+    /// replicas holding the same pairs digest alike.
+    pub fn content_digest(&self) -> Result<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        for entry in self.iter() {
+            let (key, value) = entry?;
+            for field in [key, value] {
+                hasher.update((field.len() as u64).to_le_bytes());
+                hasher.update(field.as_bytes());
+            }
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// take a consistent snapshot of the store, see `Snapshot` for the
+    /// memory and disk retention cost of holding one.
+    pub fn snapshot(&self) -> Snapshot<S> {
+        // hold the writer lock so that no write can slip in between reading the
+        // sequence and registering the snapshot
+        let _writer = self.writer.lock().unwrap();
+        let seq = self.versions.seq.load(Ordering::SeqCst);
+        *self.versions.live.lock().unwrap().entry(seq).or_insert(0) += 1;
+        Snapshot {
+            seq,
+            key_dir: self.key_dir.clone(),
+            reader: self.reader.clone(),
+            versions: self.versions.clone(),
+        }
+    }
+}
+
+impl<S: Storage> Iter<S> {
+    /// stop the iteration once `token` is cancelled: the next call to
+    /// `next` returns `KvsError::Cancelled`, and the iteration ends there
+    pub fn cancel_on(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+}
+
+impl<S: Storage> Iterator for Iter<S> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            self.cancel = None;
+            self.entries = Vec::new().into_iter();
+            return Some(Err(KvsError::Cancelled));
+        }
+        for (key, cmd_pos) in self.entries.by_ref() {
+            if let Some(value) = self.reader.try_read_at(&cmd_pos) {
+                return Some(value.map(|value| (key, value)));
+            }
+            // the log file was compacted away since the iteration started,
+            // the value has moved to the compacted file, which a compaction
+            // running meanwhile may have compacted away in turn
+            loop {
+                let cmd_pos = match self.key_dir.get(&key) {
+                    Ok(Some(cmd_pos)) => cmd_pos,
+                    // removed meanwhile
+                    Ok(None) => break,
+                    Err(e) => return Some(Err(e)),
+                };
+                if let Some(value) = self.reader.try_read_at(&cmd_pos) {
+                    return Some(value.map(|value| (key, value)));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<S: Storage> Snapshot<S> {
+    /// get the value of a key as of the moment the snapshot was taken
+    pub fn read(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        let key = key.as_ref();
+        self.reader.check_point();
+        self.reader.read_moving(key, || self.lookup(key))
+    }
+
+    /// the position of the value of a key as of the snapshot
+    fn lookup(&self, key: &str) -> Result<Option<CmdPos>> {
+        if let Some(cmd_pos) = self.key_dir.get(key)? {
+            if cmd_pos.seq <= self.seq {
+                return Ok(Some(cmd_pos));
+            }
+        }
+        // the current version is newer than the snapshot (or the key is gone),
+        // so look for the version that was current when the snapshot was taken
+        let version = self.versions.history.get(key).and_then(|versions| {
+            versions
+                .iter()
+                .rev()
+                .find(|version| version.seq <= self.seq)
+                .cloned()
+        });
+        Ok(version.and_then(|version| version.pos))
+    }
+}
+
+impl<S: Storage> Drop for Snapshot<S> {
+    fn drop(&mut self) {
+        self.versions.release(self.seq);
+    }
+}
+
+impl Replica {
+    /// follow the store in the directory `path`, polling it every
+    /// `poll_interval`
+    pub fn open(path: impl Into<PathBuf>, poll_interval: Duration) -> Result<Self> {
+        Self::with_storage(FsStorage::open(path.into())?, poll_interval)
+    }
+}
+
+impl<S: Storage> Replica<S> {
+    /// follow the store kept by `storage`, polling it every `poll_interval`
+    /// from a thread of its own, which ends with the last clone of the
+    /// replica
+    pub fn with_storage(storage: S, poll_interval: Duration) -> Result<Self> {
+        let storage = Arc::new(storage);
+        let state = ReplicaState::load(&storage)?;
+        let replica = Replica {
+            storage,
+            state: Arc::new(RwLock::new(state)),
+        };
+        replica.spawn_poll(poll_interval)?;
+        Ok(replica)
+    }
+
+    fn spawn_poll(&self, interval: Duration) -> Result<()> {
+        let storage = Arc::downgrade(&self.storage);
+        let state = Arc::downgrade(&self.state);
+        thread::Builder::new()
+            .name("kvs-replica-poll".to_owned())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                let replica = match (storage.upgrade(), state.upgrade()) {
+                    (Some(storage), Some(state)) => Replica { storage, state },
+                    _ => return,
+                };
+                if let Err(e) = replica.poll() {
+                    warn!(msg = "fail to poll the primary, retrying later", err = %e);
+                }
+            })?;
+        Ok(())
+    }
+
+    /// catch up with the primary now, without waiting for the next poll
+    pub fn poll(&self) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        let file_list = self.storage.list()?;
+        let compacted = state
+            .tailed
+            .keys()
+            .any(|file_id| file_list.binary_search(file_id).is_err());
+        if compacted {
+            *state = ReplicaState::load(&self.storage)?;
+            return Ok(());
+        }
+        match state.tail(&self.storage, &file_list) {
+            // a compaction removed a file between the listing and the read
+            Err(KvsError::IoErr(e)) if e.kind() == io::ErrorKind::NotFound => {
+                *state = ReplicaState::load(&self.storage)?;
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    /// get the value of a key as of the last poll
+    pub fn get(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        let state = self.state.read().unwrap();
+        let key = key.as_ref();
+        state.reader.read_moving(key, || state.key_dir.get(key))
+    }
+
+    /// whether a key exists as of the last poll
+    pub fn contains_key(&self, key: impl AsRef<str>) -> Result<bool> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .key_dir
+            .get(key.as_ref())?
+            .is_some())
+    }
+
+    /// the number of bytes the primary wrote to its logs that the replica
+    /// hasn't indexed yet
+    pub fn replication_lag(&self) -> Result<u64> {
+        let state = self.state.read().unwrap();
+        let mut lag = 0;
+        for file_id in self.storage.list()? {
+            let tailed = state.tailed.get(&file_id).copied().unwrap_or(0);
+            lag += self.storage.len(file_id)?.saturating_sub(tailed);
+        }
+        Ok(lag)
+    }
+}
+
+impl<S: Storage> Clone for Replica<S> {
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            state: self.state.clone(),
         }
+    }
+}
 
-        // create current log file
-        let current_file_id = file_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(current_file_id, &path)?;
-        readers.insert(current_file_id, BufReaderWithPos::new(
-            File::open(
-               to_log_file(current_file_id, &path))?
-        )?);
-        let path = Arc::new(path);
-        let reader = KvsReader {
-            path: path.clone(),
-            readers: Arc::new(readers),
-            check_point: Arc::new(AtomicU64::new(0)),
+impl<S: Storage> ReplicaState<S> {
+    /// index the whole store kept by `storage`
+    fn load(storage: &Arc<S>) -> Result<Self> {
+        let (values, _) = open_value_log(storage.as_ref(), false)?;
+        let mut state = ReplicaState {
+            key_dir: KeyDir::in_memory(KeyHasher::new(false)),
+            reader: KvsReader {
+                storage: storage.clone(),
+                readers: Arc::new(DashMap::new()),
+                check_point: Arc::new(AtomicU64::new(0)),
+                values: values.map(Box::new),
+            },
+            tailed: BTreeMap::new(),
         };
-        let key_dir = Arc::new(key_dir);
-        // return
-        Ok(KvsEngine{
-            key_dir: key_dir.clone(),
-            path: path.clone(),
-            reader: reader.clone(),
-            writer: Arc::new(Mutex::new(KvsWriter {
-                reader: reader.clone(),
-                key_dir: key_dir.clone(),
-                writer,
-                current_file_id,
-                uncompact,
-                path,
-            }))
-        })
+        state.tail(storage, &storage.list()?)?;
+        Ok(state)
+    }
+
+    /// index the records appended to the log files of `file_list` since
+    /// they were last tailed, in the order they were written
+    fn tail(&mut self, storage: &S, file_list: &[u64]) -> Result<()> {
+        for file_id in file_list {
+            if !self.tailed.contains_key(file_id) {
+                self.reader.open(*file_id)?;
+                // the hints of a file are only written once it is complete
+                if let Some(hints) = read_hints(*file_id, storage) {
+                    load_hints(*file_id, storage, hints, &self.key_dir)?;
+                    self.tailed.insert(*file_id, storage.len(*file_id)?);
+                    continue;
+                }
+            }
+            let from = self.tailed.get(file_id).copied().unwrap_or(0);
+            let mut reader = self.reader.readers.get_mut(file_id).expect("opened above");
+            let to = tail_log(*file_id, reader.value_mut(), from, &self.key_dir)?;
+            self.tailed.insert(*file_id, to);
+        }
+        // the values the new records point at may be in new value log files
+        if let Some(values) = &self.reader.values {
+            for file_id in values.storage.list()? {
+                if !values.readers.contains_key(&file_id) {
+                    values.open(file_id)?;
+                }
+            }
+        }
+        Ok(())
     }
+}
 
+impl VersionSet {
+    /// keep the version of `key` that is about to be superseded if a live
+    /// snapshot can still see it.
+    fn retain(&self, key: &str, old: Option<CmdPos>, removed_at: Option<u64>) {
+        let live = self.live.lock().unwrap();
+        let newest = match live.keys().next_back() {
+            Some(newest) => *newest,
+            None => return,
+        };
+        if let Some(old) = old {
+            if old.seq <= newest {
+                self.history
+                    .entry(key.to_owned())
+                    .or_default()
+                    .push(Version {
+                        seq: old.seq,
+                        pos: Some(old),
+                    });
+            }
+        }
+        // a removal must shadow the retained versions for later snapshots
+        if let Some(seq) = removed_at {
+            if let Some(mut versions) = self.history.get_mut(key) {
+                versions.push(Version { seq, pos: None });
+            }
+        }
+    }
 
+    /// unregister a snapshot and drop the versions no live snapshot can see.
+    fn release(&self, seq: u64) {
+        let mut live = self.live.lock().unwrap();
+        if let Some(count) = live.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&seq);
+            }
+        }
+        if live.is_empty() {
+            self.history.clear();
+            return;
+        }
+        self.history.retain(|_, versions| {
+            // a version is visible to the snapshots between its own sequence
+            // and the sequence of the version that superseded it. The last one
+            // is conservatively treated as visible to every later snapshot.
+            let ends: Vec<u64> = versions
+                .iter()
+                .skip(1)
+                .map(|v| v.seq)
+                .chain(std::iter::once(u64::MAX))
+                .collect();
+            let mut kept = Vec::new();
+            for (version, end) in versions.drain(..).zip(ends) {
+                let visible = live.range(version.seq..end).next().is_some();
+                // removals are kept as long as a retained version precedes them
+                let keep = match version.pos {
+                    Some(_) => visible,
+                    None => !kept.is_empty(),
+                };
+                if keep {
+                    kept.push(version);
+                }
+            }
+            *versions = kept;
+            versions.iter().any(|v| v.pos.is_some())
+        });
+    }
 }
 
-impl KvsWriter {
+impl<S: Storage> KvsWriter<S> {
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Cmd::Set { key, value };
+        let version = self.next_version();
+        let cmd = self.separate(Cmd::Set {
+            key,
+            value,
+            version,
+        })?;
         let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Cmd::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self
-                .key_dir
-                .insert(key, (self.current_file_id.into(), pos..self.writer.pos).into())
-            {
-                self.uncompact += old_cmd.len;
+        cmd.write_record(&mut self.writer, self.human_readable_log)?;
+        #[cfg(feature = "test-util")]
+        reach(PanicPoint::BeforeFlush);
+        self.flush_logs()?;
+        #[cfg(feature = "test-util")]
+        reach(PanicPoint::AfterFlush);
+        self.index(cmd, pos..self.writer.pos)?;
+        if self.should_compact() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// the version of `key`, 0 if it doesn't exist
+    fn version(&self, key: &str) -> Result<u64> {
+        Ok(self.key_dir.get(key)?.map_or(0, |cmd_pos| cmd_pos.version))
+    }
+
+    /// the version of the next set, above any given before, whatever the key.
+    /// The versions of the sets written to the active log start above its
+    /// file id shifted by 32 bits, so those given before the last compaction
+    /// or restart stay below without being recorded, as long as a log file
+    /// holds fewer than 2^32 records.
+    fn next_version(&mut self) -> u64 {
+        self.last_version = self.last_version.max(self.current_file_id << 32) + 1;
+        self.last_version
+    }
+
+    /// write `cmds` to the log with one flush, then index them. A remove of
+    /// a key which doesn't exist by then is skipped. A set gets the next
+    /// version, a value moved by a `SetRef` keeps its own.
+    fn write_batch(&mut self, cmds: Vec<Cmd>) -> Result<()> {
+        // the version of a key after the commands of the batch before
+        let mut versions = HashMap::new();
+        let mut written = Vec::with_capacity(cmds.len());
+        for mut cmd in cmds {
+            let current = match versions.get(cmd.key()) {
+                Some(version) => *version,
+                None => self.version(cmd.key())?,
+            };
+            match &mut cmd {
+                Cmd::Set { version, .. } => *version = self.next_version(),
+                Cmd::Remove { .. } if current == 0 => continue,
+                _ => {}
             }
+            versions.insert(cmd.key().to_owned(), cmd.version());
+            let cmd = self.separate(cmd)?;
+            let pos = self.writer.pos;
+            cmd.write_record(&mut self.writer, self.human_readable_log)?;
+            written.push((cmd, pos..self.writer.pos));
         }
-        if self.uncompact >= COMPACT_THRESHOLD {
+        // readers only see the log once flushed, so nothing is indexed before
+        self.flush_logs()?;
+        let publish = self.publish.clone();
+        let _publish = publish.write().unwrap();
+        for (cmd, range) in written {
+            self.index(cmd, range)?;
+        }
+        if self.should_compact() {
             self.compact()?;
         }
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let cmd = Cmd::Remove { key };
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Cmd::Remove { key } = cmd {
-            let old_cmd = self.key_dir.remove(&key).expect("key not found").1;
-            self.uncompact += old_cmd.len;
-            if self.uncompact >= COMPACT_THRESHOLD {
-                self.compact()?;
+    /// point the index of the key of `cmd` at its record, flushed to the
+    /// active log at `range`. The key of a remove must be indexed.
+    fn index(&mut self, cmd: Cmd, range: Range<u64>) -> Result<()> {
+        let seq = self.versions.seq.load(Ordering::SeqCst) + 1;
+        let version = cmd.version();
+        let old = self.key_dir.get(cmd.key())?;
+        if let Some(old) = &old {
+            if let Some(mut stats) = self.file_stats.get_mut(&old.file_id) {
+                stats.live_bytes = stats.live_bytes.saturating_sub(old.len);
+            }
+        }
+        {
+            let mut stats = self.file_stats.entry(self.current_file_id).or_default();
+            stats.total_bytes += range.end - range.start;
+            if let Cmd::Set { .. } | Cmd::SetRef { .. } = cmd {
+                stats.live_bytes += range.end - range.start;
+            }
+        }
+        match cmd {
+            Cmd::Set { key, .. } | Cmd::SetRef { key, .. } => {
+                if old.is_none() {
+                    self.keys.write().unwrap().insert(key.clone());
+                }
+                self.versions.retain(&key, old, None);
+                let cmd_pos = CmdPos {
+                    seq,
+                    version,
+                    ..(self.current_file_id, range).into()
+                };
+                if let Some(old_cmd) = self.key_dir.insert(key, cmd_pos)? {
+                    self.uncompact += old_cmd.len;
+                }
             }
+            Cmd::Remove { key } => {
+                self.versions.retain(&key, old, Some(seq));
+                let old_cmd = self.key_dir.remove(&key)?.expect("key not found");
+                self.keys.write().unwrap().remove(&key);
+                self.uncompact += old_cmd.len;
+            }
+        }
+        self.versions.seq.store(seq, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// drop the index entry of `key`, leaving the logs as they are
+    fn forget(&mut self, key: &str) -> Result<()> {
+        if let Some(old) = self.key_dir.remove(key)? {
+            if let Some(mut stats) = self.file_stats.get_mut(&old.file_id) {
+                stats.live_bytes = stats.live_bytes.saturating_sub(old.len);
+            }
+            self.keys.write().unwrap().remove(key);
+        }
+        Ok(())
+    }
+
+    /// write the value of a set to the value log if the values are
+    /// separated, returning the record pointing at it to write instead
+    fn separate(&mut self, cmd: Cmd) -> Result<Cmd> {
+        let values = match &mut self.value_writer {
+            Some(values) => values,
+            None => return Ok(cmd),
+        };
+        match cmd {
+            Cmd::Set { .. } => {
+                let pos = values.writer.pos;
+                cmd.write_record(&mut values.writer, false)?;
+                Ok(Cmd::SetRef {
+                    key: cmd.key().to_owned(),
+                    file_id: values.file_id,
+                    pos,
+                    len: values.writer.pos - pos,
+                    version: cmd.version(),
+                })
+            }
+            cmd => Ok(cmd),
+        }
+    }
+
+    /// make every write done so far durable, the values first
+    fn sync(&mut self) -> Result<()> {
+        self.sync_values()?;
+        self.writer.flush()?;
+        self.storage.sync(self.writer.get_ref())
+    }
+
+    /// make the values written to the active value log durable
+    fn sync_values(&mut self) -> Result<()> {
+        if let (Some(values), Some(reader)) = (&mut self.value_writer, &self.reader.values) {
+            values.writer.flush()?;
+            reader.storage.sync(values.writer.get_ref())?;
+        }
+        Ok(())
+    }
+
+    /// flush the value log, then the log, so that no record points at a
+    /// value readers can't see yet; sync them if every write is
+    fn flush_logs(&mut self) -> Result<()> {
+        if self.sync_writes {
+            return self.sync();
+        }
+        if let Some(values) = &mut self.value_writer {
+            values.writer.flush()?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn append(&mut self, key: String, suffix: String) -> Result<usize> {
+        // the bitcask log is append-only, so the whole new value is written
+        let old = self.key_dir.get(&key)?;
+        let mut value = match old {
+            Some(cmd_pos) => self.reader.read(&cmd_pos)?.unwrap_or_default(),
+            None => String::new(),
+        };
+        value.push_str(&suffix);
+        let len = value.len();
+        self.set(key, value)?;
+        Ok(len)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        let cmd = Cmd::Remove {
+            key: key.to_owned(),
         };
+        let pos = self.writer.pos;
+        cmd.write_record(&mut self.writer, self.human_readable_log)?;
+        self.flush_logs()?;
+        self.index(cmd, pos..self.writer.pos)?;
+        if self.should_compact() {
+            self.compact()?;
+        }
         Ok(())
     }
 
+    /// whether a write should trigger a compaction: enough can be reclaimed,
+    /// from enough log files
+    fn should_compact(&self) -> bool {
+        self.auto_compact
+            && self.uncompact >= COMPACT_THRESHOLD
+            && self.reader.readers.len() >= self.min_files_to_compact
+    }
+
     fn compact(&mut self) -> Result<()> {
+        self.compact_unless(None)
+    }
+
+    /// compact, giving up between two copied records once `cancel` is cancelled
+    fn compact_unless(&mut self, cancel: Option<&CancelToken>) -> Result<()> {
+        let cancelled = || cancel.is_some_and(CancelToken::is_cancelled);
         let compact_file_id = self.current_file_id + 1;
-        self.current_file_id += 2;
-        self.writer = new_log_file(self.current_file_id, &self.path)?;
+        let current_file_id = self.current_file_id + 2;
+        // the writes go on to the current log until the new one is open, so
+        // a failure here leaves the store as it was
+        let writer = retry_open(current_file_id, || self.storage.create(current_file_id))?;
+        self.reader.open(current_file_id)?;
+        self.writer = BufWriterWithPos::new(writer)?;
+        self.current_file_id = current_file_id;
 
-        let mut compact_writer = new_log_file(compact_file_id, &self.path)?;
+        let mut compact_writer = BufWriterWithPos::new(retry_open(compact_file_id, || {
+            self.storage.create(compact_file_id)
+        })?)?;
+        let mut partial = PartialLog {
+            storage: self.storage.clone(),
+            file_id: compact_file_id,
+            complete: false,
+        };
+        self.reader.open(compact_file_id)?;
         let mut compact_pos = 0;
-        for mut cmd_pos in self.key_dir.iter_mut() {
-            let CmdPos {
-                file_id,
-                kv_pos,
-                len,
-            } = cmd_pos.value_mut();
-            let mut reader = self.reader.readers.get_mut(&file_id).expect("can't find log file;");
-            if reader.value_mut().pos != *kv_pos {
-                reader.seek(SeekFrom::Start(*kv_pos))?;
+        // versions still visible to a live snapshot survive the compaction.
+        // They go first, so that replaying the log ends on the live values.
+        // The copies are only pointed at once flushed, until then a reader
+        // following them would find nothing in the compacted log.
+        let mut retained = 0;
+        let mut moved_versions = Vec::new();
+        for versions in self.versions.history.iter() {
+            for version in versions.iter() {
+                if let Some(cmd_pos) = &version.pos {
+                    if cancelled() {
+                        drop(compact_writer);
+                        return self.abandon_compaction(compact_file_id);
+                    }
+                    let mut cmd_pos = cmd_pos.clone();
+                    self.reader.copy_to(
+                        &mut cmd_pos,
+                        compact_file_id,
+                        &mut compact_writer,
+                        &mut compact_pos,
+                    )?;
+                    retained += cmd_pos.len;
+                    moved_versions.push((versions.key().clone(), version.seq, cmd_pos));
+                    #[cfg(feature = "test-util")]
+                    reach(PanicPoint::MidCompaction);
+                }
             }
-            let mut rdr = reader.value_mut().take(len.clone());
-            io::copy(&mut rdr, &mut compact_writer)?;
-
-            *file_id = compact_file_id;
-            *kv_pos = compact_pos;
-            compact_pos += *len;
+        }
+        let mut hints = Vec::with_capacity(self.key_dir.len());
+        for entry in self.key_dir.iter() {
+            let (key, mut cmd_pos) = entry?;
+            if cancelled() {
+                drop(compact_writer);
+                return self.abandon_compaction(compact_file_id);
+            }
+            self.reader.copy_to(
+                &mut cmd_pos,
+                compact_file_id,
+                &mut compact_writer,
+                &mut compact_pos,
+            )?;
+            hints.push(Hint {
+                key,
+                kv_pos: cmd_pos.kv_pos,
+                len: cmd_pos.len,
+                version: cmd_pos.version,
+            });
+            #[cfg(feature = "test-util")]
+            reach(PanicPoint::MidCompaction);
         }
         compact_writer.flush()?;
+        // the old logs are removed below, the compacted one must be on the
+        // disk first, and so must the values it points at
+        self.sync_values()?;
+        self.storage.sync(compact_writer.get_ref())?;
+        write_hints(compact_file_id, &*self.storage, &hints)?;
+        partial.complete = true;
+        #[cfg(feature = "test-util")]
+        reach(PanicPoint::AfterCompactionSync);
+
+        // the writer lock keeps the keys as they were copied, but a dropped
+        // snapshot may have released some versions meanwhile
+        for (key, seq, cmd_pos) in moved_versions {
+            if let Some(mut versions) = self.versions.history.get_mut(&key) {
+                if let Some(version) = versions.iter_mut().find(|v| v.seq == seq) {
+                    version.pos = Some(cmd_pos);
+                }
+            }
+        }
+        for hint in &hints {
+            self.key_dir.update(&hint.key, |cmd_pos| {
+                cmd_pos.file_id = compact_file_id;
+                cmd_pos.kv_pos = hint.kv_pos;
+            })?;
+        }
 
         let remove_files: Vec<_> = self
             .reader
@@ -265,22 +2456,129 @@ impl KvsWriter {
             .collect();
         for file in remove_files {
             self.reader.readers.remove(&file);
-            remove_file(to_log_file(file, &self.path))?;
+            self.file_stats.remove(&file);
+            self.storage.remove(file)?;
         }
-        // self.reader.
-        self.uncompact = 0;
+        self.file_stats.insert(
+            compact_file_id,
+            FileStats {
+                live_bytes: hints.iter().map(|hint| hint.len).sum(),
+                total_bytes: compact_pos,
+            },
+        );
+        self.file_stats
+            .insert(self.current_file_id, FileStats::default());
+        // retained versions become garbage once their snapshots are dropped
+        self.uncompact = retained;
+        self.compactions += 1;
         Ok(())
     }
+
+    fn compact_values(&mut self) -> Result<u64> {
+        let values = match &self.reader.values {
+            Some(values) => values.clone(),
+            None => return Ok(0),
+        };
+        if !self.versions.live.lock().unwrap().is_empty() {
+            return Ok(0);
+        }
+        let old_files = values.storage.list()?;
+        let mut old_len = 0;
+        for file_id in &old_files {
+            old_len += values.storage.len(*file_id)?;
+        }
+        let file_id = old_files.last().unwrap_or(&0) + 1;
+        let mut writer =
+            BufWriterWithPos::new(retry_open(file_id, || values.storage.create(file_id))?)?;
+        values.open(file_id)?;
+        let mut pos = 0;
+        let mut moved = Vec::new();
+        for entry in self.key_dir.iter() {
+            let (key, cmd_pos) = entry?;
+            if let Cmd::SetRef {
+                file_id: old_file_id,
+                pos: old_pos,
+                len,
+                version,
+                ..
+            } = self.reader.read_cmd_at(&cmd_pos)?
+            {
+                let mut value_pos = (old_file_id, old_pos..old_pos + len).into();
+                values.copy_to(&mut value_pos, file_id, &mut writer, &mut pos)?;
+                moved.push(Cmd::SetRef {
+                    key,
+                    file_id,
+                    pos: value_pos.kv_pos,
+                    len,
+                    version,
+                });
+            }
+        }
+        writer.flush()?;
+        values.storage.sync(writer.get_ref())?;
+        // the keys must point at the copies for good before the old files go
+        self.write_batch(moved)?;
+        self.storage.sync(self.writer.get_ref())?;
+        if self.value_writer.is_some() {
+            self.value_writer = Some(ValueWriter { file_id, writer });
+        }
+        for file_id in old_files {
+            values.readers.remove(&file_id);
+            values.storage.remove(file_id)?;
+        }
+        Ok(old_len.saturating_sub(pos))
+    }
+
+    /// drop the compacted log of a cancelled compaction. Nothing points at
+    /// it yet, the index and the old logs are untouched.
+    fn abandon_compaction(&self, compact_file_id: u64) -> Result<()> {
+        self.reader.readers.remove(&compact_file_id);
+        self.storage.remove(compact_file_id)?;
+        Err(KvsError::Cancelled)
+    }
 }
 
-impl KvsReader {
+impl<S: Storage> KvsReader<S> {
+    /// open a reader for a newly created log file
+    fn open(&self, file_id: u64) -> Result<()> {
+        let reader = retry_open(file_id, || self.storage.open_reader(file_id))?;
+        let reader = BufReaderWithPos::new(reader)?;
+        self.readers.insert(file_id, reader);
+        Ok(())
+    }
+
+    /// copy the record at `cmd_pos` to the end of `writer` and point `cmd_pos` at the copy
+    fn copy_to<W: Write + Seek>(
+        &self,
+        cmd_pos: &mut CmdPos,
+        file_id: u64,
+        writer: &mut BufWriterWithPos<W>,
+        pos: &mut u64,
+    ) -> Result<()> {
+        let mut reader = self
+            .readers
+            .get_mut(&cmd_pos.file_id)
+            .expect("can't find log file;");
+        if reader.value_mut().pos != cmd_pos.kv_pos {
+            reader.seek(SeekFrom::Start(cmd_pos.kv_pos))?;
+        }
+        let mut rdr = reader.value_mut().take(cmd_pos.len);
+        io::copy(&mut rdr, writer)?;
+
+        cmd_pos.file_id = file_id;
+        cmd_pos.kv_pos = *pos;
+        *pos += cmd_pos.len;
+        Ok(())
+    }
+
     fn check_point(&self) {
-        while !self.readers.is_empty() {
-            let file_id = self.readers.iter().next().unwrap();
-            if self.check_point.load(Ordering::SeqCst) <= *file_id.key() {
+        // a compaction may empty the map meanwhile, and the key is copied out
+        // so that no shard stays locked across the removal
+        while let Some(file_id) = self.readers.iter().next().map(|entry| *entry.key()) {
+            if self.check_point.load(Ordering::SeqCst) <= file_id {
                 break;
             }
-            self.readers.remove(file_id.key());
+            self.readers.remove(&file_id);
         }
     }
 
@@ -290,35 +2588,214 @@ impl KvsReader {
         if !self.readers.contains_key(&cmd_pos.file_id) {
 
         }
+        self.read_at(cmd_pos)
+    }
+
+    /// read the value at `cmd_pos`, without dropping the stale readers first.
+    /// Only for the holders of the writer lock, which keeps compactions from
+    /// removing the log file: the others read with `read_moving`.
+    fn read_at(&self, cmd_pos: &CmdPos) -> Result<Option<String>> {
+        self.try_read_at(cmd_pos)
+            .expect("inconsistency! Can't find this log file")
+            .map(Some)
+    }
+
+    /// read the value of `key` at the position `lookup` finds it, without
+    /// holding the writer lock. A compaction may remove the log file between
+    /// the lookup and the read, but only once it has moved the value and
+    /// pointed the index at the copy, so the key is looked up again then,
+    /// as long as each lookup finds it moved.
+    fn read_moving(
+        &self,
+        key: &str,
+        lookup: impl Fn() -> Result<Option<CmdPos>>,
+    ) -> Result<Option<String>> {
+        let mut missing: Option<CmdPos> = None;
+        loop {
+            let cmd_pos = match lookup()? {
+                Some(cmd_pos) => cmd_pos,
+                None => return Ok(None),
+            };
+            if missing.as_ref() == Some(&cmd_pos) {
+                return Err(KvsError::StringErr(format!(
+                    "the log file {} holding key {:?} is missing",
+                    cmd_pos.file_id, key
+                )));
+            }
+            match self.try_read_at(&cmd_pos) {
+                Some(value) => return value.map(Some),
+                None => missing = Some(cmd_pos),
+            }
+        }
+    }
+
+    /// read the value at `cmd_pos`, or `None` if its log file, or the value
+    /// log file it points at, was removed by a compaction
+    fn try_read_at(&self, cmd_pos: &CmdPos) -> Option<Result<String>> {
+        let cmd = {
+            let mut reader = self.readers.get_mut(&cmd_pos.file_id)?;
+            read_cmd(reader.value_mut(), cmd_pos)
+        };
+        match cmd {
+            Ok(Cmd::Set { value, .. }) => Some(Ok(value)),
+            Ok(Cmd::SetRef {
+                key,
+                file_id,
+                pos,
+                len,
+                ..
+            }) => match &self.values {
+                Some(values) => values.try_read_at(&(file_id, pos..pos + len).into()),
+                None => Some(Err(KvsError::StringErr(format!(
+                    "no value log holding the value of {:?}",
+                    key
+                )))),
+            },
+            Ok(Cmd::Remove { .. }) => Some(Err(KvsError::CommandNotSupported)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// read the record at `cmd_pos`, for the holders of the writer lock
+    fn read_cmd_at(&self, cmd_pos: &CmdPos) -> Result<Cmd> {
         let mut reader = self
             .readers
             .get_mut(&cmd_pos.file_id)
-            .expect("inconsistency! Can't find this log file");
-        reader.value_mut().seek(SeekFrom::Start(cmd_pos.kv_pos))?;
-        let reader = reader.value_mut().take(cmd_pos.len);
-        if let Cmd::Set { value, .. } = serde_json::from_reader(reader)? {
-            Ok(Some(value))
-        } else {
-            Err(KvsError::CommandNotSupported)
+            .expect("can't find log file;");
+        read_cmd(reader.value_mut(), cmd_pos)
+    }
+}
+
+/// read the record at `cmd_pos` of a log file
+/// whether reading an indexed record failed with `e` because the record is
+/// gone or damaged for good, see `DanglingPolicy`: unlike a failure which
+/// may pass, a record cut short by a truncated log, or in a missing file,
+/// fails however often it is read again
+fn is_dangling(e: &KvsError) -> bool {
+    match e {
+        KvsError::SerdeErr(e) => !e.is_io(),
+        KvsError::IoErr(e) => matches!(
+            e.kind(),
+            io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof
+        ),
+        e => !e.is_retryable(),
+    }
+}
+
+/// read every file of `storage` through, see `KvsEngine::prefetch`
+fn prefetch_files<S: Storage>(storage: &S) -> Result<()> {
+    for file_id in storage.list()? {
+        let mut reader = match storage.open_reader(file_id) {
+            Ok(reader) => reader,
+            // removed by a compaction meanwhile
+            Err(_) if !storage.list()?.contains(&file_id) => continue,
+            Err(e) => return Err(e),
+        };
+        io::copy(&mut reader, &mut io::sink())?;
+    }
+    Ok(())
+}
+
+fn read_cmd<R: Read + Seek>(reader: &mut BufReaderWithPos<R>, cmd_pos: &CmdPos) -> Result<Cmd> {
+    reader.seek(SeekFrom::Start(cmd_pos.kv_pos))?;
+    let reader = reader.take(cmd_pos.len);
+    serde_json::from_reader::<_, Record>(reader)?.into_cmd()
+}
+
+/// The reader of a value log and its active file, see `open_value_log`.
+type ValueLog<S> = (Option<KvsReader<S>>, Option<ValueWriter<S>>);
+
+/// open the value log of the store kept by `storage` if it has one or if
+/// its values are to be separated, with a new active file in the latter case
+fn open_value_log<S: Storage>(storage: &S, separate_values: bool) -> Result<ValueLog<S>> {
+    let values = match storage.value_log() {
+        Ok(values) => values,
+        Err(e) if separate_values => return Err(e),
+        Err(_) => return Ok((None, None)),
+    };
+    let file_list = values.list()?;
+    if file_list.is_empty() && !separate_values {
+        return Ok((None, None));
+    }
+    let readers = DashMap::new();
+    for file_id in &file_list {
+        let reader = retry_open(*file_id, || values.open_reader(*file_id))?;
+        readers.insert(*file_id, BufReaderWithPos::new(reader)?);
+    }
+    let reader = KvsReader {
+        storage: Arc::new(values),
+        readers: Arc::new(readers),
+        check_point: Arc::new(AtomicU64::new(0)),
+        values: None,
+    };
+    if !separate_values {
+        return Ok((Some(reader), None));
+    }
+    let file_id = file_list.last().unwrap_or(&0) + 1;
+    let writer = BufWriterWithPos::new(retry_open(file_id, || reader.storage.create(file_id))?)?;
+    reader.open(file_id)?;
+    Ok((Some(reader), Some(ValueWriter { file_id, writer })))
+}
+
+impl<S: Storage> Clone for KvsEngine<S> {
+    fn clone(&self) -> Self {
+        Self {
+            key_dir: self.key_dir.clone(),
+            keys: self.keys.clone(),
+            file_stats: self.file_stats.clone(),
+            storage: self.storage.clone(),
+            reader: self.reader.clone(),
+            writer: self.writer.clone(),
+            versions: self.versions.clone(),
+            publish: self.publish.clone(),
+            contention: self.contention.clone(),
+            hot_keys: self.hot_keys.clone(),
+            key_validator: self.key_validator.clone(),
+            dangling: self.dangling,
         }
     }
 }
 
-impl Clone for KvsReader {
+impl<S: Storage> Clone for KvsReader<S> {
     fn clone(&self) -> Self {
         Self {
-            path: self.path.clone(),
+            storage: self.storage.clone(),
             readers: self.readers.clone(),
-            check_point: self.check_point.clone()
+            check_point: self.check_point.clone(),
+            values: self.values.clone(),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct CmdPos {
     file_id: u64,
     kv_pos: u64,
     len: u64,
+    // sequence number of the write, 0 for records loaded at open
+    seq: u64,
+    // version of the key, see `KvsEngine::set_if_version`
+    version: u64,
+}
+
+impl SpilledEntry for CmdPos {
+    fn to_bytes(&self) -> Vec<u8> {
+        [self.file_id, self.kv_pos, self.len, self.seq, self.version]
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let n = |i: usize| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        CmdPos {
+            file_id: n(0),
+            kv_pos: n(1),
+            len: n(2),
+            seq: n(3),
+            version: n(4),
+        }
+    }
 }
 
 impl From<(u64, Range<u64>)> for CmdPos {
@@ -327,6 +2804,8 @@ impl From<(u64, Range<u64>)> for CmdPos {
             file_id,
             kv_pos: range.start,
             len: range.end - range.start,
+            seq: 0,
+            version: 0,
         }
     }
 }
@@ -362,6 +2841,26 @@ impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
     }
 }
 
+/// The log a compaction is copying to, removed if the compaction panics
+/// before it is complete: replayed after the logs it is copied from, a part
+/// of it could end on a version retained for a snapshot instead of the live
+/// one.
+struct PartialLog<S: Storage> {
+    storage: Arc<S>,
+    file_id: u64,
+    complete: bool,
+}
+
+impl<S: Storage> Drop for PartialLog<S> {
+    fn drop(&mut self) {
+        if !self.complete && thread::panicking() {
+            if let Err(e) = self.storage.remove(self.file_id) {
+                warn!(msg = "fail to remove partial compacted log", file_id = self.file_id, err = %e);
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 struct BufWriterWithPos<W: Write + Seek> {
     writer: BufWriter<W>,
@@ -376,6 +2875,10 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
             pos,
         })
     }
+
+    fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
@@ -397,68 +2900,263 @@ impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
     }
 }
 
-fn sorted_file_list(path: &PathBuf) -> Result<Vec<u64>> {
-    let mut file_list: Vec<u64> = read_dir(path)?
-        .flat_map(|f| -> Result<_> { Ok(f?.path()) })
-        .filter(|f| f.is_file() && (f.extension() == Some("log".as_ref())))
-        .flat_map(|f| {
-            f.file_name()
-                .and_then(OsStr::to_str)
-                .map(|f| f.trim_end_matches(".log"))
-                .map(|s| s.parse::<u64>())
-        })
-        .flatten()
-        .collect();
-    file_list.sort_unstable();
-    Ok(file_list)
+/// call `open`, which creates or opens the log file `file_id`, again while
+/// it fails with an I/O error which may be transient, like running out of
+/// file descriptors, backing off between the attempts. Any other error, e.g.
+/// a missing file or a failing disk, is returned at once.
+fn retry_open<T>(file_id: u64, mut open: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match open() {
+            Err(KvsError::IoErr(e)) if attempt < LOG_OPEN_ATTEMPTS && is_transient(&e) => {
+                warn!(msg = "fail to open log file, retrying", file_id, attempt, err = %e);
+                thread::sleep(LOG_OPEN_BACKOFF * 2u32.pow(attempt - 1));
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// whether `e` may go away on its own: an interrupted or timed out call, or
+/// too many open files, in the process (EMFILE) or the system (ENFILE)
+fn is_transient(e: &io::Error) -> bool {
+    const ENFILE: i32 = 23;
+    const EMFILE: i32 = 24;
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    ) || (cfg!(unix) && matches!(e.raw_os_error(), Some(ENFILE | EMFILE)))
+}
+
+/// The position of the live record of a key in a compacted log file.
+#[derive(Debug, Deserialize, Serialize)]
+struct Hint {
+    key: String,
+    kv_pos: u64,
+    len: u64,
+    #[serde(default = "first_version")]
+    version: u64,
+}
+
+/// write the hint file of the log file `file_id`, followed by a
+/// little-endian CRC32 of it
+fn write_hints<S: Storage>(file_id: u64, storage: &S, hints: &[Hint]) -> Result<()> {
+    let mut buf = serde_json::to_vec(hints)?;
+    let checksum = crc32fast::hash(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    storage.write_hints(file_id, &buf)
 }
 
-fn new_log_file(
+/// the hints of the log file `file_id`, or `None` if it has no valid hint file
+fn read_hints<S: Storage>(file_id: u64, storage: &S) -> Option<Vec<Hint>> {
+    let buf = match storage.read_hints(file_id) {
+        Ok(Some(buf)) => buf,
+        Ok(None) => return None,
+        Err(e) => {
+            warn!(msg = "unreadable hint file, replaying the log", file_id, err = %e);
+            return None;
+        }
+    };
+    if buf.len() < 4 {
+        warn!(msg = "truncated hint file, replaying the log", file_id);
+        return None;
+    }
+    let (body, trailer) = buf.split_at(buf.len() - 4);
+    let checksum = u32::from_le_bytes(trailer.try_into().expect("trailer is 4 bytes"));
+    if crc32fast::hash(body) != checksum {
+        warn!(msg = "corrupted hint file, replaying the log", file_id);
+        return None;
+    }
+    match serde_json::from_slice(body) {
+        Ok(hints) => Some(hints),
+        Err(e) => {
+            warn!(msg = "malformed hint file, replaying the log", file_id, err = %e);
+            None
+        }
+    }
+}
+
+/// load the index of the log file `file_id` from its hints, returning the
+/// number of bytes that can be saved after a compaction
+fn load_hints<S: Storage>(
     file_id: u64,
-    dir: &Path,
-) -> Result<BufWriterWithPos<File>> {
-    let path = to_log_file(file_id, dir);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(&path)?,
-    )?;
-    Ok(writer)
+    storage: &S,
+    hints: Vec<Hint>,
+    key_dir: &KeyDir<CmdPos>,
+) -> Result<u64> {
+    // everything but the hinted records is garbage, e.g. retained versions
+    let mut uncompacted = storage.len(file_id)?;
+    for Hint {
+        key,
+        kv_pos,
+        len,
+        version,
+    } in hints
+    {
+        uncompacted = uncompacted.saturating_sub(len);
+        let cmd_pos = CmdPos {
+            version,
+            ..(file_id, kv_pos..kv_pos + len).into()
+        };
+        if let Some(old_cmd) = key_dir.insert(key, cmd_pos)? {
+            uncompacted += old_cmd.len;
+        }
+    }
+    Ok(uncompacted)
 }
 
-fn to_log_file(file_id: u64, dir: &Path) -> PathBuf {
-    dir.join(format!("{}.log", file_id))
+fn load_log<R: Read + Seek>(
+    file_id: u64,
+    reader: &mut BufReaderWithPos<R>,
+    key_dir: &KeyDir<CmdPos>,
+) -> Result<u64> {
+    let replay = replay_log(file_id, reader, key_dir, None)?;
+    match replay.scan.corruption {
+        Some((_, e)) => Err(e),
+        None => Ok(replay.uncompacted),
+    }
 }
 
-fn load_log(
+/// index the records of the log file `file_id` from the offset `from` into
+/// `key_dir`, returning the offset after the last complete one. A record
+/// still being written at the end is left for the next call.
+fn tail_log<R: Read + Seek>(
     file_id: u64,
-    reader: &mut BufReaderWithPos<File>,
-    key_dir: &mut DashMap<String, CmdPos>,
+    reader: &mut BufReaderWithPos<R>,
+    from: u64,
+    key_dir: &KeyDir<CmdPos>,
 ) -> Result<u64> {
-    let mut posi = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Cmd>();
-    let mut uncompacted = 0; // number of bytes that can be saved after a compaction.
+    reader.seek(SeekFrom::Start(from))?;
+    let mut posi = from;
+    let mut stream = Deserializer::from_reader(reader).into_iter::<Record>();
     while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
+        let new_pos = from + stream.byte_offset() as u64;
+        let cmd = match cmd {
+            Err(e) if e.is_eof() => break,
+            cmd => cmd?.into_cmd()?,
+        };
+        match cmd {
+            Cmd::Remove { key } => {
+                key_dir.remove(&key)?;
+            }
+            Cmd::Set { key, version, .. } | Cmd::SetRef { key, version, .. } => {
+                let cmd_pos = CmdPos {
+                    version,
+                    ..(file_id, posi..new_pos).into()
+                };
+                key_dir.insert(key, cmd_pos)?;
+            }
+        }
+        posi = new_pos;
+    }
+    Ok(posi)
+}
+
+/// What replaying a log found.
+struct Replay {
+    // number of bytes that can be saved after a compaction
+    uncompacted: u64,
+    scan: Scan,
+}
+
+/// index the records of the log file `file_id` into `key_dir`, up to the
+/// first one which can't be read, or until `cancel` is cancelled
+fn replay_log<R: Read + Seek>(
+    file_id: u64,
+    reader: &mut BufReaderWithPos<R>,
+    key_dir: &KeyDir<CmdPos>,
+    cancel: Option<&CancelToken>,
+) -> Result<Replay> {
+    let mut uncompacted = 0;
+    let scan = scan_log(file_id, reader, |cmd, range| {
+        if cancel.is_some_and(CancelToken::is_cancelled) {
+            return Err(KvsError::Cancelled);
+        }
+        match cmd {
             Cmd::Remove { key } => {
-                if let Some(old_cmd) = key_dir.remove(&key) {
+                if let Some(old_cmd) = key_dir.remove(&key)? {
                     // old command can be compacted
-                    uncompacted += old_cmd.1.len;
+                    uncompacted += old_cmd.len;
                 }
                 // this remove command alse can be compacted
-                uncompacted += new_pos - posi;
+                uncompacted += range.end - range.start;
             }
-            Cmd::Set { key, .. } => {
-                if let Some(old_cmd) = key_dir.insert(key, (file_id, posi..new_pos).into()) {
+            Cmd::Set { key, version, .. } | Cmd::SetRef { key, version, .. } => {
+                let cmd_pos = CmdPos {
+                    version,
+                    ..(file_id, range).into()
+                };
+                if let Some(old_cmd) = key_dir.insert(key, cmd_pos)? {
                     // old command will be overwritten, so can be compacted
                     uncompacted += old_cmd.len;
                 }
             }
         }
+        Ok(())
+    })?;
+    if let Some(offset) = scan.torn {
+        uncompacted += scan.scanned - offset;
+    }
+    Ok(Replay { uncompacted, scan })
+}
+
+/// What scanning a log found.
+struct Scan {
+    records: u64,
+    scanned: u64,
+    // the offset of the last record if it was torn
+    torn: Option<u64>,
+    // the offset of the first record that can't be read, and why
+    corruption: Option<(u64, KvsError)>,
+}
+
+/// pass the records of the log file `file_id` to `f` in order, with their
+/// position in the file, up to the first one which can't be read
+fn scan_log<R: Read + Seek>(
+    file_id: u64,
+    reader: &mut BufReaderWithPos<R>,
+    mut f: impl FnMut(Cmd, Range<u64>) -> Result<()>,
+) -> Result<Scan> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let mut posi = reader.seek(SeekFrom::Start(0))?;
+    let mut stream = Deserializer::from_reader(reader).into_iter::<Record>();
+    let mut scan = Scan {
+        records: 0,
+        scanned: 0,
+        torn: None,
+        corruption: None,
+    };
+    while let Some(cmd) = stream.next() {
+        let new_pos = stream.byte_offset() as u64;
+        let cmd = match cmd {
+            Err(e) if e.is_io() => return Err(e.into()),
+            cmd => cmd.map_err(KvsError::from).and_then(Record::into_cmd),
+        };
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            // a crash while writing the last record leaves it torn, the writes
+            // it holds were never acknowledged
+            Err(KvsError::SerdeErr(e)) if e.is_eof() => {
+                warn!(
+                    msg = "torn record at the end of the log, ignoring it",
+                    file_id,
+                    offset = posi,
+                    torn = file_len - posi,
+                );
+                scan.torn = Some(posi);
+                posi = file_len;
+                break;
+            }
+            Err(e) => {
+                scan.corruption = Some((posi, e));
+                break;
+            }
+        };
+        scan.records += 1;
+        f(cmd, posi..new_pos)?;
         posi = new_pos;
     }
-    Ok(uncompacted)
+    scan.scanned = posi;
+    Ok(scan)
 }
@@ -1,16 +1,147 @@
+mod boxed_engine;
+mod cancel;
+mod contention;
+mod flush_policy;
+mod hot_keys;
+mod key_dir;
 mod kvs_engine;
 mod sled_engine;
+mod storage;
 
 // mod sled_engine;
-pub use kvs_engine::KvsEngine;
+pub use boxed_engine::BoxedEngine;
+pub use cancel::CancelToken;
+pub use flush_policy::FlushPolicy;
+pub use kvs_engine::{
+    Corruption, DanglingPolicy, Iter, KvsEngine, KvsOptions, LogRecord, RecordContent,
+    RecoveryReport, Replica, Snapshot, WriteBatch,
+};
 pub use sled_engine::SledKvsEngine;
+pub use storage::{FsStorage, LogLayout, MemFile, MemStorage, Storage, FILES_PER_DIR};
 
-use crate::Result;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// Health figures of an engine, see `Engine::stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct EngineStats {
+    /// number of live keys
+    pub keys: u64,
+    /// bytes used by the store on disk
+    pub disk_usage: u64,
+    /// bytes a compaction would reclaim
+    pub uncompacted: u64,
+    /// compactions run since the engine was opened
+    pub compactions: u64,
+}
 
 pub trait Engine: Clone + Send + 'static {
+    /// open the engine stored in the directory `path`, creating it if needed
+    fn open(path: impl Into<PathBuf>) -> Result<Self>;
+
+    /// once it returns, every read started afterwards, on any thread and
+    /// through any clone, sees the value or a later one
     fn set(&self, key: String, value: String) -> Result<()>;
 
-    fn get(&self, key: String) -> Result<Option<String>>;
+    /// the key is only borrowed, so lookups with a `&str` don't allocate
+    fn get(&self, key: impl AsRef<str>) -> Result<Option<String>>;
+
+    /// whether the key exists, without reading its value
+    fn contains_key(&self, key: impl AsRef<str>) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// the length in bytes of the value of the key, `None` if it doesn't
+    /// exist. The value is read but not returned, so only the length goes
+    /// over the network when asked through `Request::ValueLen`.
+    fn value_len(&self, key: impl AsRef<str>) -> Result<Option<usize>> {
+        Ok(self.get(key)?.map(|value| value.len()))
+    }
+
+    /// remove the key, failing with `KvsError::KeyNotFound` if it doesn't
+    /// exist. Every engine must keep to this, see `remove_lenient` for a
+    /// removal which doesn't care.
+    fn remove(&self, key: impl AsRef<str>) -> Result<()>;
+
+    /// remove the key if it exists, an absent key being no error
+    fn remove_lenient(&self, key: impl AsRef<str>) -> Result<()> {
+        self.discard(key)?;
+        Ok(())
+    }
+
+    /// remove the key if it exists, returning whether it did. Unlike
+    /// `remove`, an absent key is not an error.
+    fn discard(&self, key: impl AsRef<str>) -> Result<bool> {
+        match self.remove(key) {
+            Ok(()) => Ok(true),
+            Err(KvsError::KeyNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// remove the key only if its value is `expected`, returning whether it
+    /// did. The check and the removal are atomic, so a concurrent update of
+    /// the key makes it return `false` instead of removing the new value.
+    fn remove_if(&self, key: impl AsRef<str>, expected: impl AsRef<str>) -> Result<bool>;
+
+    /// append `suffix` to the value of `key`, or set it if the key doesn't
+    /// exist, and return the new length of the value in bytes. Concurrent
+    /// appends never lose each other's updates.
+    fn append(&self, key: String, suffix: String) -> Result<usize>;
+
+    /// set every pair at once: a reader, even a scan, sees either all of them
+    /// or none, and a pair which fails, e.g. of a key rejected, fails them
+    /// all before anything is written. A key repeated takes its last value.
+    fn set_all(&self, pairs: Vec<(String, String)>) -> Result<()>;
+
+    /// move the value of `from` to `to`, overwriting `to` if it exists like
+    /// a POSIX rename, and failing with `KvsError::KeyNotFound` if `from`
+    /// doesn't exist. Atomic: `from` is only gone once `to` holds the value.
+    /// Renaming a key to itself changes nothing.
+    fn rename(&self, from: String, to: String) -> Result<()>;
+
+    /// remove every key starting with `prefix`, returning how many were.
+    /// The keys are removed at once: a reader, even a scan, sees either all
+    /// of them or none. Whether a key with the prefix set while they are
+    /// listed is removed too depends on the engine.
+    fn remove_prefix(&self, prefix: String) -> Result<usize>;
+
+    /// the number of keys starting with `prefix`, of all the keys for an
+    /// empty prefix, counted without reading their values
+    fn count_prefix(&self, prefix: String) -> Result<usize>;
+
+    /// make all the writes done so far durable
+    fn flush(&self) -> Result<()>;
+
+    fn stats(&self) -> Result<EngineStats>;
+
+    /// up to `count` key-value pairs whose key starts with `prefix`, in key
+    /// order, starting after `start_after` if given. Fewer than `count` pairs
+    /// are returned only when the scan is exhausted.
+    fn scan(
+        &self,
+        prefix: String,
+        start_after: Option<String>,
+        count: usize,
+    ) -> Result<Vec<(String, String)>>;
+}
 
-    fn remove(&self, key: String) -> Result<()>;
+/// set every key-value pair of `pairs` in `engine`, overwriting the keys it
+/// already has, and flush it. Migrates the in-memory `KvStore` of project 1,
+/// a crate of its own, to a persistent engine: `persist_into(&store, &engine)`.
+pub fn persist_into<K, V>(
+    pairs: impl IntoIterator<Item = (K, V)>,
+    engine: &impl Engine,
+) -> Result<()>
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    for (key, value) in pairs {
+        engine.set(key.into(), value.into())?;
+    }
+    engine.flush()
 }
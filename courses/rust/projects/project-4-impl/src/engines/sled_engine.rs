@@ -1,44 +1,273 @@
-use sled::Db;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use sled::transaction::{abort, ConflictableTransactionError, TransactionError};
+use sled::{Batch, Db};
+use tracing::error;
 
-use crate::Engine;
 use crate::KvsError;
 use crate::Result;
+use crate::{Engine, EngineStats};
+
+use super::flush_policy::FlushPolicy;
 
 #[derive(Debug, Clone)]
 pub struct SledKvsEngine {
     db: Db,
+    // whether every write flushes, see `FlushPolicy::PerOperation`
+    flush_writes: bool,
+    // flushes the writes once the last clone is dropped
+    _flush_on_drop: Arc<FlushOnDrop>,
+}
+
+/// Flushes a sled database when dropped, so that the writes of an engine
+/// don't wait for a periodic flush which may never come.
+#[derive(Debug)]
+struct FlushOnDrop(Db);
+
+impl Drop for FlushOnDrop {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.flush() {
+            error!(
+                msg = "fail to flush the sled engine on drop, the last writes may be lost",
+                err = %e
+            );
+        }
+    }
 }
 
 impl Engine for SledKvsEngine {
+    fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        SledKvsEngine::open(path)
+    }
+
     fn set(&self, key: String, value: String) -> Result<()> {
         self.db.insert(key, value.into_bytes()).map(|_| ())?;
-        // self.db.flush()?;
-        Ok(())
+        self.flush_write()
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
+    fn get(&self, key: impl AsRef<str>) -> Result<Option<String>> {
         Ok(self
             .db
-            .get(key.as_bytes())?
+            .get(key.as_ref().as_bytes())?
             .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
             .map(String::from_utf8)
             .transpose()?)
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+    fn remove(&self, key: impl AsRef<str>) -> Result<()> {
+        self.db
+            .remove(key.as_ref().as_bytes())?
+            .ok_or(KvsError::KeyNotFound)?;
+        self.flush_write()
+    }
+
+    fn discard(&self, key: impl AsRef<str>) -> Result<bool> {
+        let removed = self.db.remove(key.as_ref().as_bytes())?.is_some();
+        if removed {
+            self.flush_write()?;
+        }
+        Ok(removed)
+    }
+
+    fn contains_key(&self, key: impl AsRef<str>) -> Result<bool> {
+        Ok(self.db.contains_key(key.as_ref().as_bytes())?)
+    }
+
+    /// the length of the stored bytes, without decoding them as a `String`
+    fn value_len(&self, key: impl AsRef<str>) -> Result<Option<usize>> {
+        Ok(self
+            .db
+            .get(key.as_ref().as_bytes())?
+            .map(|i_vec| i_vec.len()))
+    }
+
+    fn remove_if(&self, key: impl AsRef<str>, expected: impl AsRef<str>) -> Result<bool> {
+        let removed = self
+            .db
+            .compare_and_swap(
+                key.as_ref().as_bytes(),
+                Some(expected.as_ref().as_bytes()),
+                None::<&[u8]>,
+            )?
+            .is_ok();
+        if removed {
+            self.flush_write()?;
+        }
+        Ok(removed)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        // `update_and_fetch` retries the closure until its compare-and-swap wins
+        let value = self.db.update_and_fetch(key, |old| {
+            let mut value = old.map(<[u8]>::to_vec).unwrap_or_default();
+            value.extend_from_slice(suffix.as_bytes());
+            Some(value)
+        })?;
+        self.flush_write()?;
+        Ok(value.map_or(0, |value| value.len()))
+    }
+
+    /// remove the keys listed by a sled scan in a single transaction, which
+    /// only counts those still there. A sled transaction can't scan, so a
+    /// key with the prefix set while they are listed may be kept.
+    fn remove_prefix(&self, prefix: String) -> Result<usize> {
+        let keys = self
+            .db
+            .scan_prefix(prefix.as_bytes())
+            .keys()
+            .collect::<sled::Result<Vec<_>>>()?;
+        let removed = self.db.transaction(|tx| {
+            let mut count = 0;
+            for key in &keys {
+                if tx.remove(key)?.is_some() {
+                    count += 1;
+                }
+            }
+            Ok::<_, ConflictableTransactionError<KvsError>>(count)
+        });
+        match removed {
+            Ok(count) => {
+                self.flush_write()?;
+                Ok(count)
+            }
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    /// a sled scan of the keys, which reads their values along
+    fn count_prefix(&self, prefix: String) -> Result<usize> {
+        let mut count = 0;
+        for key in self.db.scan_prefix(prefix.as_bytes()).keys() {
+            key?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// insert the pairs in a single sled batch, applied atomically
+    fn set_all(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.as_bytes());
+        }
+        self.db.apply_batch(batch)?;
+        self.flush_write()
+    }
+
+    /// rename a key in a sled transaction, which makes both writes visible
+    /// at once
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        let renamed = self.db.transaction(|tx| {
+            let value = match tx.get(from.as_bytes())? {
+                Some(value) => value,
+                None => return abort(KvsError::KeyNotFound),
+            };
+            if from != to {
+                tx.insert(to.as_bytes(), value)?;
+                tx.remove(from.as_bytes())?;
+            }
+            Ok(())
+        });
+        match renamed {
+            Ok(()) => self.flush_write(),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
         self.db.flush()?;
         Ok(())
     }
+
+    /// sled compacts on its own, so only the keys and disk usage are known
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.db.len() as u64,
+            disk_usage: self.db.size_on_disk()?,
+            ..EngineStats::default()
+        })
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        start_after: Option<String>,
+        count: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let start = match &start_after {
+            Some(after) if *after >= prefix => Bound::Excluded(after.as_bytes()),
+            _ => Bound::Included(prefix.as_bytes()),
+        };
+        self.db
+            .range::<&[u8], _>((start, Bound::Unbounded))
+            .take_while(|entry| match entry {
+                Ok((key, _)) => key.starts_with(prefix.as_bytes()),
+                Err(_) => true,
+            })
+            .take(count)
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((
+                    String::from_utf8(key.to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                ))
+            })
+            .collect()
+    }
 }
 
 impl SledKvsEngine {
-    pub fn open(path: impl Into<std::path::PathBuf>) -> Result<Self> {
-        let db = sled::open(path.into())?;
-        Ok(Self { db })
+    /// open the store in the directory `path` with the default flush
+    /// policy, see `FlushPolicy`
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_flush_policy(path, FlushPolicy::default())
+    }
+
+    /// open the store in the directory `path`, flushing its writes as
+    /// `policy` says
+    pub fn open_with_flush_policy(path: impl Into<PathBuf>, policy: FlushPolicy) -> Result<Self> {
+        let flush_every_ms = match policy {
+            // sled counts in milliseconds, and would flush non-stop at 0
+            FlushPolicy::Periodic(interval) => Some((interval.as_millis() as u64).max(1)),
+            FlushPolicy::PerOperation | FlushPolicy::Manual => None,
+        };
+        let db = sled::Config::new()
+            .path(path.into())
+            .flush_every_ms(flush_every_ms)
+            .open()?;
+        Ok(Self {
+            _flush_on_drop: Arc::new(FlushOnDrop(db.clone())),
+            db,
+            flush_writes: policy == FlushPolicy::PerOperation,
+        })
     }
 
+    /// flush the store and drop this clone of it, returning the error of
+    /// the flush, which dropping the last clone only logs
+    pub fn close(self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// a store on `db`, whose writes are flushed as it was configured to,
+    /// not by each write
     pub fn new(db: Db) -> Self {
-        Self { db }
+        Self {
+            _flush_on_drop: Arc::new(FlushOnDrop(db.clone())),
+            db,
+            flush_writes: false,
+        }
+    }
+
+    /// flush the write just done if the policy says so
+    fn flush_write(&self) -> Result<()> {
+        if self.flush_writes {
+            self.db.flush()?;
+        }
+        Ok(())
     }
 }
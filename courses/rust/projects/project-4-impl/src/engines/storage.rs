@@ -0,0 +1,480 @@
+//! # storage
+//! where the bitcask engine keeps its files.
+//!
+//! A store is a set of log files numbered by id, each of which may have a
+//! hint file. `Storage` is how the engine creates, lists, reads and removes
+//! them, so that the same engine runs on the filesystem (`FsStorage`) or
+//! entirely in memory (`MemStorage`).
+//!
+//! A store separating its values from its keys keeps them in a second set
+//! of log files, its value log, in a storage of its own: see
+//! `Storage::value_log`.
+//!
+//! # Durability
+//!
+//! Syncing a file makes its content durable, not its name: after a power
+//! loss, a log file created or removed just before may be back to missing,
+//! or present, whatever was synced into it. `FsStorage` also syncs the
+//! directory after creating, removing or renaming a file in it, unless told
+//! not to with `FsStorage::sync_dirs`, so that the files listed at the next
+//! open are the ones the engine last saw.
+//!
+//! Only Unix lets a directory be synced, by opening it: elsewhere, e.g. on
+//! Windows, the directory entries are as durable as the filesystem makes
+//! them. Some filesystems journal them anyway, making the sync cheap.
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fmt::Debug;
+use std::fs::{self, create_dir_all, read_dir, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+use crate::{KvsError, Result};
+
+/// number of consecutive file ids sharing a subdirectory in the sharded layout
+pub const FILES_PER_DIR: u64 = 100;
+
+/// How the log files of a `FsStorage` are laid out in its directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogLayout {
+    /// every file directly in the store directory
+    #[default]
+    Flat,
+    /// files grouped by id in subdirectories of `FILES_PER_DIR` ids each:
+    /// ids 0 to 99 in `000/`, 100 to 199 in `001/` and so on, so that no
+    /// directory holds thousands of files
+    Sharded,
+}
+
+/// The files of a bitcask store, see the module documentation.
+///
+/// A log file is only ever appended to while it is the active one, and read
+/// concurrently meanwhile: a reader must see the bytes written and flushed
+/// through the writer of its file.
+pub trait Storage: Debug + Send + Sync + 'static {
+    type Reader: Read + Seek + Debug + Send + Sync;
+    type Writer: Write + Seek + Debug + Send;
+
+    /// open the storage at `path`, creating it if needed
+    fn open(path: PathBuf) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// the ids of the log files, in ascending order, or an error if two
+    /// files may be taken for the same id
+    fn list(&self) -> Result<Vec<u64>>;
+
+    /// create the new log file `file_id` and open it for writing
+    fn create(&self, file_id: u64) -> Result<Self::Writer>;
+
+    /// open the log file `file_id` for reading
+    fn open_reader(&self, file_id: u64) -> Result<Self::Reader>;
+
+    /// make what was written through `writer` durable
+    fn sync(&self, writer: &Self::Writer) -> Result<()>;
+
+    /// the length of the log file `file_id` in bytes
+    fn len(&self, file_id: u64) -> Result<u64>;
+
+    /// remove the log file `file_id` and its hint file. Readers already open
+    /// on it keep reading it.
+    fn remove(&self, file_id: u64) -> Result<()>;
+
+    /// replace the hint file of `file_id` by `hints`, so that a crash leaves
+    /// either the old or the new hint file, never a partial one
+    fn write_hints(&self, file_id: u64, hints: &[u8]) -> Result<()>;
+
+    /// the content of the hint file of `file_id`, `None` if it has none
+    fn read_hints(&self, file_id: u64) -> Result<Option<Vec<u8>>>;
+
+    /// the storage of the value log of the store, the files of which are
+    /// apart from the log files, see `KvsOptions::separate_values`. Holding
+    /// no file until one is created. Not supported by default.
+    fn value_log(&self) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Err(KvsError::StringErr(
+            "the storage doesn't support separating the values".to_owned(),
+        ))
+    }
+}
+
+/// Log files in a directory of the filesystem, named `<id>.log` with hint
+/// files `<id>.hint`, laid out as a `LogLayout`.
+#[derive(Debug, Clone)]
+pub struct FsStorage {
+    path: PathBuf,
+    layout: LogLayout,
+    sync_dirs: bool,
+}
+
+impl FsStorage {
+    /// open the directory `path`, creating it if needed. A new store is laid
+    /// out as `layout`, an existing one keeps the layout it was created with.
+    pub fn with_layout(path: impl Into<PathBuf>, layout: LogLayout) -> Result<Self> {
+        let path = path.into();
+        create_dir_all(&path)?;
+        Ok(Self {
+            layout: detect_layout(&path)?.unwrap_or(layout),
+            path,
+            sync_dirs: true,
+        })
+    }
+
+    /// whether to sync a directory after creating, removing or renaming a
+    /// file in it, on by default, see the module documentation
+    pub fn sync_dirs(mut self, sync_dirs: bool) -> Self {
+        self.sync_dirs = sync_dirs;
+        self
+    }
+
+    /// the layout of the store in the directory `path`, or `None` if it
+    /// holds none or doesn't exist. Unlike opening it, creates nothing.
+    pub fn detect(path: impl AsRef<Path>) -> Result<Option<LogLayout>> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            return Ok(None);
+        }
+        detect_layout(path)
+    }
+
+    /// the directory of the store
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn layout(&self) -> LogLayout {
+        self.layout
+    }
+
+    /// whether the directory holds a store yet
+    pub(crate) fn is_empty(&self) -> Result<bool> {
+        Ok(detect_layout(&self.path)?.is_none())
+    }
+
+    pub(crate) fn log_file(&self, file_id: u64) -> PathBuf {
+        self.file_dir(file_id).join(format!("{}.log", file_id))
+    }
+
+    fn hint_file(&self, file_id: u64) -> PathBuf {
+        self.file_dir(file_id).join(format!("{}.hint", file_id))
+    }
+
+    /// the directory holding the files of `file_id`
+    fn file_dir(&self, file_id: u64) -> PathBuf {
+        match self.layout {
+            LogLayout::Flat => self.path.clone(),
+            LogLayout::Sharded => self.path.join(format!("{:03}", file_id / FILES_PER_DIR)),
+        }
+    }
+
+    /// make the entries of the directory `dir` durable, if syncing them
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        #[cfg(unix)]
+        if self.sync_dirs {
+            File::open(dir)?.sync_all()?;
+        }
+        #[cfg(not(unix))]
+        let _ = dir;
+        Ok(())
+    }
+}
+
+impl Storage for FsStorage {
+    type Reader = File;
+    type Writer = File;
+
+    fn open(path: PathBuf) -> Result<Self> {
+        Self::with_layout(path, LogLayout::default())
+    }
+
+    fn list(&self) -> Result<Vec<u64>> {
+        // the directory of a value log is only created with its first file
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut found = match self.layout {
+            LogLayout::Flat => log_files_in(&self.path)?,
+            LogLayout::Sharded => {
+                let mut found = Vec::new();
+                for entry in read_dir(&self.path)? {
+                    let path = entry?.path();
+                    if path.is_dir() && is_shard_dir(&path) {
+                        found.extend(log_files_in(&path)?);
+                    }
+                }
+                found
+            }
+        };
+        found.sort_unstable();
+        // a file of an id elsewhere than where the engine reads it, e.g.
+        // `01.log` beside `1.log` or in another shard after a manual copy,
+        // would leave the store ambiguous: which file wins isn't ours to guess
+        for (file_id, path) in &found {
+            let expected = self.log_file(*file_id);
+            if *path != expected {
+                return Err(KvsError::StringErr(format!(
+                    "log file {} is {}, which should be {}: move or remove it",
+                    file_id,
+                    path.display(),
+                    expected.display()
+                )));
+            }
+        }
+        Ok(found.into_iter().map(|(file_id, _)| file_id).collect())
+    }
+
+    fn create(&self, file_id: u64) -> Result<File> {
+        let path = self.log_file(file_id);
+        let dir = path.parent().expect("a log file is in a directory");
+        // a shard or the value log is created along with its first file
+        let new_dir = !dir.exists();
+        create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        self.sync_dir(dir)?;
+        if new_dir {
+            self.sync_dir(dir.parent().expect("a subdirectory has a parent"))?;
+        }
+        Ok(file)
+    }
+
+    fn open_reader(&self, file_id: u64) -> Result<File> {
+        Ok(File::open(self.log_file(file_id))?)
+    }
+
+    fn sync(&self, writer: &File) -> Result<()> {
+        writer.sync_data()?;
+        Ok(())
+    }
+
+    fn len(&self, file_id: u64) -> Result<u64> {
+        Ok(fs::metadata(self.log_file(file_id))?.len())
+    }
+
+    fn remove(&self, file_id: u64) -> Result<()> {
+        fs::remove_file(self.log_file(file_id))?;
+        match fs::remove_file(self.hint_file(file_id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        let dir = self.file_dir(file_id);
+        // fails as long as the subdirectory holds other files
+        if self.layout == LogLayout::Sharded && fs::remove_dir(&dir).is_ok() {
+            return self.sync_dir(&self.path);
+        }
+        self.sync_dir(&dir)
+    }
+
+    fn write_hints(&self, file_id: u64, hints: &[u8]) -> Result<()> {
+        // written aside then renamed, so a crash never leaves a partial hint file
+        let path = self.hint_file(file_id);
+        let tmp_path = path.with_extension("hint.tmp");
+        fs::write(&tmp_path, hints)?;
+        fs::rename(&tmp_path, &path)?;
+        self.sync_dir(&self.file_dir(file_id))
+    }
+
+    fn read_hints(&self, file_id: u64) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.hint_file(file_id)) {
+            Ok(buf) => Ok(Some(buf)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// the `values` subdirectory, laid out flat
+    fn value_log(&self) -> Result<Self> {
+        Ok(Self {
+            path: self.path.join("values"),
+            layout: LogLayout::Flat,
+            sync_dirs: self.sync_dirs,
+        })
+    }
+}
+
+/// the layout of the store in `path`, or `None` if it holds no store yet
+fn detect_layout(path: &Path) -> Result<Option<LogLayout>> {
+    for entry in read_dir(path)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("log".as_ref()) {
+            return Ok(Some(LogLayout::Flat));
+        }
+        if path.is_dir() && is_shard_dir(&path) {
+            return Ok(Some(LogLayout::Sharded));
+        }
+    }
+    Ok(None)
+}
+
+fn is_shard_dir(path: &Path) -> bool {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .is_some_and(|name| name.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// the ids and paths of the log files directly in `path`
+fn log_files_in(path: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let file_list = read_dir(path)?
+        .flat_map(|f| -> Result<_> { Ok(f?.path()) })
+        .filter(|f| f.is_file() && (f.extension() == Some("log".as_ref())))
+        .flat_map(|f| {
+            let file_id = f
+                .file_name()
+                .and_then(OsStr::to_str)
+                .map(|name| name.trim_end_matches(".log"))
+                .and_then(|id| id.parse::<u64>().ok())?;
+            Some((file_id, f))
+        })
+        .collect();
+    Ok(file_list)
+}
+
+/// Log files held in memory, for tests which don't need the disk.
+///
+/// Clones share the same files. `Storage::open` gives the storage opened
+/// at the same path before in this process, if any, so that a store can be
+/// dropped and reopened like one on the disk; `MemStorage::new` always
+/// gives an empty one.
+#[derive(Debug, Clone, Default)]
+pub struct MemStorage {
+    files: Arc<Mutex<BTreeMap<u64, MemFile>>>,
+    hints: Arc<Mutex<BTreeMap<u64, Vec<u8>>>>,
+    values: Arc<OnceLock<MemStorage>>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemStorage {
+    type Reader = MemFile;
+    type Writer = MemFile;
+
+    fn open(path: PathBuf) -> Result<Self> {
+        static OPENED: OnceLock<Mutex<HashMap<PathBuf, MemStorage>>> = OnceLock::new();
+        let mut opened = OPENED.get_or_init(Default::default).lock().unwrap();
+        Ok(opened.entry(path).or_default().clone())
+    }
+
+    fn list(&self) -> Result<Vec<u64>> {
+        Ok(self.files.lock().unwrap().keys().copied().collect())
+    }
+
+    fn create(&self, file_id: u64) -> Result<MemFile> {
+        let file = MemFile::default();
+        self.files.lock().unwrap().insert(file_id, file.reopen());
+        Ok(file)
+    }
+
+    fn open_reader(&self, file_id: u64) -> Result<MemFile> {
+        match self.files.lock().unwrap().get(&file_id) {
+            Some(file) => Ok(file.reopen()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no log file {}", file_id),
+            )
+            .into()),
+        }
+    }
+
+    fn sync(&self, _writer: &MemFile) -> Result<()> {
+        Ok(())
+    }
+
+    fn len(&self, file_id: u64) -> Result<u64> {
+        Ok(self.open_reader(file_id)?.data.read().unwrap().len() as u64)
+    }
+
+    fn remove(&self, file_id: u64) -> Result<()> {
+        self.files.lock().unwrap().remove(&file_id);
+        self.hints.lock().unwrap().remove(&file_id);
+        Ok(())
+    }
+
+    fn write_hints(&self, file_id: u64, hints: &[u8]) -> Result<()> {
+        self.hints.lock().unwrap().insert(file_id, hints.to_vec());
+        Ok(())
+    }
+
+    fn read_hints(&self, file_id: u64) -> Result<Option<Vec<u8>>> {
+        Ok(self.hints.lock().unwrap().get(&file_id).cloned())
+    }
+
+    fn value_log(&self) -> Result<Self> {
+        Ok(self.values.get_or_init(MemStorage::new).clone())
+    }
+}
+
+/// A handle on a log file of a `MemStorage`, with its own position.
+#[derive(Debug, Default)]
+pub struct MemFile {
+    data: Arc<RwLock<Vec<u8>>>,
+    pos: u64,
+}
+
+impl MemFile {
+    /// a new handle on the same file, at its start
+    fn reopen(&self) -> Self {
+        Self {
+            data: self.data.clone(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.read().unwrap();
+        let start = (self.pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = self.data.write().unwrap();
+        let start = self.pos as usize;
+        if data.len() < start + buf.len() {
+            data.resize(start + buf.len(), 0);
+        }
+        data[start..start + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => (0, pos as i64),
+            SeekFrom::End(offset) => (self.data.read().unwrap().len() as i64, offset),
+            SeekFrom::Current(offset) => (self.pos as i64, offset),
+        };
+        match base.checked_add(offset) {
+            Some(pos) if pos >= 0 => {
+                self.pos = pos as u64;
+                Ok(self.pos)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            )),
+        }
+    }
+}
@@ -1,55 +1,58 @@
 use std::{net, string::FromUtf8Error};
 
-use failure::Fail;
-use sled::Guard;
+use thiserror::Error;
 
-#[derive(Fail, Debug)]
+/// The error of every fallible operation of the crate. The errors it wraps
+/// are its `source`, so it can be boxed as a `Box<dyn std::error::Error>`
+/// and walked like any other error chain.
+#[derive(Error, Debug)]
 pub enum KvsError {
-    #[fail(display = "key is not found in KvStore")]
+    #[error("key is not found in KvStore")]
     KeyNotFound,
-    #[fail(display = "command is not supported")]
+    #[error("command is not supported")]
     CommandNotSupported,
-    #[fail(display = "{}", _0)]
-    IoErr(#[cause] std::io::Error),
-    #[fail(display = "{}", _0)]
-    SerdeErr(#[cause] serde_json::Error),
-    #[fail(display = "{}", _0)]
-    IpParseErr(#[cause] net::AddrParseError),
-    #[fail(display = "{}", _0)]
+    #[error("{0}")]
+    IoErr(#[from] std::io::Error),
+    #[error("{0}")]
+    SerdeErr(#[from] serde_json::Error),
+    #[error("{0}")]
+    IpParseErr(#[from] net::AddrParseError),
+    #[error("{0}")]
     StringErr(String),
-    #[fail(display = "{}", _0)]
-    SledErr(#[cause] sled::Error),
-    #[fail(display = "{}", _0)]
-    FromUtf8Error(#[cause] FromUtf8Error),
+    #[error("{0}")]
+    SledErr(#[from] sled::Error),
+    #[error("{0}")]
+    FromUtf8Error(#[from] FromUtf8Error),
+    #[error("{msg}")]
+    Server { msg: String, retryable: bool },
+    /// a message received which is not valid in the protocol
+    #[error("malformed message: {0}")]
+    Protocol(String),
+    /// a long-running operation stopped by its `CancelToken`
+    #[error("the operation was cancelled")]
+    Cancelled,
+    /// no response from the server in time, the request may or may not
+    /// have been applied
+    #[error("timed out waiting for the server")]
+    Timeout,
+    /// a key rejected by the validator of the engine, see
+    /// `KvsEngine::validate_keys`
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
 }
 
-impl From<std::io::Error> for KvsError {
-    fn from(e: std::io::Error) -> Self {
-        Self::IoErr(e)
-    }
-}
-
-impl From<serde_json::Error> for KvsError {
-    fn from(e: serde_json::Error) -> Self {
-        Self::SerdeErr(e)
-    }
-}
-
-impl From<net::AddrParseError> for KvsError {
-    fn from(e: net::AddrParseError) -> Self {
-        Self::IpParseErr(e)
-    }
-}
-
-impl From<sled::Error> for KvsError {
-    fn from(e: sled::Error) -> Self {
-        Self::SledErr(e)
-    }
-}
-
-impl From<FromUtf8Error> for KvsError {
-    fn from(e: FromUtf8Error) -> Self {
-        Self::FromUtf8Error(e)
+impl KvsError {
+    /// whether the same request may succeed if it is sent again, e.g. a broken
+    /// connection or a timeout, as opposed to a missing key or a bad request
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            KvsError::IoErr(_) => true,
+            KvsError::SerdeErr(e) => e.is_io() || e.is_eof(),
+            KvsError::SledErr(sled::Error::Io(_)) => true,
+            KvsError::Server { retryable, .. } => *retryable,
+            KvsError::Timeout => true,
+            _ => false,
+        }
     }
 }
 
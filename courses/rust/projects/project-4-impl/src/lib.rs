@@ -2,17 +2,25 @@ mod client;
 mod cmd;
 mod engines;
 mod errors;
+mod metrics;
 mod requests;
 mod server;
+mod sharded_client;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 mod utils;
 pub mod thread_pool;
 
-pub use client::Client;
-pub use cmd::Cmd;
-pub use engines::Engine;
-pub use engines::KvsEngine;
-pub use engines::SledKvsEngine;
+pub use client::{BatchOp, Client, KvClient, LoopbackClient, Scan};
+pub use cmd::{Cmd, FORMAT_VERSION};
+pub use engines::{persist_into, BoxedEngine, CancelToken, Engine, EngineStats};
+pub use engines::{Corruption, LogRecord, RecordContent, RecoveryReport};
+pub use engines::{DanglingPolicy, KvsEngine, KvsOptions, LogLayout, WriteBatch, FILES_PER_DIR};
+pub use engines::{FlushPolicy, SledKvsEngine};
+pub use engines::{FsStorage, MemFile, MemStorage, Storage};
+pub use engines::{Iter, Replica, Snapshot};
 pub use errors::{KvsError, Result};
 pub use requests::*;
-pub use server::Server;
-pub use utils::addr_check;
+pub use server::{Middleware, Server, ShutdownHandle, DEFAULT_BACKLOG, DEFAULT_DB};
+pub use sharded_client::ShardedClient;
+pub use utils::{addr_check, DirLock};
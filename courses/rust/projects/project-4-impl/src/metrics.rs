@@ -0,0 +1,220 @@
+//! # metrics
+//! counters of the requests a `Server` handled and of their latency,
+//! exported with the health figures of its engines in the Prometheus text
+//! exposition format, see `Server::metrics_addr`.
+use std::{
+    fmt::{self, Write as _},
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tracing::warn;
+
+use crate::{Engine, EngineStats, Request, Result};
+
+/// content type of the text exposition format
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 9] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+/// name, type and help of a metric exported from `EngineStats`, and its value
+type StatFamily = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&EngineStats) -> u64,
+);
+
+const STAT_FAMILIES: [StatFamily; 4] = [
+    (
+        "kvs_compactions_total",
+        "counter",
+        "Compactions run since the engine was opened",
+        |s| s.compactions,
+    ),
+    ("kvs_keys", "gauge", "Live keys", |s| s.keys),
+    (
+        "kvs_disk_usage_bytes",
+        "gauge",
+        "Bytes used on the disk",
+        |s| s.disk_usage,
+    ),
+    (
+        "kvs_uncompacted_bytes",
+        "gauge",
+        "Bytes a compaction would reclaim",
+        |s| s.uncompacted,
+    ),
+];
+
+/// The counters of a server, shared with the thread serving them.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    // by request kind, in the order of `Request::KINDS`
+    requests: [AtomicU64; Request::KINDS.len()],
+    errors: AtomicU64,
+    // requests per latency bucket, the last one past the highest bound
+    latencies: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum_nanos: AtomicU64,
+}
+
+impl Metrics {
+    /// count a request of `kind` handled in `latency`
+    pub(crate) fn record(&self, kind: &str, latency: Duration, failed: bool) {
+        if let Some(i) = Request::KINDS.iter().position(|k| *k == kind) {
+            self.requests[i].fetch_add(1, Ordering::Relaxed);
+        }
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latencies[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_nanos
+            .fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// count a request which couldn't be read, it has no kind nor latency
+    pub(crate) fn record_malformed(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// the metrics in the text exposition format, with the stats of every
+    /// database in `databases`
+    pub(crate) fn render<'a, E: Engine>(
+        &self,
+        databases: impl IntoIterator<Item = (&'a String, &'a E)>,
+    ) -> String {
+        let mut stats = Vec::new();
+        for (db, engine) in databases {
+            match engine.stats() {
+                Ok(s) => stats.push((escape_label(db), s)),
+                Err(e) => warn!(msg = "fail to get the stats of a database", db = %db, err = %e),
+            }
+        }
+        stats.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut out = String::new();
+        self.write_to(&mut out, &stats)
+            .expect("writing to a String can't fail");
+        out
+    }
+
+    fn write_to(&self, out: &mut String, stats: &[(String, EngineStats)]) -> fmt::Result {
+        writeln!(out, "# HELP kvs_requests_total Requests handled, by type.")?;
+        writeln!(out, "# TYPE kvs_requests_total counter")?;
+        for (kind, count) in Request::KINDS.iter().zip(&self.requests) {
+            writeln!(
+                out,
+                "kvs_requests_total{{type=\"{}\"}} {}",
+                kind,
+                count.load(Ordering::Relaxed)
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP kvs_request_errors_total Requests answered with an error, malformed ones included."
+        )?;
+        writeln!(out, "# TYPE kvs_request_errors_total counter")?;
+        writeln!(
+            out,
+            "kvs_request_errors_total {}",
+            self.errors.load(Ordering::Relaxed)
+        )?;
+
+        writeln!(
+            out,
+            "# HELP kvs_request_duration_seconds Time from reading a request to sending its response."
+        )?;
+        writeln!(out, "# TYPE kvs_request_duration_seconds histogram")?;
+        let mut cumulative = 0;
+        for (i, count) in self.latencies.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            match LATENCY_BUCKETS.get(i) {
+                Some(bound) => writeln!(
+                    out,
+                    "kvs_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                    bound, cumulative
+                )?,
+                None => writeln!(
+                    out,
+                    "kvs_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+                    cumulative
+                )?,
+            }
+        }
+        writeln!(
+            out,
+            "kvs_request_duration_seconds_sum {}",
+            self.latency_sum_nanos.load(Ordering::Relaxed) as f64 / 1e9
+        )?;
+        writeln!(out, "kvs_request_duration_seconds_count {}", cumulative)?;
+
+        for (name, kind, help, value) in STAT_FAMILIES {
+            writeln!(out, "# HELP {} {}, by database.", name, help)?;
+            writeln!(out, "# TYPE {} {}", name, kind)?;
+            for (db, s) in stats {
+                writeln!(out, "{}{{db=\"{}\"}} {}", name, db, value(s))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// serve `render()` to every HTTP request on `listener` until `stop` says so
+pub(crate) fn serve(listener: TcpListener, render: impl Fn() -> String, stop: impl Fn() -> bool) {
+    for stream in listener.incoming() {
+        if stop() {
+            break;
+        }
+        let res = stream
+            .map_err(Into::into)
+            .and_then(|stream| respond(stream, &render));
+        if let Err(e) = res {
+            warn!(msg = "fail to serve the metrics", err = %e);
+        }
+    }
+}
+
+/// answer one HTTP request, with the metrics for `GET /metrics`
+fn respond(stream: TcpStream, render: impl Fn() -> String) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are of no use, but must be read before answering
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", CONTENT_TYPE, render()),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_owned()),
+    };
+    let mut writer = &stream;
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// escape a label value of the text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -1,26 +1,391 @@
 use serde::{Deserialize, Serialize};
 
+use crate::EngineStats;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+    },
+    /// the values of `keys` in order, a key repeated being read once
+    GetMany {
+        keys: Vec<String>,
+    },
+    /// the length of the value of the key, see `Engine::value_len`
+    ValueLen {
+        key: String,
+    },
+    /// whether the key exists, see `Engine::contains_key`
+    Contains {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    /// set every pair at once or none of them, see `Engine::set_all`
+    SetAll {
+        pairs: Vec<(String, String)>,
+    },
+    Remove {
+        key: String,
+    },
+    /// remove the key if it exists
+    Discard {
+        key: String,
+    },
+    /// remove the key if its value is `expected`
+    RemoveIf {
+        key: String,
+        expected: String,
+    },
+    Append {
+        key: String,
+        suffix: String,
+    },
+    /// move the value of `from` to `to`, see `Engine::rename`
+    Rename {
+        from: String,
+        to: String,
+    },
+    /// remove every key starting with `prefix`, see `Engine::remove_prefix`
+    RemovePrefix {
+        prefix: String,
+    },
+    /// count the keys starting with `prefix`, see `Engine::count_prefix`
+    CountPrefix {
+        prefix: String,
+    },
+    /// open a cursor over the keys starting with `prefix` and get its first page
+    ScanStart {
+        prefix: String,
+        count: usize,
+    },
+    /// get the next page of an open cursor
+    ScanNext {
+        cursor: u64,
+        count: usize,
+    },
+    /// close an open cursor before it is exhausted
+    ScanClose {
+        cursor: u64,
+    },
+    /// every pair whose key starts with `prefix` and whose value matches
+    /// `filter`, evaluated by the server so that only the matches are sent
+    ScanFilter {
+        prefix: String,
+        filter: ValueFilter,
+    },
+    Stats,
+    /// make the writes done so far durable, answered once they are
+    Flush,
+    /// route the next requests of the connection to the database `db`
+    Select {
+        db: String,
+    },
+    /// the request, answered with the microseconds the server spent on it
+    /// in a `server_micros` field of the response when it reports timing,
+    /// see `Server::report_timing`. The value of a get then comes whole.
+    Timed(Box<Request>),
+}
+
+impl Request {
+    /// the names of the request types, as counted in the server metrics
+    pub(crate) const KINDS: [&'static str; 20] = [
+        "get",
+        "get_many",
+        "value_len",
+        "contains",
+        "set",
+        "set_all",
+        "remove",
+        "discard",
+        "remove_if",
+        "append",
+        "rename",
+        "remove_prefix",
+        "count_prefix",
+        "scan_start",
+        "scan_next",
+        "scan_close",
+        "scan_filter",
+        "stats",
+        "flush",
+        "select",
+    ];
+
+    /// the name of the type of the request, one of `KINDS`
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::GetMany { .. } => "get_many",
+            Request::ValueLen { .. } => "value_len",
+            Request::Contains { .. } => "contains",
+            Request::Set { .. } => "set",
+            Request::SetAll { .. } => "set_all",
+            Request::Remove { .. } => "remove",
+            Request::Discard { .. } => "discard",
+            Request::RemoveIf { .. } => "remove_if",
+            Request::Append { .. } => "append",
+            Request::Rename { .. } => "rename",
+            Request::RemovePrefix { .. } => "remove_prefix",
+            Request::CountPrefix { .. } => "count_prefix",
+            Request::ScanStart { .. } => "scan_start",
+            Request::ScanNext { .. } => "scan_next",
+            Request::ScanClose { .. } => "scan_close",
+            Request::ScanFilter { .. } => "scan_filter",
+            Request::Stats => "stats",
+            Request::Flush => "flush",
+            Request::Select { .. } => "select",
+            Request::Timed(req) => req.kind(),
+        }
+    }
+}
+
+/// A predicate on the values of a `Request::ScanFilter`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ValueFilter {
+    /// values containing the string
+    ValueContains(String),
+    /// values starting with the string
+    ValuePrefix(String),
+}
+
+impl ValueFilter {
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            ValueFilter::ValueContains(pattern) => value.contains(pattern.as_str()),
+            ValueFilter::ValuePrefix(prefix) => value.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// A response sent by the server, of the type matching its request, as
+/// passed along the `Middleware` of a `Server`. It is sent as the response it
+/// wraps, which is either `Ok` or `Err`.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Response {
+    Error(ErrorResp),
+    Get(GetResp),
+    GetMany(GetManyResp),
+    ValueLen(ValueLenResp),
+    Contains(ContainsResp),
+    Set(SetResp),
+    SetAll(SetAllResp),
+    Remove(RemoveResp),
+    Discard(DiscardResp),
+    RemoveIf(RemoveIfResp),
+    Append(AppendResp),
+    Rename(RenameResp),
+    RemovePrefix(RemovePrefixResp),
+    CountPrefix(CountPrefixResp),
+    Scan(ScanResp),
+    ScanClose(ScanCloseResp),
+    ScanFilter(ScanFilterResp),
+    Stats(StatsResp),
+    Flush(FlushResp),
+    Select(SelectResp),
+}
+
+impl Response {
+    /// an `Err` response, which reads as the `Err` of any response type, e.g.
+    /// for a middleware to refuse a request
+    pub fn error(msg: impl Into<String>, retryable: bool) -> Self {
+        Self::Error(ErrorResp::Err {
+            msg: msg.into(),
+            retryable,
+        })
+    }
+}
+
+macro_rules! impl_response {
+    ($($variant:ident($resp:ident)),*) => {
+        impl Response {
+            pub fn is_err(&self) -> bool {
+                match self {
+                    $(Self::$variant(resp) => matches!(resp, $resp::Err { .. }),)*
+                }
+            }
+        }
+
+        $(impl From<$resp> for Response {
+            fn from(resp: $resp) -> Self {
+                Self::$variant(resp)
+            }
+        })*
+    };
+}
+
+impl_response!(
+    Error(ErrorResp),
+    Get(GetResp),
+    GetMany(GetManyResp),
+    ValueLen(ValueLenResp),
+    Contains(ContainsResp),
+    Set(SetResp),
+    SetAll(SetAllResp),
+    Remove(RemoveResp),
+    Discard(DiscardResp),
+    RemoveIf(RemoveIfResp),
+    Append(AppendResp),
+    Rename(RenameResp),
+    RemovePrefix(RemovePrefixResp),
+    CountPrefix(CountPrefixResp),
+    Scan(ScanResp),
+    ScanClose(ScanCloseResp),
+    ScanFilter(ScanFilterResp),
+    Stats(StatsResp),
+    Flush(FlushResp),
+    Select(SelectResp)
+);
+
+/// The response to a malformed request, which reads as the `Err` of any
+/// response type.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ErrorResp {
+    Err { msg: String, retryable: bool },
 }
 
+/// A value larger than the chunk threshold of the server comes as a
+/// sequence of `ValueChunk` frames ended by `ValueEnd`, instead of `Ok`, see
+/// `Server::chunk_threshold`.
 #[derive(Debug, Deserialize, Serialize)]
 pub enum GetResp {
     Ok(Option<String>),
-    Err(String),
+    Err {
+        msg: String,
+        retryable: bool,
+    },
+    /// the next part of the value
+    ValueChunk {
+        data: String,
+    },
+    /// the value is complete
+    ValueEnd,
+}
+
+/// the values of the keys, in the order of the request
+#[derive(Debug, Deserialize, Serialize)]
+pub enum GetManyResp {
+    Ok(Vec<Option<String>>),
+    Err { msg: String, retryable: bool },
+}
+
+/// the length of the value in bytes, `None` if the key doesn't exist
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ValueLenResp {
+    Ok(Option<usize>),
+    Err { msg: String, retryable: bool },
+}
+
+/// whether the key exists
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ContainsResp {
+    Ok(bool),
+    Err { msg: String, retryable: bool },
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum SetResp {
     Ok(()),
-    Err(String),
+    Err { msg: String, retryable: bool },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum SetAllResp {
+    Ok(()),
+    Err { msg: String, retryable: bool },
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum RemoveResp {
     Ok(()),
-    Err(String),
+    Err { msg: String, retryable: bool },
+}
+
+/// whether the key existed
+#[derive(Debug, Deserialize, Serialize)]
+pub enum DiscardResp {
+    Ok(bool),
+    Err { msg: String, retryable: bool },
+}
+
+/// whether the key was removed
+#[derive(Debug, Deserialize, Serialize)]
+pub enum RemoveIfResp {
+    Ok(bool),
+    Err { msg: String, retryable: bool },
+}
+
+/// the new length of the value
+#[derive(Debug, Deserialize, Serialize)]
+pub enum AppendResp {
+    Ok(usize),
+    Err { msg: String, retryable: bool },
+}
+
+/// A page of a cursor scan, `cursor` is `None` once the scan is exhausted.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ScanPage {
+    pub cursor: Option<u64>,
+    pub entries: Vec<(String, String)>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ScanResp {
+    Ok(ScanPage),
+    Err { msg: String, retryable: bool },
+}
+
+/// whether the cursor was open
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ScanCloseResp {
+    Ok(bool),
+    Err { msg: String, retryable: bool },
+}
+
+/// the matching pairs, in key order
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ScanFilterResp {
+    Ok(Vec<(String, String)>),
+    Err { msg: String, retryable: bool },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum StatsResp {
+    Ok(EngineStats),
+    Err { msg: String, retryable: bool },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum RenameResp {
+    Ok(()),
+    Err { msg: String, retryable: bool },
+}
+
+/// the number of keys removed
+#[derive(Debug, Deserialize, Serialize)]
+pub enum RemovePrefixResp {
+    Ok(usize),
+    Err { msg: String, retryable: bool },
+}
+
+/// the number of keys with the prefix
+#[derive(Debug, Deserialize, Serialize)]
+pub enum CountPrefixResp {
+    Ok(usize),
+    Err { msg: String, retryable: bool },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum FlushResp {
+    Ok(()),
+    Err { msg: String, retryable: bool },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum SelectResp {
+    Ok(()),
+    Err { msg: String, retryable: bool },
 }
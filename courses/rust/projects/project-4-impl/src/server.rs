@@ -1,76 +1,798 @@
+#[cfg(feature = "event-loop")]
+mod event_loop;
+
 use std::{
+    cell::RefCell,
+    collections::HashMap,
     fmt::Debug,
-    io::{BufReader, BufWriter, Write},
-    net::{TcpListener, TcpStream},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use serde_json::Deserializer;
+use serde::Deserialize;
+use serde_json::{Deserializer, Value};
+use socket2::{Domain, Protocol, Socket, Type};
 use tracing::{debug, error, info, instrument, warn};
 
-use crate::{Engine, GetResp, RemoveResp, Request, Result, SetResp};
+use crate::metrics::{self, Metrics};
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crate::{
+    AppendResp, ContainsResp, CountPrefixResp, DiscardResp, Engine, FlushResp, GetManyResp,
+    GetResp, KvsError, RemoveIfResp, RemovePrefixResp, RemoveResp, RenameResp, Request, Response,
+    Result, ScanCloseResp, ScanFilterResp, ScanPage, ScanResp, SelectResp, SetAllResp, SetResp,
+    StatsResp, ValueFilter, ValueLenResp,
+};
+
+/// name of the database a connection uses until it selects another one
+pub const DEFAULT_DB: &str = "default";
+
+/// longest time a response waits for the ones after it to be flushed together
+const FLUSH_BUDGET: Duration = Duration::from_millis(1);
+
+/// connections queued by the kernel until they are accepted, by default
+pub const DEFAULT_BACKLOG: u32 = 128;
+
+/// size in bytes above which a value is sent in chunks, by default
+const DEFAULT_CHUNK_THRESHOLD: usize = 1 << 20;
+
+/// pairs read from the engine at a time to evaluate a `Request::ScanFilter`
+const SCAN_FILTER_PAGE: usize = 1000;
 
 #[derive(Debug)]
 pub struct Server<E: Engine + Debug> {
-    engine: E,
+    // the engine of every database, by name
+    databases: HashMap<String, E>,
+    shutdown: ShutdownHandle,
+    metrics: Arc<Metrics>,
+    metrics_addr: Option<String>,
+    no_delay: bool,
+    reuse_addr: bool,
+    backlog: u32,
+    threads: Option<u32>,
+    coalesce_flushes: bool,
+    chunk_threshold: usize,
+    idle_timeout: Option<Duration>,
+    report_timing: bool,
+    // the layers every request goes through, the first one outermost
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+/// A layer of the handling of every request by a `Server`, for concerns
+/// like authentication, rate limiting or logging, see `Server::middleware`.
+pub trait Middleware: Send + Sync {
+    /// answer `req`, usually by passing it on to `next`, the layers inside
+    /// this one down to the engine, and returning its response. Either may
+    /// be changed on the way, and a refused request never reaches `next`.
+    fn handle(&self, req: Request, next: &dyn Fn(Request) -> Response) -> Response;
+}
+
+impl Debug for dyn Middleware {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Middleware")
+    }
+}
+
+/// Stops a running `Server` from another thread, e.g. a signal handler.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    stopped: Arc<AtomicBool>,
+    addr: Arc<Mutex<Option<SocketAddr>>>,
+}
+
+impl ShutdownHandle {
+    /// ask the server to stop accepting connections, flush the engine and
+    /// return from `run`
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wake up the accept loop blocked in `incoming`
+        if let Some(addr) = *self.addr.lock().unwrap() {
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
 }
 
 impl<E: Engine + Debug> Server<E> {
+    /// serve `engine` as the default database
     pub fn new(engine: E) -> Self {
-        Self { engine }
+        let mut databases = HashMap::new();
+        databases.insert(DEFAULT_DB.to_owned(), engine);
+        Self {
+            databases,
+            shutdown: ShutdownHandle::default(),
+            metrics: Arc::default(),
+            metrics_addr: None,
+            no_delay: true,
+            reuse_addr: true,
+            backlog: DEFAULT_BACKLOG,
+            threads: None,
+            coalesce_flushes: true,
+            chunk_threshold: DEFAULT_CHUNK_THRESHOLD,
+            idle_timeout: None,
+            report_timing: false,
+            middleware: Vec::new(),
+        }
+    }
+
+    /// also serve `engine` as the database `name`, which clients choose
+    /// with `Request::Select`. Replaces any database of the same name.
+    pub fn database(mut self, name: impl Into<String>, engine: E) -> Self {
+        self.databases.insert(name.into(), engine);
+        self
+    }
+
+    /// serve the request metrics and the stats of the databases in the
+    /// Prometheus text format at `http://<ip_port>/metrics`, from a thread of
+    /// its own so that scrapes don't wait behind the clients
+    pub fn metrics_addr(mut self, ip_port: impl Into<String>) -> Self {
+        self.metrics_addr = Some(ip_port.into());
+        self
+    }
+
+    /// whether accepted connections set `TCP_NODELAY`, on by default, so that
+    /// Nagle's algorithm doesn't hold back the small responses
+    pub fn no_delay(mut self, no_delay: bool) -> Self {
+        self.no_delay = no_delay;
+        self
+    }
+
+    /// whether the listener binds with `SO_REUSEADDR`, on by default, so that
+    /// a restarted server gets its address back while the connections of the
+    /// previous one are in TIME_WAIT
+    pub fn reuse_addr(mut self, reuse_addr: bool) -> Self {
+        self.reuse_addr = reuse_addr;
+        self
+    }
+
+    /// how many connections the kernel queues until the server accepts them,
+    /// 128 by default. Connections coming in a burst beyond it are refused
+    /// or dropped, depending on the system, which also caps it (on Linux at
+    /// `net.core.somaxconn`). Without `threads`, the server serves one
+    /// connection at a time, so the others wait in this queue.
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// serve up to `threads` connections at once, each on a thread of a
+    /// `SharedQueueThreadPool`, instead of one at a time. Once every thread
+    /// is busy and the queue of the pool is full, the server stops accepting
+    /// connections until a thread is free, leaving the others to wait in the
+    /// backlog. The event loop answers the requests on that many threads,
+    /// 4 by default.
+    pub fn threads(mut self, threads: u32) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// whether the responses to requests a client pipelined are flushed
+    /// together, on by default: a response is only flushed once no further
+    /// request is already received, or after a millisecond. Off, every
+    /// response is flushed on its own. A client waiting for its response
+    /// before sending the next request gets it at once either way.
+    pub fn coalesce_flushes(mut self, coalesce_flushes: bool) -> Self {
+        self.coalesce_flushes = coalesce_flushes;
+        self
+    }
+
+    /// the size in bytes above which the value of a get is sent as chunks of
+    /// at most this size, 1 MiB by default, so that a large value doesn't
+    /// make a frame of its own size for the client to buffer and parse.
+    /// `Client` reassembles the chunks.
+    pub fn chunk_threshold(mut self, chunk_threshold: usize) -> Self {
+        self.chunk_threshold = chunk_threshold;
+        self
+    }
+
+    /// close a connection which sends no request for `idle_timeout`, none by
+    /// default. Without `threads`, the server serves one connection at a
+    /// time, so an idle client otherwise holds back all the others. The
+    /// timeout applies to every read: a client stalling in the middle of a
+    /// request past it is disconnected too, with an error rather than
+    /// cleanly.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// whether a `Request::Timed` is answered with the time the server spent
+    /// on it, through the middleware and the engine, off by default. The
+    /// time excludes the network, so that a client can tell the latency of
+    /// the server from the one of the network.
+    pub fn report_timing(mut self, report_timing: bool) -> Self {
+        self.report_timing = report_timing;
+        self
+    }
+
+    /// run every request through `middleware` before the engine, inside the
+    /// middleware added before: the first one added sees a request first
+    /// and its response last. Malformed requests don't reach any.
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
     }
 
-    pub fn run(mut self, ip_port: &str) -> Result<()> {
-        let listener = TcpListener::bind(ip_port)?;
+    /// a handle which makes `run` return after flushing the engine
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub fn run(self, ip_port: &str) -> Result<()>
+    where
+        E: Sync,
+    {
+        let listener = bind(ip_port, self.reuse_addr, self.backlog)?;
+        *self.shutdown.addr.lock().unwrap() = Some(listener.local_addr()?);
+        self.spawn_metrics()?;
+        let pool = self
+            .threads
+            .map(|threads| SharedQueueThreadPool::with_capacity(threads, threads as usize))
+            .transpose()?;
+        let server = Arc::new(self);
 
-        // accept connections and process them serially
+        // accept connections and process them serially, or on the pool
         for stream in listener.incoming() {
+            if server.shutdown.is_shutdown() {
+                break;
+            }
             match stream {
-                Ok(s) => {
-                    if let Err(e) = self.handle_client(s) {
-                        error!(msg="handle commands error", err=%e);
+                Ok(s) => match &pool {
+                    // blocks while the pool is full
+                    Some(pool) => {
+                        let server = server.clone();
+                        pool.spawn(move || {
+                            if let Err(e) = server.handle_client(s) {
+                                error!(msg="handle commands error", err=%e);
+                            }
+                        });
                     }
-                }
+                    None => {
+                        if let Err(e) = server.handle_client(s) {
+                            error!(msg="handle commands error", err=%e);
+                        }
+                    }
+                },
                 Err(e) => {
                     error!(msg="handle TCP connection error", err=%e);
                 }
             }
         }
+        server.flush()
+    }
+
+    /// serve a single client, the process which spawned this one, reading
+    /// its requests from stdin and writing the responses to stdout, framed
+    /// as over TCP. Returns once stdin is closed; the shutdown handle can't
+    /// interrupt a pending read. Nothing else may be written to stdout.
+    pub fn run_stdio(self) -> Result<()> {
+        self.spawn_metrics()?;
+        let res = self.serve_requests(
+            BufReader::new(io::stdin().lock()),
+            BufWriter::new(io::stdout().lock()),
+            "stdio",
+        );
+        if let Err(e) = res {
+            error!(msg="handle commands error", err=%e);
+        }
+        self.flush()
+    }
+
+    /// serve the metrics from their own thread, if asked to
+    fn spawn_metrics(&self) -> Result<()> {
+        if let Some(metrics_addr) = &self.metrics_addr {
+            let metrics_listener = TcpListener::bind(metrics_addr)?;
+            let metrics = self.metrics.clone();
+            let databases = self.databases.clone();
+            let shutdown = self.shutdown.clone();
+            thread::spawn(move || {
+                metrics::serve(
+                    metrics_listener,
+                    || metrics.render(&databases),
+                    || shutdown.is_shutdown(),
+                )
+            });
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        info!(msg = "shutting down, flushing the engines");
+        for engine in self.databases.values() {
+            engine.flush()?;
+        }
         Ok(())
     }
 
     #[instrument]
-    fn handle_client(&mut self, stream: TcpStream) -> Result<()> {
+    fn handle_client(&self, stream: TcpStream) -> Result<()> {
         let peer_addr = stream.peer_addr()?;
-        let reader = BufReader::new(&stream);
-        let mut writer = BufWriter::new(&stream);
-        let reqs = Deserializer::from_reader(reader).into_iter::<Request>();
-        info!(msg = "recieve a request", from = format!("{}", peer_addr));
+        stream.set_nodelay(self.no_delay)?;
+        stream.set_read_timeout(self.idle_timeout)?;
+        info!(
+            msg = "recieve a request",
+            from = format!("{}", peer_addr),
+            nodelay = stream.nodelay()?
+        );
+        self.serve_requests(
+            BufReader::new(&stream),
+            BufWriter::new(&stream),
+            &peer_addr.to_string(),
+        )
+    }
 
+    /// answer the requests of `reader` on `writer` until the client is done
+    fn serve_requests<R: Read, W: Write>(
+        &self,
+        mut reader: BufReader<R>,
+        mut writer: BufWriter<W>,
+        peer_addr: &str,
+    ) -> Result<()> {
+        // when the oldest response not flushed yet was written
+        let mut unflushed: Option<Instant> = None;
+        // send the response and tell whether it is an error
         macro_rules! send_resp {
-            ($resp:expr) => {{
+            ($resp:expr, $spent:expr) => {{
                 let resp = $resp;
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
-                debug!(msg="Response sent", to=format!("{}", peer_addr), resp=?resp);
+                self.write_response(&mut writer, &resp, $spent)?;
+                if self.coalesce_flushes {
+                    unflushed.get_or_insert_with(Instant::now);
+                } else {
+                    writer.flush()?;
+                }
+                debug!(msg="Response sent", to=peer_addr, resp=?resp);
+                resp.is_err()
             }};
         }
 
-        for req in reqs {
-            match req? {
-                Request::Get { key } => send_resp!(match self.engine.get(key) {
-                    Ok(value) => GetResp::Ok(value),
-                    Err(e) => GetResp::Err(format!("{}", e)),
-                }),
-                Request::Set { key, value } => send_resp!(match self.engine.set(key, value) {
-                    Ok(_) => SetResp::Ok(()),
-                    Err(e) => SetResp::Err(format!("{}", e)),
-                }),
-                Request::Remove { key } => send_resp!(match self.engine.remove(key) {
-                    Ok(_) => RemoveResp::Ok(()),
-                    Err(e) => RemoveResp::Err(format!("{}", e)),
-                }),
+        let conn = RefCell::new(Connection {
+            engine: self.databases[DEFAULT_DB].clone(),
+            cursors: HashMap::new(),
+            next_cursor: 0,
+        });
+        let dispatch = |req| self.dispatch(&mut conn.borrow_mut(), req);
+
+        loop {
+            // flush before a read which may block, the client may be waiting
+            if let Some(since) = unflushed {
+                if !has_request(&reader) || since.elapsed() >= FLUSH_BUDGET {
+                    writer.flush()?;
+                    unflushed = None;
+                }
+            }
+            if !has_request(&reader) {
+                // wait for the next request on its own, to tell an idle
+                // connection from one stalling in the middle of a request
+                let blank = reader.buffer().len();
+                reader.consume(blank);
+                match reader.fill_buf() {
+                    Ok(_) => {}
+                    Err(e) if is_timeout(&e) => {
+                        info!(msg = "closing an idle connection", from = peer_addr);
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
             }
+            let req = match read_request(&mut reader) {
+                Ok(Some(req)) => req,
+                Ok(None) => break,
+                Err(e @ KvsError::Protocol(_)) => {
+                    warn!(msg = "malformed request", from = peer_addr, err = %e);
+                    self.metrics.record_malformed();
+                    send_resp!(Response::error(format!("{}", e), false), None);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let (req, timed) = self.unwrap_timed(req);
+            let kind = req.kind();
+            let start = Instant::now();
+            let resp = run_chain(&self.middleware, req, &dispatch);
+            let failed = send_resp!(resp, timed.then(|| start.elapsed()));
+            self.metrics.record(kind, start.elapsed(), failed);
         }
+        // the client may only have closed its half of the connection
+        writer.flush()?;
         Ok(())
     }
+
+    /// the request a `Request::Timed` wraps, and whether to report the time
+    /// spent on it, or `req` itself
+    fn unwrap_timed(&self, req: Request) -> (Request, bool) {
+        match req {
+            Request::Timed(req) => (*req, self.report_timing),
+            req => (req, false),
+        }
+    }
+
+    /// write `resp`, with the time `spent` on it if reported, else the value
+    /// of a get above the chunk threshold as chunks
+    fn write_response<W: Write>(
+        &self,
+        writer: &mut W,
+        resp: &Response,
+        spent: Option<Duration>,
+    ) -> Result<()> {
+        if let Some(spent) = spent {
+            let mut resp = serde_json::to_value(resp)?;
+            if let Some(fields) = resp.as_object_mut() {
+                let micros = u64::try_from(spent.as_micros()).unwrap_or(u64::MAX);
+                fields.insert("server_micros".to_owned(), micros.into());
+            }
+            return Ok(serde_json::to_writer(writer, &resp)?);
+        }
+        match resp {
+            Response::Get(GetResp::Ok(Some(value))) if value.len() > self.chunk_threshold => {
+                write_chunks(writer, value, self.chunk_threshold)
+            }
+            resp => Ok(serde_json::to_writer(writer, resp)?),
+        }
+    }
+
+    /// answer `req` with the selected database of the connection, the
+    /// innermost layer of the middleware
+    fn dispatch(&self, conn: &mut Connection<E>, req: Request) -> Response {
+        match req {
+            Request::Get { key } => match conn.engine.get(key) {
+                Ok(value) => GetResp::Ok(value),
+                Err(e) => GetResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::GetMany { keys } => match get_many(&conn.engine, &keys) {
+                Ok(values) => GetManyResp::Ok(values),
+                Err(e) => GetManyResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::ValueLen { key } => match conn.engine.value_len(key) {
+                Ok(len) => ValueLenResp::Ok(len),
+                Err(e) => ValueLenResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::Contains { key } => match conn.engine.contains_key(key) {
+                Ok(exists) => ContainsResp::Ok(exists),
+                Err(e) => ContainsResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::Set { key, value } => match conn.engine.set(key, value) {
+                Ok(_) => SetResp::Ok(()),
+                Err(e) => SetResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::SetAll { pairs } => match conn.engine.set_all(pairs) {
+                Ok(()) => SetAllResp::Ok(()),
+                Err(e) => SetAllResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::Remove { key } => match conn.engine.remove(key) {
+                Ok(_) => RemoveResp::Ok(()),
+                Err(e) => RemoveResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::Discard { key } => match conn.engine.discard(key) {
+                Ok(removed) => DiscardResp::Ok(removed),
+                Err(e) => DiscardResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::RemoveIf { key, expected } => match conn.engine.remove_if(key, expected) {
+                Ok(removed) => RemoveIfResp::Ok(removed),
+                Err(e) => RemoveIfResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::Append { key, suffix } => match conn.engine.append(key, suffix) {
+                Ok(len) => AppendResp::Ok(len),
+                Err(e) => AppendResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::Rename { from, to } => match conn.engine.rename(from, to) {
+                Ok(()) => RenameResp::Ok(()),
+                Err(e) => RenameResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::RemovePrefix { prefix } => match conn.engine.remove_prefix(prefix) {
+                Ok(count) => RemovePrefixResp::Ok(count),
+                Err(e) => RemovePrefixResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::CountPrefix { prefix } => match conn.engine.count_prefix(prefix) {
+                Ok(count) => CountPrefixResp::Ok(count),
+                Err(e) => CountPrefixResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::Stats => match conn.engine.stats() {
+                Ok(stats) => StatsResp::Ok(stats),
+                Err(e) => StatsResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::Flush => match conn.engine.flush() {
+                Ok(()) => FlushResp::Ok(()),
+                Err(e) => FlushResp::Err {
+                    retryable: e.is_retryable(),
+                    msg: format!("{}", e),
+                },
+            }
+            .into(),
+            Request::ScanStart { prefix, count } => {
+                let cursor = conn.next_cursor;
+                conn.next_cursor += 1;
+                conn.cursors.insert(
+                    cursor,
+                    Cursor {
+                        engine: conn.engine.clone(),
+                        prefix,
+                        last: None,
+                    },
+                );
+                match scan_page(&mut conn.cursors, cursor, count) {
+                    Ok(page) => ScanResp::Ok(page),
+                    Err(e) => ScanResp::Err {
+                        retryable: e.is_retryable(),
+                        msg: format!("{}", e),
+                    },
+                }
+                .into()
+            }
+            Request::ScanNext { cursor, count } => {
+                match scan_page(&mut conn.cursors, cursor, count) {
+                    Ok(page) => ScanResp::Ok(page),
+                    Err(e) => ScanResp::Err {
+                        retryable: e.is_retryable(),
+                        msg: format!("{}", e),
+                    },
+                }
+                .into()
+            }
+            Request::ScanClose { cursor } => {
+                ScanCloseResp::Ok(conn.cursors.remove(&cursor).is_some()).into()
+            }
+            Request::ScanFilter { prefix, filter } => {
+                match scan_filter(&conn.engine, prefix, &filter) {
+                    Ok(entries) => ScanFilterResp::Ok(entries),
+                    Err(e) => ScanFilterResp::Err {
+                        retryable: e.is_retryable(),
+                        msg: format!("{}", e),
+                    },
+                }
+                .into()
+            }
+            // only the outermost timed request is timed
+            Request::Timed(req) => self.dispatch(conn, *req),
+            Request::Select { db } => match self.databases.get(&db) {
+                Some(selected) => {
+                    conn.engine = selected.clone();
+                    SelectResp::Ok(())
+                }
+                None => SelectResp::Err {
+                    retryable: false,
+                    msg: format!("no database named {}", db),
+                },
+            }
+            .into(),
+        }
+    }
+}
+
+/// bind a listener to `ip_port`, with `SO_REUSEADDR` if `reuse_addr`,
+/// queueing up to `backlog` connections
+fn bind(ip_port: &str, reuse_addr: bool, backlog: u32) -> Result<TcpListener> {
+    let addr = ip_port
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| KvsError::StringErr(format!("{} resolves to no address", ip_port)))?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(reuse_addr)?;
+    socket.bind(&addr.into())?;
+    socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+    Ok(socket.into())
+}
+
+/// whether the start of a request is already received: its client sent it
+/// without waiting for the responses before, which can wait to be flushed
+fn has_request<R: Read>(reader: &BufReader<R>) -> bool {
+    reader
+        .buffer()
+        .iter()
+        .any(|byte| !byte.is_ascii_whitespace())
+}
+
+/// whether `e` is the expiry of a read timeout, whose kind depends on the
+/// platform
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// read the next request, or `None` once the client closed the connection.
+///
+/// A malformed request fails with `KvsError::Protocol`, leaving the reader at
+/// the start of the request after it, which a client pipelining its requests
+/// may have sent already. A request of the wrong shape is valid JSON, skipped
+/// whole. Past invalid JSON, the next request can only be guessed: it is
+/// taken to start at the next `{` received.
+fn read_request<R: Read>(reader: &mut BufReader<R>) -> Result<Option<Request>> {
+    let mut de = Deserializer::from_reader(&mut *reader);
+    let value = match Value::deserialize(&mut de) {
+        Ok(value) => value,
+        Err(e) if e.is_eof() => return Ok(None),
+        Err(e) if e.is_io() => return Err(e.into()),
+        Err(e) => {
+            let received = reader.buffer();
+            let skipped = received
+                .iter()
+                .position(|&byte| byte == b'{')
+                .unwrap_or(received.len());
+            reader.consume(skipped);
+            return Err(KvsError::Protocol(e.to_string()));
+        }
+    };
+    match Request::deserialize(value) {
+        Ok(req) => Ok(Some(req)),
+        Err(e) => Err(KvsError::Protocol(e.to_string())),
+    }
+}
+
+/// send `value` as `GetResp::ValueChunk`s of at most `size` bytes, cut at
+/// char boundaries, followed by `GetResp::ValueEnd`
+fn write_chunks<W: Write>(writer: &mut W, value: &str, size: usize) -> Result<()> {
+    let mut rest = value;
+    while !rest.is_empty() {
+        let mut end = size.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        // a chunk holds at least one char, however small `size` is
+        if end == 0 {
+            end = rest.chars().next().map_or(0, char::len_utf8);
+        }
+        let data = rest[..end].to_owned();
+        serde_json::to_writer(&mut *writer, &GetResp::ValueChunk { data })?;
+        rest = &rest[end..];
+    }
+    serde_json::to_writer(writer, &GetResp::ValueEnd)?;
+    Ok(())
+}
+
+/// run `req` through `layers`, the first one outermost, down to `inner`
+fn run_chain(
+    layers: &[Box<dyn Middleware>],
+    req: Request,
+    inner: &dyn Fn(Request) -> Response,
+) -> Response {
+    match layers.split_first() {
+        Some((layer, rest)) => layer.handle(req, &|req| run_chain(rest, req, inner)),
+        None => inner(req),
+    }
+}
+
+/// read the next page of a cursor, closing it once exhausted
+fn scan_page<E: Engine>(
+    cursors: &mut HashMap<u64, Cursor<E>>,
+    id: u64,
+    count: usize,
+) -> Result<ScanPage> {
+    let cursor = cursors
+        .get_mut(&id)
+        .ok_or_else(|| KvsError::StringErr(format!("scan cursor {} is not open", id)))?;
+    let entries = cursor
+        .engine
+        .scan(cursor.prefix.clone(), cursor.last.clone(), count)?;
+    if entries.len() < count {
+        cursors.remove(&id);
+        return Ok(ScanPage {
+            cursor: None,
+            entries,
+        });
+    }
+    cursor.last = entries.last().map(|(key, _)| key.clone());
+    Ok(ScanPage {
+        cursor: Some(id),
+        entries,
+    })
+}
+
+/// the values of `keys` in order, reading each distinct key once however
+/// many times it is repeated
+fn get_many<E: Engine>(engine: &E, keys: &[String]) -> Result<Vec<Option<String>>> {
+    let mut values: HashMap<&str, Option<String>> = HashMap::with_capacity(keys.len());
+    for key in keys {
+        if !values.contains_key(key.as_str()) {
+            values.insert(key, engine.get(key)?);
+        }
+    }
+    Ok(keys
+        .iter()
+        .map(|key| values[key.as_str()].clone())
+        .collect())
+}
+
+/// every pair of `engine` whose key starts with `prefix` and whose value
+/// matches `filter`, read a page at a time
+fn scan_filter<E: Engine>(
+    engine: &E,
+    prefix: String,
+    filter: &ValueFilter,
+) -> Result<Vec<(String, String)>> {
+    let mut matches = Vec::new();
+    let mut last = None;
+    loop {
+        let entries = engine.scan(prefix.clone(), last, SCAN_FILTER_PAGE)?;
+        let exhausted = entries.len() < SCAN_FILTER_PAGE;
+        last = entries.last().map(|(key, _)| key.clone());
+        matches.extend(
+            entries
+                .into_iter()
+                .filter(|(_, value)| filter.matches(value)),
+        );
+        if exhausted {
+            return Ok(matches);
+        }
+    }
+}
+
+/// The state of a connection: its selected database and open scan cursors.
+struct Connection<E> {
+    engine: E,
+    cursors: HashMap<u64, Cursor<E>>,
+    next_cursor: u64,
+}
+
+/// The position of an open scan: the last key sent to the client.
+#[derive(Debug)]
+struct Cursor<E> {
+    // the database selected when the scan started
+    engine: E,
+    prefix: String,
+    last: Option<String>,
 }
@@ -0,0 +1,470 @@
+//! # event loop
+//! `Server::run_event_loop`: a single thread waiting on every connection at
+//! once, woken by mio when one of them can be read or written, so that a
+//! connection left idle costs a socket and two buffers instead of holding
+//! back the ones behind it.
+//!
+//! The loop only moves bytes. Once a request is fully received, it is handed
+//! to a pool of worker threads, so that a slow request only delays the
+//! connection which sent it. A connection has its requests answered by one
+//! worker at a time, in the order they were sent.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+use tracing::{debug, error, info, warn};
+
+use super::{bind, run_chain, Connection, Server, DEFAULT_DB};
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crate::{Engine, KvsError, Request, Response, Result};
+
+/// bytes read from a connection per wakeup, so that a busy client can't
+/// starve the others
+const READ_CHUNK: usize = 16 * 1024;
+
+/// bytes of responses a connection may have waiting to be sent before its
+/// requests stop being read, so that a client which doesn't read its
+/// responses can't grow them without bound
+const OUTPUT_CAP: usize = 1 << 20;
+
+/// threads answering the requests, unless set with `Server::threads`
+const DEFAULT_WORKERS: u32 = 4;
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+/// A connection of the event loop, with what it sent and what it is sent
+/// while they wait for the socket.
+struct Peer<E> {
+    stream: TcpStream,
+    addr: SocketAddr,
+    // the state of the connection, away while a worker answers its requests
+    conn: Option<Connection<E>>,
+    // bytes received but not answered yet
+    input: Vec<u8>,
+    framer: Framer,
+    // responses not sent yet
+    output: Vec<u8>,
+    // whether the socket may have bytes to read, or room to write: mio only
+    // wakes the loop when it becomes so
+    readable: bool,
+    writable: bool,
+    // whether the client closed its half of the connection
+    closed: bool,
+    // when the connection was accepted or last got a response
+    active: Instant,
+}
+
+impl<E> Peer<E> {
+    /// whether to read the requests of the client: not while the requests
+    /// before are answered, nor while it has too many responses to read
+    fn wants_input(&self) -> bool {
+        !self.closed && self.conn.is_some() && self.output.len() < OUTPUT_CAP
+    }
+
+    /// read what the client sent, at most a chunk of it
+    fn receive(&mut self) -> io::Result<()> {
+        let mut buf = [0; READ_CHUNK];
+        match self.stream.read(&mut buf) {
+            Ok(0) => self.closed = true,
+            Ok(len) => self.input.extend_from_slice(&buf[..len]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.readable = false,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// send the pending responses, as much as the socket takes
+    fn send(&mut self) -> io::Result<()> {
+        while self.writable && !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    self.output.drain(..len);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.writable = false,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// take the requests received in full, keeping the start of the next one
+    /// until the rest of it arrives
+    fn take_requests(&mut self) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        let mut start = 0;
+        while let Some(len) = self.framer.next_frame(&self.input[start..]) {
+            frames.push(self.input[start..start + len].to_vec());
+            start += len;
+        }
+        self.input.drain(..start);
+        frames
+    }
+
+    /// whether the start of a request is already received
+    fn has_request(&self) -> bool {
+        self.input.iter().any(|byte| !byte.is_ascii_whitespace())
+    }
+}
+
+/// How far the bytes received on a connection are split into requests, kept
+/// across wakeups so that every byte is scanned once, however many reads a
+/// request spans.
+///
+/// A request is a JSON value, which ends with the object, array or string it
+/// starts with. Past anything else, the next request is taken to start at the
+/// next `{`, like in `read_request`.
+#[derive(Default)]
+struct Framer {
+    // bytes of the request scanned so far
+    scanned: usize,
+    // objects and arrays open at `scanned`
+    depth: usize,
+    in_string: bool,
+    // whether the last byte scanned is a backslash in a string
+    escaped: bool,
+    // whether the request is invalid JSON
+    garbage: bool,
+}
+
+impl Framer {
+    /// the length of the request `input` starts with, once received in full
+    fn next_frame(&mut self, input: &[u8]) -> Option<usize> {
+        while let Some(&byte) = input.get(self.scanned) {
+            if self.garbage && byte == b'{' {
+                return Some(self.take());
+            }
+            self.scanned += 1;
+            if self.garbage {
+                continue;
+            }
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => {
+                        self.in_string = false;
+                        if self.depth == 0 {
+                            return Some(self.take());
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' if self.depth > 0 => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        return Some(self.take());
+                    }
+                }
+                byte if self.depth == 0 && !byte.is_ascii_whitespace() => self.garbage = true,
+                _ => {}
+            }
+        }
+        // answered at once, the client may be waiting for the error
+        if self.garbage {
+            return Some(self.take());
+        }
+        None
+    }
+
+    /// the length of the request just scanned, starting over for the next one
+    fn take(&mut self) -> usize {
+        std::mem::take(self).scanned
+    }
+}
+
+/// The requests of a connection a worker answered, with the state of the
+/// connection it gives back.
+struct Answered<E> {
+    token: Token,
+    conn: Connection<E>,
+    output: Result<Vec<u8>>,
+}
+
+/// What the loop hands the workers their jobs with.
+struct Workers<E: Engine + Debug> {
+    pool: SharedQueueThreadPool,
+    server: Arc<Server<E>>,
+    answered: Sender<Answered<E>>,
+    waker: Arc<Waker>,
+}
+
+impl<E: Engine + Debug + Sync> Workers<E> {
+    /// answer `frames` on a worker, blocking while every one is busy and the
+    /// queue of the pool is full
+    fn answer(
+        &self,
+        token: Token,
+        addr: SocketAddr,
+        mut conn: Connection<E>,
+        frames: Vec<Vec<u8>>,
+    ) {
+        let server = self.server.clone();
+        let answered = self.answered.clone();
+        let waker = self.waker.clone();
+        self.pool.spawn(move || {
+            let output =
+                panic::catch_unwind(AssertUnwindSafe(|| server.answer(&mut conn, &frames, addr)))
+                    .unwrap_or_else(|_| Err(KvsError::StringErr("a request panicked".to_owned())));
+            // the loop is gone once the server stopped
+            if answered
+                .send(Answered {
+                    token,
+                    conn,
+                    output,
+                })
+                .is_ok()
+            {
+                if let Err(e) = waker.wake() {
+                    error!(msg = "fail to wake the event loop", err = %e);
+                }
+            }
+        });
+    }
+}
+
+impl<E: Engine + Debug + Sync> Server<E> {
+    /// serve on `ip_port` like `run`, but every connection at once from a
+    /// single thread, which hands the requests to a few worker threads, so
+    /// that many mostly idle connections neither hold a thread each nor keep
+    /// a new client waiting. Only on Unix, with the `event-loop` feature.
+    pub fn run_event_loop(self, ip_port: &str) -> Result<()> {
+        let listener = bind(ip_port, self.reuse_addr, self.backlog)?;
+        listener.set_nonblocking(true)?;
+        *self.shutdown.addr.lock().unwrap() = Some(listener.local_addr()?);
+        self.spawn_metrics()?;
+        let mut listener = TcpListener::from_std(listener);
+
+        let mut poll = Poll::new()?;
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+        let (answered, done): (_, Receiver<Answered<E>>) = mpsc::channel();
+        let workers = Workers {
+            pool: SharedQueueThreadPool::new(self.threads.unwrap_or(DEFAULT_WORKERS))?,
+            server: Arc::new(self),
+            answered,
+            waker: Arc::new(Waker::new(poll.registry(), WAKER)?),
+        };
+        let server = &*workers.server;
+
+        let mut events = Events::with_capacity(1024);
+        let mut peers: HashMap<Token, Peer<E>> = HashMap::new();
+        let mut next_token = WAKER.0 + 1;
+        while !server.shutdown.is_shutdown() {
+            // a peer not read in full yet is read again at once
+            let pending = peers
+                .values()
+                .any(|peer| peer.readable && peer.wants_input());
+            let timeout = if pending {
+                Some(Duration::ZERO)
+            } else {
+                server.next_expiry(&peers)
+            };
+            match poll.poll(&mut events, timeout) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+            if server.shutdown.is_shutdown() {
+                break;
+            }
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => server.accept(&listener, &poll, &mut peers, &mut next_token),
+                    WAKER => {}
+                    token => {
+                        if let Some(peer) = peers.get_mut(&token) {
+                            peer.readable |= event.is_readable() || event.is_read_closed();
+                            peer.writable |= event.is_writable() || event.is_error();
+                        }
+                    }
+                }
+            }
+            for Answered {
+                token,
+                conn,
+                output,
+            } in done.try_iter()
+            {
+                let Some(peer) = peers.get_mut(&token) else {
+                    continue;
+                };
+                match output {
+                    Ok(output) => {
+                        peer.conn = Some(conn);
+                        peer.output.extend_from_slice(&output);
+                        peer.active = Instant::now();
+                    }
+                    Err(e) => {
+                        error!(msg = "handle commands error", from = %peer.addr, err = %e);
+                        peers.remove(&token);
+                    }
+                }
+            }
+            peers.retain(
+                |&token, peer| match server.serve_peer(token, peer, &workers) {
+                    Ok(keep) => keep,
+                    Err(e) => {
+                        error!(msg = "handle commands error", from = %peer.addr, err = %e);
+                        false
+                    }
+                },
+            );
+        }
+        server.flush()
+    }
+
+    /// accept the connections waiting on `listener`
+    fn accept(
+        &self,
+        listener: &TcpListener,
+        poll: &Poll,
+        peers: &mut HashMap<Token, Peer<E>>,
+        next_token: &mut usize,
+    ) {
+        loop {
+            let (mut stream, addr) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if is_transient(&e) => return,
+                Err(e) => {
+                    error!(msg = "handle TCP connection error", err = %e);
+                    return;
+                }
+            };
+            let token = Token(*next_token);
+            let res = stream.set_nodelay(self.no_delay).and_then(|()| {
+                poll.registry().register(
+                    &mut stream,
+                    token,
+                    Interest::READABLE | Interest::WRITABLE,
+                )
+            });
+            if let Err(e) = res {
+                error!(msg = "handle TCP connection error", from = %addr, err = %e);
+                continue;
+            }
+            *next_token += 1;
+            info!(msg = "accept a connection", from = %addr, nodelay = self.no_delay);
+            peers.insert(
+                token,
+                Peer {
+                    stream,
+                    addr,
+                    conn: Some(Connection {
+                        engine: self.databases[DEFAULT_DB].clone(),
+                        cursors: HashMap::new(),
+                        next_cursor: 0,
+                    }),
+                    input: Vec::new(),
+                    framer: Framer::default(),
+                    output: Vec::new(),
+                    readable: false,
+                    writable: false,
+                    closed: false,
+                    active: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// read, hand to a worker and write what `peer` is ready for, returning
+    /// whether to keep the connection
+    fn serve_peer(&self, token: Token, peer: &mut Peer<E>, workers: &Workers<E>) -> Result<bool> {
+        if peer.readable && peer.wants_input() {
+            peer.receive()?;
+        }
+        if peer.conn.is_some() {
+            let frames = peer.take_requests();
+            if !frames.is_empty() {
+                let conn = peer.conn.take().expect("checked above");
+                workers.answer(token, peer.addr, conn, frames);
+            }
+        }
+        peer.send()?;
+        // a worker still answering the connection gives it back
+        if peer.conn.is_none() {
+            return Ok(true);
+        }
+        if peer.closed && peer.output.is_empty() {
+            return Ok(false);
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            if peer.active.elapsed() >= idle_timeout {
+                if peer.has_request() {
+                    warn!(msg = "closing a connection stalled in a request", from = %peer.addr);
+                } else {
+                    info!(msg = "closing an idle connection", from = %peer.addr);
+                }
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// answer the requests of `frames` on `conn`, returning the responses
+    fn answer(
+        &self,
+        conn: &mut Connection<E>,
+        frames: &[Vec<u8>],
+        addr: SocketAddr,
+    ) -> Result<Vec<u8>> {
+        let conn = RefCell::new(conn);
+        let mut output = Vec::new();
+        for frame in frames {
+            let req = match serde_json::from_slice::<Request>(frame) {
+                Ok(req) => req,
+                Err(e) => {
+                    let e = KvsError::Protocol(e.to_string());
+                    warn!(msg = "malformed request", from = %addr, err = %e);
+                    self.metrics.record_malformed();
+                    let resp = Response::error(e.to_string(), false);
+                    self.write_response(&mut output, &resp, None)?;
+                    continue;
+                }
+            };
+            let (req, timed) = self.unwrap_timed(req);
+            let kind = req.kind();
+            let start = Instant::now();
+            let dispatch = |req| self.dispatch(&mut conn.borrow_mut(), req);
+            let resp = run_chain(&self.middleware, req, &dispatch);
+            self.write_response(&mut output, &resp, timed.then(|| start.elapsed()))?;
+            debug!(msg = "Response sent", to = %addr, resp = ?resp);
+            self.metrics.record(kind, start.elapsed(), resp.is_err());
+        }
+        Ok(output)
+    }
+
+    /// how long until the first of `peers` is idle for the idle timeout
+    fn next_expiry(&self, peers: &HashMap<Token, Peer<E>>) -> Option<Duration> {
+        let idle_timeout = self.idle_timeout?;
+        peers
+            .values()
+            .filter(|peer| peer.conn.is_some())
+            .map(|peer| idle_timeout.saturating_sub(peer.active.elapsed()))
+            .min()
+    }
+}
+
+/// whether `e` only means that the socket isn't ready
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+
+use crate::{Client, KvClient, KvsError, Result};
+
+/// number of points each server owns on the ring, more points spread
+/// the keys more evenly between servers
+const VIRTUAL_NODES: usize = 128;
+
+///
+/// ShardedClient routes every key to one of several independent servers
+/// with consistent hashing, so adding or removing a server only remaps
+/// the keys owned by that server.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use kvs::{Result, ShardedClient};
+/// # fn test() -> Result<()> {
+/// let mut client = ShardedClient::connect(&["127.0.0.1:4000", "127.0.0.1:4001"])?;
+/// client.set("key1".to_owned(), "value1".to_owned())?;
+/// assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct ShardedClient {
+    ring: BTreeMap<u64, String>,
+    clients: BTreeMap<String, Client>,
+}
+
+impl ShardedClient {
+    /// connect to every server in `addrs`
+    pub fn connect(addrs: &[&str]) -> Result<Self> {
+        let mut client = Self {
+            ring: BTreeMap::new(),
+            clients: BTreeMap::new(),
+        };
+        for addr in addrs {
+            client.add_server(addr)?;
+        }
+        Ok(client)
+    }
+
+    /// connect to a new server and let it take over its share of the keys.
+    /// Keys already stored on the other servers are not moved.
+    pub fn add_server(&mut self, addr: &str) -> Result<()> {
+        if self.clients.contains_key(addr) {
+            return Ok(());
+        }
+        let client = Client::connect(addr)?;
+        for node in 0..VIRTUAL_NODES {
+            let point = hash(format!("{}#{}", addr, node).as_bytes());
+            self.ring.insert(point, addr.to_owned());
+        }
+        self.clients.insert(addr.to_owned(), client);
+        Ok(())
+    }
+
+    /// stop routing keys to a server, its keys are taken over by the others
+    pub fn remove_server(&mut self, addr: &str) {
+        self.ring.retain(|_, server| server != addr);
+        self.clients.remove(addr);
+    }
+
+    /// the address of the server which owns `key`
+    pub fn shard_for(&self, key: &str) -> Option<&str> {
+        let h = hash(key.as_bytes());
+        self.ring
+            .range(h..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, addr)| addr.as_str())
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.client_for(&key)?.get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.client_for(&key)?.set(key, value)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.client_for(&key)?.remove(key)
+    }
+
+    pub fn discard(&mut self, key: String) -> Result<bool> {
+        self.client_for(&key)?.discard(key)
+    }
+
+    pub fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        self.client_for(&key)?.remove_if(key, expected)
+    }
+
+    pub fn append(&mut self, key: String, suffix: String) -> Result<usize> {
+        self.client_for(&key)?.append(key, suffix)
+    }
+
+    fn client_for(&mut self, key: &str) -> Result<&mut Client> {
+        let addr = self
+            .shard_for(key)
+            .ok_or_else(|| KvsError::StringErr("no server to route the key to".to_owned()))?
+            .to_owned();
+        Ok(self
+            .clients
+            .get_mut(&addr)
+            .expect("every server on the ring is connected"))
+    }
+}
+
+impl KvClient for ShardedClient {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        ShardedClient::get(self, key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        ShardedClient::set(self, key, value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        ShardedClient::remove(self, key)
+    }
+
+    fn discard(&mut self, key: String) -> Result<bool> {
+        ShardedClient::discard(self, key)
+    }
+
+    fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        ShardedClient::remove_if(self, key, expected)
+    }
+
+    fn append(&mut self, key: String, suffix: String) -> Result<usize> {
+        ShardedClient::append(self, key, suffix)
+    }
+}
+
+/// the point of `bytes` on the ring: their 64-bit FNV-1a hash, mixed with
+/// the finalizer of MurmurHash3 so that keys differing in their last byte
+/// land far apart. Fixed by the format, unlike the hashers of std, so that
+/// every client, whatever its build or platform, builds the same ring.
+fn hash(bytes: &[u8]) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0100_0000_01b3;
+    let mut h = bytes.iter().fold(FNV_OFFSET, |h, &byte| {
+        (h ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    });
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
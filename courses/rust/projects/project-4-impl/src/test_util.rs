@@ -0,0 +1,219 @@
+//! # test_util
+//! harnesses for testing the engines, enabled by the `test-util` feature.
+//!
+//! `CrashTest` checks that a `KvsEngine` recovers from a crash at any point
+//! of a workload. It keeps a model of the writes alongside the engine, and
+//! simulates a crash by dropping the engine and truncating its active log
+//! file at a chosen offset, as if the bytes after it never reached the disk.
+//! The store is then reopened and compared with the model.
+//!
+//! Two durability guarantees are validated, one per kind of write:
+//!
+//! - flushed: a write acknowledged before `Engine::flush` returned is synced
+//!   to the disk, so it survives any crash. `crash_range` never cuts below
+//!   the flushed length, and every such write must be found after reopening.
+//! - acknowledged: a write which returned but wasn't flushed is handed to the
+//!   OS, so it survives a crash of the process but not of the machine, which
+//!   may lose any suffix of the unsynced log. After such a loss, the store
+//!   must hold exactly the writes whose record is whole before the cut: no
+//!   later write and no write which was never acknowledged.
+//!
+//! The logs written before the active one (older files and the output of
+//! compactions, which is synced) are not truncated.
+//!
+//! `panic_at` makes the engine panic at a chosen point of a write or of a
+//! compaction instead, as a bug in the engine or in a handler would, to check
+//! the store reopens consistently after a panic too.
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use crate::{Engine, KvsEngine, KvsError, Result};
+
+/// A point of the `KvsEngine` code where `panic_at` makes it panic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicPoint {
+    /// in the middle of writing a record, with half of it written
+    MidRecord,
+    /// once the record of a set is written, before it is flushed to the log
+    BeforeFlush,
+    /// once the record of a set is flushed to the log, before it is indexed
+    AfterFlush,
+    /// during a compaction, once a record is copied to the compacted log
+    MidCompaction,
+    /// once the compacted log is synced, before the old logs are removed
+    AfterCompactionSync,
+}
+
+thread_local! {
+    static ARMED: Cell<Option<PanicPoint>> = const { Cell::new(None) };
+}
+
+/// make the engine panic the next time the current thread reaches `point`,
+/// once. The writer lock is held there, so the engine is poisoned: the
+/// store has to be dropped and reopened.
+pub fn panic_at(point: PanicPoint) {
+    ARMED.with(|armed| armed.set(Some(point)));
+}
+
+/// whether the current thread should panic at `point`, which disarms it
+pub(crate) fn armed(point: PanicPoint) -> bool {
+    ARMED.with(|armed| {
+        let hit = armed.get() == Some(point);
+        if hit {
+            armed.set(None);
+        }
+        hit
+    })
+}
+
+/// panic if the current thread is armed to at `point`
+pub(crate) fn reach(point: PanicPoint) {
+    if armed(point) {
+        panic!("panic injected at {:?}", point);
+    }
+}
+
+/// A workload on a `KvsEngine` which can be crashed and checked.
+#[derive(Debug)]
+pub struct CrashTest {
+    dir: PathBuf,
+    engine: Option<KvsEngine>,
+    // the state of the store after every write which can't be lost anymore
+    durable: BTreeMap<String, String>,
+    // the writes to the active log since, with the offset their record ends at
+    pending: Vec<(u64, String, Option<String>)>,
+    // the active log and its length at the last flush
+    log: PathBuf,
+    flushed: u64,
+}
+
+impl CrashTest {
+    /// open a store in `dir`, which should be empty
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        let engine = KvsEngine::open(&dir)?;
+        let (log, flushed) = engine.active_log();
+        Ok(Self {
+            dir,
+            engine: Some(engine),
+            durable: BTreeMap::new(),
+            pending: Vec::new(),
+            log,
+            flushed,
+        })
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.engine().set(key.clone(), value.clone())?;
+        self.acknowledged(key, Some(value));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.engine().remove(key.clone())?;
+        self.acknowledged(key, None);
+        Ok(())
+    }
+
+    /// flush the engine, making every acknowledged write durable
+    pub fn flush(&mut self) -> Result<()> {
+        self.engine().flush()?;
+        self.settle();
+        self.flushed = self.engine().active_log().1;
+        Ok(())
+    }
+
+    /// the offsets of the active log a crash may cut it at
+    pub fn crash_range(&self) -> Range<u64> {
+        self.flushed..self.engine().active_log().1 + 1
+    }
+
+    /// crash, losing the active log from `offset` on, then reopen the store
+    /// and check it holds exactly the writes which survived
+    pub fn crash_at(&mut self, offset: u64) -> Result<()> {
+        let range = self.crash_range();
+        assert!(
+            range.contains(&offset),
+            "cut at {} outside of {:?}",
+            offset,
+            range
+        );
+        self.engine = None;
+        OpenOptions::new()
+            .write(true)
+            .open(&self.log)?
+            .set_len(offset)?;
+
+        for (end, key, value) in self.pending.drain(..) {
+            if end > offset {
+                break;
+            }
+            match value {
+                Some(value) => self.durable.insert(key, value),
+                None => self.durable.remove(&key),
+            };
+        }
+        let engine = KvsEngine::open(&self.dir)?;
+        let (log, flushed) = engine.active_log();
+        self.engine = Some(engine);
+        self.log = log;
+        self.flushed = flushed;
+        self.check()
+    }
+
+    /// compare the store with the writes which should have survived
+    fn check(&self) -> Result<()> {
+        let mut stored = BTreeMap::new();
+        loop {
+            let last = stored.keys().next_back().cloned();
+            let page = self.engine().scan(String::new(), last, 1000)?;
+            if page.is_empty() {
+                break;
+            }
+            stored.extend(page);
+        }
+        if stored == self.durable {
+            return Ok(());
+        }
+        let missing = self
+            .durable
+            .iter()
+            .find(|(key, value)| stored.get(*key) != Some(value));
+        let phantom = stored.keys().find(|key| !self.durable.contains_key(*key));
+        Err(KvsError::StringErr(format!(
+            "store differs from the surviving writes, first missing or stale: {:?}, first phantom: {:?}",
+            missing, phantom
+        )))
+    }
+
+    fn acknowledged(&mut self, key: String, value: Option<String>) {
+        let (log, end) = self.engine().active_log();
+        self.pending.push((end, key, value));
+        if log != self.log {
+            // the write triggered a compaction, which synced every write
+            // so far to the compacted log
+            self.settle();
+            self.log = log;
+            self.flushed = 0;
+        }
+    }
+
+    /// apply all pending writes to the durable state
+    fn settle(&mut self) {
+        for (_, key, value) in self.pending.drain(..) {
+            match value {
+                Some(value) => self.durable.insert(key, value),
+                None => self.durable.remove(&key),
+            };
+        }
+    }
+
+    fn engine(&self) -> &KvsEngine {
+        self.engine
+            .as_ref()
+            .expect("the engine is open between crashes")
+    }
+}
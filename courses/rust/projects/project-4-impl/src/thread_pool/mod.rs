@@ -4,6 +4,7 @@ mod shared_queue_threadpool;
 mod naive;
 
 pub use naive::NaiveThreadPool;
+pub use shared_queue_threadpool::{SharedQueueThreadPool, DEFAULT_CAPACITY};
 
 /// The trait that all thread pools should implement.
 pub trait ThreadPool {
@@ -24,4 +25,18 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Spawns a function into the thread pool only if it can take it right
+    /// away, returning whether it did. A function not taken is dropped.
+    ///
+    /// Pools which queue without bound always take it, like `spawn`. A pool
+    /// with a bounded queue gives up when it is full, so that the caller can
+    /// shed the load instead of blocking.
+    fn try_spawn<F>(&self, job: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(job);
+        true
+    }
 }
\ No newline at end of file
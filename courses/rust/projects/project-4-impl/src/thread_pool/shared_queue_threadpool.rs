@@ -1,21 +1,92 @@
-use std::sync::mpsc;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use tracing::error;
+
 use super::ThreadPool;
 
+/// number of jobs queued before `spawn` blocks, unless given to `with_capacity`
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A thread pool whose threads take their jobs from one shared queue.
+///
+/// The queue is bounded, so that a burst of jobs slower to run than to spawn
+/// can't grow the memory without limit: once it is full, `spawn` blocks until
+/// a thread takes a job, and `try_spawn` gives up.
 pub struct SharedQueueThreadPool {
-    threads: Vec<Worker>,
-    sender: 
+    sender: SyncSender<Job>,
+}
+
+impl SharedQueueThreadPool {
+    /// create a pool of `threads` threads queuing up to `capacity` jobs
+    pub fn with_capacity(threads: u32, capacity: usize) -> crate::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            spawn_worker(JobReceiver(receiver.clone()))?;
+        }
+        Ok(Self { sender })
+    }
 }
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(threads: u32) -> crate::Result<Self>
     where
-        Self: Sized {
-        todo!()
+        Self: Sized,
+    {
+        Self::with_capacity(threads, DEFAULT_CAPACITY)
     }
 
+    /// Blocks while the queue is full.
     fn spawn<F>(&self, job: F)
     where
-        F: FnOnce() + Send + 'static {
-        todo!()
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("the threads live as long as the pool");
     }
-}
\ No newline at end of file
+
+    fn try_spawn<F>(&self, job: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.sender.try_send(Box::new(job)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            Err(TrySendError::Disconnected(_)) => {
+                panic!("the threads live as long as the pool")
+            }
+        }
+    }
+}
+
+/// The end of the queue a thread takes its jobs from. It is dropped when a
+/// job panics, and then replaces the thread, so that the pool keeps its size.
+struct JobReceiver(Arc<Mutex<Receiver<Job>>>);
+
+impl Drop for JobReceiver {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            if let Err(e) = spawn_worker(JobReceiver(self.0.clone())) {
+                error!(msg = "fail to replace a panicked thread of the pool", err = %e);
+            }
+        }
+    }
+}
+
+fn spawn_worker(receiver: JobReceiver) -> crate::Result<()> {
+    thread::Builder::new().spawn(move || loop {
+        // the lock is released before the job runs
+        let job = receiver.0.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            // the pool was dropped
+            Err(_) => break,
+        }
+    })?;
+    Ok(())
+}
@@ -1,4 +1,8 @@
+use std::fs::{File, OpenOptions, TryLockError};
 use std::net::IpAddr;
+use std::path::Path;
+
+use crate::KvsError;
 
 pub fn addr_check(addr: &str) -> bool {
     let ip: Result<IpAddr, _> = addr
@@ -13,3 +17,31 @@ pub fn addr_check(addr: &str) -> bool {
         .parse();
     !(ip.is_err() || port.is_err())
 }
+
+/// An advisory lock on the `LOCK` file of a data directory, which the kvs
+/// binaries hold while they use it, so that a single process owns the data.
+/// Released when dropped, or by the system when the process dies, so a
+/// crash leaves no stale lock behind.
+#[derive(Debug)]
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// take the lock of `dir`, failing at once if another process holds it
+    pub fn acquire(dir: &Path) -> crate::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join("LOCK"))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(KvsError::StringErr(format!(
+                "{} is in use by another kvs process",
+                dir.display()
+            ))),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use kvs::{Engine, KvsEngine, Result};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tempfile::TempDir;
+
+// The system allocator, counting the bytes allocated
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size.saturating_sub(layout.size()), Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+// bytes allocated while running `f`
+fn allocated_by<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let res = f();
+    (res, ALLOCATED.load(Ordering::SeqCst) - before)
+}
+
+// A value read as `Bytes` should be the bytes of the stored value, read with
+// no more allocations than as a `String`
+#[test]
+fn get_bytes_matches_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("key1")?, None);
+    for value in ["value1", "", "naïve café 🦀"] {
+        store.set("key1".to_owned(), value.to_owned())?;
+        assert_eq!(store.get_bytes("key1")?.unwrap(), value.as_bytes());
+    }
+
+    let large = "🦀".repeat(1 << 18);
+    store.set("key2".to_owned(), large.clone())?;
+    let (value, as_string) = allocated_by(|| store.get("key2"));
+    assert_eq!(value?, Some(large.clone()));
+    let (bytes, as_bytes) = allocated_by(|| store.get_bytes("key2"));
+    assert_eq!(bytes?.unwrap(), large.as_bytes());
+    // no second buffer of the size of the value
+    assert!(
+        as_bytes < as_string + large.len() / 2,
+        "{} bytes allocated as Bytes, {} as a String",
+        as_bytes,
+        as_string
+    );
+    Ok(())
+}
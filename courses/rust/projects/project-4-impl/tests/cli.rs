@@ -1,7 +1,12 @@
 use assert_cmd::prelude::*;
+use kvs::{Client, Engine, KvsEngine, SledKvsEngine, FORMAT_VERSION};
 use predicates::str::{contains, is_empty};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::process::Command;
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -212,6 +217,514 @@ fn cli_wrong_engine() {
     }
 }
 
+/// run `kvs_server` with `args` in `dir` for a second, and return its log
+fn server_log(dir: &TempDir, args: &[&str]) -> String {
+    let log_path = dir.path().join("log");
+    let mut child = Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(args)
+        .args(["--addr", "127.0.0.1:0"])
+        .current_dir(dir)
+        .stdout(File::create(&log_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    fs::read_to_string(&log_path).unwrap()
+}
+
+#[test]
+fn cli_engine_of_empty_dir_from_flag() {
+    let temp_dir = TempDir::new().unwrap();
+    let log = server_log(&temp_dir, &["--engine", "sled"]);
+    assert!(log.contains(r#""engine":"sled""#));
+    assert!(temp_dir.path().join("db").is_file());
+
+    let temp_dir = TempDir::new().unwrap();
+    let log = server_log(&temp_dir, &[]);
+    assert!(log.contains(r#""engine":"kvs""#));
+}
+
+#[test]
+fn cli_engine_detected_from_kvs_files() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvsEngine::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+    let log = server_log(&temp_dir, &[]);
+    assert!(log.contains(r#""engine":"kvs""#));
+    let log = server_log(&temp_dir, &["--engine", "kvs"]);
+    assert!(log.contains(r#""engine":"kvs""#));
+}
+
+#[test]
+fn cli_engine_detected_from_sled_files() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = SledKvsEngine::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+    let log = server_log(&temp_dir, &[]);
+    assert!(log.contains(r#""engine":"sled""#));
+    let log = server_log(&temp_dir, &["--engine", "sled"]);
+    assert!(log.contains(r#""engine":"sled""#));
+}
+
+#[test]
+fn cli_engine_conflicting_with_files() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvsEngine::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+    Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("holds data of the kvs engine"));
+    // the kvs data is left alone
+    assert!(!temp_dir.path().join("db").exists());
+}
+
+#[test]
+fn cli_check_clean_and_corrupted() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvsEngine::open(temp_dir.path()).unwrap();
+        for i in 0..10 {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        store.remove("key0").unwrap();
+    }
+    let log_path = temp_dir.path().join("1.log");
+    let check = || {
+        Command::cargo_bin("kvs_server")
+            .unwrap()
+            .arg("--check")
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    check()
+        .success()
+        .stdout(contains(r#""db":"default""#))
+        .stdout(contains(r#""keys_recovered":9,"records":11"#))
+        .stdout(contains(r#""corruption":[]"#));
+    // nothing was written, not even a new log
+    assert!(!temp_dir.path().join("2.log").exists());
+
+    // a record torn by a crash is skipped, the store is still clean
+    let mut log = fs::read_to_string(&log_path).unwrap();
+    fs::write(&log_path, format!("{}[1,{{\"Set\"", log)).unwrap();
+    check()
+        .success()
+        .stdout(contains(r#""records_skipped":1"#))
+        .stdout(contains(r#""corruption":[]"#));
+
+    // the third record no longer parses
+    let offset = log.match_indices("][").nth(1).unwrap().0 + 1;
+    log.replace_range(offset..offset + 1, "}");
+    fs::write(&log_path, log).unwrap();
+    check()
+        .code(2)
+        .stdout(contains(r#""keys_recovered":2"#))
+        .stdout(contains(format!(r#""file_id":1,"offset":{}"#, offset)));
+}
+
+// `kvs_server bench` should run both workloads on a store of its own,
+// reporting positive throughputs, and leave the current directory alone
+#[test]
+fn cli_bench() {
+    for engine in ["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let output = Command::cargo_bin("kvs_server")
+            .unwrap()
+            .args([
+                "bench",
+                "--engine",
+                engine,
+                "--ops",
+                "500",
+                "--value-size",
+                "64",
+            ])
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let reports: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(reports.len(), 2);
+        for (report, op) in reports.iter().zip(["set", "get"]) {
+            assert_eq!(report["engine"], engine);
+            assert_eq!(report["op"], op);
+            assert_eq!(report["ops"], 500);
+            assert_eq!(report["value_size"], 64);
+            let ops_per_sec = report["ops_per_sec"].as_f64().unwrap();
+            assert!(ops_per_sec.is_finite() && ops_per_sec > 0.0);
+        }
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+    }
+
+    Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(["bench", "--ops", "0"])
+        .assert()
+        .failure();
+}
+
+// run the sets of `kvs_server bench` on the kvs engine with `--durability
+// mode`, checking only that the mode is accepted and the sets succeed
+fn bench_set(mode: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let output = Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(["--durability", mode, "bench", "--ops", "200"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let report: serde_json::Value = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .next()
+        .unwrap();
+    assert_eq!(report["op"], "set");
+}
+
+// Every durability mode should be accepted, and a malformed one refused. What
+// each mode syncs is checked against the engines, see `kvs_flush_policy_syncs`
+#[test]
+fn cli_durability_modes() {
+    for mode in ["sync", "buffered", "periodic:100"] {
+        bench_set(mode);
+    }
+
+    Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(["--durability", "periodic:0", "bench"])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(["--durability", "sometimes", "bench"])
+        .assert()
+        .failure();
+}
+
+// A write acknowledged by a server syncing every write should be there once
+// the server is killed and restarted, for both engines
+#[test]
+fn cli_durability_sync_survives_restart() {
+    for engine in ["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = "127.0.0.1:4047";
+        let start = || {
+            let child = Command::cargo_bin("kvs_server")
+                .unwrap()
+                .args(["--engine", engine, "--addr", addr, "--durability", "sync"])
+                .current_dir(&temp_dir)
+                .spawn()
+                .unwrap();
+            thread::sleep(Duration::from_secs(1));
+            child
+        };
+
+        let mut child = start();
+        Command::cargo_bin("kvs_client")
+            .unwrap()
+            .args(["--addr", addr, "set", "key1", "value1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        // SIGKILL, no chance to flush on shutdown
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+
+        let mut child = start();
+        Command::cargo_bin("kvs_client")
+            .unwrap()
+            .args(["--addr", addr, "get", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+}
+
+// A server started on the data directory of a running one should fail
+// before detecting the engine, leaving the data to the first
+#[test]
+fn cli_second_server_on_same_dir_fails() {
+    for (engine, other) in [("kvs", "sled"), ("sled", "kvs")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut first = Command::cargo_bin("kvs_server")
+            .unwrap()
+            .args(["--stdio", "--engine", engine])
+            .current_dir(&temp_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut client = Client::connect_stdio(&mut first).unwrap();
+        client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+        for name in [engine, other] {
+            Command::cargo_bin("kvs_server")
+                .unwrap()
+                .args(["--stdio", "--engine", name])
+                .current_dir(&temp_dir)
+                .assert()
+                .failure()
+                .stderr(contains("is in use by another kvs process"));
+        }
+        assert_eq!(
+            client.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+        drop(client);
+        assert!(first.wait().unwrap().success());
+
+        // the lock goes with the server
+        let value = match engine {
+            "kvs" => KvsEngine::open(temp_dir.path()).unwrap().get("key1"),
+            _ => SledKvsEngine::open(temp_dir.path()).unwrap().get("key1"),
+        };
+        assert_eq!(value.unwrap(), Some("value1".to_owned()));
+    }
+}
+
+// `kvs_admin dump-records` should list every record written, superseded
+// and removed ones included
+#[test]
+fn cli_dump_records() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvsEngine::open(temp_dir.path()).unwrap();
+        for i in 0..5 {
+            store.set(format!("key{}", i), "value".to_owned()).unwrap();
+        }
+        store
+            .set("key0".to_owned(), "longer value".to_owned())
+            .unwrap();
+        store.remove("key1").unwrap();
+        store.remove("key2").unwrap();
+    }
+    let dump = |args: &[&str]| {
+        let output = Command::cargo_bin("kvs_admin")
+            .unwrap()
+            .arg("dump-records")
+            .arg(temp_dir.path())
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let records = dump(&[]);
+    let lines: Vec<&str> = records.lines().collect();
+    assert_eq!(lines.len(), 8);
+    assert_eq!(records.matches(r#""op":"set""#).count(), 6);
+    assert_eq!(records.matches(r#""op":"remove""#).count(), 2);
+    assert!(lines[0].starts_with(r#"{"file_id":1,"offset":0,"#));
+    assert!(lines[5].contains(r#""key":"key0","value_len":12"#));
+    assert!(lines[7].contains(r#""op":"remove","key":"key2""#));
+    assert_eq!(dump(&["--file", "1"]), records);
+
+    // a torn record ends the dump of its log
+    let log_path = temp_dir.path().join("1.log");
+    let log = fs::read_to_string(&log_path).unwrap();
+    fs::write(&log_path, format!("{}[1,{{\"Set\"", log)).unwrap();
+    let records = dump(&[]);
+    assert_eq!(records.lines().count(), 9);
+    assert!(records.lines().last().unwrap().contains(r#""op":"torn""#));
+
+    Command::cargo_bin("kvs_admin")
+        .unwrap()
+        .args(["dump-records", "--file", "7"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("no log file 7"));
+}
+
+// `kvs_admin export` should print the live pairs as a JSON object, which
+// `kvs_admin import` loads back
+#[test]
+fn cli_export_import_json() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut expected = BTreeMap::new();
+    {
+        let store = KvsEngine::open(temp_dir.path()).unwrap();
+        for i in 0..100 {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            expected.insert(format!("key{}", i), format!("value{}", i));
+        }
+        store.remove("key0").unwrap();
+        expected.remove("key0");
+        for (key, value) in [("quote\"d", "line\nbreak"), ("ключ", "значение"), ("", "")]
+        {
+            store.set(key.to_owned(), value.to_owned()).unwrap();
+            expected.insert(key.to_owned(), value.to_owned());
+        }
+    }
+    let export = || {
+        let output = Command::cargo_bin("kvs_admin")
+            .unwrap()
+            .args(["export", "--format", "json"])
+            .arg(temp_dir.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    };
+    let json = export();
+    let pairs: BTreeMap<String, String> = serde_json::from_slice(&json).unwrap();
+    assert_eq!(pairs, expected);
+
+    // clear the store, then import the export back
+    {
+        let store = KvsEngine::open(temp_dir.path()).unwrap();
+        for key in expected.keys() {
+            store.remove(key).unwrap();
+        }
+    }
+    assert_eq!(export(), b"{}\n");
+    let json_path = temp_dir.path().join("export.json");
+    fs::write(&json_path, &json).unwrap();
+    Command::cargo_bin("kvs_admin")
+        .unwrap()
+        .args(["import", "--format", "json"])
+        .arg(temp_dir.path())
+        .stdin(File::open(&json_path).unwrap())
+        .assert()
+        .success()
+        .stderr(contains("imported 102 pairs"));
+    assert_eq!(export(), json);
+
+    // not a JSON object of strings
+    fs::write(&json_path, r#"{"key": 1}"#).unwrap();
+    Command::cargo_bin("kvs_admin")
+        .unwrap()
+        .args(["import", "--format", "json"])
+        .arg(temp_dir.path())
+        .stdin(File::open(&json_path).unwrap())
+        .assert()
+        .failure();
+}
+
+// `kvs_admin export` should refuse a directory holding no kvs store, neither
+// creating a missing one nor writing a log in the data of the sled engine
+#[test]
+fn cli_export_refuses_foreign_directories() {
+    let temp_dir = TempDir::new().unwrap();
+    let missing = temp_dir.path().join("missing");
+    Command::cargo_bin("kvs_admin")
+        .unwrap()
+        .arg("export")
+        .arg(&missing)
+        .assert()
+        .failure()
+        .stderr(contains("holds no kvs store"));
+    assert!(!missing.exists());
+
+    let sled_dir = temp_dir.path().join("sled");
+    drop(SledKvsEngine::open(&sled_dir).unwrap());
+    let entries = || fs::read_dir(&sled_dir).unwrap().count();
+    let before = entries();
+    for subcommand in ["export", "import"] {
+        Command::cargo_bin("kvs_admin")
+            .unwrap()
+            .arg(subcommand)
+            .arg(&sled_dir)
+            .stdin(Stdio::null())
+            .assert()
+            .failure()
+            .stderr(contains("holds data of the sled engine"));
+    }
+    assert_eq!(entries(), before);
+}
+
+// `kvs_admin upgrade` should rewrite a store of an older record format in the
+// current one, in place or into a new directory, with the same live pairs,
+// and refuse a store already current
+#[test]
+fn cli_upgrade() {
+    let old_store = || {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(
+            temp_dir.path().join("1.log"),
+            r#"{"Set":{"key":"key1","value":"old1"}}{"Set":{"key":"key2","value":"old2"}}{"Remove":{"key":"key2"}}{"Set":{"key":"key3","value":"old3"}}"#,
+        )
+        .unwrap();
+        assert_eq!(KvsEngine::format_version(temp_dir.path()).unwrap(), Some(0));
+        temp_dir
+    };
+    let check = |dir: &Path| {
+        assert_eq!(
+            KvsEngine::format_version(dir).unwrap(),
+            Some(FORMAT_VERSION)
+        );
+        let store = KvsEngine::open(dir).unwrap();
+        assert_eq!(store.get("key1").unwrap(), Some("old1".to_owned()));
+        assert_eq!(store.get("key2").unwrap(), None);
+        assert_eq!(store.get("key3").unwrap(), Some("old3".to_owned()));
+        assert_eq!(store.stats().unwrap().keys, 2);
+    };
+    let upgrade = |args: &[&OsStr]| {
+        let mut cmd = Command::cargo_bin("kvs_admin").unwrap();
+        cmd.arg("upgrade").args(args);
+        cmd
+    };
+
+    let temp_dir = old_store();
+    upgrade(&[temp_dir.path().as_os_str()])
+        .assert()
+        .success()
+        .stderr(contains("from format version 0 to 1: rewrote 2 pairs"));
+    check(temp_dir.path());
+    upgrade(&[temp_dir.path().as_os_str()])
+        .assert()
+        .failure()
+        .stderr(contains("already at format version 1"));
+
+    let temp_dir = old_store();
+    let dest_dir = TempDir::new().unwrap();
+    let dest = dest_dir.path().join("upgraded");
+    upgrade(&[
+        temp_dir.path().as_os_str(),
+        "--to".as_ref(),
+        dest.as_os_str(),
+    ])
+    .assert()
+    .success()
+    .stderr(contains("rewrote 2 pairs"));
+    check(&dest);
+    assert_eq!(KvsEngine::format_version(temp_dir.path()).unwrap(), Some(0));
+    upgrade(&[
+        temp_dir.path().as_os_str(),
+        "--to".as_ref(),
+        dest.as_os_str(),
+    ])
+    .assert()
+    .failure()
+    .stderr(contains("already holds a store"));
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -264,8 +777,8 @@ fn cli_access_server(engine: &str, addr: &str) {
         .args(&["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .success()
-        .stdout(contains("Key not found"));
+        .code(1)
+        .stdout("Key not found\n");
 
     Command::cargo_bin("kvs_client")
         .unwrap()
@@ -320,8 +833,8 @@ fn cli_access_server(engine: &str, addr: &str) {
         .args(&["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .success()
-        .stdout(contains("Key not found"));
+        .code(1)
+        .stdout("Key not found\n");
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -335,3 +848,297 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs_server --pid-file` should write its pid and remove the file on SIGTERM
+#[cfg(unix)]
+#[test]
+fn cli_pid_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let pid_path = temp_dir.path().join("kvs.pid");
+    let mut child = Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4006", "--pid-file"])
+        .arg(&pid_path)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let pid = fs::read_to_string(&pid_path).expect("pid file is not created");
+    assert_eq!(pid, child.id().to_string());
+
+    Command::new("kill")
+        .args(["-TERM", &pid])
+        .assert()
+        .success();
+    let status = child.wait().unwrap();
+    assert!(status.success());
+    assert!(!pid_path.exists());
+}
+
+// `kvs_server --log-file` should write to a new file at the path once the
+// old one is renamed and SIGHUP sent, as logrotate does
+#[cfg(unix)]
+#[test]
+fn cli_log_file_reopened_on_sighup() {
+    let temp_dir = TempDir::new().unwrap();
+    let log_path = temp_dir.path().join("kvs.log");
+    let rotated_path = temp_dir.path().join("kvs.log.1");
+    let mut child = Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4024", "--log-file"])
+        .arg(&log_path)
+        .current_dir(&temp_dir)
+        .stdout(File::create(temp_dir.path().join("stdout")).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    fs::rename(&log_path, &rotated_path).unwrap();
+    let pid = child.id().to_string();
+    Command::new("kill").args(["-HUP", &pid]).assert().success();
+    thread::sleep(Duration::from_millis(500));
+    Command::new("kill")
+        .args(["-TERM", &pid])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+
+    let rotated = fs::read_to_string(&rotated_path).unwrap();
+    assert!(rotated.contains("starting the server"));
+    assert!(!rotated.contains("shutting down"));
+    let log = fs::read_to_string(&log_path).expect("log file is not reopened");
+    assert!(log.contains("reopened log file"));
+    assert!(log.contains("shutting down"));
+    let stdout = fs::read_to_string(temp_dir.path().join("stdout")).unwrap();
+    assert!(stdout.is_empty());
+}
+
+// `kvs_client get` should tell a missing key from a stored "nil" by its exit code
+#[test]
+fn cli_get_missing_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "nil"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(0)
+        .stdout("nil\n");
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stdout("Key not found\n");
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs_client exists` should print whether the key exists, and succeed either
+// way
+#[test]
+fn cli_exists() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4045";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "exists", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(0)
+        .stdout("true\n");
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "exists", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(0)
+        .stdout("false\n");
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs_client get` should print an empty line for an empty value, and only
+// fail on a missing key
+#[test]
+fn cli_get_empty_value() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", ""])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(0)
+        .stdout("\n");
+    Command::cargo_bin("kvs_client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stdout("Key not found\n");
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// number of `count` simultaneous connections which get through to a server
+// started with `--backlog backlog`, busy with another one meanwhile
+fn connections_queued(backlog: &str, count: usize) -> usize {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4027";
+    let mut child = Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(["--addr", addr, "--backlog", backlog])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let addr = addr.parse().unwrap();
+    // served until dropped, the others wait in the backlog
+    let _busy = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let queued: Vec<_> = (0..count)
+        .filter_map(|_| TcpStream::connect_timeout(&addr, Duration::from_millis(200)).ok())
+        .collect();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    queued.len()
+}
+
+// A larger backlog should queue more of a burst of connections. Best effort:
+// how a full backlog drops connections depends on the system.
+#[test]
+fn cli_backlog() {
+    let small = connections_queued("1", 20);
+    let large = connections_queued("256", 20);
+    assert_eq!(large, 20);
+    assert!(small < large, "{} queued with a backlog of 1", small);
+}
+
+// With `--threads`, a client should be served while another connection is
+// open, rather than wait behind it
+#[test]
+fn cli_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4054";
+    let mut child = Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(["--addr", addr, "--threads", "2"])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let _busy = TcpStream::connect(addr).unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let res = Client::connect(addr).and_then(|mut client| {
+            client.set("key1".to_owned(), "value1".to_owned())?;
+            client.get("key1".to_owned())
+        });
+        let _ = sender.send(res);
+    });
+    let res = receiver.recv_timeout(Duration::from_secs(5));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert_eq!(
+        res.expect("the client waited behind the open connection")
+            .unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Accepted connections should get `TCP_NODELAY` unless turned off
+#[test]
+fn cli_no_delay() {
+    for (args, nodelay) in [
+        (&[][..], r#""nodelay":true"#),
+        (&["--no-delay", "false"][..], r#""nodelay":false"#),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = "127.0.0.1:4009";
+        let log_path = temp_dir.path().join("log");
+        let mut child = Command::cargo_bin("kvs_server")
+            .unwrap()
+            .args(["--addr", addr])
+            .args(args)
+            .current_dir(&temp_dir)
+            .stdout(File::create(&log_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        let res = Command::cargo_bin("kvs_client")
+            .unwrap()
+            .args(["--addr", addr, "get", "key1"])
+            .current_dir(&temp_dir)
+            .output();
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+        res.unwrap();
+        let log = fs::read_to_string(&log_path).unwrap();
+        assert!(log.contains(nodelay), "{}", log);
+    }
+}
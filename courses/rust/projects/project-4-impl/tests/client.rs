@@ -0,0 +1,1438 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    BatchOp, Client, Engine, EngineStats, ErrorResp, GetResp, KvClient, KvsEngine, KvsError,
+    LoopbackClient, Middleware, Request, Response, Result, Server, SetResp, ShardedClient,
+    ValueFilter,
+};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// start a kvs server on `addr` in a background thread
+fn start_server(addr: &'static str) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvsEngine::open(temp_dir.path()).unwrap();
+    thread::spawn(move || Server::new(engine).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(200));
+    temp_dir
+}
+
+// Keys should be stored on the shard the ring assigns them to,
+// and spread roughly evenly between shards.
+#[test]
+fn sharded_client_routes_keys() -> Result<()> {
+    let addrs = ["127.0.0.1:4010", "127.0.0.1:4011", "127.0.0.1:4012"];
+    let _dirs: Vec<_> = addrs.iter().map(|addr| start_server(addr)).collect();
+
+    let mut client = ShardedClient::connect(&addrs)?;
+    let mut per_shard = HashMap::new();
+    for i in 0..300 {
+        let key = format!("key{}", i);
+        *per_shard
+            .entry(client.shard_for(&key).unwrap().to_owned())
+            .or_insert(0) += 1;
+        client.set(key, format!("value{}", i))?;
+    }
+    for addr in &addrs {
+        let count = per_shard[*addr];
+        assert!(count > 50 && count < 150, "{} owns {} keys", addr, count);
+    }
+    // the ring only depends on the addresses, every client builds the same
+    let shards: Vec<_> = (0..10)
+        .map(|i| client.shard_for(&format!("key{}", i)).unwrap())
+        .collect();
+    let [a, b, c] = addrs;
+    assert_eq!(shards, [a, c, a, a, c, c, c, b, b, c]);
+
+    // every key is on its shard and only there, the servers handle
+    // connections one by one so the sharded client disconnects first
+    let owners: Vec<_> = (0..300)
+        .map(|i| client.shard_for(&format!("key{}", i)).unwrap().to_owned())
+        .collect();
+    drop(client);
+    for addr in &addrs {
+        let mut shard = Client::connect(addr)?;
+        for (i, owner) in owners.iter().enumerate() {
+            let key = format!("key{}", i);
+            let expected = if owner == addr {
+                Some(format!("value{}", i))
+            } else {
+                None
+            };
+            assert_eq!(shard.get(key)?, expected);
+        }
+    }
+    let mut client = ShardedClient::connect(&addrs)?;
+    for i in 0..300 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    // adding a server only remaps a fraction of the keys
+    let _dir = start_server("127.0.0.1:4013");
+    let before = owners;
+    client.add_server("127.0.0.1:4013")?;
+    let moved = (0..300)
+        .filter(|i| client.shard_for(&format!("key{}", i)).unwrap() != before[*i])
+        .count();
+    assert!(moved > 0 && moved < 150, "{} keys moved", moved);
+    for (i, owner) in before.iter().enumerate() {
+        let key = format!("key{}", i);
+        let shard = client.shard_for(&key).unwrap();
+        assert!(shard == owner || shard == "127.0.0.1:4013");
+    }
+
+    Ok(())
+}
+
+// An engine failing with an I/O error for its first `failures` calls, then
+// panicking after the next `drops` ones, so that a server serving on a pool
+// drops the connection of a request it applied without answering it.
+#[derive(Debug, Clone)]
+struct FlakyEngine {
+    calls: Arc<AtomicUsize>,
+    failures: usize,
+    drops: usize,
+}
+
+impl FlakyEngine {
+    fn call(&self) -> Result<()> {
+        let call = self.calls.fetch_add(1, Ordering::SeqCst);
+        if call < self.failures {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "simulated I/O error").into())
+        } else if call < self.failures + self.drops {
+            panic!("simulated dropped response");
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Engine for FlakyEngine {
+    fn open(_path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(Self {
+            calls: Arc::new(AtomicUsize::new(0)),
+            failures: 0,
+            drops: 0,
+        })
+    }
+
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        self.call()
+    }
+
+    fn get(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        self.call()?;
+        Ok(Some(key.as_ref().to_owned()))
+    }
+
+    fn remove(&self, _key: impl AsRef<str>) -> Result<()> {
+        self.call()?;
+        Err(KvsError::KeyNotFound)
+    }
+
+    fn remove_if(&self, _key: impl AsRef<str>, _expected: impl AsRef<str>) -> Result<bool> {
+        self.call()?;
+        Ok(false)
+    }
+
+    fn append(&self, _key: String, suffix: String) -> Result<usize> {
+        self.call()?;
+        Ok(suffix.len())
+    }
+
+    fn set_all(&self, _pairs: Vec<(String, String)>) -> Result<()> {
+        self.call()
+    }
+
+    fn rename(&self, _from: String, _to: String) -> Result<()> {
+        self.call()
+    }
+
+    fn remove_prefix(&self, _prefix: String) -> Result<usize> {
+        self.call()?;
+        Ok(0)
+    }
+
+    fn count_prefix(&self, _prefix: String) -> Result<usize> {
+        self.call()?;
+        Ok(0)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.call()?;
+        Ok(EngineStats::default())
+    }
+
+    fn scan(&self, _: String, _: Option<String>, _: usize) -> Result<Vec<(String, String)>> {
+        self.call()?;
+        Ok(Vec::new())
+    }
+}
+
+// Retryable errors should be retried, the others should not.
+#[test]
+fn client_retries_only_retryable_errors() -> Result<()> {
+    assert!(KvsError::IoErr(io::Error::new(io::ErrorKind::TimedOut, "timeout")).is_retryable());
+    assert!(!KvsError::KeyNotFound.is_retryable());
+    assert!(!KvsError::CommandNotSupported.is_retryable());
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let engine = FlakyEngine {
+        calls: calls.clone(),
+        failures: 2,
+        drops: 0,
+    };
+    thread::spawn(move || Server::new(engine).run("127.0.0.1:4014").unwrap());
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = Client::connect("127.0.0.1:4014")?;
+    client.set_retries(3);
+    // two simulated I/O errors, then success
+    assert_eq!(client.get("key1".to_owned())?, Some("key1".to_owned()));
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    // a missing key is reported right away
+    match client.remove("key1".to_owned()) {
+        Err(KvsError::Server { retryable, .. }) => assert!(!retryable),
+        res => panic!("unexpected result {:?}", res.map_err(|e| e.to_string())),
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+    // a request which mustn't be applied twice isn't resent once sent
+    calls.store(0, Ordering::SeqCst);
+    match client.append("key1".to_owned(), "a".to_owned()) {
+        Err(e) => assert!(e.is_retryable()),
+        Ok(len) => panic!("unexpected length {}", len),
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    calls.store(0, Ordering::SeqCst);
+    match client.discard("key1".to_owned()) {
+        Err(e) => assert!(e.is_retryable()),
+        Ok(removed) => panic!("unexpected result {}", removed),
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    calls.store(0, Ordering::SeqCst);
+    match client.remove_if("key1".to_owned(), "value1".to_owned()) {
+        Err(e) => assert!(e.is_retryable()),
+        Ok(removed) => panic!("unexpected result {}", removed),
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // without retries the I/O error reaches the caller
+    calls.store(0, Ordering::SeqCst);
+    client.set_retries(0);
+    match client.get("key1".to_owned()) {
+        Err(e) => assert!(e.is_retryable()),
+        Ok(v) => panic!("unexpected value {:?}", v),
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+// A request which mustn't be applied twice shouldn't be resent once the
+// server applied it, even if the response never comes.
+#[test]
+fn client_does_not_resend_applied_requests() -> Result<()> {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let engine = FlakyEngine {
+        calls: calls.clone(),
+        failures: 0,
+        drops: 1,
+    };
+    thread::spawn(move || {
+        Server::new(engine)
+            .threads(2)
+            .run("127.0.0.1:4055")
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = Client::connect("127.0.0.1:4055")?;
+    client.set_retries(3);
+    match client.rename("key1".to_owned(), "key2".to_owned()) {
+        Err(e) => assert!(e.is_retryable()),
+        Ok(()) => panic!("unexpected rename"),
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    // the next request goes over a new connection
+    client.rename("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    calls.store(0, Ordering::SeqCst);
+    match client.delete_prefix("key".to_owned()) {
+        Err(e) => assert!(e.is_retryable()),
+        Ok(count) => panic!("unexpected count {}", count),
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    calls.store(0, Ordering::SeqCst);
+    match client.remove("key1".to_owned()) {
+        Err(e) => assert!(e.is_retryable()),
+        Ok(()) => panic!("unexpected remove"),
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // a request failing on its last attempt leaves the connection to a new one
+    calls.store(0, Ordering::SeqCst);
+    client.set_retries(0);
+    assert!(client.get("key1".to_owned()).is_err());
+    assert_eq!(client.get("key1".to_owned())?, Some("key1".to_owned()));
+    Ok(())
+}
+
+// client logic written against `KvClient`, shared by the tests below
+fn client_logic<C: KvClient>(client: &mut C) -> Result<()> {
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    match client.remove("key1".to_owned()) {
+        Err(KvsError::Server { retryable, .. }) => assert!(!retryable),
+        res => panic!("unexpected result {:?}", res.map_err(|e| e.to_string())),
+    }
+    assert!(!client.discard("key1".to_owned())?);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(client.discard("key1".to_owned())?);
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(!client.remove_if("key1".to_owned(), "value2".to_owned())?);
+    assert!(client.remove_if("key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.append("key2".to_owned(), "ab".to_owned())?, 2);
+    assert_eq!(client.append("key2".to_owned(), "cde".to_owned())?, 5);
+    assert_eq!(client.get("key2".to_owned())?, Some("abcde".to_owned()));
+    client.set("key3".to_owned(), String::new())?;
+    assert_eq!(client.get("key3".to_owned())?, Some(String::new()));
+    assert_eq!(client.get("key4".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn tcp_client_logic() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4015");
+    client_logic(&mut Client::connect("127.0.0.1:4015")?)
+}
+
+// The server binary should serve the process which spawned it over its pipes
+#[test]
+fn stdio_client_logic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut child = Command::cargo_bin("kvs_server")
+        .unwrap()
+        .arg("--stdio")
+        .current_dir(&temp_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut client = Client::connect_stdio(&mut child)?;
+    client_logic(&mut client)?;
+    assert_eq!(client.stats()?.keys, 2);
+
+    // closing stdin stops the server
+    drop(client);
+    assert!(child.wait()?.success());
+    Ok(())
+}
+
+fn spawn_stdio_server(temp_dir: &TempDir) -> Result<std::process::Child> {
+    Ok(Command::cargo_bin("kvs_server")
+        .unwrap()
+        .args(["--stdio", "--engine", "sled"])
+        .current_dir(temp_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?)
+}
+
+// Writes flushed on request should survive the server being killed before
+// the engine flushes them on its own
+#[test]
+fn flushed_writes_survive_kill() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut child = spawn_stdio_server(&temp_dir)?;
+    let mut client = Client::connect_stdio(&mut child)?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.flush()?;
+    child.kill()?;
+    child.wait()?;
+    drop(client);
+
+    let mut child = spawn_stdio_server(&temp_dir)?;
+    let mut client = Client::connect_stdio(&mut child)?;
+    for i in 0..100 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    drop(client);
+    assert!(child.wait()?.success());
+    Ok(())
+}
+
+#[test]
+fn loopback_client_logic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    client_logic(&mut LoopbackClient::new(KvsEngine::open(temp_dir.path())?))
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct User {
+    name: String,
+    age: u32,
+    tags: Vec<String>,
+}
+
+// Typed values should round-trip through the server as JSON
+#[test]
+fn typed_values() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4022");
+    let mut client = Client::connect("127.0.0.1:4022")?;
+    let user = User {
+        name: "ann".to_owned(),
+        age: 42,
+        tags: vec!["admin".to_owned()],
+    };
+    client.set_as("user:1".to_owned(), &user)?;
+    assert_eq!(client.get_as::<User>("user:1".to_owned())?, Some(user));
+    assert_eq!(client.get_as::<User>("user:2".to_owned())?, None);
+    assert_eq!(
+        client.get("user:1".to_owned())?.as_deref(),
+        Some(r#"{"name":"ann","age":42,"tags":["admin"]}"#)
+    );
+
+    // a value which isn't of the type is an error, not a missing key
+    client.set("count".to_owned(), "3".to_owned())?;
+    assert_eq!(client.get_as::<u64>("count".to_owned())?, Some(3));
+    assert!(matches!(
+        client.get_as::<User>("count".to_owned()),
+        Err(KvsError::SerdeErr(_))
+    ));
+    Ok(())
+}
+
+// Scanning should walk all matching keys in order, one page at a time.
+#[test]
+fn scan_in_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvsEngine::open(temp_dir.path())?;
+    for i in 0..100_000 {
+        engine.set(format!("key{:06}", i), format!("value{}", i))?;
+    }
+    engine.set("other".to_owned(), "value".to_owned())?;
+    let server_engine = engine.clone();
+    thread::spawn(move || Server::new(server_engine).run("127.0.0.1:4016").unwrap());
+    thread::sleep(Duration::from_millis(200));
+    let mut client = Client::connect("127.0.0.1:4016")?;
+
+    let mut page = client.scan_start("key".to_owned(), 1000)?;
+    let mut pages = 1;
+    let mut expected = 0;
+    loop {
+        assert!(page.entries.len() <= 1000);
+        for (key, value) in page.entries {
+            assert_eq!(key, format!("key{:06}", expected));
+            assert_eq!(value, format!("value{}", expected));
+            expected += 1;
+        }
+        match page.cursor {
+            Some(cursor) => page = client.scan_next(cursor, 1000)?,
+            None => break,
+        }
+        pages += 1;
+    }
+    assert_eq!(expected, 100_000);
+    assert!(pages == 100 || pages == 101);
+
+    // writes between pages are seen by the rest of the scan
+    engine.remove("key000150")?;
+    let keys: Vec<String> = client
+        .scan("key0001".to_owned(), 7)
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys.len(), 99);
+    assert_eq!(keys.first().unwrap(), "key000100");
+    assert_eq!(keys.last().unwrap(), "key000199");
+    assert!(!keys.contains(&"key000150".to_owned()));
+
+    assert_eq!(client.scan("nothing".to_owned(), 10).count(), 0);
+    assert!(client.scan_next(12345, 10).is_err());
+    Ok(())
+}
+
+// Clients with different prefixes should each see only their own keys
+#[test]
+fn prefixed_clients_are_isolated() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4025");
+    let connect = |prefix: &str| -> Result<Client> {
+        Ok(Client::connect("127.0.0.1:4025")?.with_prefix(prefix.to_owned()))
+    };
+
+    let mut app1 = connect("app1:")?;
+    for i in 0..10 {
+        app1.set(format!("key{}", i), "one".to_owned())?;
+    }
+    drop(app1);
+    let mut app2 = connect("app2:")?;
+    app2.set("key0".to_owned(), "two".to_owned())?;
+    assert_eq!(app2.get("key0".to_owned())?, Some("two".to_owned()));
+    assert_eq!(app2.get("key1".to_owned())?, None);
+    app2.remove("key0".to_owned())?;
+    assert!(app2.remove("key1".to_owned()).is_err());
+    assert!(!app2.discard("key9".to_owned())?);
+    assert_eq!(app2.scan(String::new(), 3).count(), 0);
+    drop(app2);
+
+    let mut app1 = connect("app1:")?;
+    assert_eq!(app1.get("key0".to_owned())?, Some("one".to_owned()));
+    assert_eq!(app1.append("key0".to_owned(), "!".to_owned())?, 4);
+    assert!(app1.remove_if("key0".to_owned(), "one!".to_owned())?);
+    // scans add the prefix to the scanned one and strip it from the keys
+    let keys: Vec<String> = app1
+        .scan("key".to_owned(), 3)
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    let expected: Vec<String> = (1..10).map(|i| format!("key{}", i)).collect();
+    assert_eq!(keys, expected);
+    drop(app1);
+
+    // a client without prefix sees the keys as stored
+    let mut plain = connect("")?;
+    assert_eq!(plain.get("app1:key1".to_owned())?, Some("one".to_owned()));
+    assert_eq!(plain.get("key1".to_owned())?, None);
+    assert_eq!(plain.scan(String::new(), 3).count(), 9);
+    Ok(())
+}
+
+// A rename should reach the engine, its errors coming back as server errors
+#[test]
+fn client_rename() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4026");
+    let mut client = Client::connect("127.0.0.1:4026")?;
+    client.set("staging:x".to_owned(), "1".to_owned())?;
+    client.set("prod:x".to_owned(), "0".to_owned())?;
+    client.rename("staging:x".to_owned(), "prod:x".to_owned())?;
+    assert_eq!(client.get("staging:x".to_owned())?, None);
+    assert_eq!(client.get("prod:x".to_owned())?, Some("1".to_owned()));
+    match client.rename("staging:x".to_owned(), "prod:x".to_owned()) {
+        Err(KvsError::Server { msg, retryable }) => {
+            assert_eq!(msg, KvsError::KeyNotFound.to_string());
+            assert!(!retryable);
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+    Ok(())
+}
+
+// Deleting a prefix should remove only the matching keys of the server, in
+// a single request, and count them
+#[test]
+fn client_delete_prefix() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4029");
+    let mut client = Client::connect("127.0.0.1:4029")?;
+    for key_id in 0..20 {
+        client.set(format!("tmp:{}", key_id), "1".to_owned())?;
+        client.set(format!("key{}", key_id), "1".to_owned())?;
+    }
+    client.set("tmp".to_owned(), "1".to_owned())?;
+    assert_eq!(client.delete_prefix("tmp:".to_owned())?, 20);
+    assert_eq!(client.delete_prefix("tmp:".to_owned())?, 0);
+    for key_id in 0..20 {
+        assert_eq!(client.get(format!("tmp:{}", key_id))?, None);
+        assert_eq!(client.get(format!("key{}", key_id))?, Some("1".to_owned()));
+    }
+    assert_eq!(client.get("tmp".to_owned())?, Some("1".to_owned()));
+    Ok(())
+}
+
+// Getting many keys at once should answer every position in order, reading
+// a key repeated in the request only once
+#[test]
+fn client_get_many_reads_repeated_keys_once() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvsEngine::open(temp_dir.path())?;
+    engine.set("a".to_owned(), "1".to_owned())?;
+    engine.set("b".to_owned(), "2".to_owned())?;
+    let engine = engine.track_hot_keys(10);
+    let server = Server::new(engine.clone());
+    thread::spawn(move || server.run("127.0.0.1:4040").unwrap());
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = Client::connect("127.0.0.1:4040")?;
+    let keys = ["a", "b", "a", "a", "z", "z"].map(str::to_owned).to_vec();
+    let one = Some("1".to_owned());
+    assert_eq!(
+        client.get_many(keys)?,
+        [
+            one.clone(),
+            Some("2".to_owned()),
+            one.clone(),
+            one,
+            None,
+            None
+        ]
+    );
+    assert_eq!(
+        engine.hot_keys(10),
+        [
+            ("a".to_owned(), 1),
+            ("b".to_owned(), 1),
+            ("z".to_owned(), 1)
+        ]
+    );
+    assert_eq!(client.get_many(Vec::new())?, []);
+    Ok(())
+}
+
+// Counting a prefix should count the matching keys of the server, within
+// the namespace of a prefixed client
+#[test]
+fn client_count_prefix() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4033");
+    let mut client = Client::connect("127.0.0.1:4033")?;
+    for key_id in 0..20 {
+        client.set(format!("tmp:{}", key_id), "1".to_owned())?;
+    }
+    client.set("key".to_owned(), "1".to_owned())?;
+    assert_eq!(client.count_prefix("tmp:".to_owned())?, 20);
+    assert_eq!(client.count_prefix(String::new())?, 21);
+
+    let mut prefixed = client.with_prefix("app:".to_owned());
+    prefixed.set("tmp:1".to_owned(), "1".to_owned())?;
+    assert_eq!(prefixed.count_prefix("tmp:".to_owned())?, 1);
+    assert_eq!(prefixed.count_prefix(String::new())?, 1);
+    Ok(())
+}
+
+// Each built-in filter should return only the pairs of the server matching
+// it, across pages of the engine and within the namespace of a prefixed client
+#[test]
+fn client_scan_filter() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4038");
+    let mut client = Client::connect("127.0.0.1:4038")?;
+    for key_id in 0..1500 {
+        let value = if key_id % 500 == 7 {
+            "error: disk"
+        } else {
+            "ok"
+        };
+        client.set(format!("log:{:04}", key_id), value.to_owned())?;
+    }
+    client.set("other".to_owned(), "error: net".to_owned())?;
+
+    let contains = client.scan_filter(
+        "log:".to_owned(),
+        ValueFilter::ValueContains("disk".to_owned()),
+    )?;
+    let keys: Vec<&str> = contains.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, ["log:0007", "log:0507", "log:1007"]);
+    let prefix = client.scan_filter(String::new(), ValueFilter::ValuePrefix("error".to_owned()))?;
+    assert_eq!(prefix.len(), 4);
+    assert_eq!(prefix[3], ("other".to_owned(), "error: net".to_owned()));
+    assert!(client
+        .scan_filter(String::new(), ValueFilter::ValuePrefix("disk".to_owned()))?
+        .is_empty());
+
+    let mut prefixed = client.with_prefix("app:".to_owned());
+    prefixed.set("key".to_owned(), "error: app".to_owned())?;
+    assert_eq!(
+        prefixed.scan_filter(
+            String::new(),
+            ValueFilter::ValueContains("error".to_owned())
+        )?,
+        [("key".to_owned(), "error: app".to_owned())]
+    );
+    Ok(())
+}
+
+// Streaming a scan into a bounded channel should hand every pair in order to
+// a slow consumer, and stop and close its cursor once the receiver is gone
+#[test]
+fn client_scan_into_channel() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4039");
+    let mut client = Client::connect("127.0.0.1:4039")?;
+    for key_id in 0..1000 {
+        client.set(format!("key{:04}", key_id), format!("value{}", key_id))?;
+    }
+    client.set("other".to_owned(), "1".to_owned())?;
+
+    let (tx, rx) = mpsc::sync_channel(8);
+    let consumer = thread::spawn(move || {
+        let mut entries = Vec::new();
+        for entry in rx {
+            if entries.len() % 100 == 0 {
+                thread::sleep(Duration::from_millis(10));
+            }
+            entries.push(entry);
+        }
+        entries
+    });
+    assert_eq!(client.scan_into("key".to_owned(), tx)?, 1000);
+    let expected: Vec<(String, String)> = (0..1000)
+        .map(|key_id| (format!("key{:04}", key_id), format!("value{}", key_id)))
+        .collect();
+    assert_eq!(consumer.join().unwrap(), expected);
+
+    // the second cursor of the connection, given up on after 150 pairs
+    let (tx, rx) = mpsc::sync_channel(8);
+    let consumer = thread::spawn(move || rx.iter().take(150).count());
+    let sent = client.scan_into("key".to_owned(), tx)?;
+    assert_eq!(consumer.join().unwrap(), 150);
+    assert!((150..=158).contains(&sent), "{} pairs sent", sent);
+    assert!(client.scan_next(1, 10).is_err());
+    assert!(!client.scan_close(1)?);
+    assert_eq!(client.get("other".to_owned())?, Some("1".to_owned()));
+    Ok(())
+}
+
+// The length of a value should come back without the value, in bytes
+#[test]
+fn client_value_len() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4028");
+    let mut client = Client::connect("127.0.0.1:4028")?;
+    assert_eq!(client.value_len("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.value_len("key1".to_owned())?, Some(6));
+    client.set("key2".to_owned(), "ключ 🔑".to_owned())?;
+    assert_eq!(client.value_len("key2".to_owned())?, Some("ключ 🔑".len()));
+    Ok(())
+}
+
+// The stats of the server should reflect the writes of the client
+#[test]
+fn client_stats() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4017");
+    let mut client = Client::connect("127.0.0.1:4017")?;
+    assert_eq!(client.stats()?, EngineStats::default());
+
+    for iter in 0..2 {
+        for key_id in 0..100 {
+            client.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    client.remove("key0".to_owned())?;
+    let stats = client.stats()?;
+    assert_eq!(stats.keys, 99);
+    assert!(stats.disk_usage > 0);
+    // the first value of every key and the removed value can be reclaimed
+    assert!(stats.uncompacted > 0 && stats.uncompacted < stats.disk_usage);
+    assert_eq!(stats.compactions, 0);
+    Ok(())
+}
+
+// Databases selected by a connection should not see each other's keys.
+#[test]
+fn select_isolates_databases() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::new(KvsEngine::open(temp_dir.path())?)
+        .database("one", KvsEngine::open(temp_dir.path().join("one"))?)
+        .database("two", KvsEngine::open(temp_dir.path().join("two"))?);
+    thread::spawn(move || server.run("127.0.0.1:4018").unwrap());
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = Client::connect("127.0.0.1:4018")?;
+    client.set("key".to_owned(), "default".to_owned())?;
+    client.select("one".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, None);
+    client.set("key".to_owned(), "one".to_owned())?;
+    client.set("only-one".to_owned(), "one".to_owned())?;
+    client.select("two".to_owned())?;
+    client.set("key".to_owned(), "two".to_owned())?;
+    assert_eq!(client.get("only-one".to_owned())?, None);
+    assert!(client.select("three".to_owned()).is_err());
+    // a failed select keeps the current database
+    assert_eq!(client.get("key".to_owned())?, Some("two".to_owned()));
+    drop(client);
+
+    // a new connection starts on the default database
+    let mut client = Client::connect("127.0.0.1:4018")?;
+    assert_eq!(client.get("key".to_owned())?, Some("default".to_owned()));
+    client.select("one".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("one".to_owned()));
+    client.remove("key".to_owned())?;
+    client.select(kvs::DEFAULT_DB.to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("default".to_owned()));
+    Ok(())
+}
+
+// A malformed request should get an error back, and leave the connection
+// usable for the next request.
+#[test]
+fn malformed_request_keeps_connection() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4019");
+    let mut stream = TcpStream::connect("127.0.0.1:4019")?;
+    let mut responses = serde_json::Deserializer::from_reader(stream.try_clone()?);
+
+    for garbage in [&b"{{not json at all"[..], br#"{"Fetch":{"key":"key1"}}"#] {
+        stream.write_all(garbage)?;
+        match ErrorResp::deserialize(&mut responses)? {
+            ErrorResp::Err { msg, retryable } => {
+                assert!(msg.starts_with("malformed message"), "{}", msg);
+                assert!(!retryable);
+            }
+        }
+
+        serde_json::to_writer(
+            &mut stream,
+            &Request::Get {
+                key: "key1".to_owned(),
+            },
+        )?;
+        match GetResp::deserialize(&mut responses)? {
+            GetResp::Ok(value) => assert_eq!(value, None),
+            resp => panic!("unexpected response {:?}", resp),
+        }
+    }
+    Ok(())
+}
+
+// A malformed request pipelined ahead of others should get an error back,
+// and the requests after it their own responses
+#[test]
+fn malformed_request_keeps_pipelined_requests() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4050");
+    let mut stream = TcpStream::connect("127.0.0.1:4050")?;
+    let mut responses = serde_json::Deserializer::from_reader(stream.try_clone()?);
+
+    let get = serde_json::to_vec(&Request::Get {
+        key: "key1".to_owned(),
+    })?;
+    for garbage in [
+        &br#"{"Get":{"key":nope}}"#[..],
+        br#"{"Fetch":{"key":"key1"}}"#,
+    ] {
+        stream.write_all(&[garbage, &get[..], &get[..]].concat())?;
+        match ErrorResp::deserialize(&mut responses)? {
+            ErrorResp::Err { msg, .. } => assert!(msg.starts_with("malformed message"), "{}", msg),
+        }
+        for _ in 0..2 {
+            match GetResp::deserialize(&mut responses)? {
+                GetResp::Ok(value) => assert_eq!(value, None),
+                resp => panic!("unexpected response {:?}", resp),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Coalescing the flushes should still answer an interactive client at once,
+// and answer every request of a pipelined one
+#[test]
+fn responses_flushed_in_time() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4023");
+    let mut stream = TcpStream::connect("127.0.0.1:4023")?;
+    stream.set_nodelay(true)?;
+    // a response held back would time out instead of hanging the test
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut responses = serde_json::Deserializer::from_reader(stream.try_clone()?);
+    let get = |key: &str| Request::Get {
+        key: key.to_owned(),
+    };
+
+    for _ in 0..100 {
+        let start = Instant::now();
+        serde_json::to_writer(&mut stream, &get("key1"))?;
+        match GetResp::deserialize(&mut responses)? {
+            GetResp::Ok(value) => assert_eq!(value, None),
+            resp => panic!("unexpected response {:?}", resp),
+        }
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    let mut pipelined = Vec::new();
+    for _ in 0..1000 {
+        serde_json::to_writer(&mut pipelined, &get("key1"))?;
+    }
+    stream.write_all(&pipelined)?;
+    for _ in 0..1000 {
+        assert!(matches!(
+            GetResp::deserialize(&mut responses)?,
+            GetResp::Ok(None)
+        ));
+    }
+    Ok(())
+}
+
+// A value over the chunk threshold should come in chunks of at most the
+// threshold, cut between chars, and be reassembled by the client, in a
+// batch too; a value under it should still come in a single frame
+#[test]
+fn large_values_sent_in_chunks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvsEngine::open(temp_dir.path())?;
+    let server = Server::new(engine).chunk_threshold(1000);
+    thread::spawn(move || server.run("127.0.0.1:4034").unwrap());
+    thread::sleep(Duration::from_millis(200));
+
+    let large: String = (0..5000).map(|i| ["a", "é", "€", "𝄞"][i % 4]).collect();
+    let mut client = Client::connect("127.0.0.1:4034")?;
+    client.set("large".to_owned(), large.clone())?;
+    client.set("small".to_owned(), "x".repeat(1000))?;
+    assert_eq!(client.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(client.get("small".to_owned())?, Some("x".repeat(1000)));
+    let results = client.batch(
+        vec![
+            BatchOp::Get {
+                key: "large".to_owned(),
+            },
+            BatchOp::Get {
+                key: "small".to_owned(),
+            },
+        ],
+        Duration::from_secs(5),
+    )?;
+    assert_eq!(results[0].as_ref().ok(), Some(&Some(large.clone())));
+    assert_eq!(results[1].as_ref().ok(), Some(&Some("x".repeat(1000))));
+    drop(client);
+
+    let mut stream = TcpStream::connect("127.0.0.1:4034")?;
+    let mut responses = serde_json::Deserializer::from_reader(stream.try_clone()?);
+    let get = |key: &str| Request::Get {
+        key: key.to_owned(),
+    };
+    serde_json::to_writer(&mut stream, &get("large"))?;
+    let mut chunks = Vec::new();
+    loop {
+        match GetResp::deserialize(&mut responses)? {
+            GetResp::ValueChunk { data } => {
+                assert!(data.len() <= 1000 && !data.is_empty());
+                chunks.push(data);
+            }
+            GetResp::ValueEnd => break,
+            resp => panic!("unexpected response {:?}", resp),
+        }
+    }
+    assert!(chunks.len() >= large.len() / 1000);
+    assert_eq!(chunks.concat(), large);
+    serde_json::to_writer(&mut stream, &get("small"))?;
+    assert!(matches!(
+        GetResp::deserialize(&mut responses)?,
+        GetResp::Ok(Some(value)) if value == "x".repeat(1000)
+    ));
+    Ok(())
+}
+
+// A connection which sends no request for the idle timeout should be closed
+// by the server, freeing it for the next client
+#[test]
+fn server_closes_idle_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvsEngine::open(temp_dir.path())?;
+    let server = Server::new(engine).idle_timeout(Duration::from_millis(300));
+    thread::spawn(move || server.run("127.0.0.1:4041").unwrap());
+    thread::sleep(Duration::from_millis(200));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4041")?;
+    let mut responses = serde_json::Deserializer::from_reader(stream.try_clone()?);
+    let set = Request::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    serde_json::to_writer(&mut stream, &set)?;
+    assert!(matches!(
+        SetResp::deserialize(&mut responses)?,
+        SetResp::Ok(())
+    ));
+
+    let idle = Instant::now();
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut buf = [0; 16];
+    assert_eq!(stream.read(&mut buf)?, 0);
+    assert!(idle.elapsed() >= Duration::from_millis(250));
+    drop(responses);
+    drop(stream);
+
+    let mut client = Client::connect("127.0.0.1:4041")?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A server reporting timing should send the time it spent on a timed
+// request, within the round trip the client measured
+#[test]
+fn client_timed_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::new(KvsEngine::open(temp_dir.path())?).report_timing(true);
+    thread::spawn(move || server.run("127.0.0.1:4043").unwrap());
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = Client::connect("127.0.0.1:4043")?;
+    let start = Instant::now();
+    let spent = client.set_timed("key1".to_owned(), "value1".to_owned())?;
+    assert!(spent > Duration::ZERO && spent <= start.elapsed());
+    let start = Instant::now();
+    let (value, spent) = client.get_timed("key1".to_owned())?;
+    assert_eq!(value, Some("value1".to_owned()));
+    assert!(spent <= start.elapsed());
+    assert_eq!(client.get_timed("key2".to_owned())?.0, None);
+    // the untimed requests are answered as usual
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    let _dir = start_server("127.0.0.1:4044");
+    let mut client = Client::connect("127.0.0.1:4044")?;
+    let err = client
+        .set_timed("key1".to_owned(), "value1".to_owned())
+        .unwrap_err();
+    assert!(err.to_string().contains("doesn't report timing"), "{}", err);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A client given several addresses should skip the dead ones and connect to
+// the first live one, failing with every error when none is
+#[test]
+fn client_connect_any() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4035");
+    let mut client = Client::connect_any(&["127.0.0.1:4036", "127.0.0.1:4035"])?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    let err = Client::connect_any(&["127.0.0.1:4036", "127.0.0.1:4037"])
+        .err()
+        .expect("connected to a dead address");
+    let msg = err.to_string();
+    assert!(msg.contains("127.0.0.1:4036") && msg.contains("127.0.0.1:4037"));
+    assert!(Client::connect_any(&[]).is_err());
+    Ok(())
+}
+
+// A batch should get the results of its operations in order, a failing one
+// not stopping the others, and leave the connection usable
+#[test]
+fn client_batch() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4031");
+    let mut client = Client::connect("127.0.0.1:4031")?;
+    let ops = vec![
+        BatchOp::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        BatchOp::Remove {
+            key: "missing".to_owned(),
+        },
+        BatchOp::Get {
+            key: "key1".to_owned(),
+        },
+        BatchOp::Remove {
+            key: "key1".to_owned(),
+        },
+        BatchOp::Get {
+            key: "key1".to_owned(),
+        },
+    ];
+    let results = client.batch(ops, Duration::from_secs(5))?;
+    assert_eq!(results.len(), 5);
+    assert!(matches!(results[0], Ok(None)));
+    assert!(matches!(results[1], Err(KvsError::Server { .. })));
+    assert_eq!(results[2].as_ref().ok(), Some(&Some("value1".to_owned())));
+    assert!(matches!(results[3], Ok(None)));
+    assert!(matches!(results[4], Ok(None)));
+
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// An atomic set_many should set all its pairs or, one of them failing, none;
+// otherwise each pair should be set or fail on its own
+#[test]
+fn client_set_many() -> Result<()> {
+    let addr = "127.0.0.1:4046";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvsEngine::open(temp_dir.path())?.validate_keys(|key| {
+        if key.starts_with("bad") {
+            Err(KvsError::InvalidKey(key.to_owned()))
+        } else {
+            Ok(Cow::Borrowed(key))
+        }
+    });
+    thread::spawn(move || Server::new(engine).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(200));
+    let mut client = Client::connect(addr)?;
+    let pairs = |keys: &[&str]| {
+        keys.iter()
+            .map(|key| (key.to_string(), format!("{}-value", key)))
+            .collect::<Vec<_>>()
+    };
+
+    let results = client.set_many(pairs(&["key1", "key2"]), true)?;
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|res| res.is_ok()));
+    assert_eq!(
+        client.get("key2".to_owned())?,
+        Some("key2-value".to_owned())
+    );
+
+    assert!(matches!(
+        client.set_many(pairs(&["key3", "bad1", "key4"]), true),
+        Err(KvsError::Server { .. })
+    ));
+    assert_eq!(client.get("key3".to_owned())?, None);
+    assert_eq!(client.get("key4".to_owned())?, None);
+
+    let results = client.set_many(pairs(&["key3", "bad1", "key4"]), false)?;
+    assert!(results[0].is_ok());
+    assert!(matches!(results[1], Err(KvsError::Server { .. })));
+    assert!(results[2].is_ok());
+    assert_eq!(
+        client.get("key3".to_owned())?,
+        Some("key3-value".to_owned())
+    );
+    assert_eq!(
+        client.get("key4".to_owned())?,
+        Some("key4-value".to_owned())
+    );
+    Ok(())
+}
+
+// A non-atomic set_many cut off by the server should report which sets
+// were answered, failing only the others
+#[test]
+fn client_set_many_partial_on_disconnect() -> Result<()> {
+    let addr = "127.0.0.1:4056";
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        // every set is read, so that closing the connection doesn't reset it
+        let reqs: Vec<Request> = serde_json::Deserializer::from_reader(stream)
+            .into_iter()
+            .take(4)
+            .collect::<serde_json::Result<_>>()
+            .unwrap();
+        for _ in &reqs[..2] {
+            serde_json::to_writer(&mut writer, &SetResp::Ok(())).unwrap();
+        }
+    });
+
+    let mut client = Client::connect(addr)?;
+    let pairs = (0..4)
+        .map(|i| (format!("key{}", i), "value".to_owned()))
+        .collect();
+    let results = client.set_many(pairs, false)?;
+    assert_eq!(results.len(), 4);
+    assert!(results[..2].iter().all(|res| res.is_ok()));
+    for res in &results[2..] {
+        match res {
+            Err(e) => assert!(e.is_retryable()),
+            Ok(()) => panic!("unexpected set"),
+        }
+    }
+    Ok(())
+}
+
+// A server answering every get and set, each response padded with 64 KB of
+// whitespace, so that its responses fill the socket buffers long before
+// the requests of a large pipelined call are all sent
+fn start_padded_server(addr: &'static str) {
+    let listener = TcpListener::bind(addr).unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = io::BufWriter::new(stream.unwrap());
+            let reqs = serde_json::Deserializer::from_reader(stream.get_ref().try_clone().unwrap());
+            let padding = vec![b' '; 64 * 1024];
+            for req in reqs.into_iter::<Request>() {
+                let res = match req.unwrap() {
+                    Request::Get { key } => {
+                        serde_json::to_writer(&mut stream, &GetResp::Ok(Some(key)))
+                    }
+                    Request::Set { .. } => serde_json::to_writer(&mut stream, &SetResp::Ok(())),
+                    req => panic!("unexpected request {:?}", req),
+                };
+                res.unwrap();
+                stream.write_all(&padding).unwrap();
+                stream.flush().unwrap();
+            }
+        }
+    });
+    thread::sleep(Duration::from_millis(100));
+}
+
+// A non-atomic set_many larger than the socket buffers should complete, not
+// leave the client and the server both blocked on writing
+#[test]
+fn client_set_many_large() -> Result<()> {
+    let addr = "127.0.0.1:4048";
+    start_padded_server(addr);
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let res = Client::connect(addr).and_then(|mut client| {
+            let pairs = (0..500)
+                .map(|i| (format!("key{}", i), "x".repeat(16 * 1024)))
+                .collect();
+            client.set_many(pairs, false)
+        });
+        sender.send(res).unwrap();
+    });
+    let results = receiver
+        .recv_timeout(Duration::from_secs(30))
+        .expect("set_many is stuck")?;
+    assert_eq!(results.len(), 500);
+    assert!(results.iter().all(Result::is_ok));
+    Ok(())
+}
+
+// A batch larger than the socket buffers should complete, not leave the
+// client and the server both blocked on writing
+#[test]
+fn client_batch_large() -> Result<()> {
+    let addr = "127.0.0.1:4049";
+    start_padded_server(addr);
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let res = Client::connect(addr).and_then(|mut client| {
+            let ops = (0..500)
+                .map(|i| BatchOp::Set {
+                    key: format!("key{}", i),
+                    value: "x".repeat(16 * 1024),
+                })
+                .collect();
+            client.batch(ops, Duration::from_secs(60))
+        });
+        sender.send(res).unwrap();
+    });
+    let results = receiver
+        .recv_timeout(Duration::from_secs(30))
+        .expect("batch is stuck")?;
+    assert_eq!(results.len(), 500);
+    assert!(results.iter().all(Result::is_ok));
+    Ok(())
+}
+
+// A server answering every request, after a delay for the set of `slow`,
+// recording the requests it answered
+fn start_slow_server(addr: &'static str, answered: Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind(addr).unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let answered = answered.clone();
+            thread::spawn(move || {
+                let reqs = serde_json::Deserializer::from_reader(stream.try_clone().unwrap());
+                for req in reqs.into_iter::<Request>() {
+                    match req.unwrap() {
+                        Request::Get { key } => {
+                            answered.lock().unwrap().push(key.clone());
+                            serde_json::to_writer(&mut stream, &GetResp::Ok(Some(key))).unwrap();
+                        }
+                        Request::Set { key, .. } => {
+                            if key == "slow" {
+                                thread::sleep(Duration::from_millis(500));
+                            }
+                            answered.lock().unwrap().push(key);
+                            serde_json::to_writer(&mut stream, &SetResp::Ok(())).unwrap();
+                        }
+                        req => panic!("unexpected request {:?}", req),
+                    }
+                    stream.flush().unwrap();
+                }
+            });
+        }
+    });
+}
+
+// A batch timing out partway should report the operations answered before
+// the delay and time the others out, then go on on a new connection
+#[test]
+fn client_batch_partial_on_timeout() -> Result<()> {
+    let answered = Arc::new(Mutex::new(Vec::new()));
+    start_slow_server("127.0.0.1:4030", answered.clone());
+    let mut client = Client::connect("127.0.0.1:4030")?;
+    let set = |key: &str| BatchOp::Set {
+        key: key.to_owned(),
+        value: "value".to_owned(),
+    };
+    let ops = vec![
+        set("key1"),
+        set("key2"),
+        BatchOp::Get {
+            key: "key1".to_owned(),
+        },
+        set("slow"),
+        set("key3"),
+        set("key4"),
+    ];
+    let start = Instant::now();
+    let results = client.batch(ops, Duration::from_millis(200))?;
+    assert!(start.elapsed() < Duration::from_millis(450));
+    assert_eq!(results.len(), 6);
+    assert!(results[..3].iter().all(Result::is_ok));
+    assert!(results[3..]
+        .iter()
+        .all(|res| matches!(res, Err(KvsError::Timeout))));
+    // the server had answered exactly the operations reported as done
+    assert_eq!(*answered.lock().unwrap(), ["key1", "key2", "key1"]);
+
+    // the late responses don't reach the next request
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(client.get("next".to_owned())?, Some("next".to_owned()));
+    Ok(())
+}
+
+// fetch the metrics of a server over HTTP
+fn scrape(addr: &str) -> Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(stream, "GET /metrics HTTP/1.1\r\nHost: {}\r\n\r\n", addr)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+// The metrics endpoint should count the requests by type and the errors,
+// and export the stats of the engine.
+#[test]
+fn metrics_reflect_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvsEngine::open(temp_dir.path())?;
+    thread::spawn(move || {
+        Server::new(engine)
+            .metrics_addr("127.0.0.1:4021")
+            .run("127.0.0.1:4020")
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = Client::connect("127.0.0.1:4020")?;
+    for i in 0..3 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    client.get("key1".to_owned())?;
+    client.get("missing".to_owned())?;
+    assert!(client.remove("missing".to_owned()).is_err());
+
+    let expected = [
+        "kvs_requests_total{type=\"set\"} 3",
+        "kvs_requests_total{type=\"get\"} 2",
+        "kvs_requests_total{type=\"remove\"} 1",
+        "kvs_requests_total{type=\"append\"} 0",
+        "kvs_request_errors_total 1",
+        "kvs_request_duration_seconds_bucket{le=\"+Inf\"} 6",
+        "kvs_request_duration_seconds_count 6",
+        "kvs_keys{db=\"default\"} 3",
+        "kvs_compactions_total{db=\"default\"} 0",
+    ];
+    // a request is counted once its response is sent, so the last one may
+    // not be yet
+    let mut metrics = scrape("127.0.0.1:4021")?;
+    for _ in 0..20 {
+        if expected
+            .iter()
+            .all(|line| metrics.lines().any(|l| l == *line))
+        {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+        metrics = scrape("127.0.0.1:4021")?;
+    }
+    assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"), "{}", metrics);
+    assert!(metrics.contains("\r\nContent-Type: text/plain; version=0.0.4\r\n"));
+    for line in expected {
+        assert!(
+            metrics.lines().any(|l| l == line),
+            "no {} in {}",
+            line,
+            metrics
+        );
+    }
+    Ok(())
+}
+
+// Records every request and whether its response is an error
+struct Logging(Arc<Mutex<Vec<String>>>);
+
+impl Middleware for Logging {
+    fn handle(&self, req: Request, next: &dyn Fn(Request) -> Response) -> Response {
+        self.0.lock().unwrap().push(format!("log {:?}", req));
+        let resp = next(req);
+        self.0
+            .lock()
+            .unwrap()
+            .push(format!("log err={}", resp.is_err()));
+        resp
+    }
+}
+
+// Refuses any request about a key under `admin/`
+struct Auth(Arc<Mutex<Vec<String>>>);
+
+impl Middleware for Auth {
+    fn handle(&self, req: Request, next: &dyn Fn(Request) -> Response) -> Response {
+        let key = match &req {
+            Request::Get { key } | Request::Set { key, .. } | Request::Remove { key } => key,
+            _ => "",
+        };
+        if key.starts_with("admin/") {
+            self.0.lock().unwrap().push("auth refused".to_owned());
+            return Response::error("unauthorized", false);
+        }
+        self.0.lock().unwrap().push("auth ok".to_owned());
+        next(req)
+    }
+}
+
+// Middleware should run around the engine in the order added, and a refused
+// request should never reach the engine
+#[test]
+fn middleware_runs_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvsEngine::open(temp_dir.path())?;
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let server = Server::new(engine.clone())
+        .middleware(Logging(log.clone()))
+        .middleware(Auth(log.clone()));
+    thread::spawn(move || server.run("127.0.0.1:4032").unwrap());
+    thread::sleep(Duration::from_millis(200));
+    let mut client = Client::connect("127.0.0.1:4032")?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            r#"log Set { key: "key1", value: "value1" }"#,
+            "auth ok",
+            "log err=false"
+        ]
+    );
+    log.lock().unwrap().clear();
+
+    match client.set("admin/key".to_owned(), "value".to_owned()) {
+        Err(KvsError::Server { msg, .. }) => assert_eq!(msg, "unauthorized"),
+        res => panic!("expected the set to be refused, got {:?}", res),
+    }
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            r#"log Set { key: "admin/key", value: "value" }"#,
+            "auth refused",
+            "log err=true"
+        ]
+    );
+    assert_eq!(engine.get("admin/key")?, None);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use kvs::{Engine, KvsEngine, Result};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// A writer keeping everything logged in memory.
+#[derive(Clone, Default)]
+struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Hammering one key from many threads should report the key as hot.
+#[test]
+fn hot_key_contention_is_reported() -> Result<()> {
+    let logs = Logs::default();
+    let make_writer = logs.clone();
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::fmt()
+            .with_writer(move || make_writer.clone())
+            .finish(),
+    )
+    .unwrap();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?.warn_on_contention(Duration::from_nanos(1));
+
+    // keep writing until the warning shows up, giving up after a while
+    let deadline = Instant::now() + Duration::from_secs(20);
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            let logs = logs.clone();
+            thread::spawn(move || {
+                let mut i = 0;
+                while Instant::now() < deadline
+                    && !String::from_utf8_lossy(&logs.0.lock().unwrap()).contains("contended")
+                {
+                    store.set("hot".to_owned(), format!("value{}", i)).unwrap();
+                    i += 1;
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let logs = String::from_utf8_lossy(&logs.0.lock().unwrap()).into_owned();
+    assert!(
+        logs.contains("writer lock is contended"),
+        "no warning in {:?}",
+        logs
+    );
+    assert!(logs.contains("hot_key=\"hot\""), "no hot key in {:?}", logs);
+    Ok(())
+}
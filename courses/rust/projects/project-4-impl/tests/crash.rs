@@ -0,0 +1,156 @@
+use kvs::test_util::{panic_at, CrashTest, PanicPoint};
+use kvs::{Engine, KvsEngine, KvsOptions, Result};
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::collections::BTreeMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use tempfile::TempDir;
+
+// run `rounds` of random writes on a few keys, each ended by a crash at a
+// random point of the log, flushing before some of the crashes
+fn crash_workload(seed: u64, rounds: usize, value_len: usize) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut test = CrashTest::new(temp_dir.path())?;
+    let mut written = Vec::new();
+    for round in 0..rounds {
+        for _ in 0..rng.gen_range(1, 50) {
+            let key = format!("key{}", rng.gen_range(0, 20));
+            if rng.gen_bool(0.2) && written.contains(&key) {
+                written.retain(|k| *k != key);
+                test.remove(key)?;
+            } else {
+                let value: String = (0..value_len)
+                    .map(|_| rng.sample(rand::distributions::Alphanumeric))
+                    .collect();
+                if !written.contains(&key) {
+                    written.push(key.clone());
+                }
+                test.set(key, value)?;
+            }
+            if rng.gen_bool(0.05) {
+                test.flush()?;
+            }
+        }
+        let range = test.crash_range();
+        let offset = rng.gen_range(range.start, range.end);
+        test.crash_at(offset).inspect_err(|_| {
+            eprintln!("seed {} round {}: crash at {} failed", seed, round, offset);
+        })?;
+        // removes of keys lost in the crash would fail
+        written.clear();
+    }
+    Ok(())
+}
+
+// A store should recover the writes before the cut, wherever the log is cut,
+// including in the middle of a record.
+#[test]
+fn recover_from_crash_at_any_offset() -> Result<()> {
+    for seed in 0..20 {
+        crash_workload(seed, 20, 10)?;
+    }
+    Ok(())
+}
+
+// Crashes right after a compaction shouldn't lose the compacted writes.
+#[test]
+fn recover_from_crash_after_compaction() -> Result<()> {
+    crash_workload(42, 40, 10_000)
+}
+
+fn pairs(store: &KvsEngine) -> Result<BTreeMap<String, String>> {
+    store.iter().collect()
+}
+
+// reopen the store in `dir`, checking it holds `expected` and can still be
+// written and compacted
+fn reopen_and_check(dir: &Path, expected: &BTreeMap<String, String>) -> Result<()> {
+    assert!(KvsEngine::check(dir)?.is_clean());
+    let store = KvsEngine::open(dir)?;
+    assert_eq!(&pairs(&store)?, expected);
+    store.set("after".to_owned(), "reopen".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let store = KvsEngine::open(dir)?;
+    let mut expected = expected.clone();
+    expected.insert("after".to_owned(), "reopen".to_owned());
+    assert_eq!(pairs(&store)?, expected);
+    Ok(())
+}
+
+// panic at `point` during a set, or a compaction for the compaction points,
+// with a snapshot open if `snapshot`, then check the store reopens with every
+// write which returned and the panicked one whole or not at all
+fn panic_then_reopen(point: PanicPoint, snapshot: bool) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvsOptions::default().auto_compact(false);
+    let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+    let mut expected = BTreeMap::new();
+    for i in 0..20 {
+        store.set(format!("key{}", i), "flushed".to_owned())?;
+        expected.insert(format!("key{}", i), "flushed".to_owned());
+    }
+    store.flush()?;
+    // compactions keep the versions a snapshot sees, copied first
+    let snapshot = snapshot.then(|| store.snapshot());
+    for i in 0..10 {
+        store.set(format!("key{}", i), "acknowledged".to_owned())?;
+        expected.insert(format!("key{}", i), "acknowledged".to_owned());
+    }
+    store.remove("key19")?;
+    expected.remove("key19");
+
+    let compaction = matches!(
+        point,
+        PanicPoint::MidCompaction | PanicPoint::AfterCompactionSync
+    );
+    panic_at(point);
+    let res = catch_unwind(AssertUnwindSafe(|| {
+        if compaction {
+            store.compact()
+        } else {
+            store.set("key5".to_owned(), "panicked".to_owned())
+        }
+    }));
+    assert!(res.is_err(), "no panic at {:?}", point);
+    // the engine is poisoned
+    let res = catch_unwind(AssertUnwindSafe(|| {
+        store.set("key0".to_owned(), "poisoned".to_owned())
+    }));
+    assert!(res.is_err());
+    drop(snapshot);
+    drop(store);
+
+    if compaction {
+        return reopen_and_check(temp_dir.path(), &expected);
+    }
+    let store = KvsEngine::open(temp_dir.path())?;
+    let written = pairs(&store)?["key5"] == "panicked";
+    drop(store);
+    if written {
+        expected.insert("key5".to_owned(), "panicked".to_owned());
+    }
+    reopen_and_check(temp_dir.path(), &expected)
+}
+
+// A store should reopen consistently after a panic at any point of a write or
+// of a compaction, whether a snapshot made the compaction keep old versions
+#[test]
+fn reopen_after_panic() -> Result<()> {
+    for point in [
+        PanicPoint::MidRecord,
+        PanicPoint::BeforeFlush,
+        PanicPoint::AfterFlush,
+        PanicPoint::MidCompaction,
+        PanicPoint::AfterCompactionSync,
+    ] {
+        for snapshot in [false, true] {
+            panic_then_reopen(point, snapshot).inspect_err(|_| {
+                eprintln!("panic at {:?}, snapshot {}: reopen failed", point, snapshot);
+            })?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use kvs::{KvsEngine, KvsError};
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::string::FromUtf8Error;
+use tempfile::TempDir;
+
+fn open_on_file(dir: &TempDir) -> Result<KvsEngine, Box<dyn Error>> {
+    let path = dir.path().join("file");
+    File::create(&path)?;
+    // `?` boxes a `KvsError` like any other error
+    Ok(KvsEngine::open(path)?)
+}
+
+// A `KvsError` should be usable as a `Box<dyn Error>`, exposing the error it
+// wraps as its source
+#[test]
+fn boxed_error_walks_source_chain() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let err = open_on_file(&temp_dir).unwrap_err();
+    let kvs_err = err.downcast_ref::<KvsError>().expect("not a KvsError");
+    assert!(matches!(kvs_err, KvsError::IoErr(_)));
+
+    let source = err.source().expect("no source");
+    assert!(source.downcast_ref::<io::Error>().is_some());
+    assert_eq!(err.to_string(), source.to_string());
+
+    let chain: Vec<_> = std::iter::successors(Some(&*err), |&e| e.source()).collect();
+    assert_eq!(chain.len(), 2);
+}
+
+// Only the errors wrapping another error should have a source
+#[test]
+fn only_wrapping_errors_have_a_source() {
+    let errors: Vec<Box<dyn Error + Send + Sync>> = vec![
+        Box::new(KvsError::KeyNotFound),
+        Box::new(KvsError::StringErr("message".to_owned())),
+        Box::new(KvsError::Protocol("message".to_owned())),
+    ];
+    for err in errors {
+        assert!(err.source().is_none(), "{}", err);
+    }
+
+    let utf8_err = String::from_utf8(vec![0xff]).unwrap_err();
+    let err: Box<dyn Error> = Box::new(KvsError::from(utf8_err));
+    assert!(err
+        .source()
+        .and_then(|e| e.downcast_ref::<FromUtf8Error>())
+        .is_some());
+}
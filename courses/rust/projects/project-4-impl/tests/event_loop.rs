@@ -0,0 +1,172 @@
+use kvs::{Client, GetResp, KvsEngine, Middleware, Request, Response, Result, Server};
+use serde::Deserialize;
+use std::fs;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// number of threads of this process
+fn thread_count() -> usize {
+    fs::read_dir("/proc/self/task")
+        .expect("unable to list the threads")
+        .count()
+}
+
+// Hundreds of idle connections should all be held by the thread of the event
+// loop, without keeping a new client waiting behind them
+#[test]
+fn event_loop_serves_past_idle_connections() -> Result<()> {
+    let addr = "127.0.0.1:4042";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::new(KvsEngine::open(temp_dir.path())?);
+    let handle = server.shutdown_handle();
+    let serving = thread::spawn(move || server.run_event_loop(addr));
+    thread::sleep(Duration::from_millis(200));
+    let threads = thread_count();
+
+    let mut idle = (0..300)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<io::Result<Vec<_>>>()?;
+    let get = serde_json::to_vec(&Request::Get {
+        key: "key1".to_owned(),
+    })?;
+    let (start, rest) = get.split_at(get.len() / 2);
+    idle[0].write_all(start)?;
+
+    let mut client = Client::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(thread_count(), threads);
+    drop(client);
+
+    // the half sent request is answered once complete
+    idle[0].write_all(rest)?;
+    let mut responses = serde_json::Deserializer::from_reader(idle[0].try_clone()?);
+    assert!(matches!(
+        GetResp::deserialize(&mut responses)?,
+        GetResp::Ok(Some(value)) if value == "value1"
+    ));
+
+    drop(idle);
+    handle.shutdown();
+    serving.join().unwrap()
+}
+
+// Holds back the gets of the key "slow".
+struct Slow;
+
+impl Middleware for Slow {
+    fn handle(&self, req: Request, next: &dyn Fn(Request) -> Response) -> Response {
+        if matches!(&req, Request::Get { key } if key == "slow") {
+            thread::sleep(Duration::from_secs(2));
+        }
+        next(req)
+    }
+}
+
+// A slow request is answered by a worker, and only delays its own connection
+#[test]
+fn event_loop_answers_past_a_slow_request() -> Result<()> {
+    let addr = "127.0.0.1:4051";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::new(KvsEngine::open(temp_dir.path())?).middleware(Slow);
+    let handle = server.shutdown_handle();
+    let serving = thread::spawn(move || server.run_event_loop(addr));
+    thread::sleep(Duration::from_millis(200));
+
+    let slow = thread::spawn(move || -> Result<Option<String>> {
+        Client::connect(addr)?.get("slow".to_owned())
+    });
+    thread::sleep(Duration::from_millis(200));
+    let start = Instant::now();
+    let mut client = Client::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(slow.join().unwrap()?, None);
+    drop(client);
+
+    handle.shutdown();
+    serving.join().unwrap()
+}
+
+// Requests sent a byte at a time behind invalid JSON are each answered
+// once, in order
+#[test]
+fn event_loop_frames_requests_across_reads() -> Result<()> {
+    let addr = "127.0.0.1:4052";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::new(KvsEngine::open(temp_dir.path())?);
+    let handle = server.shutdown_handle();
+    let serving = thread::spawn(move || server.run_event_loop(addr));
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = Client::connect(addr)?;
+    client.set("key1".to_owned(), "va{l}\"ue1".to_owned())?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    let get = serde_json::to_vec(&Request::Get {
+        key: "key1".to_owned(),
+    })?;
+    stream.write_all(b"not json ")?;
+    let mut sent = get.clone();
+    sent.extend_from_slice(&serde_json::to_vec(&Request::Flush)?);
+    sent.extend_from_slice(&get);
+    for byte in &sent {
+        stream.write_all(&[*byte])?;
+        stream.flush()?;
+    }
+    let mut responses = serde_json::Deserializer::from_reader(stream.try_clone()?);
+    assert!(matches!(
+        GetResp::deserialize(&mut responses)?,
+        GetResp::Err { .. }
+    ));
+    for expected in ["get", "flush", "get"] {
+        let resp = serde_json::Value::deserialize(&mut responses)?;
+        if expected == "get" {
+            assert_eq!(resp, serde_json::json!({ "Ok": "va{l}\"ue1" }));
+        } else {
+            assert!(resp.get("Err").is_none(), "{}", resp);
+        }
+    }
+
+    drop(client);
+    handle.shutdown();
+    serving.join().unwrap()
+}
+
+// A client which sends requests without reading the responses stops being
+// read once its responses pile up, without holding back the others
+#[test]
+fn event_loop_stops_reading_a_client_not_reading() -> Result<()> {
+    let addr = "127.0.0.1:4053";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::new(KvsEngine::open(temp_dir.path())?);
+    let handle = server.shutdown_handle();
+    let serving = thread::spawn(move || server.run_event_loop(addr));
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = Client::connect(addr)?;
+    client.set("key1".to_owned(), "v".repeat(64 * 1024))?;
+
+    // the writes block once the server stops reading
+    let mut greedy = TcpStream::connect(addr)?;
+    greedy.set_write_timeout(Some(Duration::from_millis(500)))?;
+    let gets = serde_json::to_vec(&Request::Get {
+        key: "key1".to_owned(),
+    })?
+    .repeat(1000);
+    let mut sent = 0;
+    while greedy.write_all(&gets).is_ok() {
+        sent += 1;
+        assert!(sent < 100_000, "the server never stopped reading");
+    }
+
+    assert_eq!(client.get("key1".to_owned())?, Some("v".repeat(64 * 1024)));
+    drop(client);
+    drop(greedy);
+    handle.shutdown();
+    serving.join().unwrap()
+}
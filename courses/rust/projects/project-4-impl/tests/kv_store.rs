@@ -1,9 +1,19 @@
-use kvs::{KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::Engine;
+use kvs::{
+    BoxedEngine, CancelToken, DanglingPolicy, FlushPolicy, KvsEngine, KvsError, KvsOptions,
+    LogLayout, MemFile, MemStorage, Replica, Result, SledKvsEngine, Storage, WriteBatch,
+    FILES_PER_DIR, FORMAT_VERSION,
+};
+use std::borrow::Cow;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
-use kvs::Engine;
 
 // Should get previously stored value
 #[test]
@@ -14,14 +24,14 @@ fn get_stored_value() -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvsEngine::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
 
     Ok(())
 }
@@ -33,16 +43,16 @@ fn overwrite_value() -> Result<()> {
     let store = KvsEngine::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
     store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvsEngine::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
 
     Ok(())
 }
@@ -54,12 +64,12 @@ fn get_non_existent_value() -> Result<()> {
     let store = KvsEngine::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key2")?, None);
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvsEngine::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key2")?, None);
 
     Ok(())
 }
@@ -68,7 +78,7 @@ fn get_non_existent_value() -> Result<()> {
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvsEngine::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(store.remove("key1").is_err());
     Ok(())
 }
 
@@ -77,8 +87,54 @@ fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvsEngine::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1").is_ok());
+    assert_eq!(store.get("key1")?, None);
+    Ok(())
+}
+
+// Prefetching a reopened store should leave its content as it was
+#[test]
+fn prefetch_keeps_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    {
+        let store = KvsEngine::open(temp_dir.path())?;
+        for i in 0..1000 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("new{}", i))?;
+            store.remove(format!("key{}", 999 - i))?;
+        }
+    }
+    let store = KvsEngine::open(temp_dir.path())?;
+    let before: Vec<(String, String)> = store.iter().collect::<Result<_>>()?;
+    store.prefetch()?;
+    let after: Vec<(String, String)> = store.iter().collect::<Result<_>>()?;
+    assert_eq!(before, after);
+    assert_eq!(after.len(), 900);
+    assert_eq!(store.get("key0")?, Some("new0".to_owned()));
+    assert_eq!(store.get("key500")?, Some("value500".to_owned()));
+    assert_eq!(store.get("key999")?, None);
+
+    // the store is written as usual afterwards
+    store.set("key999".to_owned(), "value999".to_owned())?;
+    assert_eq!(store.get("key999")?, Some("value999".to_owned()));
+    Ok(())
+}
+
+// Prefetching a store separating its values should read its value log too
+#[test]
+fn prefetch_reads_value_log() -> Result<()> {
+    let storage = FlakyStorage::default();
+    let options = KvsOptions::default().separate_values(true);
+    let store = KvsEngine::with_storage(storage.clone(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".repeat(100))?;
+    }
+    let value_reads = storage.value_reads.load(Ordering::SeqCst);
+    store.prefetch()?;
+    assert!(storage.value_reads.load(Ordering::SeqCst) > value_reads);
+    assert_eq!(store.get("key99")?, Some("value".repeat(100)));
     Ok(())
 }
 
@@ -210,3 +266,2268 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// A snapshot should keep seeing the values as of the moment it was taken,
+// even across later overwrites, removes and compactions.
+#[test]
+fn snapshot_ignores_later_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let snapshot = store.snapshot();
+    store.set("key1".to_owned(), "value1-new".to_owned())?;
+    store.remove("key2")?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(snapshot.read("key1")?, Some("value1".to_owned()));
+    assert_eq!(snapshot.read("key2")?, Some("value2".to_owned()));
+    assert_eq!(snapshot.read("key3")?, None);
+    assert_eq!(store.get("key1")?, Some("value1-new".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+
+    // enough overwrites to trigger compactions while the snapshot is alive
+    for iter in 0..2000 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{:0>100}", iter))?;
+        }
+    }
+    assert_eq!(snapshot.read("key1")?, Some("value1".to_owned()));
+    assert_eq!(snapshot.read("key2")?, Some("value2".to_owned()));
+
+    // a snapshot taken later sees the later writes
+    let later = store.snapshot();
+    drop(snapshot);
+    assert_eq!(later.read("key1")?, Some(format!("{:0>100}", 1999)));
+    store.remove("key1")?;
+    assert_eq!(later.read("key1")?, Some(format!("{:0>100}", 1999)));
+    assert_eq!(store.get("key1")?, None);
+
+    Ok(())
+}
+
+fn open_engine<E: Engine>(path: &Path) -> Result<E> {
+    E::open(path)
+}
+
+// sled only releases the lock on its directory once its background threads
+// notice the store is dropped, so reopening it right away may find it locked
+fn reopen_engine<E: Engine>(path: &Path) -> Result<E> {
+    for _ in 0..100 {
+        match E::open(path) {
+            Err(KvsError::SledErr(e)) if e.to_string().contains("could not acquire lock") => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            res => return res,
+        }
+    }
+    E::open(path)
+}
+
+fn set_get_through_trait<E: Engine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine: E = open_engine(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    engine.remove("key1")?;
+    assert_eq!(engine.get("key1")?, None);
+    Ok(())
+}
+
+// Every engine should be openable by path through the `Engine` trait
+#[test]
+fn open_through_engine_trait() -> Result<()> {
+    set_get_through_trait::<KvsEngine>()?;
+    set_get_through_trait::<KvsEngine<MemStorage>>()?;
+    set_get_through_trait::<SledKvsEngine>()
+}
+
+// The index should find the same keys whichever hasher it uses, spilling or
+// not, and the hasher shouldn't matter to the files
+#[test]
+fn fast_key_hash() -> Result<()> {
+    for index_spill in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvsOptions::default()
+            .fast_key_hash(true)
+            .index_spill(index_spill)
+            .index_memory_cap(100);
+        let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+        for i in 0..1000 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        for i in (0..1000).step_by(3) {
+            store.remove(format!("key{}", i))?;
+        }
+        store.compact()?;
+        for i in 0..1000 {
+            let expected = (i % 3 != 0).then(|| format!("value{}", i));
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        drop(store);
+
+        let store = KvsEngine::open(temp_dir.path())?;
+        assert_eq!(store.stats()?.keys, 666);
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+        assert_eq!(store.get("key3")?, None);
+    }
+    Ok(())
+}
+
+// The engine should run the same on an in-memory storage, compaction and
+// reopening included, without touching the disk
+#[test]
+fn engine_on_memory_storage() -> Result<()> {
+    let storage = MemStorage::new();
+    let store = KvsEngine::with_storage(storage.clone(), KvsOptions::default())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2")?;
+    for iter in 0..2000 {
+        store.set("key3".to_owned(), format!("{:0>1000}", iter))?;
+    }
+    assert!(store.stats()?.compactions > 0);
+    assert!(storage.list()?.len() <= 3);
+    drop(store);
+
+    let store = KvsEngine::with_storage(storage, KvsOptions::default())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some(format!("{:0>1000}", 1999)));
+
+    concurrent_append::<KvsEngine<MemStorage>>()?;
+    discard_keys::<KvsEngine<MemStorage>>()?;
+    remove_if_keys::<KvsEngine<MemStorage>>()
+}
+
+// A `MemStorage` whose next `failures` log file creations fail as if the
+// process had run out of file descriptors, and whose creations and syncs
+// fail while `failing_create` and `failing_sync` are set as if the disk had.
+// It counts the creations attempted in `creations`, the syncs in `syncs`,
+// and the files of its value log opened for reading in `value_reads`.
+#[derive(Debug, Clone, Default)]
+struct FlakyStorage {
+    inner: MemStorage,
+    failures: Arc<AtomicUsize>,
+    failing_create: Arc<AtomicBool>,
+    failing_sync: Arc<AtomicBool>,
+    creations: Arc<AtomicUsize>,
+    syncs: Arc<AtomicUsize>,
+    reads: Arc<AtomicUsize>,
+    value_reads: Arc<AtomicUsize>,
+}
+
+impl Storage for FlakyStorage {
+    type Reader = MemFile;
+    type Writer = MemFile;
+
+    fn open(path: PathBuf) -> Result<Self> {
+        Ok(Self {
+            inner: MemStorage::open(path)?,
+            failures: Arc::default(),
+            failing_create: Arc::default(),
+            failing_sync: Arc::default(),
+            creations: Arc::default(),
+            syncs: Arc::default(),
+            reads: Arc::default(),
+            value_reads: Arc::default(),
+        })
+    }
+
+    fn list(&self) -> Result<Vec<u64>> {
+        self.inner.list()
+    }
+
+    fn create(&self, file_id: u64) -> Result<MemFile> {
+        self.creations.fetch_add(1, Ordering::SeqCst);
+        if self.failing_create.load(Ordering::SeqCst) {
+            // EIO
+            return Err(io::Error::from_raw_os_error(5).into());
+        }
+        let fail = self
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if fail {
+            // EMFILE
+            return Err(io::Error::from_raw_os_error(24).into());
+        }
+        self.inner.create(file_id)
+    }
+
+    fn open_reader(&self, file_id: u64) -> Result<MemFile> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.open_reader(file_id)
+    }
+
+    fn sync(&self, writer: &MemFile) -> Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        if self.failing_sync.load(Ordering::SeqCst) {
+            // EIO
+            return Err(io::Error::from_raw_os_error(5).into());
+        }
+        self.inner.sync(writer)
+    }
+
+    fn len(&self, file_id: u64) -> Result<u64> {
+        self.inner.len(file_id)
+    }
+
+    fn remove(&self, file_id: u64) -> Result<()> {
+        self.inner.remove(file_id)
+    }
+
+    fn write_hints(&self, file_id: u64, hints: &[u8]) -> Result<()> {
+        self.inner.write_hints(file_id, hints)
+    }
+
+    fn read_hints(&self, file_id: u64) -> Result<Option<Vec<u8>>> {
+        self.inner.read_hints(file_id)
+    }
+
+    fn value_log(&self) -> Result<Self> {
+        Ok(Self {
+            inner: self.inner.value_log()?,
+            reads: self.value_reads.clone(),
+            ..Self::default()
+        })
+    }
+}
+
+// Creating a log file should be retried when it fails for a while, but not
+// on a failure of the disk, and a compaction failing for good should leave
+// the store usable
+#[test]
+fn log_file_creation_retried() -> Result<()> {
+    let storage = FlakyStorage::default();
+    storage.failures.store(2, Ordering::SeqCst);
+    let store = KvsEngine::with_storage(storage.clone(), KvsOptions::default())?;
+    assert_eq!(storage.failures.load(Ordering::SeqCst), 0);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value1".to_owned())?;
+    }
+
+    storage.failures.store(3, Ordering::SeqCst);
+    store.compact()?;
+    assert_eq!(store.stats()?.compactions, 1);
+
+    storage.failures.store(usize::MAX, Ordering::SeqCst);
+    assert!(matches!(store.compact(), Err(KvsError::IoErr(_))));
+    storage.failures.store(0, Ordering::SeqCst);
+
+    storage.failing_create.store(true, Ordering::SeqCst);
+    let creations = storage.creations.load(Ordering::SeqCst);
+    assert!(matches!(store.compact(), Err(KvsError::IoErr(_))));
+    assert_eq!(storage.creations.load(Ordering::SeqCst), creations + 1);
+    storage.failing_create.store(false, Ordering::SeqCst);
+    store.set("key0".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key0")?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvsEngine::with_storage(storage, KvsOptions::default())?;
+    assert_eq!(store.get("key0")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key99")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A writer keeping everything logged in memory.
+#[derive(Clone, Default)]
+struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Closing a store should return the failure of its last flush, which
+// dropping the last clone of one can only log
+#[test]
+fn close_returns_flush_failure() -> Result<()> {
+    let storage = FlakyStorage::default();
+    let store = KvsEngine::with_storage(storage.clone(), KvsOptions::default())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.clone().close()?;
+    storage.failing_sync.store(true, Ordering::SeqCst);
+    assert!(matches!(store.close(), Err(KvsError::IoErr(_))));
+
+    storage.failing_sync.store(false, Ordering::SeqCst);
+    let store = KvsEngine::with_storage(storage.clone(), KvsOptions::default())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    let clone = store.clone();
+    storage.failing_sync.store(true, Ordering::SeqCst);
+    let logs = Logs::default();
+    let make_writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || make_writer.clone())
+        .finish();
+    tracing::subscriber::with_default(subscriber, || {
+        drop(store);
+        assert!(logs.0.lock().unwrap().is_empty());
+        drop(clone);
+    });
+    let logs = String::from_utf8_lossy(&logs.0.lock().unwrap()).into_owned();
+    assert!(logs.contains("ERROR"), "{}", logs);
+    assert!(
+        logs.contains("fail to flush the kvs engine on drop"),
+        "{}",
+        logs
+    );
+    Ok(())
+}
+
+// Opening a store whose logs are mostly tombstones and overwritten values
+// should compact it when asked to, and leave it alone below the ratio
+#[test]
+fn compact_on_open() -> Result<()> {
+    let storage = MemStorage::new();
+    let options = KvsOptions::default().auto_compact(false);
+    for round in 0..5 {
+        let store = KvsEngine::with_storage(storage.clone(), options)?;
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("value{}", round))?;
+        }
+        for key_id in 100..1000 {
+            store.remove(format!("key{}", key_id))?;
+        }
+    }
+    let files = storage.list()?.len();
+    assert_eq!(files, 5);
+
+    let store = KvsEngine::with_storage(storage.clone(), options.compact_on_open(0.99))?;
+    assert_eq!(store.stats()?.compactions, 0);
+    drop(store);
+    assert_eq!(storage.list()?.len(), files + 1);
+
+    let store = KvsEngine::with_storage(storage.clone(), options.compact_on_open(0.5))?;
+    assert_eq!(store.stats()?.compactions, 1);
+    assert!(storage.list()?.len() <= 2);
+    assert_eq!(store.stats()?.keys, 100);
+    drop(store);
+
+    let store = KvsEngine::with_storage(storage, options.compact_on_open(0.5))?;
+    assert_eq!(store.stats()?.compactions, 0);
+    assert_eq!(store.stats()?.keys, 100);
+    for key_id in 0..1000 {
+        let expected = (key_id < 100).then(|| "value4".to_owned());
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    Ok(())
+}
+
+// Garbage left below the write threshold should be compacted by the periodic
+// check once the writes stop, and a store mostly live should be left alone
+#[test]
+fn compact_check_interval() -> Result<()> {
+    let storage = MemStorage::new();
+    let options = KvsOptions::default().compact_check_interval(Duration::from_millis(20), 0.5);
+    let store = KvsEngine::with_storage(storage.clone(), options)?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "value0".to_owned())?;
+    }
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.stats()?.compactions, 0);
+
+    for key_id in 100..1000 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(store.stats()?.uncompacted < 1024 * 1024);
+    let mut waited = 0;
+    while store.stats()?.compactions == 0 {
+        assert!(waited < 250, "the periodic check never compacted");
+        thread::sleep(Duration::from_millis(20));
+        waited += 1;
+    }
+    assert_eq!(store.stats()?.keys, 100);
+    for key_id in 0..1000 {
+        let expected = (key_id < 100).then(|| "value0".to_owned());
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+
+    // the versions a snapshot retains are kept by a compaction, which
+    // mustn't be repeated for them
+    let options = KvsOptions::default().compact_check_interval(Duration::from_millis(20), 0.3);
+    let store = KvsEngine::with_storage(MemStorage::new(), options)?;
+    let set_all = |value: &str| -> Result<()> {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), value.to_owned())?;
+        }
+        Ok(())
+    };
+    set_all("value0")?;
+    let snapshot = store.snapshot();
+    set_all("value1")?;
+    set_all("value2")?;
+    let mut waited = 0;
+    while store.stats()?.compactions == 0 {
+        assert!(waited < 250, "the periodic check never compacted");
+        thread::sleep(Duration::from_millis(20));
+        waited += 1;
+    }
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.stats()?.compactions, 1);
+    assert_eq!(snapshot.read("key0")?, Some("value0".to_owned()));
+    Ok(())
+}
+
+// Writes should not compact a store with fewer log files than the minimum,
+// whatever the amount of garbage, and should once it has enough
+#[test]
+fn compaction_waits_for_min_files() -> Result<()> {
+    let storage = MemStorage::new();
+    let options = KvsOptions::default().min_files_to_compact(3);
+    for round in 0..2 {
+        let store = KvsEngine::with_storage(storage.clone(), options)?;
+        for iter in 0..2000 {
+            store.set("key1".to_owned(), format!("{:0>1000}", iter))?;
+        }
+        let stats = store.stats()?;
+        assert!(stats.uncompacted > 1024 * 1024);
+        assert_eq!(stats.compactions, 0);
+        assert_eq!(storage.list()?.len(), round + 1);
+    }
+
+    let store = KvsEngine::with_storage(storage, options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.stats()?.compactions, 1);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A compaction cancelled halfway should drop its partial log and leave the
+// store readable, writable and compactable again
+#[test]
+fn compaction_cancelled() -> Result<()> {
+    let storage = MemStorage::new();
+    let options = KvsOptions::default().auto_compact(false);
+    let store = KvsEngine::with_storage(storage.clone(), options)?;
+    for key_id in 0..20000 {
+        store.set(format!("key{}", key_id), format!("{:0>100}", key_id))?;
+    }
+    let files = storage.list()?;
+    let compact_file_id = files.iter().max().unwrap() + 1;
+
+    // cancel as soon as the compacted log starts filling up
+    let token = CancelToken::new();
+    let canceller = {
+        let storage = storage.clone();
+        let token = token.clone();
+        thread::spawn(move || {
+            while storage.len(compact_file_id).unwrap_or(0) == 0 {
+                thread::yield_now();
+            }
+            token.cancel();
+        })
+    };
+    assert!(matches!(
+        store.compact_cancellable(&token),
+        Err(KvsError::Cancelled)
+    ));
+    canceller.join().unwrap();
+    assert!(!storage.list()?.contains(&compact_file_id));
+    assert_eq!(store.stats()?.compactions, 0);
+    for key_id in (0..20000).step_by(97) {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{:0>100}", key_id))
+        );
+    }
+
+    // an iteration stops at the cancellation, once
+    let token = CancelToken::new();
+    let mut iter = store.iter().cancel_on(token.clone());
+    assert!(iter.next().unwrap().is_ok());
+    token.cancel();
+    assert!(matches!(iter.next(), Some(Err(KvsError::Cancelled))));
+    assert!(iter.next().is_none());
+
+    store.set("key0".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.stats()?.compactions, 1);
+    assert_eq!(store.iter().count(), 20000);
+    drop(store);
+
+    let store = KvsEngine::with_storage(storage, options)?;
+    assert_eq!(store.get("key0")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key19999")?, Some(format!("{:0>100}", 19999)));
+    Ok(())
+}
+
+// A check given a cancelled token should stop without a report
+#[test]
+fn check_cancelled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    let token = CancelToken::new();
+    let report = KvsEngine::check_cancellable(temp_dir.path(), &token)?;
+    assert_eq!(report.keys_recovered, 100);
+    token.cancel();
+    assert!(matches!(
+        KvsEngine::check_cancellable(temp_dir.path(), &token),
+        Err(KvsError::Cancelled)
+    ));
+    Ok(())
+}
+
+fn concurrent_append<E: Engine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..100 {
+                    store.append("log".to_owned(), i.to_string()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let log = store.get("log")?.unwrap();
+    assert_eq!(log.len(), 800);
+    for i in 0..8 {
+        assert_eq!(log.matches(&i.to_string()).count(), 100);
+    }
+    Ok(())
+}
+
+// a writer overwriting a few keys while readers check that every write
+// they know returned is seen, or a later one
+fn read_your_writes<E: Engine>(store: E) -> Result<()> {
+    const KEYS: usize = 10;
+    let written: Arc<Vec<AtomicUsize>> = Arc::new((0..KEYS).map(|_| AtomicUsize::new(0)).collect());
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let written = written.clone();
+            let stop = stop.clone();
+            thread::spawn(move || -> Result<()> {
+                while !stop.load(Ordering::SeqCst) {
+                    for (key_id, written) in written.iter().enumerate() {
+                        let last = written.load(Ordering::SeqCst);
+                        if last == 0 {
+                            continue;
+                        }
+                        let value = store
+                            .get(format!("key{}", key_id))?
+                            .expect("a written key is there");
+                        let seen: usize = value[..8].parse().unwrap();
+                        assert!(seen >= last, "read {} after {} returned", seen, last);
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    // past the compaction threshold of the kvs engine
+    let padding = "x".repeat(500);
+    for i in 1..3000 {
+        let key_id = i % KEYS;
+        store.set(format!("key{}", key_id), format!("{:08}{}", i, padding))?;
+        written[key_id].store(i, Ordering::SeqCst);
+    }
+    stop.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// Once a set returns, reads on other threads should never see an older
+// value, through compactions and moves between the tiers of the index
+#[test]
+fn reads_see_returned_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    read_your_writes(store.clone())?;
+    assert!(store.stats()?.compactions > 0);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvsOptions::default()
+        .index_spill(true)
+        .index_memory_cap(3)
+        .separate_values(true);
+    read_your_writes(KvsEngine::open_with_options(temp_dir.path(), options)?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    read_your_writes(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// A versioned set should only apply on the current version, which every
+// write of the key bumps, durably and through compactions, and which a key
+// removed and set again never gets back
+#[test]
+fn set_if_version_rejects_stale_writers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get_with_version("key1")?, None);
+    assert_eq!(
+        store.set_if_version("key1".to_owned(), "a".to_owned(), 1)?,
+        None
+    );
+    let first = store
+        .set_if_version("key1".to_owned(), "a".to_owned(), 0)?
+        .unwrap();
+    assert_eq!(
+        store.get_with_version("key1")?,
+        Some(("a".to_owned(), first))
+    );
+    store.set("key1".to_owned(), "b".to_owned())?;
+    let (value, second) = store.get_with_version("key1")?.unwrap();
+    assert_eq!(value, "b");
+    assert!(second > first);
+    // the writer which read the first version lost the race
+    assert_eq!(
+        store.set_if_version("key1".to_owned(), "c".to_owned(), first)?,
+        None
+    );
+    assert_eq!(store.get("key1")?, Some("b".to_owned()));
+
+    // racing read-modify-writes each retry until they win, losing no update
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let (value, version) = store.get_with_version("counter")?.unwrap();
+                        let next = (value.parse::<u64>().unwrap() + 1).to_string();
+                        if store
+                            .set_if_version("counter".to_owned(), next, version)?
+                            .is_some()
+                        {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let (value, counted) = store.get_with_version("counter")?.unwrap();
+    assert_eq!(value, "200");
+
+    store.compact()?;
+    drop(store);
+    let store = KvsEngine::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_with_version("key1")?,
+        Some(("b".to_owned(), second))
+    );
+    assert_eq!(
+        store.get_with_version("counter")?,
+        Some(("200".to_owned(), counted))
+    );
+
+    // a key removed and set again gets a version it never had, even once
+    // its records are compacted away and the store reopened
+    store.remove("key1")?;
+    store.compact()?;
+    drop(store);
+    let store = KvsEngine::open(temp_dir.path())?;
+    assert_eq!(
+        store.set_if_version("key1".to_owned(), "d".to_owned(), second)?,
+        None
+    );
+    let again = store
+        .set_if_version("key1".to_owned(), "d".to_owned(), 0)?
+        .unwrap();
+    assert!(again > second);
+
+    // a moved value keeps its version
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvsOptions::default().separate_values(true);
+    let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "a".to_owned())?;
+    store.set("key1".to_owned(), "b".to_owned())?;
+    let (_, version) = store.get_with_version("key1")?.unwrap();
+    store.compact_values()?;
+    assert_eq!(
+        store.get_with_version("key1")?,
+        Some(("b".to_owned(), version))
+    );
+    drop(store);
+    let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(
+        store.get_with_version("key1")?,
+        Some(("b".to_owned(), version))
+    );
+    Ok(())
+}
+
+// Appends from many threads should all be kept
+#[test]
+fn concurrent_append_loses_no_update() -> Result<()> {
+    concurrent_append::<KvsEngine>()?;
+    concurrent_append::<SledKvsEngine>()
+}
+
+fn discard_keys<E: Engine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    assert!(!store.discard("key1")?);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.discard("key1")?);
+    assert_eq!(store.get("key1")?, None);
+    assert!(!store.discard("key1")?);
+
+    // racing discards of a key remove it once
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    let barrier = Arc::new(Barrier::new(4));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                (0..100)
+                    .filter(|i| store.discard(format!("key{}", i)).unwrap())
+                    .count()
+            })
+        })
+        .collect();
+    let removed: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    assert_eq!(removed, 100);
+    Ok(())
+}
+
+// Discarding should report whether the key existed, and never fail on absence
+#[test]
+fn discard_absent_and_present_keys() -> Result<()> {
+    discard_keys::<KvsEngine>()?;
+    discard_keys::<SledKvsEngine>()
+}
+
+// the outcome of each removal, errors by their message
+fn removal_outcomes<E: Engine>() -> Result<Vec<std::result::Result<(), String>>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let outcomes = vec![
+        store.remove("key1"),
+        store.remove("key1"),
+        store.remove("absent"),
+        store.remove_lenient("key2"),
+        store.remove_lenient("key2"),
+        store.remove_lenient("absent"),
+    ];
+    assert!(matches!(outcomes[1], Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, None);
+    Ok(outcomes
+        .into_iter()
+        .map(|outcome| outcome.map_err(|e| e.to_string()))
+        .collect())
+}
+
+// `remove` and `remove_lenient` should behave the same on every engine
+#[test]
+fn remove_behaves_alike_across_engines() -> Result<()> {
+    let kvs = removal_outcomes::<KvsEngine>()?;
+    assert_eq!(kvs, removal_outcomes::<SledKvsEngine>()?);
+    let not_found = Err(KvsError::KeyNotFound.to_string());
+    assert_eq!(
+        kvs,
+        [Ok(()), not_found.clone(), not_found, Ok(()), Ok(()), Ok(())]
+    );
+    Ok(())
+}
+
+// Engines of different types held side by side behind `BoxedEngine` should
+// each pass the same assertions
+#[test]
+fn boxed_engines_behave_alike() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines = vec![
+        BoxedEngine::new(KvsEngine::open(kvs_dir.path())?),
+        BoxedEngine::new(SledKvsEngine::open(sled_dir.path())?),
+    ];
+    for engine in &engines {
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+        assert!(engine.contains_key("key1")?);
+        assert_eq!(engine.append("key1".to_owned(), "!".to_owned())?, 7);
+        assert!(!engine.remove_if("key1", "value1")?);
+        assert!(engine.remove_if("key1", "value1!")?);
+        assert!(matches!(engine.remove("key1"), Err(KvsError::KeyNotFound)));
+        assert!(!engine.discard("key1")?);
+
+        // a clone is a handle on the same store
+        let clone = engine.clone();
+        clone.set("key2".to_owned(), "value2".to_owned())?;
+        clone.set("key3".to_owned(), "value3".to_owned())?;
+        assert_eq!(engine.get("key2")?, Some("value2".to_owned()));
+        assert_eq!(
+            engine.scan("key".to_owned(), Some("key2".to_owned()), 10)?,
+            vec![("key3".to_owned(), "value3".to_owned())]
+        );
+        engine.flush()?;
+        assert_eq!(engine.stats()?.keys, 2);
+    }
+    assert!(format!("{:?}", engines[1]).contains("SledKvsEngine"));
+
+    // `open` gives the default engine
+    assert_eq!(
+        removal_outcomes::<BoxedEngine>()?,
+        removal_outcomes::<KvsEngine>()?
+    );
+    Ok(())
+}
+
+// bytes sled has written to the disk, which only grows as it flushes
+fn sled_disk_usage(store: &SledKvsEngine) -> Result<u64> {
+    Ok(store.stats()?.disk_usage)
+}
+
+// Every set and remove should reach the disk before it returns
+#[test]
+fn sled_flush_per_operation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open_with_flush_policy(temp_dir.path(), FlushPolicy::PerOperation)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let after_set = sled_disk_usage(&store)?;
+    assert!(after_set > 0);
+    store.remove("key1")?;
+    assert!(sled_disk_usage(&store)? > after_set);
+    Ok(())
+}
+
+// Writes should reach the disk within the interval, without a flush
+#[test]
+fn sled_flush_periodic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = FlushPolicy::Periodic(Duration::from_millis(20));
+    let store = SledKvsEngine::open_with_flush_policy(temp_dir.path(), policy)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    thread::sleep(Duration::from_millis(300));
+    let after_set = sled_disk_usage(&store)?;
+    assert!(after_set > 0);
+    store.remove("key1")?;
+    thread::sleep(Duration::from_millis(300));
+    assert!(sled_disk_usage(&store)? > after_set);
+    Ok(())
+}
+
+// Writes should only reach the disk on `flush`
+#[test]
+fn sled_flush_manual() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open_with_flush_policy(temp_dir.path(), FlushPolicy::Manual)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1")?;
+    // sled would have flushed on its own by now
+    thread::sleep(Duration::from_millis(700));
+    assert_eq!(sled_disk_usage(&store)?, 0);
+    store.flush()?;
+    assert!(sled_disk_usage(&store)? > 0);
+
+    // flushed writes survive a reopen
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+    drop(store);
+    let store = SledKvsEngine::open_with_flush_policy(temp_dir.path(), FlushPolicy::Manual)?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A set should survive dropping the engine, flushed whatever the policy,
+// without a remove or a flush after it
+#[test]
+fn sled_flush_on_drop() -> Result<()> {
+    for policy in [FlushPolicy::default(), FlushPolicy::Manual] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = SledKvsEngine::open_with_flush_policy(temp_dir.path(), policy)?;
+        let clone = store.clone();
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+        clone.set("key2".to_owned(), "value2".to_owned())?;
+        drop(clone);
+
+        let store = reopen_engine::<SledKvsEngine>(temp_dir.path())?;
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+// A store syncing every write should sync once per set and remove, and one
+// buffering them only on `flush`
+#[test]
+fn kvs_flush_policy_syncs() -> Result<()> {
+    let storage = FlakyStorage::default();
+    let options = KvsOptions::default().flush_policy(FlushPolicy::PerOperation);
+    let store = KvsEngine::with_storage(storage.clone(), options)?;
+    let syncs = storage.syncs.load(Ordering::SeqCst);
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value1".to_owned())?;
+    }
+    store.remove("key0")?;
+    assert_eq!(storage.syncs.load(Ordering::SeqCst), syncs + 11);
+    drop(store);
+
+    let storage = FlakyStorage::default();
+    let options = KvsOptions::default().flush_policy(FlushPolicy::Manual);
+    let store = KvsEngine::with_storage(storage.clone(), options)?;
+    let syncs = storage.syncs.load(Ordering::SeqCst);
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value1".to_owned())?;
+    }
+    store.remove("key0")?;
+    assert_eq!(storage.syncs.load(Ordering::SeqCst), syncs);
+    store.flush()?;
+    assert_eq!(storage.syncs.load(Ordering::SeqCst), syncs + 1);
+    Ok(())
+}
+
+fn remove_if_keys<E: Engine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    assert!(!store.remove_if("key1", "value1")?);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(!store.remove_if("key1", "value2")?);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert!(store.remove_if("key1", "value1")?);
+    assert_eq!(store.get("key1")?, None);
+
+    // a remove racing with an update either removes the old value, or fails
+    // and leaves the update in place
+    for i in 0..200 {
+        let key = format!("key{}", i);
+        store.set(key.clone(), "old".to_owned())?;
+        let barrier = Arc::new(Barrier::new(2));
+        let updater = {
+            let (store, key, barrier) = (store.clone(), key.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                store.set(key, "new".to_owned()).unwrap();
+            })
+        };
+        barrier.wait();
+        let removed = store.remove_if(&key, "old")?;
+        updater.join().unwrap();
+        let value = store.get(&key)?;
+        assert!(
+            value == Some("new".to_owned()) || removed && value.is_none(),
+            "removed: {}, value: {:?}",
+            removed,
+            value
+        );
+        // once the update is in, the stale value doesn't match anymore
+        if value.is_some() {
+            assert!(!store.remove_if(&key, "old")?);
+            assert_eq!(store.get(&key)?, Some("new".to_owned()));
+        }
+    }
+    Ok(())
+}
+
+// Removing on a stale value should fail, whatever the interleaving
+#[test]
+fn remove_if_loses_to_racing_update() -> Result<()> {
+    remove_if_keys::<KvsEngine>()?;
+    remove_if_keys::<SledKvsEngine>()
+}
+
+fn rename_keys<E: Engine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    assert!(matches!(
+        store.rename("staging:x".to_owned(), "prod:x".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    store.set("staging:x".to_owned(), "1".to_owned())?;
+    store.set("prod:x".to_owned(), "0".to_owned())?;
+    store.rename("staging:x".to_owned(), "prod:x".to_owned())?;
+    assert_eq!(store.get("staging:x")?, None);
+    assert_eq!(store.get("prod:x")?, Some("1".to_owned()));
+    store.rename("prod:x".to_owned(), "prod:x".to_owned())?;
+    assert_eq!(store.get("prod:x")?, Some("1".to_owned()));
+    drop(store);
+
+    let store = reopen_engine::<E>(temp_dir.path())?;
+    assert_eq!(store.get("staging:x")?, None);
+    assert_eq!(store.get("prod:x")?, Some("1".to_owned()));
+
+    // a value renamed along a chain of keys is always found by a reader
+    // looking it up from the last key it was seen under onwards
+    const RENAMES: usize = 1000;
+    store.set("key0".to_owned(), "value".to_owned())?;
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut seen = 0;
+                while seen < RENAMES {
+                    let mut i = seen;
+                    while store.get(format!("key{}", i))?.is_none() {
+                        i += 1;
+                        assert!(i <= RENAMES, "key{} was renamed to no key", seen);
+                    }
+                    seen = i;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for i in 0..RENAMES {
+        store.rename(format!("key{}", i), format!("key{}", i + 1))?;
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// Renaming should move the value atomically, overwriting the target
+#[test]
+fn rename_is_atomic() -> Result<()> {
+    rename_keys::<KvsEngine>()?;
+    rename_keys::<SledKvsEngine>()
+}
+
+fn empty_values<E: Engine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    store.set("key1".to_owned(), String::new())?;
+    assert_eq!(store.get("key1")?, Some(String::new()));
+    assert_eq!(store.get("key2")?, None);
+    assert!(store.remove_if("key1", "")?);
+    assert_eq!(store.get("key1")?, None);
+    store.set("key1".to_owned(), String::new())?;
+    assert_eq!(store.append("key1".to_owned(), String::new())?, 0);
+    assert_eq!(store.get("key1")?, Some(String::new()));
+    drop(store);
+
+    // an empty value is also kept apart from a removed key when replaying
+    let store = reopen_engine::<E>(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some(String::new()));
+    store.remove("key1")?;
+    assert_eq!(store.get("key1")?, None);
+    Ok(())
+}
+
+// An empty value should round-trip as `Some("")`, distinct from an absent key
+#[test]
+fn empty_value_is_not_absent() -> Result<()> {
+    empty_values::<KvsEngine>()?;
+    empty_values::<KvsEngine<MemStorage>>()?;
+    empty_values::<SledKvsEngine>()
+}
+
+fn remove_prefixes<E: Engine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    for key_id in 0..50 {
+        store.set(format!("session:{}", key_id), "1".to_owned())?;
+        store.set(format!("user:{}", key_id), "1".to_owned())?;
+    }
+    store.set("session".to_owned(), "1".to_owned())?;
+    store.remove("session:0")?;
+    assert_eq!(store.remove_prefix("session:".to_owned())?, 49);
+    assert_eq!(store.remove_prefix("session:".to_owned())?, 0);
+    assert_eq!(store.scan("session:".to_owned(), None, 100)?, Vec::new());
+    assert_eq!(store.get("session")?, Some("1".to_owned()));
+    assert_eq!(store.scan("user:".to_owned(), None, 100)?.len(), 50);
+    drop(store);
+
+    let store = reopen_engine::<E>(temp_dir.path())?;
+    assert_eq!(store.get("session:1")?, None);
+    assert_eq!(store.get("user:1")?, Some("1".to_owned()));
+    assert_eq!(store.remove_prefix(String::new())?, 51);
+    assert_eq!(store.scan(String::new(), None, 100)?, Vec::new());
+    Ok(())
+}
+
+// A key validator should reject the keys it doesn't accept, leaving the
+// store unchanged, and the keys it rewrites should be stored and looked up
+// rewritten
+#[test]
+fn validated_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?.validate_keys(|key| {
+        if key.len() > 16 {
+            return Err(KvsError::InvalidKey(format!("{} is over 16 bytes", key)));
+        }
+        Ok(Cow::Borrowed(key))
+    });
+    let long = "k".repeat(17);
+    store.set("k".repeat(16), "value".to_owned())?;
+    for res in [
+        store.set(long.clone(), "value".to_owned()),
+        store.remove(&long),
+        store.get(&long).map(|_| ()),
+    ] {
+        assert!(matches!(res, Err(KvsError::InvalidKey(_))));
+    }
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned());
+    batch.set(long, "value".to_owned());
+    assert!(matches!(
+        store.write_batch(batch),
+        Err(KvsError::InvalidKey(_))
+    ));
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.count_prefix(String::new())?, 1);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        KvsEngine::open(temp_dir.path())?.validate_keys(|key| Ok(Cow::Owned(key.to_lowercase())));
+    store.set("Key1".to_owned(), "value1".to_owned())?;
+    store.set("KEY1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    assert_eq!(
+        store.scan(String::new(), None, 10)?,
+        [("key1".to_owned(), "value2".to_owned())]
+    );
+    store.remove("kEy1")?;
+    assert_eq!(store.get("Key1")?, None);
+    Ok(())
+}
+
+fn count_prefixes<E: Engine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    assert_eq!(store.count_prefix(String::new())?, 0);
+    for key_id in 0..50 {
+        store.set(format!("session:{}", key_id), "1".to_owned())?;
+        store.set(format!("user:{}", key_id), "1".to_owned())?;
+    }
+    store.set("session".to_owned(), "1".to_owned())?;
+    store.set("session:1".to_owned(), "2".to_owned())?;
+    store.remove("session:0")?;
+    assert_eq!(store.count_prefix("session:".to_owned())?, 49);
+    assert_eq!(store.count_prefix("session".to_owned())?, 50);
+    assert_eq!(store.count_prefix("user:4".to_owned())?, 11);
+    assert_eq!(store.count_prefix("admin:".to_owned())?, 0);
+    assert_eq!(store.count_prefix(String::new())?, 100);
+    drop(store);
+
+    let store = reopen_engine::<E>(temp_dir.path())?;
+    assert_eq!(store.count_prefix("session:".to_owned())?, 49);
+    assert_eq!(store.count_prefix(String::new())?, 100);
+    Ok(())
+}
+
+// Counting a prefix should count exactly the live keys starting with it,
+// all of them for an empty prefix
+#[test]
+fn count_prefix_counts_matching_keys() -> Result<()> {
+    count_prefixes::<KvsEngine>()?;
+    count_prefixes::<KvsEngine<MemStorage>>()?;
+    count_prefixes::<SledKvsEngine>()
+}
+
+// Filtering a scan should return exactly the pairs the predicate holds for,
+// in key order, skipping removed keys
+#[test]
+fn scan_filter_matches_predicate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{:02}", key_id), format!("value{}", key_id * 2))?;
+    }
+    store.remove("key04")?;
+    assert_eq!(store.scan_filter(|_, _| false)?, []);
+    assert_eq!(store.scan_filter(|_, _| true)?.len(), 99);
+
+    // key04 held value8 before its removal
+    let entries =
+        store.scan_filter(|key, value| key.starts_with("key0") && value.ends_with('8'))?;
+    assert_eq!(entries, [("key09".to_owned(), "value18".to_owned())]);
+    let entries = store.scan_filter(|_, value| value.ends_with("44"))?;
+    assert_eq!(
+        entries,
+        [
+            ("key22".to_owned(), "value44".to_owned()),
+            ("key72".to_owned(), "value144".to_owned()),
+        ]
+    );
+    Ok(())
+}
+
+// The content digest should be a SHA-256 of the pairs alone: equal for a
+// compacted copy, different once a value changes
+#[test]
+fn content_digest_ignores_layout() -> Result<()> {
+    let hex = |digest: [u8; 32]| -> String {
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path().join("store"))?;
+    assert_eq!(
+        hex(store.content_digest()?),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        hex(store.content_digest()?),
+        "d0c6a26d3508aa9ac2c785c03f17b8b877eb4db4fa0a65be69a90e788421cee0"
+    );
+
+    // overwritten and removed pairs leave garbage the copy drops, and key1
+    // keeps its value
+    for key_id in (0..1000).rev() {
+        store.set(format!("key{:03}", key_id), "stale".to_owned())?;
+    }
+    store.set("gone".to_owned(), "value".to_owned())?;
+    store.remove("gone")?;
+    for key_id in 0..1000 {
+        store.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+    }
+    let digest = store.content_digest()?;
+    assert_eq!(
+        hex(digest),
+        "3dc9358feec45307bb684eca83da47d9a28a1cbd7f758837559f93e61000cb7f"
+    );
+
+    store.compact_to(temp_dir.path().join("copy"))?;
+    let copy = KvsEngine::open(temp_dir.path().join("copy"))?;
+    assert_eq!(copy.content_digest()?, digest);
+    copy.set("key500".to_owned(), "value501".to_owned())?;
+    assert_ne!(copy.content_digest()?, digest);
+    Ok(())
+}
+
+// Removing a prefix should remove exactly the keys starting with it, for
+// good, and count them
+#[test]
+fn remove_prefix_removes_matching_keys() -> Result<()> {
+    remove_prefixes::<KvsEngine>()?;
+    remove_prefixes::<KvsEngine<MemStorage>>()?;
+    remove_prefixes::<SledKvsEngine>()
+}
+
+// A scan or a count running along removals of a prefix should see either
+// all of its keys or none of them
+#[test]
+fn remove_prefix_is_atomic_to_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (store, done) = (store.clone(), done.clone());
+        thread::spawn(move || -> Result<()> {
+            for _ in 0..200 {
+                let mut batch = WriteBatch::new();
+                for key_id in 0..100 {
+                    batch.set(format!("session:{:03}", key_id), "1".to_owned());
+                }
+                store.write_batch(batch)?;
+                assert_eq!(store.remove_prefix("session:".to_owned())?, 100);
+            }
+            done.store(true, Ordering::SeqCst);
+            Ok(())
+        })
+    };
+    while !done.load(Ordering::SeqCst) {
+        let scanned = store.scan("session:".to_owned(), None, 1000)?.len();
+        assert!(scanned == 0 || scanned == 100, "scanned {} keys", scanned);
+        let counted = store.count_prefix("session:".to_owned())?;
+        assert!(counted == 0 || counted == 100, "counted {} keys", counted);
+    }
+    writer.join().unwrap()
+}
+
+// A scan running along sets of the same keys should see the values of a
+// single `set_all` only
+#[test]
+fn set_all_is_atomic_to_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    let pairs = |value: &str| -> Vec<(String, String)> {
+        (0..100)
+            .map(|key_id| (format!("key{:03}", key_id), value.to_owned()))
+            .collect()
+    };
+    store.set_all(pairs("a"))?;
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (store, done) = (store.clone(), done.clone());
+        thread::spawn(move || -> Result<()> {
+            for round in 0..200 {
+                store.set_all(pairs(if round % 2 == 0 { "b" } else { "a" }))?;
+            }
+            done.store(true, Ordering::SeqCst);
+            Ok(())
+        })
+    };
+    while !done.load(Ordering::SeqCst) {
+        let scanned = store.scan("key".to_owned(), None, 1000)?;
+        assert_eq!(scanned.len(), 100);
+        assert!(
+            scanned.iter().all(|(_, value)| *value == scanned[0].1),
+            "scanned a partial set_all"
+        );
+    }
+    writer.join().unwrap()
+}
+
+fn value_lens<E: Engine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    assert_eq!(store.value_len("key1")?, None);
+    for value in ["value1", "", "naïve café", "日本語", "🦀🦀"] {
+        store.set("key1".to_owned(), value.to_owned())?;
+        assert_eq!(store.value_len("key1")?, Some(value.len()));
+    }
+    // the byte length, not the number of characters
+    assert_eq!(store.value_len("key1")?, Some(8));
+    store.remove("key1")?;
+    assert_eq!(store.value_len("key1")?, None);
+    Ok(())
+}
+
+// The length of a value should be its length in bytes, multibyte characters
+// included
+#[test]
+fn value_len_in_bytes() -> Result<()> {
+    value_lens::<KvsEngine>()?;
+    value_lens::<KvsEngine<MemStorage>>()?;
+    value_lens::<SledKvsEngine>()
+}
+
+// A coalescing batch should write only the last of many overwrites of a key
+#[test]
+fn coalescing_batch_writes_one_record_per_key() -> Result<()> {
+    let single = KvsEngine::with_storage(MemStorage::new(), KvsOptions::default())?;
+    single.set("key1".to_owned(), "value999".to_owned())?;
+
+    let store = KvsEngine::with_storage(MemStorage::new(), KvsOptions::default())?;
+    let mut batch = WriteBatch::new().coalesce(true);
+    for i in 0..1000 {
+        batch.set("key1".to_owned(), format!("value{}", i));
+    }
+    assert_eq!(batch.len(), 1000);
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key1")?, Some("value999".to_owned()));
+    let stats = store.stats()?;
+    assert_eq!(stats.uncompacted, 0);
+    assert_eq!(stats.disk_usage, single.stats()?.disk_usage);
+
+    // without coalescing, every overwrite is written
+    let store = KvsEngine::with_storage(MemStorage::new(), KvsOptions::default())?;
+    let mut batch = WriteBatch::new();
+    for i in 0..1000 {
+        batch.set("key1".to_owned(), format!("value{}", i));
+    }
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key1")?, Some("value999".to_owned()));
+    assert!(store.stats()?.uncompacted > 0);
+    Ok(())
+}
+
+// A batch should apply its writes in order, skipping removes of absent keys,
+// and be replayed like single writes
+#[test]
+fn batch_applies_writes_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for coalesce in [false, true] {
+        let mut batch = WriteBatch::new().coalesce(coalesce);
+        batch
+            .remove("key1".to_owned())
+            .set("key1".to_owned(), "value2".to_owned())
+            .set("key2".to_owned(), "value3".to_owned())
+            .remove("key2".to_owned())
+            .remove("key3".to_owned());
+        store.write_batch(batch)?;
+        assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+        assert_eq!(store.get("key2")?, None);
+        assert_eq!(store.get("key3")?, None);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    store.write_batch(WriteBatch::new())?;
+    drop(store);
+
+    let store = KvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    Ok(())
+}
+
+fn dir_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum()
+}
+
+// Under a skewed load, the keys accessed the most should come first among
+// the hot keys, counted at least as many times as they were accessed
+#[test]
+fn hot_keys_under_skewed_access() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    store.set("hot".to_owned(), "value".to_owned())?;
+    store.get("hot")?;
+    assert!(store.hot_keys(10).is_empty());
+
+    let store = store.track_hot_keys(8);
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..1000 {
+                    store.get("hot")?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for i in 0..3000 {
+        store.set(format!("cold{}", i), "value".to_owned())?;
+        if i % 10 == 0 {
+            store.set("warm".to_owned(), "value".to_owned())?;
+        }
+        store.get(format!("cold{}", i % 1000))?;
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    let hot_keys = store.hot_keys(2);
+    assert_eq!(hot_keys.len(), 2);
+    assert_eq!(hot_keys[0].0, "hot");
+    assert!(hot_keys[0].1 >= 4000);
+    assert_eq!(hot_keys[1].0, "warm");
+    assert!(hot_keys[1].1 >= 300 && hot_keys[1].1 < 4000);
+    assert!(store.hot_keys(100).len() <= 8);
+    Ok(())
+}
+
+// With the values separated, a compaction should leave the value log alone,
+// and the value log should be reclaimed apart, whatever reads run meanwhile
+#[test]
+fn separated_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let values_dir = temp_dir.path().join("values");
+    let options = KvsOptions::default()
+        .auto_compact(false)
+        .separate_values(true);
+    let value = |key_id: usize, iter: usize| format!("{:0>10000}", key_id * 10 + iter);
+    let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), value(key_id, iter))?;
+        }
+    }
+    for key_id in 50..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.flush()?;
+    let values_size = dir_size(&values_dir);
+    assert!(values_size > 3 * 100 * 10000);
+    // the log holds only the keys
+    assert!(dir_size(temp_dir.path()) - values_size < 100 * 10000);
+
+    store.compact()?;
+    assert_eq!(dir_size(&values_dir), values_size);
+    for key_id in 0..100 {
+        let expected = (key_id < 50).then(|| value(key_id, 2));
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    drop(store);
+
+    // the values are read whatever the option, and written inline without it
+    let store = KvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key0")?, Some(value(0, 2)));
+    store.set("key0".to_owned(), value(0, 3))?;
+    drop(store);
+
+    let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0")?, Some(value(0, 3)));
+    let snapshot = store.snapshot();
+    assert_eq!(store.compact_values()?, 0);
+    drop(snapshot);
+    let stop = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let store = store.clone();
+        let stop = stop.clone();
+        thread::spawn(move || -> Result<()> {
+            while !stop.load(Ordering::SeqCst) {
+                for key_id in 1..50 {
+                    assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id, 2)));
+                }
+                assert_eq!(store.iter().count(), 50);
+            }
+            Ok(())
+        })
+    };
+    assert!(store.compact_values()? > values_size / 2);
+    store.set("key1".to_owned(), value(1, 2))?;
+    store.compact_values()?;
+    stop.store(true, Ordering::SeqCst);
+    reader.join().unwrap()?;
+    assert!(dir_size(&values_dir) < values_size / 2);
+    assert!(store.stats()?.disk_usage >= dir_size(&values_dir) - 4096);
+    drop(store);
+
+    let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+    for key_id in 1..100 {
+        let expected = (key_id < 50).then(|| value(key_id, 2));
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    assert_eq!(store.get("key0")?, Some(value(0, 3)));
+
+    // the same on an in-memory storage
+    let storage = MemStorage::new();
+    let mem_store = KvsEngine::with_storage(storage.clone(), options)?;
+    for iter in 0..3 {
+        mem_store.set("key1".to_owned(), value(1, iter))?;
+    }
+    assert!(mem_store.compact_values()? > 0);
+    drop(mem_store);
+    let mem_store = KvsEngine::with_storage(storage, options)?;
+    assert_eq!(mem_store.get("key1")?, Some(value(1, 2)));
+
+    // a copy holds the values in its log
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.compact_to(dest_dir.path())?;
+    assert!(!dest_dir.path().join("values").exists());
+    let copy = KvsEngine::open(dest_dir.path())?;
+    assert_eq!(copy.get("key1")?, Some(value(1, 2)));
+    Ok(())
+}
+
+// Compacting to a new directory should copy only the live data
+#[test]
+fn compact_to_new_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in 50..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.flush()?;
+    let source_size = dir_size(temp_dir.path());
+
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.compact_to(dest_dir.path())?;
+    assert_eq!(dir_size(temp_dir.path()), source_size);
+    assert!(dir_size(dest_dir.path()) < source_size);
+    // an existing store is never overwritten
+    assert!(store.compact_to(dest_dir.path()).is_err());
+
+    let copy = KvsEngine::open(dest_dir.path())?;
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(copy.get(key.clone())?, store.get(key)?);
+    }
+    assert_eq!(copy.get("key0")?, Some("9".to_owned()));
+    assert_eq!(copy.get("key50")?, None);
+    Ok(())
+}
+
+// `try_set` should give up instead of waiting while another thread writes
+#[test]
+fn try_set_fails_fast_on_held_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let store = store.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            // keep the writer lock busy with large values
+            let value = "v".repeat(64 * 1024);
+            while !stop.load(Ordering::SeqCst) {
+                store.set("busy".to_owned(), value.clone()).unwrap();
+            }
+        })
+    };
+
+    let mut attempts = Vec::new();
+    for i in 0..100_000 {
+        let stored = store.try_set(format!("probe{}", i), i.to_string())?;
+        attempts.push(stored);
+        if !stored {
+            break;
+        }
+    }
+    stop.store(true, Ordering::SeqCst);
+    writer.join().unwrap();
+
+    assert_eq!(
+        attempts.last(),
+        Some(&false),
+        "try_set never found the lock held"
+    );
+    for (i, stored) in attempts.into_iter().enumerate() {
+        let expected = if stored { Some(i.to_string()) } else { None };
+        assert_eq!(store.try_get(format!("probe{}", i))?, expected);
+    }
+    Ok(())
+}
+
+// Logs written before the record format was versioned should stay readable,
+// mixed with the records written now
+#[test]
+fn read_legacy_and_versioned_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"old1"}}{"Set":{"key":"key2","value":"old2"}}{"Set":{"key":"key3","value":"old3"}}{"Remove":{"key":"key2"}}"#,
+    )?;
+
+    let store = KvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("old1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    store.set("key3".to_owned(), "new3".to_owned())?;
+    store.set("key4".to_owned(), "new4".to_owned())?;
+    store.flush()?;
+    drop(store);
+
+    // the new records carry the format version
+    let log = fs::read_to_string(temp_dir.path().join("2.log"))?;
+    assert!(log.starts_with(&format!("[{},", FORMAT_VERSION)));
+
+    let store = KvsEngine::open(temp_dir.path())?;
+    let copy_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.compact_to(copy_dir.path())?;
+    for store in [store, KvsEngine::open(copy_dir.path())?] {
+        assert_eq!(store.get("key1")?, Some("old1".to_owned()));
+        assert_eq!(store.get("key2")?, None);
+        assert_eq!(store.get("key3")?, Some("new3".to_owned()));
+        assert_eq!(store.get("key4")?, Some("new4".to_owned()));
+    }
+    Ok(())
+}
+
+// A record of a format version this build doesn't know is an error
+#[test]
+fn reject_unknown_format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"[255,{"Set":{"key":"key1","value":"value1"}}]"#,
+    )?;
+    assert!(KvsEngine::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// A corrupted hint file should be ignored and its log replayed instead
+#[test]
+fn corrupted_hint_file_falls_back_to_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    let hint_files = || -> Vec<std::path::PathBuf> {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("hint".as_ref()))
+            .collect()
+    };
+    let mut iter = 0;
+    while hint_files().is_empty() {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+    }
+    // written after the compaction, so not covered by the hints
+    store.remove("key0")?;
+    store.set("key1".to_owned(), "after".to_owned())?;
+    drop(store);
+
+    let check = || -> Result<()> {
+        let store = KvsEngine::open(temp_dir.path())?;
+        assert_eq!(store.get("key0")?, None);
+        assert_eq!(store.get("key1")?, Some("after".to_owned()));
+        for key_id in 2..1000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}", iter - 1))
+            );
+        }
+        Ok(())
+    };
+    // with the valid hints
+    check()?;
+
+    let hint_file = hint_files().pop().unwrap();
+    let mut buf = fs::read(&hint_file)?;
+    let middle = buf.len() / 2;
+    buf[middle] ^= 0xff;
+    fs::write(&hint_file, &buf)?;
+    check()
+}
+
+// The files of a store should not depend on the byte order of the host: the
+// records are JSON text and the checksum of a hint file is little-endian
+#[test]
+fn store_files_are_byte_order_independent() -> Result<()> {
+    let storage = MemStorage::new();
+    let store =
+        KvsEngine::with_storage(storage.clone(), KvsOptions::default().auto_compact(false))?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact()?;
+    drop(store);
+
+    let mut hints = 0;
+    for file_id in storage.list()? {
+        let mut log = String::new();
+        storage.open_reader(file_id)?.read_to_string(&mut log)?;
+        for record in
+            serde_json::Deserializer::from_str(&log).into_iter::<(u8, serde_json::Value)>()
+        {
+            assert_eq!(record?.0, FORMAT_VERSION);
+        }
+        if let Some(buf) = storage.read_hints(file_id)? {
+            let (body, trailer) = buf.split_at(buf.len() - 4);
+            assert_eq!(trailer, crc32fast::hash(body).to_le_bytes());
+            serde_json::from_slice::<serde_json::Value>(body)?;
+            hints += 1;
+        }
+    }
+    assert_eq!(hints, 1);
+    Ok(())
+}
+
+// Syncing the directories after creating, removing or renaming files can't
+// be checked without a power loss, only that it works in every layout
+#[test]
+fn sync_dirs_in_every_layout() -> Result<()> {
+    let flat = KvsOptions::default();
+    let sharded = flat.layout(LogLayout::Sharded);
+    for options in [
+        flat,
+        sharded,
+        sharded.separate_values(true),
+        flat.sync_dirs(false),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        for i in 0..50 {
+            store.remove(format!("key{}", i))?;
+        }
+        // compactions create, rename and remove files
+        store.compact()?;
+        store.compact_values()?;
+        store.compact()?;
+        drop(store);
+
+        let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+        for i in 0..100 {
+            let expected = (i >= 50).then(|| format!("value{}", i));
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+    }
+    Ok(())
+}
+
+// A log file copied under another name of its id, or into another shard,
+// makes the store ambiguous: opening it should fail naming the file rather
+// than pick one of the copies
+#[test]
+fn open_refuses_ambiguous_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter()
+        .find(|path| path.extension() == Some("log".as_ref()))
+        .expect("a log file");
+    let name = log.file_name().unwrap().to_str().unwrap().to_owned();
+    let copy = temp_dir.path().join(format!("0{}", name));
+    fs::copy(&log, &copy)?;
+    let err = KvsEngine::open(temp_dir.path()).unwrap_err().to_string();
+    assert!(err.contains(&copy.display().to_string()), "{}", err);
+    fs::remove_file(&copy)?;
+    assert_eq!(
+        KvsEngine::open(temp_dir.path())?.get("key1")?,
+        Some("value1".to_owned())
+    );
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sharded = KvsOptions::default().layout(LogLayout::Sharded);
+    let store = KvsEngine::open_with_options(temp_dir.path(), sharded)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let shard = temp_dir.path().join("000");
+    let log = fs::read_dir(&shard)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter()
+        .find(|path| path.extension() == Some("log".as_ref()))
+        .expect("a log file");
+    let other_shard = temp_dir.path().join("001");
+    fs::create_dir(&other_shard)?;
+    let copy = other_shard.join(log.file_name().unwrap());
+    fs::copy(&log, &copy)?;
+    let err = KvsEngine::open_with_options(temp_dir.path(), sharded)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains(&copy.display().to_string()), "{}", err);
+    Ok(())
+}
+
+// A replica should serve what the primary wrote once it polled, report how
+// far behind it is until then, and follow the primary through a compaction
+#[test]
+fn replica_follows_primary() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = KvsEngine::open(temp_dir.path())?;
+    primary.set("key1".to_owned(), "value1".to_owned())?;
+    // polled by hand only
+    let replica = Replica::open(temp_dir.path(), Duration::from_secs(3600))?;
+    assert_eq!(replica.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(replica.replication_lag()?, 0);
+
+    primary.set("key1".to_owned(), "value2".to_owned())?;
+    primary.set("key2".to_owned(), "value3".to_owned())?;
+    assert!(replica.replication_lag()? > 0);
+    assert_eq!(replica.get("key1")?, Some("value1".to_owned()));
+    assert!(!replica.contains_key("key2")?);
+    replica.poll()?;
+    assert_eq!(replica.replication_lag()?, 0);
+    assert_eq!(replica.get("key1")?, Some("value2".to_owned()));
+    assert_eq!(replica.get("key2")?, Some("value3".to_owned()));
+
+    primary.remove("key1")?;
+    primary.compact()?;
+    primary.set("key3".to_owned(), "value4".to_owned())?;
+    replica.poll()?;
+    assert_eq!(replica.get("key1")?, None);
+    assert_eq!(replica.get("key2")?, Some("value3".to_owned()));
+    assert_eq!(replica.get("key3")?, Some("value4".to_owned()));
+
+    // and without being told to
+    let replica = Replica::open(temp_dir.path(), Duration::from_millis(10))?;
+    primary.set("key4".to_owned(), "value5".to_owned())?;
+    for _ in 0..500 {
+        if replica.contains_key("key4")? {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(replica.get("key4")?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// a store opened with `policy` whose first record, the value of key1, is
+// overwritten behind its back, so that the index entry of key1 dangles
+fn dangling_store(policy: DanglingPolicy, truncate: bool) -> Result<(TempDir, KvsEngine)> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        KvsEngine::open_with_options(temp_dir.path(), KvsOptions::default().on_dangling(policy))?;
+    // key1 is the one damaged, the last record of the log when it's truncated
+    let keys = if truncate {
+        ["key2", "key1"]
+    } else {
+        ["key1", "key2"]
+    };
+    for key in keys {
+        store.set(key.to_owned(), key.replace("key", "value"))?;
+    }
+    let log = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.path().extension() == Some("log".as_ref()))
+        .expect("a log file");
+    let mut file = fs::OpenOptions::new().write(true).open(log.path())?;
+    if truncate {
+        file.set_len(file.metadata()?.len() - 5)?;
+    } else {
+        file.write_all(b"garbage")?;
+    }
+    Ok((temp_dir, store))
+}
+
+// A get of a key whose value can't be read, overwritten or cut short by a
+// truncation, should fail, read it as missing, or drop it from the index, as
+// the policy says, other keys being unaffected
+#[test]
+fn get_dangling_entry_per_policy() -> Result<()> {
+    for truncate in [false, true] {
+        let (_temp_dir, store) = dangling_store(DanglingPolicy::Strict, truncate)?;
+        assert!(store.get("key1").is_err());
+        assert!(store.get("key1").is_err());
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+        let (_temp_dir, store) = dangling_store(DanglingPolicy::Lenient, truncate)?;
+        assert_eq!(store.get("key1")?, None);
+        assert_eq!(store.get("key1")?, None);
+        assert!(store.contains_key("key1")?);
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+        let (_temp_dir, store) = dangling_store(DanglingPolicy::SelfHeal, truncate)?;
+        assert_eq!(store.get("key1")?, None);
+        assert!(!store.contains_key("key1")?);
+        assert_eq!(store.count_prefix("key".to_owned())?, 1);
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+        // the key can be written again
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    }
+    Ok(())
+}
+
+// A sharded store should spread its log files over subdirectories and
+// recover from all of them. The layout of an existing store is detected.
+#[test]
+fn sharded_layout_recovers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sharded = KvsOptions::default().layout(LogLayout::Sharded);
+    // every open starts a new log file
+    let opens = FILES_PER_DIR * 2 + 10;
+    for i in 0..opens {
+        let store = KvsEngine::open_with_options(temp_dir.path(), sharded)?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let mut entries: Vec<String> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    entries.sort();
+    assert_eq!(entries, ["000", "001", "002"]);
+
+    let store = KvsEngine::open(temp_dir.path())?;
+    for i in 0..opens {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert!(fs::read_dir(temp_dir.path())?.all(|entry| entry.unwrap().path().is_dir()));
+
+    // and a flat store stays flat
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvsEngine::open(temp_dir.path())?.set("key".to_owned(), "value".to_owned())?;
+    let store = KvsEngine::open_with_options(temp_dir.path(), sharded)?;
+    assert_eq!(store.get("key")?, Some("value".to_owned()));
+    assert!(fs::read_dir(temp_dir.path())?.all(|entry| entry.unwrap().path().is_file()));
+    Ok(())
+}
+
+// The index estimate should grow linearly with the number of keys and their length
+#[test]
+fn index_memory_estimate_grows_with_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.index_memory_estimate(), 0);
+
+    let set_keys = |from: usize, to: usize, len: usize| -> Result<usize> {
+        for i in from..to {
+            store.set(format!("{:0width$}", i, width = len), "value".to_owned())?;
+        }
+        Ok(store.index_memory_estimate())
+    };
+    let short_1000 = set_keys(0, 1000, 10)?;
+    let short_2000 = set_keys(1000, 2000, 10)?;
+    assert_eq!(short_2000, 2 * short_1000);
+    // overwrites don't grow the index
+    assert_eq!(set_keys(0, 1000, 10)?, short_2000);
+
+    // 1000 keys longer by 100 bytes, held twice
+    let long_1000 = set_keys(0, 1000, 110)? - short_2000;
+    assert!(long_1000 >= short_1000 + 2 * 100 * 1000);
+    assert!(long_1000 <= short_1000 + 3 * 100 * 1000);
+    Ok(())
+}
+
+// Overwriting the keys of a file should raise its fragmentation, and only its
+#[test]
+fn overwrites_fragment_their_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvsOptions::default().auto_compact(false);
+    let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // the keys are in the first file, the writes go to the second
+    let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.file_fragmentation(), [(1, 0.0), (2, 0.0)]);
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let fragmentation = store.file_fragmentation();
+    assert_eq!(fragmentation[0].0, 1);
+    assert!(
+        (0.4..0.6).contains(&fragmentation[0].1),
+        "{:?}",
+        fragmentation
+    );
+    assert_eq!(fragmentation[1], (2, 0.0));
+    for i in 50..100 {
+        store.remove(format!("key{}", i))?;
+    }
+    let fragmentation = store.file_fragmentation();
+    assert_eq!(fragmentation[0], (1, 1.0));
+    // the removes themselves are garbage
+    assert!(fragmentation[1].1 > 0.0);
+
+    // the same figures are found when reopening
+    drop(store);
+    let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(&store.file_fragmentation()[..2], &fragmentation[..]);
+
+    // a compaction leaves one log holding only live values
+    store.compact()?;
+    let fragmentation = store.file_fragmentation();
+    assert_eq!(fragmentation.len(), 2);
+    assert!(fragmentation.iter().all(|(_, ratio)| *ratio == 0.0));
+    Ok(())
+}
+
+// Without auto compaction, the logs should only shrink on an explicit compaction
+#[test]
+fn no_compaction_unless_asked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvsOptions::default().auto_compact(false);
+    let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+
+    let mut size = dir_size(temp_dir.path());
+    for iter in 0..100 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+        let new_size = dir_size(temp_dir.path());
+        assert!(new_size > size, "the logs shrank without compaction");
+        size = new_size;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 0);
+    // far past the threshold auto compaction runs at
+    assert!(stats.uncompacted > 4 * 1024 * 1024);
+
+    store.compact()?;
+    assert_eq!(store.stats()?.compactions, 1);
+    assert!(dir_size(temp_dir.path()) < size / 10);
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value99".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Pretty-printed records should be legible in the log, and read back like
+// compact ones, even when both kinds are mixed
+#[test]
+fn human_readable_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pretty = KvsOptions::default().human_readable_log(true);
+    let store = KvsEngine::open_with_options(temp_dir.path(), pretty)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..50 {
+        store.remove(format!("key{}", i))?;
+    }
+    assert_eq!(store.get("key50")?, Some("value50".to_owned()));
+    assert_eq!(store.get("key0")?, None);
+
+    let log = fs::read_to_string(temp_dir.path().join("1.log"))?;
+    assert!(log.starts_with("[\n  1,\n  {\n    \"Set\": {\n"), "{}", log);
+    assert!(log.contains("\n]\n[\n"));
+    assert!(log.ends_with("\n]\n"));
+
+    // compact records follow the pretty ones
+    drop(store);
+    let store = KvsEngine::open(temp_dir.path())?;
+    for i in 50..75 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        store.set(format!("key{}", i), format!("compact{}", i))?;
+    }
+
+    let check = |store: &KvsEngine| -> Result<()> {
+        for i in 0..100 {
+            let expected = match i {
+                0..=49 => None,
+                50..=74 => Some(format!("compact{}", i)),
+                _ => Some(format!("value{}", i)),
+            };
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        Ok(())
+    };
+    drop(store);
+    let store = KvsEngine::open_with_options(temp_dir.path(), pretty)?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    check(&KvsEngine::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+// With a tiny memory cap, most of the index should spill to the disk and
+// still find every key, across writes, compactions and reopens
+#[test]
+fn index_spill() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvsOptions::default().index_spill(true).index_memory_cap(16);
+    let store = KvsEngine::open_with_options(temp_dir.path(), options)?;
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    let unspilled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let unspilled = KvsEngine::open(unspilled_dir.path())?;
+    for i in 0..1000 {
+        unspilled.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    assert!(store.index_memory_estimate() < unspilled.index_memory_estimate());
+
+    let check = |store: &KvsEngine| -> Result<()> {
+        assert_eq!(store.stats()?.keys, 900);
+        for i in 0..1000 {
+            let expected = match i % 10 {
+                0 => None,
+                1 => Some(format!("new{}", i)),
+                _ => Some(format!("value{}", i)),
+            };
+            assert_eq!(store.get(format!("key{:04}", i))?, expected);
+        }
+        let pairs: Vec<(String, String)> = store.iter().collect::<Result<_>>()?;
+        assert_eq!(pairs.len(), 900);
+        assert!(pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        let scanned = store.scan("key05".to_owned(), None, 1000)?;
+        assert_eq!(scanned.len(), 90);
+        Ok(())
+    };
+    // overwrite and remove spilled keys as well as in memory ones
+    for i in (0..1000).step_by(10) {
+        store.remove(format!("key{:04}", i))?;
+        store.set(format!("key{:04}", i + 1), format!("new{}", i + 1))?;
+    }
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    // loaded from the hints of the compacted log
+    check(&KvsEngine::open_with_options(temp_dir.path(), options)?)?;
+    check(&KvsEngine::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+// Iterating should see every key while another thread overwrites and compacts
+#[test]
+fn iterate_while_writing_and_compacting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{:04}", key_id), "value0".to_owned())?;
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let store = store.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut iter = 0;
+            while !stop.load(Ordering::SeqCst) {
+                iter += 1;
+                for key_id in (0..1000).step_by(7) {
+                    store
+                        .set(format!("key{:04}", key_id), format!("value{}", iter))
+                        .unwrap();
+                }
+                store.compact().unwrap();
+            }
+            iter
+        })
+    };
+
+    for _ in 0..20 {
+        let pairs: Vec<(String, String)> = store.iter().collect::<Result<_>>()?;
+        assert_eq!(pairs.len(), 1000);
+        for (key_id, (key, value)) in pairs.iter().enumerate() {
+            assert_eq!(*key, format!("key{:04}", key_id));
+            assert!(value.starts_with("value"));
+        }
+    }
+    stop.store(true, Ordering::SeqCst);
+    assert!(writer.join().unwrap() > 0);
+    Ok(())
+}
+
+// Reads racing compactions should find the moved values, never panic on a
+// log file compacted away between the lookup and the read
+#[test]
+fn read_while_compacting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvsEngine::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("{}-0", key_id))?;
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..8)
+        .map(|reader_id| {
+            let store = store.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut reads = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    for key_id in 0..100 {
+                        let key = format!("key{}", key_id);
+                        let value = if reader_id % 2 == 0 {
+                            store.get(&key).unwrap()
+                        } else {
+                            store.try_get(&key).unwrap()
+                        };
+                        assert!(value.unwrap().starts_with(&format!("{}-", key_id)));
+                        reads += 1;
+                    }
+                }
+                reads
+            })
+        })
+        .collect();
+
+    for round in 1..=50 {
+        for key_id in (0..100).step_by(3) {
+            store.set(format!("key{}", key_id), format!("{}-{}", key_id, round))?;
+        }
+        store.compact()?;
+    }
+    stop.store(true, Ordering::SeqCst);
+    for reader in readers {
+        assert!(reader.join().unwrap() > 0);
+    }
+    for key_id in (0..100).step_by(3) {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}-50", key_id))
+        );
+    }
+    Ok(())
+}
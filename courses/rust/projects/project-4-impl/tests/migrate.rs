@@ -0,0 +1,36 @@
+use kvs::{persist_into, Engine, KvsEngine, Result};
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+// Project 1's in-memory `KvStore` lives in a crate of its own, also named
+// `kvs`, so it can't be a dependency. It wraps a `HashMap` and iterates by
+// reference the same way, which stands in for it here.
+type KvStore = HashMap<String, String>;
+
+// Every pair of an in-memory store should survive in the engine persisted
+// into, reopened
+#[test]
+fn persist_in_memory_store() -> Result<()> {
+    let mut store = KvStore::new();
+    for key_id in 0..100 {
+        store.insert(format!("key{}", key_id), format!("value{}", key_id));
+    }
+    store.insert("empty".to_owned(), String::new());
+    store.remove("key0");
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "stale".to_owned())?;
+    engine.set("other".to_owned(), "kept".to_owned())?;
+    persist_into(&store, &engine)?;
+    drop(engine);
+
+    let engine = KvsEngine::open(temp_dir.path())?;
+    for (key, value) in &store {
+        assert_eq!(engine.get(key.clone())?, Some(value.clone()));
+    }
+    assert_eq!(engine.get("key0")?, None);
+    assert_eq!(engine.get("other")?, Some("kept".to_owned()));
+    assert_eq!(engine.count_prefix(String::new())?, 101);
+    Ok(())
+}
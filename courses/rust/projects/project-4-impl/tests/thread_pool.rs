@@ -1,5 +1,6 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Barrier, Mutex};
+use std::thread;
 
 use kvs::thread_pool::*;
 use kvs::Result;
@@ -52,11 +53,11 @@ fn naive_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
-// #[test]
-// fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
-//     let pool = SharedQueueThreadPool::new(4)?;
-//     spawn_counter(pool)
-// }
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    spawn_counter(pool)
+}
 
 // #[test]
 // fn rayon_thread_pool_spawn_counter() -> Result<()> {
@@ -64,7 +65,60 @@ fn naive_thread_pool_spawn_counter() -> Result<()> {
 //     spawn_counter(pool)
 // }
 
-// #[test]
-// fn shared_queue_thread_pool_panic_task() -> Result<()> {
-//     spawn_panic_task::<SharedQueueThreadPool>()
-// }
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<SharedQueueThreadPool>()
+}
+
+// A flood of jobs should fill the queue up to its capacity and no further,
+// and every job taken should still run.
+#[test]
+fn shared_queue_thread_pool_stays_bounded() -> Result<()> {
+    const THREADS: u32 = 2;
+    const CAPACITY: usize = 4;
+
+    let pool = SharedQueueThreadPool::with_capacity(THREADS, CAPACITY)?;
+    let started = Arc::new(Barrier::new(THREADS as usize + 1));
+    let (release, gate) = mpsc::channel::<()>();
+    let gate = Arc::new(Mutex::new(gate));
+    let counter = Arc::new(AtomicUsize::new(0));
+    let wg = WaitGroup::new();
+    let job = || {
+        let (counter, wg) = (counter.clone(), wg.clone());
+        move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            drop(wg);
+        }
+    };
+
+    // keep every thread busy until released
+    for _ in 0..THREADS {
+        let (started, gate, job) = (started.clone(), gate.clone(), job());
+        pool.spawn(move || {
+            started.wait();
+            gate.lock().unwrap().recv().unwrap();
+            job();
+        });
+    }
+    started.wait();
+
+    let queued = (0..1000).filter(|_| pool.try_spawn(job())).count();
+    assert_eq!(queued, CAPACITY);
+
+    // a blocking spawn waits for room, then runs like the others
+    let spawner = {
+        let job = job();
+        thread::spawn(move || pool.spawn(job))
+    };
+    for _ in 0..THREADS {
+        release.send(()).unwrap();
+    }
+    spawner.join().unwrap();
+
+    wg.wait();
+    assert_eq!(
+        counter.load(Ordering::SeqCst),
+        THREADS as usize + CAPACITY + 1
+    );
+    Ok(())
+}